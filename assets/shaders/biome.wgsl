@@ -0,0 +1,133 @@
+#define_import_path voxel::biome
+
+#import voxel::noise::{NOISE_PERLIN, fbm2, hash3, ridged2}
+#import voxel::block::{BLOCK_STONE, BLOCK_DIRT, BLOCK_GRASS, BLOCK_SAND, BLOCK_SNOW, BLOCK_GRAVEL}
+
+// Mirrors `src/biome.rs`.
+
+const BIOME_PLAINS: u32 = 0u;
+const BIOME_DESERT: u32 = 1u;
+const BIOME_MOUNTAINS: u32 = 2u;
+const BIOME_TUNDRA: u32 = 3u;
+const BIOME_OCEAN: u32 = 4u;
+const BIOME_COUNT: u32 = 5u;
+
+const CLIMATE_FREQUENCY: f32 = 1.0 / 1024.0;
+const BIOME_FREQUENCY: f32 = 1.0 / 128.0;
+const BIOME_BLEND: f32 = 0.04;
+const FILLER_DEPTH: f32 = 3.0;
+
+struct BiomeParams {
+  climate: vec2<f32>,
+  noise: u32,
+  ridged: u32,
+  scale: f32,
+  octaves: u32,
+  amplitude: f32,
+  offset: f32,
+}
+
+struct TerrainParams {
+  biomes: array<BiomeParams, BIOME_COUNT>,
+  climate_octaves: u32,
+  warp_octaves: u32,
+  density_octaves: u32,
+  cave_octaves: u32,
+}
+
+// Part of the `voxel_gen.wgsl` bind group.
+@group(0) @binding(16) var<uniform> terrain: TerrainParams;
+
+fn biome_height(biome: u32, world_xz: vec2<f32>, seed: u32) -> f32 {
+  let params = terrain.biomes[biome];
+  let p = world_xz * BIOME_FREQUENCY * params.scale;
+  var noise: f32;
+  if (params.ridged != 0u) {
+    noise = ridged2(params.noise, p, seed, params.octaves, 2.0, 0.5);
+  } else {
+    noise = fbm2(params.noise, p, seed, params.octaves, 2.0, 0.5);
+  }
+  return noise * params.amplitude + params.offset;
+}
+
+fn biome_surface_block(biome: u32, depth: f32) -> u32 {
+  if (depth >= 1.0 + FILLER_DEPTH) {
+    return BLOCK_STONE;
+  }
+  let top = depth < 1.0;
+  switch biome {
+    case BIOME_PLAINS: { return select(BLOCK_DIRT, BLOCK_GRASS, top); }
+    case BIOME_DESERT: { return BLOCK_SAND; }
+    case BIOME_MOUNTAINS: { return select(BLOCK_GRAVEL, BLOCK_STONE, top); }
+    case BIOME_TUNDRA: { return select(BLOCK_DIRT, BLOCK_SNOW, top); }
+    default: { return select(BLOCK_GRAVEL, BLOCK_SAND, top); }
+  }
+}
+
+fn climate_at(world_xz: vec2<f32>, seed: u32) -> vec2<f32> {
+  let p = world_xz * CLIMATE_FREQUENCY;
+  let temperature = fbm2(NOISE_PERLIN, p, seed + 101u, terrain.climate_octaves, 2.0, 0.5);
+  let humidity = fbm2(NOISE_PERLIN, p, seed + 211u, terrain.climate_octaves, 2.0, 0.5);
+  return clamp(vec2<f32>(temperature, humidity) * 0.5 + 0.5, vec2<f32>(0.0), vec2<f32>(1.0));
+}
+
+fn biome_weights(climate: vec2<f32>) -> array<f32, BIOME_COUNT> {
+  var weights: array<f32, BIOME_COUNT>;
+  var total = 0.0;
+  for (var biome = 0u; biome < BIOME_COUNT; biome++) {
+    let d = climate - terrain.biomes[biome].climate;
+    let d2 = dot(d, d) / BIOME_BLEND;
+    weights[biome] = 1.0 / (d2 * d2 * d2 * d2 + 1e-6);
+    total += weights[biome];
+  }
+  for (var biome = 0u; biome < BIOME_COUNT; biome++) {
+    weights[biome] /= total;
+  }
+  return weights;
+}
+
+fn dominant_biome(weights: array<f32, BIOME_COUNT>) -> u32 {
+  var best = 0u;
+  for (var biome = 1u; biome < BIOME_COUNT; biome++) {
+    if (weights[biome] > weights[best]) {
+      best = biome;
+    }
+  }
+  return best;
+}
+
+// Shared by a column's height, surface blocks and `biome_at`.
+fn column_weights(world_xz: vec2<f32>, seed: u32) -> array<f32, BIOME_COUNT> {
+  return biome_weights(climate_at(world_xz, seed));
+}
+
+fn biome_at(world_xz: vec2<f32>, seed: u32) -> u32 {
+  return dominant_biome(column_weights(world_xz, seed));
+}
+
+// Picked at random by weight, borders dither instead of forming straight seams.
+fn surface_biome_at(world_xz: vec2<f32>, seed: u32) -> u32 {
+  let weights = column_weights(world_xz, seed);
+  let column = vec3<i32>(vec2<i32>(floor(world_xz)), 0);
+  let pick = f32(hash3(column, seed + 307u) >> 8u) * (1.0 / 16777216.0);
+  var sum = 0.0;
+  for (var biome = 0u; biome < BIOME_COUNT; biome++) {
+    sum += weights[biome];
+    if (pick < sum) {
+      return biome;
+    }
+  }
+  return dominant_biome(weights);
+}
+
+// Weights of the column at `world_xz`, height noise sampled at the domain warped `warped`.
+fn blended_height(world_xz: vec2<f32>, warped: vec2<f32>, seed: u32) -> f32 {
+  let weights = column_weights(world_xz, seed);
+  var height = 0.0;
+  for (var biome = 0u; biome < BIOME_COUNT; biome++) {
+    if (weights[biome] > 1e-3) {
+      height += weights[biome] * biome_height(biome, warped, seed + 7u);
+    }
+  }
+  return height;
+}
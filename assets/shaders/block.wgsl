@@ -0,0 +1,32 @@
+#define_import_path voxel::block
+
+// Mirrors `BlockId` in `src/block.rs`.
+const BLOCK_AIR: u32 = 0u;
+const BLOCK_STONE: u32 = 1u;
+const BLOCK_DIRT: u32 = 2u;
+const BLOCK_GRASS: u32 = 3u;
+const BLOCK_SAND: u32 = 4u;
+const BLOCK_SNOW: u32 = 5u;
+const BLOCK_GRAVEL: u32 = 6u;
+const BLOCK_WOOD: u32 = 7u;
+const BLOCK_LEAVES: u32 = 8u;
+const BLOCK_COAL_ORE: u32 = 9u;
+const BLOCK_IRON_ORE: u32 = 10u;
+const BLOCK_WATER: u32 = 11u;
+
+fn block_color(block: u32) -> vec3<f32> {
+  switch block {
+    case BLOCK_STONE: { return vec3<f32>(0.5, 0.5, 0.52); }
+    case BLOCK_DIRT: { return vec3<f32>(0.45, 0.32, 0.2); }
+    case BLOCK_GRASS: { return vec3<f32>(0.3, 0.6, 0.2); }
+    case BLOCK_SAND: { return vec3<f32>(0.86, 0.8, 0.55); }
+    case BLOCK_SNOW: { return vec3<f32>(0.95, 0.97, 1.0); }
+    case BLOCK_GRAVEL: { return vec3<f32>(0.42, 0.4, 0.38); }
+    case BLOCK_WOOD: { return vec3<f32>(0.4, 0.26, 0.13); }
+    case BLOCK_LEAVES: { return vec3<f32>(0.18, 0.42, 0.15); }
+    case BLOCK_COAL_ORE: { return vec3<f32>(0.2, 0.2, 0.2); }
+    case BLOCK_IRON_ORE: { return vec3<f32>(0.7, 0.55, 0.45); }
+    case BLOCK_WATER: { return vec3<f32>(0.15, 0.35, 0.65); }
+    default: { return vec3<f32>(1.0, 0.0, 1.0); }
+  }
+}
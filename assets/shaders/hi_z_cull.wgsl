@@ -0,0 +1,109 @@
+struct HiZCull {
+  clip_from_world: mat4x4<f32>,
+  pyramid_size: vec2<u32>,
+  mip_count: u32,
+  enabled: u32,
+  chunk_size: f32,
+  vertices_per_chunk: u32,
+  water_vertices_per_chunk: u32,
+};
+
+struct ChunkCoord {
+  coord: vec3<i32>,
+  global_index: u32,
+};
+
+struct DrawIndirectArgs {
+  vertex_count: u32,
+  instance_count: u32,
+  first_vertex: u32,
+  first_instance: u32,
+};
+
+@group(0) @binding(0) var<uniform> cull: HiZCull;
+@group(0) @binding(1) var<storage, read> chunks: array<ChunkCoord>;
+@group(0) @binding(2) var depth_pyramid: texture_2d<f32>;
+@group(0) @binding(3) var<storage, read_write> draw_args: array<DrawIndirectArgs>;
+@group(0) @binding(4) var<storage, read> visible: array<u32>;
+@group(0) @binding(5) var<storage, read> vertex_counts: array<u32>;
+@group(0) @binding(6) var<storage, read> water_vertex_counts: array<u32>;
+@group(0) @binding(7) var<storage, read_write> water_draw_args: array<DrawIndirectArgs>;
+
+fn is_occluded(min_corner: vec3<f32>, max_corner: vec3<f32>) -> bool {
+  var uv_min = vec2<f32>(1.0);
+  var uv_max = vec2<f32>(0.0);
+  var nearest = 0.0;
+
+  for (var i = 0u; i < 8u; i++) {
+    let corner = select(
+      min_corner,
+      max_corner,
+      vec3<bool>((i & 1u) != 0u, (i & 2u) != 0u, (i & 4u) != 0u),
+    );
+    let clip = cull.clip_from_world * vec4<f32>(corner, 1.0);
+
+    // Crosses the near plane, the camera is (almost) inside the box.
+    if (clip.w <= 0.0) {
+      return false;
+    }
+
+    let ndc = clip.xyz / clip.w;
+    let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    uv_min = min(uv_min, uv);
+    uv_max = max(uv_max, uv);
+    nearest = max(nearest, ndc.z);
+  }
+
+  uv_min = clamp(uv_min, vec2<f32>(0.0), vec2<f32>(1.0));
+  uv_max = clamp(uv_max, vec2<f32>(0.0), vec2<f32>(1.0));
+  if (any(uv_min >= uv_max)) {
+    // Off screen, left to frustum culling.
+    return false;
+  }
+
+  // Pick the level where the footprint covers at most 2x2 texels.
+  let size = (uv_max - uv_min) * vec2<f32>(cull.pyramid_size);
+  let mip = min(u32(ceil(log2(max(max(size.x, size.y), 1.0)))), cull.mip_count - 1u);
+  let mip_size = textureDimensions(depth_pyramid, mip);
+  let lo = min(vec2<u32>(uv_min * vec2<f32>(mip_size)), mip_size - 1u);
+  let hi = min(vec2<u32>(uv_max * vec2<f32>(mip_size)), mip_size - 1u);
+
+  let farthest = min(
+    min(textureLoad(depth_pyramid, lo, mip).r, textureLoad(depth_pyramid, vec2<u32>(hi.x, lo.y), mip).r),
+    min(textureLoad(depth_pyramid, vec2<u32>(lo.x, hi.y), mip).r, textureLoad(depth_pyramid, hi, mip).r),
+  );
+
+  // Reverse-Z: the box is hidden when even its nearest point is behind everything drawn there.
+  return nearest < farthest;
+}
+
+@compute @workgroup_size(64,1,1)
+fn cull_chunks(@builtin(global_invocation_id) gid: vec3<u32>) {
+  let chunk = gid.x;
+  if (chunk >= arrayLength(&chunks)) {
+      return;
+  }
+
+  var args: DrawIndirectArgs;
+  // The block mesher keeps counting past the slot's budget, the faces past it were dropped.
+  args.vertex_count = min(vertex_counts[chunk], cull.vertices_per_chunk);
+  args.instance_count = 1u;
+  args.first_vertex = chunk * cull.vertices_per_chunk;
+  args.first_instance = 0u;
+
+  if (chunks[chunk].global_index == 0xFFFFFFFFu || visible[chunk] == 0u) {
+    args.instance_count = 0u;
+  } else if (cull.enabled != 0u) {
+    let min_corner = vec3<f32>(chunks[chunk].coord) * cull.chunk_size;
+    if (is_occluded(min_corner, min_corner + vec3<f32>(cull.chunk_size))) {
+      args.instance_count = 0u;
+    }
+  }
+
+  draw_args[chunk] = args;
+
+  var water_args = args;
+  water_args.vertex_count = min(water_vertex_counts[chunk], cull.water_vertices_per_chunk);
+  water_args.first_vertex = chunk * cull.water_vertices_per_chunk;
+  water_draw_args[chunk] = water_args;
+}
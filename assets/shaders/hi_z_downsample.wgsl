@@ -0,0 +1,25 @@
+@group(0) @binding(0) var input_depth: texture_2d<f32>;
+@group(0) @binding(1) var output_depth: texture_storage_2d<r32float, write>;
+
+// Reverse-Z: smaller depth is farther away, so every texel keeps the farthest depth it covers.
+@compute @workgroup_size(8,8,1)
+fn downsample(@builtin(global_invocation_id) gid: vec3<u32>) {
+  let out_size = textureDimensions(output_depth);
+  if (gid.x >= out_size.x || gid.y >= out_size.y) {
+      return;
+  }
+
+  let in_max = textureDimensions(input_depth) - 1u;
+  let base = gid.xy * 2u;
+
+  // 3x3 footprint so odd source sizes never drop their last row or column.
+  var farthest = 1.0;
+  for (var y = 0u; y < 3u; y++) {
+    for (var x = 0u; x < 3u; x++) {
+      let texel = min(base + vec2<u32>(x, y), in_max);
+      farthest = min(farthest, textureLoad(input_depth, texel, 0).r);
+    }
+  }
+
+  textureStore(output_depth, gid.xy, vec4<f32>(farthest, 0.0, 0.0, 0.0));
+}
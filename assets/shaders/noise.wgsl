@@ -0,0 +1,215 @@
+#define_import_path voxel::noise
+
+// Seeded gradient noise. `src/noise.rs` mirrors every function operation for operation,
+// keep the two in sync.
+
+const NOISE_VALUE: u32 = 0u;
+const NOISE_PERLIN: u32 = 1u;
+const NOISE_SIMPLEX: u32 = 2u;
+
+// PCG hash
+fn hash(x: u32) -> u32 {
+  let state = x * 747796405u + 2891336453u;
+  let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+  return (word >> 22u) ^ word;
+}
+
+fn hash3(p: vec3<i32>, seed: u32) -> u32 {
+  return hash(bitcast<u32>(p.x) ^ hash(bitcast<u32>(p.y) ^ hash(bitcast<u32>(p.z) ^ hash(seed))));
+}
+
+// Uniform in [-1, 1), exact in f32 so both sides agree.
+fn hash_to_signed(h: u32) -> f32 {
+  return f32(h >> 8u) * (2.0 / 16777216.0) - 1.0;
+}
+
+fn fade(t: f32) -> f32 {
+  return t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+}
+
+// Improved Perlin noise gradients, 12 cube edge directions.
+fn grad(h: u32, x: f32, y: f32, z: f32) -> f32 {
+  let b = h & 15u;
+  var u = y;
+  if (b < 8u) {
+    u = x;
+  }
+  var v = z;
+  if (b < 4u) {
+    v = y;
+  } else if (b == 12u || b == 14u) {
+    v = x;
+  }
+  var result = v;
+  if ((b & 2u) != 0u) {
+    result = -v;
+  }
+  if ((b & 1u) != 0u) {
+    return result - u;
+  }
+  return result + u;
+}
+
+fn value_noise3(p: vec3<f32>, seed: u32) -> f32 {
+  let cell = floor(p);
+  let i = vec3<i32>(cell);
+  let f = p - cell;
+  let u = vec3<f32>(fade(f.x), fade(f.y), fade(f.z));
+
+  let c000 = hash_to_signed(hash3(i, seed));
+  let c100 = hash_to_signed(hash3(i + vec3<i32>(1, 0, 0), seed));
+  let c010 = hash_to_signed(hash3(i + vec3<i32>(0, 1, 0), seed));
+  let c110 = hash_to_signed(hash3(i + vec3<i32>(1, 1, 0), seed));
+  let c001 = hash_to_signed(hash3(i + vec3<i32>(0, 0, 1), seed));
+  let c101 = hash_to_signed(hash3(i + vec3<i32>(1, 0, 1), seed));
+  let c011 = hash_to_signed(hash3(i + vec3<i32>(0, 1, 1), seed));
+  let c111 = hash_to_signed(hash3(i + vec3<i32>(1, 1, 1), seed));
+
+  let x00 = c000 + (c100 - c000) * u.x;
+  let x10 = c010 + (c110 - c010) * u.x;
+  let x01 = c001 + (c101 - c001) * u.x;
+  let x11 = c011 + (c111 - c011) * u.x;
+  let y0 = x00 + (x10 - x00) * u.y;
+  let y1 = x01 + (x11 - x01) * u.y;
+  return y0 + (y1 - y0) * u.z;
+}
+
+fn perlin_noise3(p: vec3<f32>, seed: u32) -> f32 {
+  let cell = floor(p);
+  let i = vec3<i32>(cell);
+  let f = p - cell;
+  let u = vec3<f32>(fade(f.x), fade(f.y), fade(f.z));
+
+  let g000 = grad(hash3(i, seed), f.x, f.y, f.z);
+  let g100 = grad(hash3(i + vec3<i32>(1, 0, 0), seed), f.x - 1.0, f.y, f.z);
+  let g010 = grad(hash3(i + vec3<i32>(0, 1, 0), seed), f.x, f.y - 1.0, f.z);
+  let g110 = grad(hash3(i + vec3<i32>(1, 1, 0), seed), f.x - 1.0, f.y - 1.0, f.z);
+  let g001 = grad(hash3(i + vec3<i32>(0, 0, 1), seed), f.x, f.y, f.z - 1.0);
+  let g101 = grad(hash3(i + vec3<i32>(1, 0, 1), seed), f.x - 1.0, f.y, f.z - 1.0);
+  let g011 = grad(hash3(i + vec3<i32>(0, 1, 1), seed), f.x, f.y - 1.0, f.z - 1.0);
+  let g111 = grad(hash3(i + vec3<i32>(1, 1, 1), seed), f.x - 1.0, f.y - 1.0, f.z - 1.0);
+
+  let x00 = g000 + (g100 - g000) * u.x;
+  let x10 = g010 + (g110 - g010) * u.x;
+  let x01 = g001 + (g101 - g001) * u.x;
+  let x11 = g011 + (g111 - g011) * u.x;
+  let y0 = x00 + (x10 - x00) * u.y;
+  let y1 = x01 + (x11 - x01) * u.y;
+  return y0 + (y1 - y0) * u.z;
+}
+
+fn simplex_corner(d: vec3<f32>, h: u32) -> f32 {
+  let t = 0.6 - d.x * d.x - d.y * d.y - d.z * d.z;
+  if (t <= 0.0) {
+    return 0.0;
+  }
+  let t2 = t * t;
+  return t2 * t2 * grad(h, d.x, d.y, d.z);
+}
+
+fn simplex_noise3(p: vec3<f32>, seed: u32) -> f32 {
+  let F3 = 1.0 / 3.0;
+  let G3 = 1.0 / 6.0;
+
+  let s = (p.x + p.y + p.z) * F3;
+  let cell = floor(p + vec3<f32>(s));
+  let i = vec3<i32>(cell);
+  let t = (cell.x + cell.y + cell.z) * G3;
+  let d0 = p - (cell - vec3<f32>(t));
+
+  var o1: vec3<i32>;
+  var o2: vec3<i32>;
+  if (d0.x >= d0.y) {
+    if (d0.y >= d0.z) {
+      o1 = vec3<i32>(1, 0, 0); o2 = vec3<i32>(1, 1, 0);
+    } else if (d0.x >= d0.z) {
+      o1 = vec3<i32>(1, 0, 0); o2 = vec3<i32>(1, 0, 1);
+    } else {
+      o1 = vec3<i32>(0, 0, 1); o2 = vec3<i32>(1, 0, 1);
+    }
+  } else {
+    if (d0.y < d0.z) {
+      o1 = vec3<i32>(0, 0, 1); o2 = vec3<i32>(0, 1, 1);
+    } else if (d0.x < d0.z) {
+      o1 = vec3<i32>(0, 1, 0); o2 = vec3<i32>(0, 1, 1);
+    } else {
+      o1 = vec3<i32>(0, 1, 0); o2 = vec3<i32>(1, 1, 0);
+    }
+  }
+
+  let d1 = d0 - vec3<f32>(o1) + vec3<f32>(G3);
+  let d2 = d0 - vec3<f32>(o2) + vec3<f32>(2.0 * G3);
+  let d3 = d0 - vec3<f32>(1.0) + vec3<f32>(3.0 * G3);
+
+  let n = simplex_corner(d0, hash3(i, seed))
+        + simplex_corner(d1, hash3(i + o1, seed))
+        + simplex_corner(d2, hash3(i + o2, seed))
+        + simplex_corner(d3, hash3(i + vec3<i32>(1, 1, 1), seed));
+
+  // Scale to roughly [-1, 1]
+  return 32.0 * n;
+}
+
+fn noise3(kind: u32, p: vec3<f32>, seed: u32) -> f32 {
+  switch kind {
+    case NOISE_VALUE: { return value_noise3(p, seed); }
+    case NOISE_SIMPLEX: { return simplex_noise3(p, seed); }
+    default: { return perlin_noise3(p, seed); }
+  }
+}
+
+// Fractal Brownian motion, normalised back to roughly [-1, 1].
+fn fbm3(kind: u32, p: vec3<f32>, seed: u32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+  var sum = 0.0;
+  var amplitude = 1.0;
+  var total = 0.0;
+  var q = p;
+  for (var octave = 0u; octave < octaves; octave++) {
+    sum += noise3(kind, q, seed + octave) * amplitude;
+    total += amplitude;
+    amplitude *= gain;
+    q *= lacunarity;
+  }
+  return sum / total;
+}
+
+fn fbm2(kind: u32, p: vec2<f32>, seed: u32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+  return fbm3(kind, vec3<f32>(p, 0.0), seed, octaves, lacunarity, gain);
+}
+
+// Ridged multifractal in [0, 1], sharp crests where the noise crosses zero.
+fn ridged3(kind: u32, p: vec3<f32>, seed: u32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+  var sum = 0.0;
+  var amplitude = 1.0;
+  var total = 0.0;
+  var weight = 1.0;
+  var q = p;
+  for (var octave = 0u; octave < octaves; octave++) {
+    var ridge = 1.0 - abs(noise3(kind, q, seed + octave));
+    ridge = ridge * ridge * weight;
+    weight = clamp(ridge * 2.0, 0.0, 1.0);
+    sum += ridge * amplitude;
+    total += amplitude;
+    amplitude *= gain;
+    q *= lacunarity;
+  }
+  return sum / total;
+}
+
+fn ridged2(kind: u32, p: vec2<f32>, seed: u32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+  return ridged3(kind, vec3<f32>(p, 0.0), seed, octaves, lacunarity, gain);
+}
+
+// Offsets `p` by an fBm vector field, sample the noise of your choice at the result.
+fn domain_warp3(kind: u32, p: vec3<f32>, seed: u32, octaves: u32, strength: f32) -> vec3<f32> {
+  let offset = vec3<f32>(
+    fbm3(kind, p, seed, octaves, 2.0, 0.5),
+    fbm3(kind, p + vec3<f32>(5.2, 1.3, 7.1), seed + 1013u, octaves, 2.0, 0.5),
+    fbm3(kind, p + vec3<f32>(1.7, 9.2, 3.4), seed + 2029u, octaves, 2.0, 0.5),
+  );
+  return p + offset * strength;
+}
+
+fn domain_warp2(kind: u32, p: vec2<f32>, seed: u32, octaves: u32, strength: f32) -> vec2<f32> {
+  return domain_warp3(kind, vec3<f32>(p, 0.0), seed, octaves, strength).xy;
+}
@@ -0,0 +1,31 @@
+#import bevy_render::view::View
+#import voxel::block::block_color
+
+@group(0) @binding(0) var<uniform> view: View;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+    @location(1) @interpolate(flat) block: u32,
+};
+
+@vertex
+fn vs_main(@location(0) position: vec4<f32>) -> VertexOutput {
+    var out: VertexOutput;
+    out.world_position = position.xyz;
+    out.block = u32(position.w);
+    out.clip_position = view.clip_from_world * vec4<f32>(position.xyz, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = normalize(cross(dpdx(in.world_position), dpdy(in.world_position)));
+    let light = 0.4 + 0.6 * abs(dot(normal, normalize(vec3<f32>(0.3, 1.0, 0.5))));
+    return vec4<f32>(block_color(in.block) * light, 1.0);
+}
+
+@fragment
+fn fs_water(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(block_color(in.block), 0.6);
+}
@@ -1,5 +1,21 @@
+#import voxel::noise::{NOISE_PERLIN, domain_warp2, fbm3, hash3, ridged3}
+#import voxel::biome::{biome_surface_block, blended_height, surface_biome_at, terrain}
+#import voxel::block::{BLOCK_AIR, BLOCK_WATER}
+
+const MESHER_HEIGHT_FIELD: u32 = 0u;
+const MESHER_BLOCKS: u32 = 1u;
+
 struct Globals {
   chunk_size: u32,
+  seed: u32,
+  vertices_per_chunk: u32,
+  mesher: u32,
+  density_threshold: f32,
+  // Whether generators read the surface from the eroded regions.
+  erosion: u32,
+  water_vertices_per_chunk: u32,
+  // `ChunkJobs::ticket` of this frame's jobs, stamped on their solid masks.
+  job_ticket: u32,
 };
 
 struct ChunkCoord {
@@ -9,13 +25,111 @@ struct ChunkCoord {
 
 struct Vertex {
   pos: vec3<f32>,
-  _pad: f32,
+  // `BlockId` of the face, colours the fragment.
+  block: f32,
+};
+
+// Mirrors `VoxelWrite` in `decoration.rs`.
+struct VoxelWrite {
+  slot: u32,
+  voxel: u32,
+  block: u32,
+  // Block the voxel must hold, 0xFFFFFFFF for any.
+  replace: u32,
+  density: f32,
+};
+
+const SOLID_MASK_WORDS: u32 = 128u;
+
+struct ChunkSolidMask {
+  coord: vec3<i32>,
+  // Ticket of the job that wrote the mask, 0 for slots never meshed.
+  ticket: u32,
+  // Vertices the meshers wanted to write, faces past the slot's budget were dropped.
+  vertex_count: u32,
+  water_vertex_count: u32,
+  padding: vec2<u32>,
+  bits: array<u32, SOLID_MASK_WORDS>,
 };
 
 @group(0) @binding(0) var<uniform> globals: Globals;
-@group(0) @binding(1) var<storage, read> chunks: array<ChunkCoord>;
+// Chunks to generate this frame, `global_index` is the slot their voxels and vertices go to.
+@group(0) @binding(1) var<storage, read> jobs: array<ChunkCoord>;
 @group(0) @binding(2) var<storage, read_write> voxel_buffer: array<f32>;
 @group(0) @binding(3) var<storage, read_write> vertecies_output: array<Vertex>;
+@group(0) @binding(4) var<storage, read_write> solid_masks: array<ChunkSolidMask>;
+// Vertices written to each slot, the draw count of its indirect args.
+@group(0) @binding(5) var<storage, read_write> vertex_counts: array<atomic<u32>>;
+// Open addressing table from chunk coordinate to the slot of every chunk with voxels.
+@group(0) @binding(6) var<storage, read> chunk_lookup: array<ChunkCoord>;
+// `BlockId` of every voxel, laid out like `voxel_buffer`.
+@group(0) @binding(7) var<storage, read_write> blocks: array<u32>;
+@group(0) @binding(8) var<storage, read> voxel_writes: array<VoxelWrite>;
+// Regions with a slot of `region_heights`, mirrors `RegionEntry` in `erosion.rs`.
+@group(0) @binding(9) var<storage, read> region_table: array<RegionEntry>;
+// Per slot a header of `[coord.x, coord.y, ticket, _]` bit cast to floats, then the heights.
+@group(0) @binding(10) var<storage, read_write> region_heights: array<f32>;
+@group(0) @binding(11) var<storage, read_write> region_scratch: array<f32>;
+@group(0) @binding(12) var<uniform> erosion: ErosionParams;
+// Sea level, lakes and rivers around the loaded chunks, mirrors `WaterData` in `water.rs`.
+@group(0) @binding(13) var<storage, read> water_bodies: WaterBodies;
+// Translucent output, laid out like `vertecies_output` with its own budget per slot.
+@group(0) @binding(14) var<storage, read_write> water_vertices: array<Vertex>;
+@group(0) @binding(15) var<storage, read_write> water_vertex_counts: array<atomic<u32>>;
+// Binding 16, `terrain`, is declared by `voxel::biome`.
+
+const TERRAIN_FREQUENCY: f32 = 1.0 / 128.0;
+
+// Mirrored by `terrain_height` in `terrain_generator.rs`.
+fn terrain_height(world_xz: vec2<f32>, seed: u32) -> f32 {
+  let warped = domain_warp2(NOISE_PERLIN, world_xz * TERRAIN_FREQUENCY, seed, terrain.warp_octaves, 0.5) / TERRAIN_FREQUENCY;
+  return blended_height(world_xz, warped, seed);
+}
+
+// Eroded height of the column when its region has been eroded, the raw terrain otherwise.
+fn surface_height(world_xz: vec2<f32>, seed: u32) -> f32 {
+  if (globals.erosion != 0u) {
+    let column = vec2<i32>(floor(world_xz));
+    let region = vec2<i32>(div_floor(column.x, REGION_COLUMNS), div_floor(column.y, REGION_COLUMNS));
+    for (var i = 0u; i < arrayLength(&region_table); i++) {
+      let entry = region_table[i];
+      if (entry.state == REGION_READY && all(entry.coord == region)) {
+        let local = vec2<u32>(column - region * REGION_COLUMNS + REGION_MARGIN);
+        return region_heights[region_base(entry.slot) + local.y * REGION_SIZE + local.x];
+      }
+    }
+  }
+  return terrain_height(world_xz, seed);
+}
+
+// Mirrored by `terrain_block` in `terrain_generator.rs`.
+fn terrain_block(world: vec3<f32>, seed: u32) -> u32 {
+  let depth = surface_height(world.xz, seed) - world.y;
+  return biome_surface_block(surface_biome_at(world.xz, seed), depth);
+}
+
+fn write_vertex(index: u32, pos: vec3<f32>, block: u32) {
+  vertecies_output[index] = Vertex(pos, f32(block));
+}
+
+fn write_water_vertex(index: u32, pos: vec3<f32>) {
+  water_vertices[index] = Vertex(pos, f32(BLOCK_WATER));
+}
+
+const DENSITY_FREQUENCY: f32 = 1.0 / 32.0;
+const DENSITY_HEIGHT_BIAS: f32 = 1.0 / 12.0;
+const CAVE_FREQUENCY: f32 = 1.0 / 48.0;
+const CAVE_RIDGE: f32 = 0.85;
+const CAVE_STRENGTH: f32 = 8.0;
+
+// Mirrored by `terrain_density` in `terrain_generator.rs`.
+fn terrain_density(world: vec3<f32>, seed: u32) -> f32 {
+  let surface = surface_height(world.xz, seed);
+  let bias = (surface - world.y) * DENSITY_HEIGHT_BIAS;
+  let shape = fbm3(NOISE_PERLIN, world * DENSITY_FREQUENCY, seed + 31u, terrain.density_octaves, 2.0, 0.5);
+  let caves = ridged3(NOISE_PERLIN, world * CAVE_FREQUENCY, seed + 67u, terrain.cave_octaves, 2.0, 0.5);
+  return shape + bias - max(caves - CAVE_RIDGE, 0.0) * CAVE_STRENGTH;
+}
 
 fn height_at(x: u32, y: u32, chunk: u32, N: u32) -> f32 {
   let voxel_id =
@@ -27,6 +141,48 @@ fn height_at(x: u32, y: u32, chunk: u32, N: u32) -> f32 {
   return voxel_buffer[voxel_id];
 }
 
+// The z=1 slice holds the water surface of each column, written by `apply_water`.
+fn water_at(x: u32, y: u32, chunk: u32, N: u32) -> f32 {
+  return voxel_buffer[chunk * N * N * N + 1u * N * N + y * N + x];
+}
+
+// Water quad over a height-field quad whose terrain dips below the water surface.
+fn emit_water_quad(x: u32, y: u32, job: u32) {
+  let N = globals.chunk_size;
+  let chunk = jobs[job].global_index;
+  let w00 = water_at(x, y, chunk, N);
+  let w10 = water_at(x + 1u, y, chunk, N);
+  let w01 = water_at(x, y + 1u, chunk, N);
+  let w11 = water_at(x + 1u, y + 1u, chunk, N);
+  let h00 = height_at(x, y, chunk, N);
+  let h10 = height_at(x + 1u, y, chunk, N);
+  let h01 = height_at(x, y + 1u, chunk, N);
+  let h11 = height_at(x + 1u, y + 1u, chunk, N);
+
+  // The chunk the surface lies in meshes it.
+  if (w00 < 0.0 || w00 >= f32(N) || all(vec4<f32>(h00, h10, h01, h11) >= vec4<f32>(w00, w10, w01, w11))) {
+    return;
+  }
+
+  let first = atomicAdd(&water_vertex_counts[chunk], 6u);
+  if (first + 6u > globals.water_vertices_per_chunk) {
+    return;
+  }
+
+  let base = chunk * globals.water_vertices_per_chunk + first;
+  let chunk_offset = vec3<f32>(jobs[job].coord) * f32(N);
+  let p00 = chunk_offset + vec3<f32>(f32(x), w00, f32(y));
+  let p10 = chunk_offset + vec3<f32>(f32(x + 1u), w10, f32(y));
+  let p01 = chunk_offset + vec3<f32>(f32(x), w01, f32(y + 1u));
+  let p11 = chunk_offset + vec3<f32>(f32(x + 1u), w11, f32(y + 1u));
+  write_water_vertex(base + 0u, p00);
+  write_water_vertex(base + 1u, p10);
+  write_water_vertex(base + 2u, p01);
+  write_water_vertex(base + 3u, p10);
+  write_water_vertex(base + 4u, p11);
+  write_water_vertex(base + 5u, p01);
+}
+
 @compute @workgroup_size(8,8,1)
 fn generate_vertecies(@builtin(global_invocation_id) gid: vec3<u32>) {
   let N = globals.chunk_size;
@@ -37,23 +193,27 @@ fn generate_vertecies(@builtin(global_invocation_id) gid: vec3<u32>) {
 
   let x = gid.x;
   let y = gid.y;
-  let chunk = gid.z;
+  let job = gid.z;
 
   // Bounds: only quads
   if (x >= N - 1u || y >= N - 1u) {
       return;
   }
 
-  if (chunk >= arrayLength(&chunks)) {
+  if (job >= arrayLength(&jobs) || jobs[job].global_index == 0xFFFFFFFFu) {
       return;
   }
 
-  let quad_index =
-      chunk * quads_per_row * quads_per_row +
-      y * quads_per_row +
-      x;
+  let chunk = jobs[job].global_index;
 
-  let vertex_base = quad_index * 6u;
+  let quad_index = y * quads_per_row + x;
+  let vertex_base = chunk * globals.vertices_per_chunk + quad_index * verts_per_quad;
+
+  if (quad_index == 0u) {
+    atomicStore(&vertex_counts[chunk], verts_per_chunk);
+  }
+
+  emit_water_quad(x, y, job);
 
   // Heights
   let h00 = height_at(x,     y,     chunk, N);
@@ -61,43 +221,48 @@ fn generate_vertecies(@builtin(global_invocation_id) gid: vec3<u32>) {
   let h01 = height_at(x,     y + 1u,chunk, N);
   let h11 = height_at(x + 1u,y + 1u,chunk, N);
 
-  if (max(h00, max(h10, max(h01, h11))) < 0.001) {
+  // Surface below or above this chunk, the chunk under or over it meshes it.
+  if (max(h00, max(h10, max(h01, h11))) < 0.001 || min(h00, min(h10, min(h01, h11))) > f32(N)) {
     let p = vec3<f32>(0.0);
     for (var i = 0u; i < 6u; i++) {
-        vertecies_output[vertex_base + i].pos = p;
+        write_vertex(vertex_base + i, p, BLOCK_AIR);
     }
     return;
   }
 
   // World offset
   let chunk_offset =
-      vec3<f32>(chunks[chunk].coord) * f32(N);
+      vec3<f32>(jobs[job].coord) * f32(N);
 
   let p00 = chunk_offset + vec3<f32>(f32(x),     h00, f32(y));
   let p10 = chunk_offset + vec3<f32>(f32(x + 1u),h10, f32(y));
   let p01 = chunk_offset + vec3<f32>(f32(x),     h01, f32(y + 1u));
   let p11 = chunk_offset + vec3<f32>(f32(x + 1u),h11, f32(y + 1u));
 
+  let block = blocks[chunk * N * N * N + y * N + x];
+
   // Triangle 1
-  vertecies_output[vertex_base + 0u].pos = p00;
-  vertecies_output[vertex_base + 1u].pos = p10;
-  vertecies_output[vertex_base + 2u].pos = p01;
+  write_vertex(vertex_base + 0u, p00, block);
+  write_vertex(vertex_base + 1u, p10, block);
+  write_vertex(vertex_base + 2u, p01, block);
 
   // Triangle 2
-  vertecies_output[vertex_base + 3u].pos = p10;
-  vertecies_output[vertex_base + 4u].pos = p11;
-  vertecies_output[vertex_base + 5u].pos = p01;
+  write_vertex(vertex_base + 3u, p10, block);
+  write_vertex(vertex_base + 4u, p11, block);
+  write_vertex(vertex_base + 5u, p01, block);
 }
 
 @compute @workgroup_size(4,4,4)
 fn generate_height_map(@builtin(global_invocation_id) gid: vec3<u32>) {
-  let chunk_index = gid.z / globals.chunk_size;
-  let voxel_z     = gid.z % globals.chunk_size;
+  let job     = gid.z / globals.chunk_size;
+  let voxel_z = gid.z % globals.chunk_size;
 
-  if (chunk_index >= arrayLength(&chunks)) {
+  if (job >= arrayLength(&jobs) || jobs[job].global_index == 0xFFFFFFFFu) {
       return;
   }
 
+  let chunk_index = jobs[job].global_index;
+
   let voxel_local = vec3<u32>(gid.x, gid.y, voxel_z);
 
   let voxels_per_chunk =
@@ -109,15 +274,594 @@ fn generate_height_map(@builtin(global_invocation_id) gid: vec3<u32>) {
       voxel_local.y * globals.chunk_size +
       voxel_local.x;
 
-  if (chunks[chunk_index].global_index == 0xFFFFFFFFu) {
-      voxel_buffer[voxel_id] = 0.0;
+  // The mesher lays the heightmap out as (x, h, y), store heights relative to the chunk's bottom.
+  let coord = jobs[job].coord;
+  let n = i32(globals.chunk_size);
+  let world_xz = vec2<f32>(vec2<i32>(coord.x * n + i32(gid.x), coord.z * n + i32(gid.y)));
+
+  voxel_buffer[voxel_id] = surface_height(world_xz, globals.seed) - f32(coord.y * n);
+  blocks[voxel_id] = biome_surface_block(surface_biome_at(world_xz, globals.seed), 0.0);
+}
+
+@compute @workgroup_size(4,4,4)
+fn generate_density(@builtin(global_invocation_id) gid: vec3<u32>) {
+  let N = globals.chunk_size;
+  let job = gid.z / N;
+
+  if (job >= arrayLength(&jobs) || jobs[job].global_index == 0xFFFFFFFFu) {
+      return;
+  }
+
+  let local = vec3<u32>(gid.x, gid.y, gid.z % N);
+  let voxel_id = jobs[job].global_index * N * N * N + local.z * N * N + local.y * N + local.x;
+  let world = vec3<f32>(jobs[job].coord * i32(N) + vec3<i32>(local));
+
+  let density = terrain_density(world, globals.seed);
+  voxel_buffer[voxel_id] = density;
+  blocks[voxel_id] = select(BLOCK_AIR, terrain_block(world, globals.seed), density >= globals.density_threshold);
+}
+
+// Feature blocks of the chunks generated this frame, dispatched between the generator and the mesher.
+@compute @workgroup_size(64,1,1)
+fn apply_voxel_writes(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if (gid.x >= arrayLength(&voxel_writes)) {
+      return;
+  }
+
+  let entry = voxel_writes[gid.x];
+  if (entry.slot == 0xFFFFFFFFu) {
+      return;
+  }
+
+  let N = globals.chunk_size;
+  let voxel_id = entry.slot * N * N * N + entry.voxel;
+  if (entry.replace != 0xFFFFFFFFu && blocks[voxel_id] != entry.replace) {
+      return;
+  }
+
+  blocks[voxel_id] = entry.block;
+  voxel_buffer[voxel_id] = max(voxel_buffer[voxel_id], entry.density);
+}
+
+@compute @workgroup_size(64,1,1)
+fn reset_vertex_counts(@builtin(global_invocation_id) gid: vec3<u32>) {
+  let job = gid.x;
+  if (job >= arrayLength(&jobs) || jobs[job].global_index == 0xFFFFFFFFu) {
       return;
   }
+  atomicStore(&vertex_counts[jobs[job].global_index], 0u);
+  atomicStore(&water_vertex_counts[jobs[job].global_index], 0u);
+}
+
+// Slot of the chunk at `coord` if its voxels are in the voxel buffer. Mirrors `ChunkLookup`.
+fn lookup_slot(coord: vec3<i32>) -> u32 {
+  let size = arrayLength(&chunk_lookup);
+  var index = hash3(coord, 0u) & (size - 1u);
+  for (var probe = 0u; probe < size; probe++) {
+    let entry = chunk_lookup[index];
+    if (entry.global_index == 0xFFFFFFFFu) {
+      return 0xFFFFFFFFu;
+    }
+    if (all(entry.coord == coord)) {
+      return entry.global_index;
+    }
+    index = (index + 1u) & (size - 1u);
+  }
+  return 0xFFFFFFFFu;
+}
+
+fn is_solid(slot: u32, local: vec3<u32>) -> bool {
+  let N = globals.chunk_size;
+  return voxel_buffer[slot * N * N * N + local.z * N * N + local.y * N + local.x] >= globals.density_threshold;
+}
+
+// Index of a voxel of the chunk or of its neighbours, 0xFFFFFFFF when that neighbour has no voxels yet.
+fn neighbour_voxel(coord: vec3<i32>, slot: u32, local: vec3<i32>) -> u32 {
+  let n = i32(globals.chunk_size);
+  let chunk_offset = vec3<i32>(floor(vec3<f32>(local) / f32(n)));
+  var neighbour = slot;
+  if (any(chunk_offset != vec3<i32>(0))) {
+    neighbour = lookup_slot(coord + chunk_offset);
+    if (neighbour == 0xFFFFFFFFu) {
+      return 0xFFFFFFFFu;
+    }
+  }
 
-  let world_pos =
-      vec3<f32>(chunks[chunk_index].coord) * f32(globals.chunk_size) +
-      vec3<f32>(voxel_local);
+  let p = vec3<u32>(local - chunk_offset * n);
+  let N = globals.chunk_size;
+  return neighbour * N * N * N + p.z * N * N + p.y * N + p.x;
+}
+
+// Neighbours in chunks without voxels yet count as solid, the chunk is remeshed once they load.
+fn neighbour_solid(coord: vec3<i32>, slot: u32, local: vec3<i32>) -> bool {
+  let voxel = neighbour_voxel(coord, slot, local);
+  return voxel == 0xFFFFFFFFu || voxel_buffer[voxel] >= globals.density_threshold;
+}
 
-  voxel_buffer[voxel_id] =
-      sin(world_pos.x) + cos(world_pos.y) + sin(world_pos.z);
-}
\ No newline at end of file
+// Water faces are only drawn towards air.
+fn neighbour_hides_water(coord: vec3<i32>, slot: u32, local: vec3<i32>) -> bool {
+  let voxel = neighbour_voxel(coord, slot, local);
+  return voxel == 0xFFFFFFFFu || voxel_buffer[voxel] >= globals.density_threshold || blocks[voxel] == BLOCK_WATER;
+}
+
+fn emit_water_faces(coord: vec3<i32>, slot: u32, local: vec3<u32>) {
+  let origin = vec3<f32>(coord * i32(globals.chunk_size) + vec3<i32>(local));
+  for (var face = 0u; face < 6u; face++) {
+    if (neighbour_hides_water(coord, slot, vec3<i32>(local) + FACE_NORMALS[face])) {
+      continue;
+    }
+
+    let first = atomicAdd(&water_vertex_counts[slot], 6u);
+    if (first + 6u > globals.water_vertices_per_chunk) {
+      continue;
+    }
+
+    let base = slot * globals.water_vertices_per_chunk + first;
+    let c0 = origin + FACE_CORNERS[face * 4u + 0u];
+    let c1 = origin + FACE_CORNERS[face * 4u + 1u];
+    let c2 = origin + FACE_CORNERS[face * 4u + 2u];
+    let c3 = origin + FACE_CORNERS[face * 4u + 3u];
+    write_water_vertex(base + 0u, c0);
+    write_water_vertex(base + 1u, c1);
+    write_water_vertex(base + 2u, c2);
+    write_water_vertex(base + 3u, c0);
+    write_water_vertex(base + 4u, c2);
+    write_water_vertex(base + 5u, c3);
+  }
+}
+
+const FACE_NORMALS = array<vec3<i32>, 6>(
+  vec3<i32>(-1, 0, 0), vec3<i32>(1, 0, 0),
+  vec3<i32>(0, -1, 0), vec3<i32>(0, 1, 0),
+  vec3<i32>(0, 0, -1), vec3<i32>(0, 0, 1),
+);
+
+// Corners of each face of the unit cube, in the order of `FACE_NORMALS`.
+const FACE_CORNERS = array<vec3<f32>, 24>(
+  vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(0.0, 1.0, 1.0), vec3<f32>(0.0, 1.0, 0.0),
+  vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(1.0, 1.0, 0.0), vec3<f32>(1.0, 1.0, 1.0), vec3<f32>(1.0, 0.0, 1.0),
+  vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), vec3<f32>(1.0, 0.0, 1.0), vec3<f32>(0.0, 0.0, 1.0),
+  vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(0.0, 1.0, 1.0), vec3<f32>(1.0, 1.0, 1.0), vec3<f32>(1.0, 1.0, 0.0),
+  vec3<f32>(0.0, 0.0, 0.0), vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0),
+  vec3<f32>(0.0, 0.0, 1.0), vec3<f32>(1.0, 0.0, 1.0), vec3<f32>(1.0, 1.0, 1.0), vec3<f32>(0.0, 1.0, 1.0),
+);
+
+// One cube face per solid voxel side facing air, water faces go to the translucent output.
+// Faces past the slot's vertex budget are dropped.
+@compute @workgroup_size(4,4,4)
+fn generate_block_vertices(@builtin(global_invocation_id) gid: vec3<u32>) {
+  let N = globals.chunk_size;
+  let job = gid.z / N;
+
+  if (job >= arrayLength(&jobs) || jobs[job].global_index == 0xFFFFFFFFu) {
+      return;
+  }
+
+  let slot = jobs[job].global_index;
+  let local = vec3<u32>(gid.x, gid.y, gid.z % N);
+  let coord = jobs[job].coord;
+  if (!is_solid(slot, local)) {
+    if (blocks[slot * N * N * N + local.z * N * N + local.y * N + local.x] == BLOCK_WATER) {
+      emit_water_faces(coord, slot, local);
+    }
+    return;
+  }
+
+  let origin = vec3<f32>(coord * i32(N) + vec3<i32>(local));
+  let block = blocks[slot * N * N * N + local.z * N * N + local.y * N + local.x];
+
+  for (var face = 0u; face < 6u; face++) {
+    if (neighbour_solid(coord, slot, vec3<i32>(local) + FACE_NORMALS[face])) {
+      continue;
+    }
+
+    let first = atomicAdd(&vertex_counts[slot], 6u);
+    if (first + 6u > globals.vertices_per_chunk) {
+      continue;
+    }
+
+    let base = slot * globals.vertices_per_chunk + first;
+    let c0 = origin + FACE_CORNERS[face * 4u + 0u];
+    let c1 = origin + FACE_CORNERS[face * 4u + 1u];
+    let c2 = origin + FACE_CORNERS[face * 4u + 2u];
+    let c3 = origin + FACE_CORNERS[face * 4u + 3u];
+
+    write_vertex(base + 0u, c0, block);
+    write_vertex(base + 1u, c1, block);
+    write_vertex(base + 2u, c2, block);
+    write_vertex(base + 3u, c0, block);
+    write_vertex(base + 4u, c2, block);
+    write_vertex(base + 5u, c3, block);
+  }
+}
+
+// One bit per voxel, set when the voxel is solid for the active mesher.
+// Read back on the CPU to compute face connectivity for occlusion culling.
+@compute @workgroup_size(128,1,1)
+fn generate_solid_mask(@builtin(global_invocation_id) gid: vec3<u32>) {
+  let N = globals.chunk_size;
+  let word = gid.x;
+  let job = gid.z;
+
+  if (word >= SOLID_MASK_WORDS || job >= arrayLength(&jobs)) {
+      return;
+  }
+
+  if (jobs[job].global_index == 0xFFFFFFFFu) {
+      return;
+  }
+
+  let chunk = jobs[job].global_index;
+
+  if (word == 0u) {
+      solid_masks[chunk].coord = jobs[job].coord;
+      solid_masks[chunk].ticket = globals.job_ticket;
+      solid_masks[chunk].vertex_count = atomicLoad(&vertex_counts[chunk]);
+      solid_masks[chunk].water_vertex_count = atomicLoad(&water_vertex_counts[chunk]);
+  }
+
+  var bits = 0u;
+  for (var i = 0u; i < 32u; i++) {
+    let voxel = word * 32u + i;
+    let x = voxel % N;
+    let y = (voxel / N) % N;
+    let z = voxel / (N * N);
+
+    if (globals.mesher == MESHER_BLOCKS) {
+      if (voxel_buffer[chunk * N * N * N + voxel] >= globals.density_threshold) {
+        bits |= 1u << i;
+      }
+    // The mesher lays the heightmap out as (x, h, y), so the column of (x, z) is height_at(x, z).
+    } else if (f32(y) <= height_at(x, z, chunk, N)) {
+      bits |= 1u << i;
+    }
+  }
+
+  solid_masks[chunk].bits[word] = bits;
+}
+
+// Erosion of the height field a region at a time, mirrors `src/erosion.rs`. Every entry
+// point runs over all regions of `region_table`, only those marked for erosion do work.
+
+const REGION_COLUMNS: i32 = 128;
+const REGION_MARGIN: i32 = 16;
+const REGION_SIZE: u32 = 160u;
+const REGION_HEADER: u32 = 4u;
+const REGION_BLEND: f32 = 8.0;
+
+const REGION_EMPTY: u32 = 0u;
+const REGION_ERODE: u32 = 1u;
+const REGION_READY: u32 = 2u;
+
+struct RegionEntry {
+  coord: vec2<i32>,
+  slot: u32,
+  state: u32,
+};
+
+struct ErosionParams {
+  droplets: u32,
+  lifetime: u32,
+  inertia: f32,
+  capacity: f32,
+  min_capacity: f32,
+  deposition: f32,
+  erosion: f32,
+  evaporation: f32,
+  gravity: f32,
+  talus: f32,
+  thermal_rate: f32,
+  _pad: u32,
+};
+
+fn div_floor(a: i32, b: i32) -> i32 {
+  return select(a / b, (a - b + 1) / b, a < 0);
+}
+
+fn region_base(slot: u32) -> u32 {
+  return slot * (REGION_HEADER + REGION_SIZE * REGION_SIZE) + REGION_HEADER;
+}
+
+// World column of the first height of a region, margin included.
+fn region_origin(region: vec2<i32>) -> vec2<i32> {
+  return region * REGION_COLUMNS - REGION_MARGIN;
+}
+
+fn eroding_region(index: u32) -> bool {
+  return index < arrayLength(&region_table) && region_table[index].state == REGION_ERODE;
+}
+
+@compute @workgroup_size(8,8,1)
+fn erosion_fill(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if (!eroding_region(gid.z) || gid.x >= REGION_SIZE || gid.y >= REGION_SIZE) {
+      return;
+  }
+
+  let entry = region_table[gid.z];
+  let base = region_base(entry.slot);
+  if (all(gid.xy == vec2<u32>(0u))) {
+      region_heights[base - REGION_HEADER + 2u] = bitcast<f32>(0u);
+  }
+
+  let world_xz = vec2<f32>(region_origin(entry.coord) + vec2<i32>(gid.xy));
+  region_heights[base + gid.y * REGION_SIZE + gid.x] = terrain_height(world_xz, globals.seed);
+}
+
+// Mirrors `droplet_random` in `erosion.rs`.
+fn droplet_random(droplet: u32, region: vec2<i32>, k: u32) -> f32 {
+  let h = hash3(vec3<i32>(i32(droplet), region), globals.seed + 601u + k);
+  return f32(h >> 8u) * (1.0 / 16777216.0);
+}
+
+// Bilinear height in `z` and gradient in `xy` at a position inside the grid.
+fn region_height_gradient(base: u32, pos: vec2<f32>) -> vec3<f32> {
+  let cell = floor(pos);
+  let f = pos - cell;
+  let i = base + u32(cell.y) * REGION_SIZE + u32(cell.x);
+  let nw = region_heights[i];
+  let ne = region_heights[i + 1u];
+  let sw = region_heights[i + REGION_SIZE];
+  let se = region_heights[i + REGION_SIZE + 1u];
+  let gradient = vec2<f32>(
+      (ne - nw) * (1.0 - f.y) + (se - sw) * f.y,
+      (sw - nw) * (1.0 - f.x) + (se - ne) * f.x,
+  );
+  let height = nw * (1.0 - f.x) * (1.0 - f.y) + ne * f.x * (1.0 - f.y)
+      + sw * (1.0 - f.x) * f.y + se * f.x * f.y;
+  return vec3<f32>(gradient, height);
+}
+
+fn region_deposit(base: u32, pos: vec2<f32>, amount: f32) {
+  let cell = floor(pos);
+  let f = pos - cell;
+  let i = base + u32(cell.y) * REGION_SIZE + u32(cell.x);
+  region_heights[i] += amount * (1.0 - f.x) * (1.0 - f.y);
+  region_heights[i + 1u] += amount * f.x * (1.0 - f.y);
+  region_heights[i + REGION_SIZE] += amount * (1.0 - f.x) * f.y;
+  region_heights[i + REGION_SIZE + 1u] += amount * f.x * f.y;
+}
+
+// One droplet per invocation. Droplets crossing paths race on the heights, which only
+// makes the result differ slightly from the sequential CPU reference.
+@compute @workgroup_size(64,1,1)
+fn erosion_hydraulic(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if (!eroding_region(gid.z) || gid.x >= erosion.droplets) {
+      return;
+  }
+
+  let entry = region_table[gid.z];
+  let base = region_base(entry.slot);
+  let max_pos = f32(REGION_SIZE - 2u);
+
+  var pos = vec2<f32>(droplet_random(gid.x, entry.coord, 0u), droplet_random(gid.x, entry.coord, 1u)) * max_pos;
+  var dir = vec2<f32>(0.0);
+  var speed = 1.0;
+  var water = 1.0;
+  var sediment = 0.0;
+
+  for (var step = 0u; step < erosion.lifetime; step++) {
+    let sample = region_height_gradient(base, pos);
+    dir = dir * erosion.inertia - sample.xy * (1.0 - erosion.inertia);
+    if (dot(dir, dir) < 1e-12) {
+      break;
+    }
+    dir = normalize(dir);
+    let next = pos + dir;
+    if (any(next < vec2<f32>(0.0)) || any(next >= vec2<f32>(max_pos))) {
+      break;
+    }
+
+    let dh = region_height_gradient(base, next).z - sample.z;
+    let capacity = max(-dh * speed * water * erosion.capacity, erosion.min_capacity);
+    if (sediment > capacity || dh > 0.0) {
+      let amount = select((sediment - capacity) * erosion.deposition, min(dh, sediment), dh > 0.0);
+      sediment -= amount;
+      region_deposit(base, pos, amount);
+    } else {
+      let amount = min((capacity - sediment) * erosion.erosion, -dh);
+      sediment += amount;
+      region_deposit(base, pos, -amount);
+    }
+
+    speed = sqrt(max(speed * speed + dh * erosion.gravity, 0.0));
+    water *= 1.0 - erosion.evaporation;
+    pos = next;
+  }
+}
+
+// One thermal iteration into `region_scratch`, copied back by `erosion_thermal_copy`.
+@compute @workgroup_size(8,8,1)
+fn erosion_thermal(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if (!eroding_region(gid.z) || gid.x >= REGION_SIZE || gid.y >= REGION_SIZE) {
+      return;
+  }
+
+  let slot = region_table[gid.z].slot;
+  let base = region_base(slot);
+  let size = i32(REGION_SIZE);
+  let x = i32(gid.x);
+  let y = i32(gid.y);
+  let h = region_heights[base + gid.y * REGION_SIZE + gid.x];
+  var delta = 0.0;
+  for (var ny = max(y - 1, 0); ny <= min(y + 1, size - 1); ny++) {
+    for (var nx = max(x - 1, 0); nx <= min(x + 1, size - 1); nx++) {
+      let d = h - region_heights[base + u32(ny * size + nx)];
+      if (d > erosion.talus) {
+        delta -= (d - erosion.talus) * erosion.thermal_rate / 8.0;
+      } else if (-d > erosion.talus) {
+        delta += (-d - erosion.talus) * erosion.thermal_rate / 8.0;
+      }
+    }
+  }
+  region_scratch[slot * REGION_SIZE * REGION_SIZE + gid.y * REGION_SIZE + gid.x] = h + delta;
+}
+
+@compute @workgroup_size(8,8,1)
+fn erosion_thermal_copy(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if (!eroding_region(gid.z) || gid.x >= REGION_SIZE || gid.y >= REGION_SIZE) {
+      return;
+  }
+
+  let slot = region_table[gid.z].slot;
+  let i = gid.y * REGION_SIZE + gid.x;
+  region_heights[region_base(slot) + i] = region_scratch[slot * REGION_SIZE * REGION_SIZE + i];
+}
+
+// Mirrors `edge_falloff` in `erosion.rs`.
+fn edge_falloff(local: vec2<i32>) -> f32 {
+  let core = local - REGION_MARGIN;
+  let distance = min(core, vec2<i32>(REGION_COLUMNS - 1) - core);
+  return clamp(f32(min(distance.x, distance.y)) / REGION_BLEND, 0.0, 1.0);
+}
+
+// Fades the erosion out towards the region's edge and marks the region eroded.
+@compute @workgroup_size(8,8,1)
+fn erosion_finish(@builtin(global_invocation_id) gid: vec3<u32>) {
+  if (!eroding_region(gid.z) || gid.x >= REGION_SIZE || gid.y >= REGION_SIZE) {
+      return;
+  }
+
+  let entry = region_table[gid.z];
+  let base = region_base(entry.slot);
+  if (all(gid.xy == vec2<u32>(0u))) {
+      region_heights[base - REGION_HEADER] = bitcast<f32>(entry.coord.x);
+      region_heights[base - REGION_HEADER + 1u] = bitcast<f32>(entry.coord.y);
+      region_heights[base - REGION_HEADER + 2u] = bitcast<f32>(globals.job_ticket);
+  }
+
+  let local = vec2<i32>(gid.xy);
+  let raw = terrain_height(vec2<f32>(region_origin(entry.coord) + local), globals.seed);
+  let i = base + gid.y * REGION_SIZE + gid.x;
+  region_heights[i] = raw + (region_heights[i] - raw) * edge_falloff(local);
+}
+
+// Water bodies, mirrors `src/water.rs`. Rivers carve their bed into freshly generated
+// voxels and air below a column's water surface is filled with water.
+
+const MAX_LAKES: u32 = 64u;
+const MAX_RIVERS: u32 = 64u;
+const MAX_RIVER_SEGMENTS: u32 = 6144u;
+const RIVER_BANK_SLOPE: f32 = 1.5;
+const CARVE_STRENGTH: f32 = 0.25;
+const NO_WATER: f32 = -1e30;
+const NO_CARVE: f32 = 1e30;
+
+struct Lake {
+  center: vec2<f32>,
+  radius: f32,
+  level: f32,
+};
+
+struct River {
+  min: vec2<f32>,
+  max: vec2<f32>,
+  first_segment: u32,
+  segment_count: u32,
+  width: f32,
+  depth: f32,
+};
+
+struct RiverSegment {
+  start: vec2<f32>,
+  end: vec2<f32>,
+  start_level: f32,
+  end_level: f32,
+  _pad: vec2<f32>,
+};
+
+struct WaterBodies {
+  sea_level: f32,
+  has_sea: u32,
+  lake_count: u32,
+  river_count: u32,
+  lakes: array<Lake, MAX_LAKES>,
+  rivers: array<River, MAX_RIVERS>,
+  segments: array<RiverSegment, MAX_RIVER_SEGMENTS>,
+};
+
+struct ColumnWater {
+  carve: f32,
+  level: f32,
+};
+
+// Mirrors `WaterData::column`.
+fn column_water(xz: vec2<f32>) -> ColumnWater {
+  var water = ColumnWater(NO_CARVE, select(NO_WATER, water_bodies.sea_level, water_bodies.has_sea != 0u));
+
+  for (var i = 0u; i < water_bodies.lake_count; i++) {
+    let lake = water_bodies.lakes[i];
+    if (distance(xz, lake.center) < lake.radius) {
+      water.level = max(water.level, lake.level);
+    }
+  }
+
+  for (var i = 0u; i < water_bodies.river_count; i++) {
+    let river = water_bodies.rivers[i];
+    if (any(xz < river.min) || any(xz > river.max)) {
+      continue;
+    }
+
+    var best = 1e30;
+    var level = 0.0;
+    for (var s = river.first_segment; s < river.first_segment + river.segment_count; s++) {
+      let segment = water_bodies.segments[s];
+      let along = segment.end - segment.start;
+      let t = clamp(dot(xz - segment.start, along) / max(dot(along, along), 1e-6), 0.0, 1.0);
+      let d = distance(xz, segment.start + along * t);
+      if (d < best) {
+        best = d;
+        level = mix(segment.start_level, segment.end_level, t);
+      }
+    }
+
+    var carve = level + (best - river.width) * RIVER_BANK_SLOPE;
+    if (best < river.width) {
+      let t = best / river.width;
+      carve = level - river.depth * (1.0 - t * t);
+      water.level = max(water.level, level);
+    }
+    water.carve = min(water.carve, carve);
+  }
+  return water;
+}
+
+// Mirrors `apply_water` in `water.rs`, dispatched between the generator and the feature writes.
+@compute @workgroup_size(4,4,4)
+fn apply_water(@builtin(global_invocation_id) gid: vec3<u32>) {
+  let N = globals.chunk_size;
+  let job = gid.z / N;
+
+  if (job >= arrayLength(&jobs) || jobs[job].global_index == 0xFFFFFFFFu) {
+      return;
+  }
+
+  let local = vec3<u32>(gid.x, gid.y, gid.z % N);
+  let voxel_id = jobs[job].global_index * N * N * N + local.z * N * N + local.y * N + local.x;
+  let coord = jobs[job].coord;
+  let n = i32(N);
+
+  // The z=0 slice holds the heights, the z=1 slice the water surface.
+  if (globals.mesher == MESHER_HEIGHT_FIELD) {
+    if (local.z > 1u) {
+      return;
+    }
+    let water = column_water(vec2<f32>(vec2<i32>(coord.x * n + i32(local.x), coord.z * n + i32(local.y))));
+    let bottom = f32(coord.y * n);
+    if (local.z == 0u) {
+      voxel_buffer[voxel_id] = min(voxel_buffer[voxel_id], water.carve - bottom);
+    } else {
+      voxel_buffer[voxel_id] = water.level - bottom;
+    }
+    return;
+  }
+
+  let world = coord * n + vec3<i32>(local);
+  let water = column_water(vec2<f32>(world.xz));
+  let y = f32(world.y);
+  if (y > water.carve) {
+    voxel_buffer[voxel_id] = min(voxel_buffer[voxel_id], globals.density_threshold + (water.carve - y) * CARVE_STRENGTH);
+  }
+  if (voxel_buffer[voxel_id] < globals.density_threshold) {
+    blocks[voxel_id] = select(BLOCK_AIR, BLOCK_WATER, y < water.level);
+  }
+}
@@ -0,0 +1,334 @@
+//! Biomes chosen by low frequency temperature and humidity noise.
+//! Mirrors `assets/shaders/biome.wgsl`.
+//!
+//! Gameplay code asks for the biome of a column with [`biome_at`], passing the
+//! [`TerrainParams`] resource.
+
+use bevy::{prelude::*, render::render_resource::ShaderType};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::BlockId,
+    noise::{NoiseKind, fbm2, hash3, ridged2},
+    terrain_generator::TerrainParams,
+};
+
+const CLIMATE_FREQUENCY: f32 = 1.0 / 1024.0;
+const BIOME_FREQUENCY: f32 = 1.0 / 128.0;
+/// Climate distance over which neighbouring biomes fade into each other.
+const BIOME_BLEND: f32 = 0.04;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Biome {
+    Plains = 0,
+    Desert = 1,
+    Mountains = 2,
+    Tundra = 3,
+    Ocean = 4,
+}
+
+/// Blocks the top of a column is made of, stone below.
+#[derive(Clone, Copy, Debug)]
+pub struct BiomeSurface {
+    pub top: BlockId,
+    pub filler: BlockId,
+    pub filler_depth: f32,
+}
+
+/// How often features are placed, in expected features per 16x16 column area.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BiomeDecoration {
+    pub trees: f32,
+    pub boulders: f32,
+}
+
+/// Climate and height noise of a biome, one row of the [`BiomeTable`].
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub struct BiomeParams {
+    /// Temperature and humidity, both in [0, 1], the biome is centred on.
+    pub climate: [f32; 2],
+    pub noise: NoiseKind,
+    /// Ridged noise instead of fbm.
+    #[serde(default)]
+    pub ridged: bool,
+    /// Frequency relative to the base terrain frequency of 1/128.
+    pub scale: f32,
+    pub octaves: u32,
+    pub amplitude: f32,
+    /// Height of the noise's zero.
+    pub offset: f32,
+}
+
+impl BiomeParams {
+    pub fn height(&self, world_xz: Vec2, seed: u32) -> f32 {
+        let p = world_xz * BIOME_FREQUENCY * self.scale;
+        let noise = if self.ridged {
+            ridged2(self.noise, p, seed, self.octaves, 2.0, 0.5)
+        } else {
+            fbm2(self.noise, p, seed, self.octaves, 2.0, 0.5)
+        };
+        noise * self.amplitude + self.offset
+    }
+}
+
+/// Parameters of every biome, in [`Biome::ALL`] order.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BiomeTable(pub [BiomeParams; 5]);
+
+impl Default for BiomeTable {
+    fn default() -> Self {
+        let params = |climate, noise, ridged, scale, octaves, amplitude, offset| BiomeParams {
+            climate,
+            noise,
+            ridged,
+            scale,
+            octaves,
+            amplitude,
+            offset,
+        };
+        Self([
+            params([0.55, 0.5], NoiseKind::Perlin, false, 1.0, 4, 6.0, 4.0),
+            params([0.9, 0.15], NoiseKind::Simplex, true, 2.0, 2, 8.0, 2.0),
+            params([0.35, 0.25], NoiseKind::Perlin, true, 0.5, 5, 72.0, 8.0),
+            params([0.1, 0.5], NoiseKind::Perlin, false, 1.0, 3, 10.0, 6.0),
+            params([0.6, 0.9], NoiseKind::Perlin, false, 1.0, 3, 6.0, -20.0),
+        ])
+    }
+}
+
+impl BiomeTable {
+    pub fn get_mut(&mut self, biome: Biome) -> &mut BiomeParams {
+        &mut self.0[biome as usize]
+    }
+}
+
+/// `BiomeParams` of `biome.wgsl`.
+#[derive(Clone, Copy, ShaderType, Debug)]
+pub struct GpuBiomeParams {
+    climate: Vec2,
+    noise: u32,
+    ridged: u32,
+    scale: f32,
+    octaves: u32,
+    amplitude: f32,
+    offset: f32,
+}
+
+impl From<&BiomeParams> for GpuBiomeParams {
+    fn from(params: &BiomeParams) -> Self {
+        Self {
+            climate: Vec2::from(params.climate),
+            noise: params.noise as u32,
+            ridged: params.ridged as u32,
+            scale: params.scale,
+            octaves: params.octaves,
+            amplitude: params.amplitude,
+            offset: params.offset,
+        }
+    }
+}
+
+impl Biome {
+    pub const ALL: [Biome; 5] = [
+        Biome::Plains,
+        Biome::Desert,
+        Biome::Mountains,
+        Biome::Tundra,
+        Biome::Ocean,
+    ];
+
+    pub const fn surface(self) -> BiomeSurface {
+        let (top, filler) = match self {
+            Biome::Plains => (BlockId::GRASS, BlockId::DIRT),
+            Biome::Desert => (BlockId::SAND, BlockId::SAND),
+            Biome::Mountains => (BlockId::STONE, BlockId::GRAVEL),
+            Biome::Tundra => (BlockId::SNOW, BlockId::DIRT),
+            Biome::Ocean => (BlockId::SAND, BlockId::GRAVEL),
+        };
+        BiomeSurface {
+            top,
+            filler,
+            filler_depth: 3.0,
+        }
+    }
+
+    pub const fn decoration(self) -> BiomeDecoration {
+        match self {
+            Biome::Plains => BiomeDecoration {
+                trees: 2.0,
+                boulders: 0.2,
+            },
+            Biome::Desert => BiomeDecoration {
+                trees: 0.0,
+                boulders: 0.5,
+            },
+            Biome::Mountains => BiomeDecoration {
+                trees: 0.3,
+                boulders: 1.5,
+            },
+            Biome::Tundra => BiomeDecoration {
+                trees: 0.5,
+                boulders: 0.3,
+            },
+            Biome::Ocean => BiomeDecoration {
+                trees: 0.0,
+                boulders: 0.0,
+            },
+        }
+    }
+
+    pub fn surface_block(self, depth: f32) -> BlockId {
+        let surface = self.surface();
+        if depth < 1.0 {
+            surface.top
+        } else if depth < 1.0 + surface.filler_depth {
+            surface.filler
+        } else {
+            BlockId::STONE
+        }
+    }
+}
+
+/// Temperature and humidity in [0, 1].
+pub fn climate_at(world_xz: Vec2, seed: u32, octaves: u32) -> Vec2 {
+    let p = world_xz * CLIMATE_FREQUENCY;
+    let temperature = fbm2(
+        NoiseKind::Perlin,
+        p,
+        seed.wrapping_add(101),
+        octaves,
+        2.0,
+        0.5,
+    );
+    let humidity = fbm2(
+        NoiseKind::Perlin,
+        p,
+        seed.wrapping_add(211),
+        octaves,
+        2.0,
+        0.5,
+    );
+    (Vec2::new(temperature, humidity) * 0.5 + 0.5).clamp(Vec2::ZERO, Vec2::ONE)
+}
+
+/// Blend weight of every biome in [`Biome::ALL`] order, summing to one.
+pub fn biome_weights(climate: Vec2, table: &BiomeTable) -> [f32; 5] {
+    let mut weights = table.0.map(|params| {
+        let d2 = climate.distance_squared(Vec2::from(params.climate)) / BIOME_BLEND;
+        1.0 / (d2 * d2 * d2 * d2 + 1e-6)
+    });
+    let total: f32 = weights.iter().sum();
+    for weight in &mut weights {
+        *weight /= total;
+    }
+    weights
+}
+
+/// Biome weights of a world column, shared by its height, surface blocks and [`biome_at`].
+pub fn column_weights(world_xz: Vec2, terrain: &TerrainParams) -> [f32; 5] {
+    biome_weights(
+        climate_at(world_xz, terrain.seed, terrain.octaves.climate),
+        &terrain.biomes,
+    )
+}
+
+/// Biome with the largest weight at a world column, the biome the column's
+/// height leans towards.
+pub fn biome_at(world_xz: Vec2, terrain: &TerrainParams) -> Biome {
+    dominant_biome(&column_weights(world_xz, terrain))
+}
+
+fn dominant_biome(weights: &[f32; 5]) -> Biome {
+    let mut best = 0;
+    for (i, weight) in weights.iter().enumerate().skip(1) {
+        if *weight > weights[best] {
+            best = i;
+        }
+    }
+    Biome::ALL[best]
+}
+
+/// Biome a column takes its surface blocks from: picked at random by weight,
+/// so borders dither instead of forming straight seams.
+pub fn surface_biome_at(world_xz: Vec2, terrain: &TerrainParams) -> Biome {
+    let weights = column_weights(world_xz, terrain);
+    let column = world_xz.floor().as_ivec2().extend(0);
+    let pick = (hash3(column, terrain.seed.wrapping_add(307)) >> 8) as f32 * (1.0 / 16777216.0);
+    let mut sum = 0.0;
+    for (i, weight) in weights.iter().enumerate() {
+        sum += weight;
+        if pick < sum {
+            return Biome::ALL[i];
+        }
+    }
+    dominant_biome(&weights)
+}
+
+/// Surface height of a column: every biome's height noise, sampled at the
+/// domain warped `warped`, blended by the column's [`column_weights`].
+pub fn blended_height(world_xz: Vec2, warped: Vec2, terrain: &TerrainParams) -> f32 {
+    let weights = column_weights(world_xz, terrain);
+    let seed = terrain.seed.wrapping_add(7);
+    let mut height = 0.0;
+    for (i, params) in terrain.biomes.0.iter().enumerate() {
+        // Far away biomes don't contribute, skip their noise.
+        if weights[i] > 1e-3 {
+            height += weights[i] * params.height(warped, seed);
+        }
+    }
+    height
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain_generator::terrain_height;
+
+    #[test]
+    fn weights_sum_to_one() {
+        let table = BiomeTable::default();
+        for x in 0..=10 {
+            for y in 0..=10 {
+                let climate = Vec2::new(x as f32, y as f32) / 10.0;
+                let total: f32 = biome_weights(climate, &table).iter().sum();
+                assert!((total - 1.0).abs() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn climate_centres_pick_their_biome() {
+        let table = BiomeTable::default();
+        for (biome, params) in Biome::ALL.into_iter().zip(table.0) {
+            let weights = biome_weights(Vec2::from(params.climate), &table);
+            assert_eq!(dominant_biome(&weights), biome);
+        }
+    }
+
+    #[test]
+    fn height_follows_the_biome_reported_at_the_column() {
+        // Flat biomes at distinct heights, a column's height gives away its weights.
+        let mut terrain = TerrainParams {
+            seed: 3,
+            ..default()
+        };
+        for (i, params) in terrain.biomes.0.iter_mut().enumerate() {
+            params.amplitude = 0.0;
+            params.offset = i as f32 * 100.0;
+        }
+        let mut biomes = [0; 5];
+        for x in -32..32 {
+            for z in -32..32 {
+                let world_xz = Vec2::new(x as f32, z as f32) * 97.0;
+                let weights = column_weights(world_xz, &terrain);
+                let biome = biome_at(world_xz, &terrain);
+                assert_eq!(biome, dominant_biome(&weights));
+                let expected: f32 = (0..5).map(|i| weights[i] * i as f32 * 100.0).sum();
+                // Weights below 1e-3 are skipped, off by at most 5 * 1e-3 * 400.
+                assert!((terrain_height(world_xz, &terrain) - expected).abs() <= 2.0);
+                biomes[biome as usize] += 1;
+            }
+        }
+        assert!(biomes.iter().filter(|&&count| count > 0).count() >= 3);
+    }
+}
@@ -0,0 +1,53 @@
+use bevy::math::Vec3;
+use bytemuck::{Pod, Zeroable};
+use serde::{Deserialize, Serialize};
+
+/// Block type of a voxel, stored next to its density in the `blocks` buffer.
+/// Mirrors the constants of `assets/shaders/block.wgsl`.
+#[repr(transparent)]
+#[derive(
+    Clone, Copy, PartialEq, Eq, Hash, Debug, Default, Pod, Zeroable, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct BlockId(pub u32);
+
+impl BlockId {
+    pub const AIR: BlockId = BlockId(0);
+    pub const STONE: BlockId = BlockId(1);
+    pub const DIRT: BlockId = BlockId(2);
+    pub const GRASS: BlockId = BlockId(3);
+    pub const SAND: BlockId = BlockId(4);
+    pub const SNOW: BlockId = BlockId(5);
+    pub const GRAVEL: BlockId = BlockId(6);
+    pub const WOOD: BlockId = BlockId(7);
+    pub const LEAVES: BlockId = BlockId(8);
+    pub const COAL_ORE: BlockId = BlockId(9);
+    pub const IRON_ORE: BlockId = BlockId(10);
+    /// Not solid, meshed into the translucent output.
+    pub const WATER: BlockId = BlockId(11);
+}
+
+impl BlockId {
+    /// Whether the block mesher draws the block as an opaque cube.
+    pub const fn is_solid(self) -> bool {
+        !matches!(self, BlockId::AIR | BlockId::WATER)
+    }
+
+    /// Mirrors `block_color` of `block.wgsl`.
+    pub fn color(self) -> Vec3 {
+        match self {
+            BlockId::STONE => Vec3::new(0.5, 0.5, 0.52),
+            BlockId::DIRT => Vec3::new(0.45, 0.32, 0.2),
+            BlockId::GRASS => Vec3::new(0.3, 0.6, 0.2),
+            BlockId::SAND => Vec3::new(0.86, 0.8, 0.55),
+            BlockId::SNOW => Vec3::new(0.95, 0.97, 1.0),
+            BlockId::GRAVEL => Vec3::new(0.42, 0.4, 0.38),
+            BlockId::WOOD => Vec3::new(0.4, 0.26, 0.13),
+            BlockId::LEAVES => Vec3::new(0.18, 0.42, 0.15),
+            BlockId::COAL_ORE => Vec3::new(0.2, 0.2, 0.2),
+            BlockId::IRON_ORE => Vec3::new(0.7, 0.55, 0.45),
+            BlockId::WATER => Vec3::new(0.15, 0.35, 0.65),
+            _ => Vec3::new(1.0, 0.0, 1.0),
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Breaking and placing blocks along the view ray of the [`FlyCamera`], with the
+//! targeted block outlined.
+
+use bevy::prelude::*;
+
+use crate::{
+    block::BlockId,
+    fly_camera::{FlyCamera, cursor_locked},
+    sculpt::sculpting,
+    selection::{SELECT_REACH, Selection, selecting},
+    voxel_world::{VoxelWorld, flush_voxel_edits},
+};
+
+/// How far away blocks can be reached, in blocks.
+pub const REACH: f32 = 8.0;
+
+/// Blocks the number keys select, `Digit1` first.
+const HOTBAR: [BlockId; 9] = [
+    BlockId::STONE,
+    BlockId::DIRT,
+    BlockId::GRASS,
+    BlockId::SAND,
+    BlockId::SNOW,
+    BlockId::GRAVEL,
+    BlockId::WOOD,
+    BlockId::LEAVES,
+    BlockId::IRON_ORE,
+];
+
+const HOTBAR_KEYS: [KeyCode; 9] = [
+    KeyCode::Digit1,
+    KeyCode::Digit2,
+    KeyCode::Digit3,
+    KeyCode::Digit4,
+    KeyCode::Digit5,
+    KeyCode::Digit6,
+    KeyCode::Digit7,
+    KeyCode::Digit8,
+    KeyCode::Digit9,
+];
+
+/// Block right click places.
+#[derive(Resource, Clone, Copy, Debug, Deref, DerefMut)]
+pub struct SelectedBlock(pub BlockId);
+
+impl Default for SelectedBlock {
+    fn default() -> Self {
+        Self(HOTBAR[0])
+    }
+}
+
+pub struct BlockInteractionPlugin;
+
+impl Plugin for BlockInteractionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SelectedBlock>().add_systems(
+            Update,
+            (
+                select_block,
+                interact_with_blocks.run_if(cursor_locked.and(not(sculpting)).and(not(selecting))),
+                highlight_target.run_if(not(sculpting)),
+            )
+                .chain()
+                .before(flush_voxel_edits),
+        );
+    }
+}
+
+fn select_block(keys: Res<ButtonInput<KeyCode>>, mut selected: ResMut<SelectedBlock>) {
+    for (key, block) in HOTBAR_KEYS.iter().zip(HOTBAR) {
+        if keys.just_pressed(*key) {
+            **selected = block;
+        }
+    }
+}
+
+/// Left click breaks the targeted block, right click places the selected block
+/// against the face the view ray hit.
+fn interact_with_blocks(
+    mouse: Res<ButtonInput<MouseButton>>,
+    selected: Res<SelectedBlock>,
+    cameras: Query<&GlobalTransform, With<FlyCamera>>,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    let breaking = mouse.just_pressed(MouseButton::Left);
+    let placing = mouse.just_pressed(MouseButton::Right);
+    if !breaking && !placing {
+        return;
+    }
+
+    for transform in &cameras {
+        let Some(hit) = voxel_world.raycast(transform.translation(), *transform.forward(), REACH)
+        else {
+            continue;
+        };
+
+        if breaking {
+            voxel_world.set_block(hit.voxel, BlockId::AIR);
+        } else if hit.normal != IVec3::ZERO {
+            let target = hit.voxel + hit.normal;
+            if voxel_world.is_solid(target) == Some(false) {
+                voxel_world.set_block(target, **selected);
+            }
+        }
+    }
+}
+
+/// Outlines the block the view ray hits and the face it entered through, within the
+/// reach of the active tool.
+fn highlight_target(
+    cameras: Query<&GlobalTransform, With<FlyCamera>>,
+    voxel_world: Res<VoxelWorld>,
+    selection: Option<Res<Selection>>,
+    mut gizmos: Gizmos,
+) {
+    let reach = if selection.is_some_and(|selection| selection.active) {
+        SELECT_REACH
+    } else {
+        REACH
+    };
+    for transform in &cameras {
+        let Some(hit) = voxel_world.raycast(transform.translation(), *transform.forward(), reach)
+        else {
+            continue;
+        };
+
+        let center = hit.voxel.as_vec3() + 0.5;
+        // Slightly larger than the block so its faces don't hide the lines.
+        gizmos.cuboid(
+            Transform::from_translation(center).with_scale(Vec3::splat(1.005)),
+            Color::srgba(0.05, 0.05, 0.05, 0.9),
+        );
+        if hit.normal != IVec3::ZERO {
+            let normal = hit.normal.as_vec3();
+            gizmos.rect(
+                Isometry3d::new(
+                    center + normal * 0.505,
+                    Quat::from_rotation_arc(Vec3::Z, normal),
+                ),
+                Vec2::splat(0.8),
+                Color::srgba(1.0, 1.0, 1.0, 0.35),
+            );
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use bevy::{camera::primitives::Aabb, prelude::*, render::gpu_readback::ReadbackComplete};
+
+use crate::{
+    chunk_connectivity::{ChunkSolidMask, invalidate_chunk_connectivity},
+    chunk_queue::{
+        ChunkBudget, ChunkJob, ChunkJobs, advance_job_ticket, regenerate_on_generator_change,
+        remesh_loaded_neighbours, requeue_dropped_jobs, schedule_chunk_jobs,
+    },
+    chunks_partition::{CHUNK_COUNT, CHUNK_SIZE, ChunkSlots, LoadedChunks, chunks_partition},
+    erosion::schedule_region_erosion,
+    terrain_generator::{ChunkVoxelUploads, poll_chunk_gen_tasks},
+    voxel_compute_grid::{CHUNK_VERTICES_COUNT, CHUNK_WATER_VERTICES_COUNT},
+    water::update_water_bodies,
+};
+
+/// Chunk coordinate of a loaded chunk entity.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug, Deref)]
+pub struct ChunkPos(pub IVec3);
+
+/// Slot of the GPU buffers the chunk's voxels and vertices live in.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug, Deref)]
+pub struct ChunkSlot(pub u32);
+
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChunkState {
+    /// Got a slot, waiting for its turn in the generation queue.
+    Queued,
+    /// Voxels are being generated.
+    Generating,
+    /// Voxels are in the chunk's slot, vertices are being written.
+    Meshing,
+    /// Mesh is on the GPU and the chunk is drawn.
+    Ready,
+    /// Lost its slot, despawned next frame.
+    Unloading,
+}
+
+/// Marks chunks whose slot holds a finished mesh, they stay drawn while remeshing.
+#[derive(Component)]
+pub struct ChunkMeshed;
+
+/// Written every time a chunk becomes [`ChunkState::Ready`], again after every remesh.
+/// [`ChunkSlots::entity_of`] finds its entity.
+#[derive(Message, Clone, Copy, Debug)]
+pub struct ChunkLoaded {
+    pub pos: IVec3,
+    pub remeshed: bool,
+}
+
+#[derive(Message, Clone, Copy, Debug)]
+pub struct ChunkUnloaded {
+    pub entity: Entity,
+    pub pos: IVec3,
+}
+
+pub struct ChunkLifecyclePlugin;
+
+impl Plugin for ChunkLifecyclePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<ChunkLoaded>()
+            .add_message::<ChunkUnloaded>()
+            .init_resource::<ChunkBudget>()
+            .init_resource::<ChunkJobs>()
+            .init_resource::<ChunkVoxelUploads>()
+            .add_systems(
+                Update,
+                (
+                    advance_job_ticket,
+                    regenerate_on_generator_change,
+                    requeue_dropped_jobs,
+                    remesh_loaded_neighbours,
+                    invalidate_chunk_connectivity,
+                    schedule_region_erosion,
+                    update_water_bodies,
+                    schedule_chunk_jobs,
+                    poll_chunk_gen_tasks,
+                    update_loaded_chunks,
+                )
+                    .chain()
+                    .after(chunks_partition),
+            )
+            .add_systems(Update, despawn_unloading_chunks);
+    }
+}
+
+pub fn chunk_bundle(pos: IVec3, slot: u32) -> impl Bundle {
+    let min = pos.as_vec3() * CHUNK_SIZE;
+    (
+        ChunkPos(pos),
+        ChunkSlot(slot),
+        ChunkState::Queued,
+        Aabb::from_min_max(min, min + Vec3::splat(CHUNK_SIZE)),
+    )
+}
+
+/// Only chunks with a finished mesh in their slot are drawn.
+fn update_loaded_chunks(
+    chunks: Query<(&ChunkPos, &ChunkSlot, &ChunkState), With<ChunkMeshed>>,
+    mut loaded: ResMut<LoadedChunks>,
+) {
+    loaded.chunks.clear();
+    loaded.chunks.resize(CHUNK_COUNT, (IVec3::ZERO, u32::MAX));
+    for (pos, slot, state) in &chunks {
+        if *state != ChunkState::Unloading {
+            loaded.chunks[**slot as usize] = (**pos, **slot);
+        }
+    }
+}
+
+fn despawn_unloading_chunks(
+    mut commands: Commands,
+    chunks: Query<(Entity, &ChunkPos, &ChunkState)>,
+    mut unloaded: MessageWriter<ChunkUnloaded>,
+) {
+    for (entity, pos, state) in &chunks {
+        if *state == ChunkState::Unloading {
+            unloaded.write(ChunkUnloaded { entity, pos: **pos });
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// The solid masks are written after the mesher, so a chunk whose mask carries
+/// the ticket of its last job has been generated and meshed.
+pub fn mark_meshed_chunks_ready(
+    event: On<ReadbackComplete>,
+    mut commands: Commands,
+    slots: Res<ChunkSlots>,
+    mut chunks: Query<(&ChunkPos, &mut ChunkState, &ChunkJob, Has<ChunkMeshed>)>,
+    mut loaded: MessageWriter<ChunkLoaded>,
+) {
+    let masks: Vec<ChunkSolidMask> = bytemuck::pod_collect_to_vec(&event.data);
+    for mask in &masks {
+        if mask.ticket == 0 {
+            continue;
+        }
+
+        let Some(entity) = slots.entity_of(IVec3::from_array(mask.coord)) else {
+            continue;
+        };
+
+        let Ok((pos, mut state, job, remeshed)) = chunks.get_mut(entity) else {
+            continue;
+        };
+
+        if **job != mask.ticket || !matches!(*state, ChunkState::Generating | ChunkState::Meshing) {
+            continue;
+        }
+
+        if mask.vertex_count as usize > CHUNK_VERTICES_COUNT
+            || mask.water_vertex_count as usize > CHUNK_WATER_VERTICES_COUNT
+        {
+            warn!(
+                "chunk {} needs {} vertices and {} water vertices, slots hold {CHUNK_VERTICES_COUNT} \
+                 and {CHUNK_WATER_VERTICES_COUNT}, the faces past them are missing",
+                **pos, mask.vertex_count, mask.water_vertex_count
+            );
+        }
+
+        *state = ChunkState::Ready;
+        commands.entity(entity).insert(ChunkMeshed);
+        loaded.write(ChunkLoaded {
+            pos: **pos,
+            remeshed,
+        });
+    }
+}
@@ -0,0 +1,279 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::{prelude::*, render::gpu_readback::ReadbackComplete};
+
+use crate::{
+    chunk::ChunkPos,
+    chunk_queue::{ChunkJob, RemeshChunk},
+    chunks_partition::{CHUNK_SIZE, ChunkSlots},
+};
+
+pub const SOLID_MASK_WORDS: usize =
+    CHUNK_SIZE as usize * CHUNK_SIZE as usize * CHUNK_SIZE as usize / 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Face {
+    NegX,
+    PosX,
+    NegY,
+    PosY,
+    NegZ,
+    PosZ,
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [
+        Face::NegX,
+        Face::PosX,
+        Face::NegY,
+        Face::PosY,
+        Face::NegZ,
+        Face::PosZ,
+    ];
+
+    pub fn offset(self) -> IVec3 {
+        match self {
+            Face::NegX => IVec3::NEG_X,
+            Face::PosX => IVec3::X,
+            Face::NegY => IVec3::NEG_Y,
+            Face::PosY => IVec3::Y,
+            Face::NegZ => IVec3::NEG_Z,
+            Face::PosZ => IVec3::Z,
+        }
+    }
+
+    pub fn opposite(self) -> Face {
+        match self {
+            Face::NegX => Face::PosX,
+            Face::PosX => Face::NegX,
+            Face::NegY => Face::PosY,
+            Face::PosY => Face::NegY,
+            Face::NegZ => Face::PosZ,
+            Face::PosZ => Face::NegZ,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// For every face, the set of faces reachable from it through air inside the chunk.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FaceConnectivity([u8; 6]);
+
+impl FaceConnectivity {
+    pub const ALL: FaceConnectivity = FaceConnectivity([0b11_1111; 6]);
+    pub const NONE: FaceConnectivity = FaceConnectivity([0; 6]);
+
+    pub fn connects(&self, a: Face, b: Face) -> bool {
+        self.0[a as usize] & b.bit() != 0
+    }
+
+    /// Flood fills the air of a chunk and records which faces every air region touches.
+    /// `is_solid` is queried with local voxel coordinates in `0..CHUNK_SIZE`.
+    pub fn from_solid(is_solid: impl Fn(UVec3) -> bool) -> Self {
+        let n = CHUNK_SIZE as u32;
+        let index = |p: UVec3| (p.x + p.y * n + p.z * n * n) as usize;
+
+        let mut visited = vec![false; (n * n * n) as usize];
+        let mut queue = VecDeque::new();
+        let mut result = FaceConnectivity::NONE;
+
+        for z in 0..n {
+            for y in 0..n {
+                for x in 0..n {
+                    let start = UVec3::new(x, y, z);
+                    if visited[index(start)] || is_solid(start) {
+                        continue;
+                    }
+
+                    visited[index(start)] = true;
+                    queue.push_back(start);
+
+                    let mut touched = 0u8;
+                    while let Some(p) = queue.pop_front() {
+                        for face in Face::ALL {
+                            let next = p.as_ivec3() + face.offset();
+                            if next.cmplt(IVec3::ZERO).any()
+                                || next.cmpge(IVec3::splat(n as i32)).any()
+                            {
+                                touched |= face.bit();
+                                continue;
+                            }
+
+                            let next = next.as_uvec3();
+                            if !visited[index(next)] && !is_solid(next) {
+                                visited[index(next)] = true;
+                                queue.push_back(next);
+                            }
+                        }
+                    }
+
+                    for face in Face::ALL {
+                        if touched & face.bit() != 0 {
+                            result.0[face as usize] |= touched;
+                        }
+                    }
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Builds connectivity from a packed bit mask, one bit per voxel, set when solid.
+    pub fn from_solid_mask(mask: &[u32]) -> Self {
+        let n = CHUNK_SIZE as u32;
+        Self::from_solid(|p| {
+            let bit = p.x + p.y * n + p.z * n * n;
+            mask[(bit / 32) as usize] & (1 << (bit % 32)) != 0
+        })
+    }
+}
+
+/// Face connectivity of every chunk meshed so far, keyed by chunk coordinate, with the
+/// ticket of the mask it was read from. Chunks missing from the map are treated as fully
+/// open.
+#[derive(Resource, Default)]
+pub struct ChunkConnectivity {
+    chunks: HashMap<IVec3, (u32, FaceConnectivity)>,
+}
+
+impl ChunkConnectivity {
+    pub fn get(&self, chunk: IVec3) -> FaceConnectivity {
+        self.chunks
+            .get(&chunk)
+            .map_or(FaceConnectivity::ALL, |(_, faces)| *faces)
+    }
+
+    /// Forgets a chunk, open until its next mask is read back.
+    pub fn remove(&mut self, chunk: IVec3) {
+        self.chunks.remove(&chunk);
+    }
+}
+
+/// Layout of one entry of the solid mask buffer written by `generate_solid_mask`.
+#[repr(C)]
+#[derive(Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ChunkSolidMask {
+    pub coord: [i32; 3],
+    /// [`ChunkJobs::ticket`](crate::chunk_queue::ChunkJobs::ticket) of the job that wrote it,
+    /// 0 for slots never meshed.
+    pub ticket: u32,
+    /// Vertices the meshers wanted to write, faces past the slot's budget were dropped.
+    pub vertex_count: u32,
+    pub water_vertex_count: u32,
+    padding: [u32; 2],
+    pub bits: [u32; SOLID_MASK_WORDS],
+}
+
+pub fn read_solid_masks(
+    event: On<ReadbackComplete>,
+    slots: Res<ChunkSlots>,
+    jobs: Query<&ChunkJob>,
+    mut connectivity: ResMut<ChunkConnectivity>,
+) {
+    let masks: Vec<ChunkSolidMask> = bytemuck::pod_collect_to_vec(&event.data);
+    for mask in &masks {
+        if mask.ticket == 0 {
+            continue;
+        }
+
+        // Slots keep the mask of their previous chunk, and chunks the mask of their
+        // previous job, until the current job finishes.
+        let coord = IVec3::from_array(mask.coord);
+        let Some(job) = slots
+            .entity_of(coord)
+            .and_then(|entity| jobs.get(entity).ok())
+        else {
+            continue;
+        };
+        if **job != mask.ticket
+            || connectivity
+                .chunks
+                .get(&coord)
+                .is_some_and(|(ticket, _)| *ticket == mask.ticket)
+        {
+            continue;
+        }
+
+        connectivity.chunks.insert(
+            coord,
+            (mask.ticket, FaceConnectivity::from_solid_mask(&mask.bits)),
+        );
+    }
+}
+
+/// Forgets the connectivity of chunks whose voxels are edited or regenerated, so nothing
+/// is culled through them until their new mask is read back.
+#[allow(clippy::type_complexity)]
+pub fn invalidate_chunk_connectivity(
+    chunks: Query<&ChunkPos, Or<(Changed<RemeshChunk>, Changed<ChunkJob>)>>,
+    mut connectivity: ResMut<ChunkConnectivity>,
+) {
+    for pos in &chunks {
+        connectivity.remove(**pos);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::hash3;
+
+    const N: u32 = CHUNK_SIZE as u32;
+
+    #[test]
+    fn solid_chunks_connect_no_faces() {
+        assert_eq!(
+            FaceConnectivity::from_solid(|_| true),
+            FaceConnectivity::NONE
+        );
+    }
+
+    #[test]
+    fn air_chunks_connect_every_face() {
+        assert_eq!(
+            FaceConnectivity::from_solid(|_| false),
+            FaceConnectivity::ALL
+        );
+    }
+
+    #[test]
+    fn walls_split_the_faces_on_either_side() {
+        let faces = FaceConnectivity::from_solid(|p| p.x == N / 2);
+        assert!(!faces.connects(Face::NegX, Face::PosX));
+        assert!(!faces.connects(Face::PosX, Face::NegX));
+        // Both halves still touch the faces the wall runs through.
+        for side in [Face::NegX, Face::PosX] {
+            for face in [Face::NegY, Face::PosY, Face::NegZ, Face::PosZ] {
+                assert!(faces.connects(side, face));
+                assert!(faces.connects(face, side));
+            }
+        }
+    }
+
+    #[test]
+    fn masks_agree_with_their_voxels() {
+        let scattered = |p: UVec3| !hash3(p.as_ivec3(), 5).is_multiple_of(3);
+        let wall = |p: UVec3| p.y == N / 2;
+        assert_eq!(
+            FaceConnectivity::from_solid_mask(&mask(scattered)),
+            FaceConnectivity::from_solid(scattered)
+        );
+        let faces = FaceConnectivity::from_solid_mask(&mask(wall));
+        assert_eq!(faces, FaceConnectivity::from_solid(wall));
+        assert!(!faces.connects(Face::NegY, Face::PosY));
+    }
+
+    fn mask(is_solid: impl Fn(UVec3) -> bool) -> [u32; SOLID_MASK_WORDS] {
+        let mut mask = [0; SOLID_MASK_WORDS];
+        for bit in 0..N * N * N {
+            if is_solid(UVec3::new(bit % N, bit / N % N, bit / (N * N))) {
+                mask[(bit / 32) as usize] |= 1 << (bit % 32);
+            }
+        }
+        mask
+    }
+}
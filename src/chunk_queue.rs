@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk::{ChunkLoaded, ChunkMeshed, ChunkPos, ChunkSlot, ChunkState},
+    chunk_connectivity::Face,
+    chunks_partition::{CHUNK_COUNT, CHUNK_SIZE, CHUNK_VOXELS_COUNT, ChunkSlots, VisibleChunks},
+    decoration::{VoxelWrite, decoration_writes},
+    erosion::{ErosionRegions, ErosionSettings, ground_height},
+    schematic::DecorationStructures,
+    terrain_generator::{
+        ActiveTerrainGenerator, ChunkGenTask, ChunkMesher, TerrainParams, spawn_chunk_gen_task,
+    },
+    voxel_world::ReadBackVoxels,
+    water::{WaterBodies, WaterSettings},
+};
+
+/// How many queued chunks are generated and meshed per frame.
+#[derive(Resource, Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum ChunkBudget {
+    Chunks(usize),
+    /// Frame time to spend, with an estimate of what one chunk costs.
+    Millis {
+        budget: f32,
+        per_chunk: f32,
+    },
+}
+
+impl Default for ChunkBudget {
+    fn default() -> Self {
+        ChunkBudget::Chunks(32)
+    }
+}
+
+impl ChunkBudget {
+    pub fn chunks_per_frame(&self) -> usize {
+        let chunks = match *self {
+            ChunkBudget::Chunks(chunks) => chunks,
+            ChunkBudget::Millis { budget, per_chunk } => {
+                (budget / per_chunk.max(f32::EPSILON)) as usize
+            }
+        };
+        chunks.clamp(1, CHUNK_COUNT)
+    }
+}
+
+/// Work for the GPU this frame, `(coord, slot)`.
+#[derive(Resource, Default, ExtractResource, Clone)]
+pub struct ChunkJobs {
+    /// Generated by the GPU generator, then meshed.
+    pub generate: Vec<(IVec3, u32)>,
+    /// Voxels already in their slot, only meshed.
+    pub mesh: Vec<(IVec3, u32)>,
+    /// Applied to the voxel buffer after the generator, before meshing.
+    pub writes: Vec<VoxelWrite>,
+    /// Changes every frame, never 0. The solid masks and eroded regions of this frame's
+    /// jobs are stamped with it.
+    pub ticket: u32,
+}
+
+/// Capacity of the voxel writes buffer, chunks whose writes don't fit wait for the next frame.
+pub const MAX_VOXEL_WRITES: usize = 1 << 16;
+const _: () = assert!(CHUNK_VOXELS_COUNT <= MAX_VOXEL_WRITES);
+
+/// [`ChunkJobs::ticket`] of the frame the chunk's last GPU job was handed out in.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Deref)]
+pub struct ChunkJob(pub u32);
+
+/// Tickets of the frames whose chunk jobs or erosion the compute node dropped, because
+/// its pipelines weren't compiled yet. Shared by the main and render worlds.
+#[derive(Resource, Clone, Default)]
+pub struct DroppedJobs(Arc<Mutex<DroppedTickets>>);
+
+#[derive(Default)]
+struct DroppedTickets {
+    chunks: Vec<u32>,
+    erosion: Vec<u32>,
+}
+
+impl DroppedJobs {
+    pub fn push_chunks(&self, ticket: u32) {
+        self.0.lock().unwrap().chunks.push(ticket);
+    }
+
+    pub fn push_erosion(&self, ticket: u32) {
+        self.0.lock().unwrap().erosion.push(ticket);
+    }
+
+    fn take_chunks(&self) -> Vec<u32> {
+        std::mem::take(&mut self.0.lock().unwrap().chunks)
+    }
+
+    pub fn take_erosion(&self) -> Vec<u32> {
+        std::mem::take(&mut self.0.lock().unwrap().erosion)
+    }
+}
+
+/// Chunk whose voxels changed around it, remeshed from the voxels already in its slot
+/// once it is [`ChunkState::Ready`].
+#[derive(Component)]
+pub struct RemeshChunk;
+
+impl ChunkJobs {
+    pub fn is_empty(&self) -> bool {
+        self.generate.is_empty() && self.mesh.is_empty()
+    }
+}
+
+/// Picks this frame's jobs from the queued and remesh chunks: chunks some camera can see
+/// first, then by distance to the nearest camera.
+#[allow(clippy::too_many_arguments)]
+pub fn schedule_chunk_jobs(
+    mut commands: Commands,
+    cameras: Query<(&GlobalTransform, &VisibleChunks)>,
+    mut chunks: Query<(
+        Entity,
+        &ChunkPos,
+        &ChunkSlot,
+        &mut ChunkState,
+        Has<RemeshChunk>,
+    )>,
+    budget: Res<ChunkBudget>,
+    generator: Res<ActiveTerrainGenerator>,
+    terrain: Res<TerrainParams>,
+    structures: Res<DecorationStructures>,
+    erosion: Res<ErosionSettings>,
+    regions: Res<ErosionRegions>,
+    water: Res<WaterBodies>,
+    mut jobs: ResMut<ChunkJobs>,
+) {
+    jobs.generate.clear();
+    jobs.mesh.clear();
+    jobs.writes.clear();
+    let ticket = jobs.ticket;
+
+    let in_view: HashSet<u32> = cameras
+        .iter()
+        .flat_map(|(_, visible)| visible.slots.iter().copied())
+        .collect();
+    let cam_positions: Vec<Vec3> = cameras
+        .iter()
+        .map(|(transform, _)| transform.translation())
+        .collect();
+
+    let priority = |pos: IVec3, slot: u32| {
+        let center = (pos.as_vec3() + 0.5) * CHUNK_SIZE;
+        let distance = cam_positions
+            .iter()
+            .map(|cam| cam.distance_squared(center))
+            .fold(f32::INFINITY, f32::min);
+        (!in_view.contains(&slot), distance)
+    };
+
+    // GPU generators read the eroded surface, which has to be there first.
+    let eroded = |pos: IVec3| {
+        !erosion.enabled || generator.shader_entry_point().is_none() || regions.is_chunk_ready(pos)
+    };
+    let ground = |xz: IVec2| {
+        water
+            .data
+            .ground(xz, ground_height(&erosion, &regions, xz, &terrain))
+    };
+
+    let mut queued: Vec<_> = chunks
+        .iter_mut()
+        .filter(|(_, pos, _, state, remesh)| {
+            (**state == ChunkState::Queued && eroded(***pos))
+                || (**state == ChunkState::Ready && *remesh)
+        })
+        .map(|(entity, pos, slot, state, _)| {
+            (priority(**pos, **slot), entity, **pos, **slot, state)
+        })
+        .collect();
+    queued.sort_by(|a, b| a.0.0.cmp(&b.0.0).then(a.0.1.total_cmp(&b.0.1)));
+
+    for (_, entity, pos, slot, mut state) in queued.into_iter().take(budget.chunks_per_frame()) {
+        if *state == ChunkState::Ready {
+            jobs.mesh.push((pos, slot));
+            commands
+                .entity(entity)
+                .remove::<RemeshChunk>()
+                .insert(ChunkJob(ticket));
+        } else if generator.shader_entry_point().is_some() {
+            if generator.decorated() {
+                let writes = decoration_writes(
+                    pos,
+                    slot,
+                    &terrain,
+                    &structures.0,
+                    generator.density_threshold(),
+                    &ground,
+                );
+                // A chunk writes each of its voxels at most once, so it always fits
+                // in a later frame's budget.
+                if jobs.writes.len() + writes.len() > MAX_VOXEL_WRITES {
+                    continue;
+                }
+                jobs.writes.extend(writes);
+            }
+            jobs.generate.push((pos, slot));
+            commands
+                .entity(entity)
+                .insert((ReadBackVoxels, ChunkJob(ticket)));
+        } else {
+            spawn_chunk_gen_task(
+                &mut commands,
+                &generator,
+                *terrain,
+                structures.0.clone(),
+                water.data.clone(),
+                entity,
+                pos,
+            );
+        }
+
+        *state = if *state == ChunkState::Ready {
+            ChunkState::Meshing
+        } else {
+            ChunkState::Generating
+        };
+    }
+}
+
+/// Starts this frame's jobs, everything handed to the GPU from here on carries the new ticket.
+pub fn advance_job_ticket(mut jobs: ResMut<ChunkJobs>) {
+    jobs.ticket = jobs.ticket.wrapping_add(1).max(1);
+}
+
+/// Chunks whose job was dropped go back to the queue, remeshes of drawn chunks
+/// keep their voxels and mesh again.
+pub fn requeue_dropped_jobs(
+    mut commands: Commands,
+    dropped: Res<DroppedJobs>,
+    mut chunks: Query<
+        (Entity, &mut ChunkState, &ChunkJob, Has<ChunkMeshed>),
+        Without<ChunkGenTask>,
+    >,
+) {
+    let dropped = dropped.take_chunks();
+    if dropped.is_empty() {
+        return;
+    }
+
+    for (entity, mut state, job, meshed) in &mut chunks {
+        if !dropped.contains(job) {
+            continue;
+        }
+        match *state {
+            ChunkState::Meshing if meshed => {
+                *state = ChunkState::Ready;
+                commands.entity(entity).insert(RemeshChunk);
+            }
+            ChunkState::Generating | ChunkState::Meshing => *state = ChunkState::Queued,
+            _ => {}
+        }
+    }
+}
+
+/// The block mesher counts faces towards chunks without voxels as hidden,
+/// so the neighbours of a newly loaded chunk mesh their side of the border again.
+pub fn remesh_loaded_neighbours(
+    mut commands: Commands,
+    mut loaded: MessageReader<ChunkLoaded>,
+    generator: Res<ActiveTerrainGenerator>,
+    slots: Res<ChunkSlots>,
+    chunks: Query<&ChunkState>,
+) {
+    if generator.mesher() != ChunkMesher::Blocks {
+        loaded.clear();
+        return;
+    }
+
+    for chunk in loaded.read().filter(|chunk| !chunk.remeshed) {
+        for face in Face::ALL {
+            let Some(neighbour) = slots.entity_of(chunk.pos + face.offset()) else {
+                continue;
+            };
+            // Queued chunks mesh after this one is drawn and see its voxels.
+            if matches!(
+                chunks.get(neighbour),
+                Ok(ChunkState::Generating | ChunkState::Meshing | ChunkState::Ready)
+            ) {
+                commands.entity(neighbour).insert(RemeshChunk);
+            }
+        }
+    }
+}
+
+/// A new generator, terrain, erosion, water or structures regenerates every chunk, old
+/// meshes stay drawn until then.
+#[allow(clippy::too_many_arguments)]
+pub fn regenerate_on_generator_change(
+    mut commands: Commands,
+    generator: Res<ActiveTerrainGenerator>,
+    terrain: Res<TerrainParams>,
+    erosion: Res<ErosionSettings>,
+    water: Res<WaterSettings>,
+    structures: Res<DecorationStructures>,
+    mut regions: ResMut<ErosionRegions>,
+    mut chunks: Query<(Entity, &mut ChunkState)>,
+) {
+    // Eroded regions are built from the terrain's heights.
+    let regions_stale = (erosion.is_changed() && !erosion.is_added())
+        || (terrain.is_changed() && !terrain.is_added());
+    if regions_stale {
+        *regions = ErosionRegions::default();
+    }
+    let water_changed = water.is_changed() && !water.is_added();
+    let structures_changed = structures.is_changed() && !structures.is_added();
+    if !regions_stale
+        && !water_changed
+        && !structures_changed
+        && (!generator.is_changed() || generator.is_added())
+    {
+        return;
+    }
+
+    for (entity, mut state) in &mut chunks {
+        if *state != ChunkState::Unloading {
+            *state = ChunkState::Queued;
+            commands
+                .entity(entity)
+                .remove::<(ChunkGenTask, ChunkJob, RemeshChunk)>();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::terrain_generator::GpuHeightMapGenerator;
+
+    #[test]
+    fn budgets_hand_out_between_one_and_every_slot() {
+        assert_eq!(ChunkBudget::Chunks(0).chunks_per_frame(), 1);
+        assert_eq!(ChunkBudget::Chunks(8).chunks_per_frame(), 8);
+        assert_eq!(
+            ChunkBudget::Chunks(usize::MAX).chunks_per_frame(),
+            CHUNK_COUNT
+        );
+        let millis = |budget, per_chunk| ChunkBudget::Millis { budget, per_chunk };
+        assert_eq!(millis(4.0, 0.5).chunks_per_frame(), 8);
+        assert_eq!(millis(0.1, 1.0).chunks_per_frame(), 1);
+        assert_eq!(millis(4.0, 0.0).chunks_per_frame(), CHUNK_COUNT);
+        assert_eq!(millis(f32::NAN, 1.0).chunks_per_frame(), 1);
+    }
+
+    #[test]
+    fn chunks_in_view_are_scheduled_before_nearer_ones() {
+        let mut world = World::new();
+        world.insert_resource(ChunkBudget::Chunks(2));
+        world.insert_resource(ActiveTerrainGenerator::new(GpuHeightMapGenerator));
+        world.init_resource::<TerrainParams>();
+        world.init_resource::<DecorationStructures>();
+        world.init_resource::<ErosionSettings>();
+        world.init_resource::<ErosionRegions>();
+        world.init_resource::<WaterBodies>();
+        world.init_resource::<ChunkJobs>();
+
+        // Slot 0 is next to the camera, slots 1 and 2 are far away but only 2 is seen.
+        world.spawn((GlobalTransform::default(), VisibleChunks { slots: vec![2] }));
+        for (slot, pos) in [IVec3::ZERO, IVec3::new(4, 0, 0), IVec3::new(5, 0, 0)]
+            .into_iter()
+            .enumerate()
+        {
+            world.spawn((ChunkPos(pos), ChunkSlot(slot as u32), ChunkState::Queued));
+        }
+        world.run_system_once(schedule_chunk_jobs).unwrap();
+
+        let jobs = world.resource::<ChunkJobs>();
+        assert_eq!(jobs.generate, [(IVec3::new(5, 0, 0), 2), (IVec3::ZERO, 0)]);
+    }
+}
@@ -1,21 +1,142 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
 use bevy::{
     camera::primitives::{Aabb, Frustum},
     math::Affine3A,
     prelude::*,
-    render::{extract_resource::ExtractResource, render_resource::encase::private::Length},
+    render::{extract_component::ExtractComponent, extract_resource::ExtractResource},
+};
+
+use crate::{
+    chunk::{ChunkState, chunk_bundle},
+    chunk_connectivity::{ChunkConnectivity, Face},
 };
 
+/// Slot table uploaded to the GPU: `(coord, slot)` for every chunk with a mesh,
+/// `(IVec3::ZERO, u32::MAX)` for every other slot. Indexed by slot.
 #[derive(Resource, Default, ExtractResource, Clone)]
-pub struct VisibleChunks {
+pub struct LoadedChunks {
     pub chunks: Vec<(IVec3, u32)>,
 }
 
+/// Which slot and entity every loaded chunk has, kept stable while the chunk stays loaded.
+#[derive(Resource)]
+pub struct ChunkSlots {
+    slots: HashMap<IVec3, (u32, Entity)>,
+    free: Vec<u32>,
+}
+
+impl Default for ChunkSlots {
+    fn default() -> Self {
+        Self {
+            slots: HashMap::new(),
+            free: (0..CHUNK_COUNT as u32).rev().collect(),
+        }
+    }
+}
+
+impl ChunkSlots {
+    pub fn slot_of(&self, chunk: IVec3) -> Option<u32> {
+        self.slots.get(&chunk).map(|(slot, _)| *slot)
+    }
+
+    pub fn entity_of(&self, chunk: IVec3) -> Option<Entity> {
+        self.slots.get(&chunk).map(|(_, entity)| *entity)
+    }
+}
+
+/// Slots of the loaded chunks a camera can see this frame.
+#[derive(Component, Default, Clone, ExtractComponent)]
+pub struct VisibleChunks {
+    pub slots: Vec<u32>,
+}
+
+/// Shape of the region loaded around every camera, in chunks around the camera's chunk.
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub enum LoadShape {
+    Box { extent_xz: i32, extent_y: i32 },
+    Cylinder { radius: i32, extent_y: i32 },
+    Sphere { radius: i32 },
+}
+
+impl LoadShape {
+    pub const fn contains(&self, offset: IVec3) -> bool {
+        let (x, y, z) = (offset.x, offset.y, offset.z);
+        match *self {
+            LoadShape::Box {
+                extent_xz,
+                extent_y,
+            } => x.abs() <= extent_xz && z.abs() <= extent_xz && y.abs() <= extent_y,
+            LoadShape::Cylinder { radius, extent_y } => {
+                x * x + z * z <= radius * radius && y.abs() <= extent_y
+            }
+            LoadShape::Sphere { radius } => x * x + y * y + z * z <= radius * radius,
+        }
+    }
+
+    /// Half extents of the box bounding the shape.
+    pub const fn extent(&self) -> IVec3 {
+        match *self {
+            LoadShape::Box {
+                extent_xz,
+                extent_y,
+            } => IVec3::new(extent_xz, extent_y, extent_xz),
+            LoadShape::Cylinder { radius, extent_y } => IVec3::new(radius, extent_y, radius),
+            LoadShape::Sphere { radius } => IVec3::splat(radius),
+        }
+    }
+
+    pub const fn chunk_count(&self) -> usize {
+        let extent = self.extent();
+        let mut count = 0;
+        let mut x = -extent.x;
+        while x <= extent.x {
+            let mut y = -extent.y;
+            while y <= extent.y {
+                let mut z = -extent.z;
+                while z <= extent.z {
+                    if self.contains(IVec3::new(x, y, z)) {
+                        count += 1;
+                    }
+                    z += 1;
+                }
+                y += 1;
+            }
+            x += 1;
+        }
+        count
+    }
+
+    /// Whether the shape's chunks fit in the [`CHUNK_COUNT`] slots of the GPU buffers.
+    pub const fn fits_budget(&self) -> bool {
+        self.chunk_count() <= CHUNK_COUNT
+    }
+
+    fn offsets(&self) -> impl Iterator<Item = IVec3> + '_ {
+        let extent = self.extent();
+        (-extent.x..=extent.x)
+            .flat_map(move |x| (-extent.y..=extent.y).map(move |y| (x, y)))
+            .flat_map(move |(x, y)| (-extent.z..=extent.z).map(move |z| IVec3::new(x, y, z)))
+            .filter(|offset| self.contains(*offset))
+    }
+}
+
+impl Default for LoadShape {
+    fn default() -> Self {
+        DEFAULT_LOAD_SHAPE
+    }
+}
+
 pub const CHUNK_SIZE: f32 = 16.0;
 pub const CHUNK_EXTENT_XZ: i32 = 6;
 pub const CHUNK_EXTENT_Y: i32 = 4;
-pub const CHUNKS_XZ: usize = (CHUNK_EXTENT_XZ * 2 + 1) as usize;
-pub const CHUNKS_Y: usize = (CHUNK_EXTENT_Y * 2 + 1) as usize;
-pub const CHUNK_COUNT: usize = CHUNKS_XZ * CHUNKS_XZ * CHUNKS_Y;
+pub const DEFAULT_LOAD_SHAPE: LoadShape = LoadShape::Cylinder {
+    radius: CHUNK_EXTENT_XZ,
+    extent_y: CHUNK_EXTENT_Y,
+};
+/// Slot budget of the GPU buffers. A [`LoadShape`] needing more slots is refused, the
+/// union of several cameras' regions is truncated to the chunks nearest to a camera.
+pub const CHUNK_COUNT: usize = DEFAULT_LOAD_SHAPE.chunk_count();
 pub const CHUNK_VOXELS_COUNT: usize =
     CHUNK_SIZE as usize * CHUNK_SIZE as usize * CHUNK_SIZE as usize;
 
@@ -25,50 +146,204 @@ const LOCAL_AABB: Aabb = Aabb {
     half_extents: HALF,
 };
 
-fn chunk_global_index(chunk: IVec3) -> u32 {
-    let x_index = (chunk.x + CHUNK_EXTENT_XZ) as u32;
-    let y_index = (chunk.y + CHUNK_EXTENT_Y) as u32;
-    let z_index = (chunk.z + CHUNK_EXTENT_XZ) as u32;
-
-    x_index + y_index * CHUNKS_XZ as u32 + z_index * CHUNKS_XZ as u32 * CHUNKS_XZ as u32
+fn camera_chunk(transform: &GlobalTransform) -> IVec3 {
+    (transform.translation() / CHUNK_SIZE).floor().as_ivec3()
 }
 
 pub fn chunks_partition(
-    query: Query<(&GlobalTransform, &Frustum), With<Camera3d>>,
-    mut visible: ResMut<VisibleChunks>,
+    mut commands: Commands,
+    mut cameras: Query<
+        (
+            Entity,
+            &GlobalTransform,
+            &Frustum,
+            Option<&mut VisibleChunks>,
+        ),
+        With<Camera3d>,
+    >,
+    shape: Res<LoadShape>,
+    mut slots: ResMut<ChunkSlots>,
+    mut connectivity: ResMut<ChunkConnectivity>,
+    mut accepted: Local<Option<LoadShape>>,
 ) {
-    if visible.chunks.len() != CHUNK_COUNT {
-        visible.chunks.resize(CHUNK_COUNT, (IVec3::ZERO, u32::MAX));
-    } else {
-        visible.chunks.fill((IVec3::ZERO, u32::MAX));
+    // The last shape that fit stays loaded when a bigger one is asked for.
+    if accepted.is_none() || shape.is_changed() {
+        if shape.fits_budget() {
+            *accepted = Some(*shape);
+        } else {
+            error!(
+                "{:?} needs {} chunk slots, only {CHUNK_COUNT} exist",
+                *shape,
+                shape.chunk_count()
+            );
+        }
     }
+    let shape = accepted.unwrap_or(DEFAULT_LOAD_SHAPE);
+
+    let cam_chunks: Vec<IVec3> = cameras
+        .iter()
+        .map(|(_, transform, ..)| camera_chunk(transform))
+        .collect();
 
-    let Ok((transform, frustum)) = query.single() else {
-        return;
+    let distance_to_cameras = |chunk: IVec3| {
+        cam_chunks
+            .iter()
+            .map(|cam_chunk| (chunk - cam_chunk).length_squared())
+            .min()
+            .unwrap_or(i32::MAX)
     };
 
-    let cam_pos = transform.translation();
-    let cam_chunk = (cam_pos / CHUNK_SIZE).floor().as_ivec3();
-
-    let mut idx = 0;
-    for dx in -CHUNK_EXTENT_XZ..=CHUNK_EXTENT_XZ {
-        for dy in -CHUNK_EXTENT_Y..=CHUNK_EXTENT_Y {
-            for dz in -CHUNK_EXTENT_XZ..=CHUNK_EXTENT_XZ {
-                let chunk_coord = cam_chunk + IVec3::new(dx, dy, dz);
-                let world_from_local =
-                    Affine3A::from_translation(chunk_coord.as_vec3() * CHUNK_SIZE);
-                if frustum.intersects_obb(
-                    &LOCAL_AABB,
-                    &world_from_local,
-                    true, // intersect near plane
-                    true, // intersect far plane
-                ) {
-                    let chunk_coord = cam_chunk + IVec3::new(dx, dy, dz);
-
-                    visible.chunks[idx] = (chunk_coord, chunk_global_index(chunk_coord));
-                    idx += 1;
+    // Union of every camera's load region, nearest first so the slot budget
+    // goes to the chunks closest to some camera.
+    let mut wanted = HashSet::new();
+    for cam_chunk in &cam_chunks {
+        wanted.extend(shape.offsets().map(|offset| *cam_chunk + offset));
+    }
+
+    let mut wanted: Vec<IVec3> = wanted.into_iter().collect();
+    wanted.sort_by_key(|chunk| distance_to_cameras(*chunk));
+    wanted.truncate(CHUNK_COUNT);
+    let wanted: HashSet<IVec3> = wanted.into_iter().collect();
+
+    let ChunkSlots { slots, free } = &mut *slots;
+    slots.retain(|chunk, (slot, entity)| {
+        let keep = wanted.contains(chunk);
+        if !keep {
+            free.push(*slot);
+            commands.entity(*entity).insert(ChunkState::Unloading);
+            connectivity.remove(*chunk);
+        }
+        keep
+    });
+    for chunk in &wanted {
+        if !slots.contains_key(chunk)
+            && let Some(slot) = free.pop()
+        {
+            let entity = commands.spawn(chunk_bundle(*chunk, slot)).id();
+            slots.insert(*chunk, (slot, entity));
+        }
+    }
+
+    for (entity, transform, frustum, visible) in &mut cameras {
+        let mut visible_slots = Vec::new();
+        visible_chunks(
+            camera_chunk(transform),
+            frustum,
+            &shape,
+            &connectivity,
+            |chunk| {
+                if let Some((slot, _)) = slots.get(&chunk) {
+                    visible_slots.push(*slot);
                 }
+            },
+        );
+
+        match visible {
+            Some(mut visible) => visible.slots = visible_slots,
+            None => {
+                commands.entity(entity).insert(VisibleChunks {
+                    slots: visible_slots,
+                });
+            }
+        }
+    }
+}
+
+/// Breadth-first walk from the camera chunk. A neighbour is only entered when the face we
+/// came in through connects to the face we leave through, and we never step back towards
+/// a direction already travelled, so chunks sealed off by rock are never reached.
+fn visible_chunks(
+    cam_chunk: IVec3,
+    frustum: &Frustum,
+    shape: &LoadShape,
+    connectivity: &ChunkConnectivity,
+    mut visit: impl FnMut(IVec3),
+) {
+    let in_frustum = |chunk_coord: IVec3| {
+        let world_from_local = Affine3A::from_translation(chunk_coord.as_vec3() * CHUNK_SIZE);
+        frustum.intersects_obb(
+            &LOCAL_AABB,
+            &world_from_local,
+            true, // intersect near plane
+            true, // intersect far plane
+        )
+    };
+
+    let mut reached = HashSet::new();
+    let mut queue = VecDeque::new();
+    reached.insert(cam_chunk);
+    queue.push_back((cam_chunk, None::<Face>, 0u8));
+
+    while let Some((chunk_coord, entered_through, travelled)) = queue.pop_front() {
+        if !in_frustum(chunk_coord) && chunk_coord != cam_chunk {
+            continue;
+        }
+
+        visit(chunk_coord);
+
+        let faces = connectivity.get(chunk_coord);
+        for face in Face::ALL {
+            if travelled & (1 << face.opposite() as u8) != 0 {
+                continue;
+            }
+
+            if let Some(entered) = entered_through
+                && !faces.connects(entered, face)
+            {
+                continue;
+            }
+
+            let next = chunk_coord + face.offset();
+            if !shape.contains(next - cam_chunk) || !reached.insert(next) {
+                continue;
             }
+
+            queue.push_back((next, Some(face.opposite()), travelled | 1 << face as u8));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes_count_the_chunks_they_contain() {
+        let cylinder = LoadShape::Cylinder {
+            radius: CHUNK_EXTENT_XZ,
+            extent_y: CHUNK_EXTENT_Y,
+        };
+        // 113 columns within a radius of 6, 9 chunks high.
+        assert_eq!(cylinder.chunk_count(), 113 * 9);
+        assert_eq!(cylinder.chunk_count(), cylinder.offsets().count());
+        let sphere = LoadShape::Sphere {
+            radius: CHUNK_EXTENT_XZ,
+        };
+        assert_eq!(sphere.chunk_count(), 925);
+        assert_eq!(sphere.chunk_count(), sphere.offsets().count());
+
+        assert!(cylinder.contains(IVec3::new(6, 4, 0)));
+        assert!(!cylinder.contains(IVec3::new(6, 0, 1)));
+        assert!(!cylinder.contains(IVec3::new(0, 5, 0)));
+        assert!(sphere.contains(IVec3::new(0, 6, 0)));
+        assert!(!sphere.contains(IVec3::new(4, 4, 4)));
+    }
+
+    #[test]
+    fn boxes_at_the_default_radius_exceed_the_budget() {
+        assert_eq!(CHUNK_COUNT, LoadShape::default().chunk_count());
+        assert!(LoadShape::default().fits_budget());
+        assert!(
+            LoadShape::Sphere {
+                radius: CHUNK_EXTENT_XZ
+            }
+            .fits_budget()
+        );
+        let square = LoadShape::Box {
+            extent_xz: CHUNK_EXTENT_XZ,
+            extent_y: CHUNK_EXTENT_Y,
+        };
+        assert_eq!(square.chunk_count(), 13 * 13 * 9);
+        assert!(!square.fits_budget());
+    }
+}
@@ -0,0 +1,339 @@
+//! Trees, boulders, ore veins and hand-authored structures placed from the world seed.
+//!
+//! Features are anchored in cells the size of a chunk and never reach further
+//! than [`MAX_FEATURE_REACH`] out of their cell, so a chunk is decorated by
+//! every feature of its own and its neighbouring cells, clipped to the chunk.
+//! Parts spilling into chunks that load later are placed again when they do.
+//! Structures may reach up to a chunk out of their cell, see [`MAX_STRUCTURE_REACH`].
+
+use std::{collections::HashMap, sync::Arc};
+
+use bevy::prelude::*;
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    biome::biome_at,
+    block::BlockId,
+    chunks_partition::CHUNK_SIZE,
+    noise::{hash, hash3},
+    schematic::{Schematic, StructureSpawn},
+    terrain_generator::{ChunkVoxels, TerrainParams, terrain_height},
+    water::WaterData,
+};
+
+/// Blocks a feature may extend out of its anchor cell, less than a chunk.
+const MAX_FEATURE_REACH: i32 = 4;
+/// Blocks a structure may extend from its anchor, the neighbouring cells are the
+/// farthest a chunk looks for features.
+pub const MAX_STRUCTURE_REACH: i32 = CHUNK_SIZE as i32;
+const ORE_VEINS_PER_CELL: u32 = 2;
+/// Density written into voxels a feature makes solid, on top of the threshold.
+const FEATURE_DENSITY: f32 = 1.0;
+
+/// Write to one voxel of a slot, applied by `apply_voxel_writes` after generation.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug)]
+pub struct VoxelWrite {
+    pub slot: u32,
+    pub voxel: u32,
+    pub block: u32,
+    /// `BlockId` the voxel must hold for the write to apply, `u32::MAX` for any.
+    pub replace: u32,
+    pub density: f32,
+}
+
+#[derive(Clone, Debug)]
+pub enum Feature {
+    Tree {
+        base: IVec3,
+        height: i32,
+    },
+    Boulder {
+        center: IVec3,
+        radius: f32,
+    },
+    OreVein {
+        start: IVec3,
+        ore: BlockId,
+        length: u32,
+        seed: u32,
+    },
+    /// Schematic with its origin on `anchor`.
+    Structure {
+        anchor: IVec3,
+        schematic: Arc<Schematic>,
+    },
+}
+
+impl Feature {
+    /// Calls `place(world, block, replace)` for every block of the feature, `replace`
+    /// being the block it may overwrite or `None` for any.
+    pub fn place(&self, mut place: impl FnMut(IVec3, BlockId, Option<BlockId>)) {
+        match self {
+            &Feature::Tree { base, height } => {
+                let top = base + IVec3::Y * height;
+                for offset in sphere_offsets(2.5) {
+                    place(top + offset, BlockId::LEAVES, Some(BlockId::AIR));
+                }
+                for y in 1..=height {
+                    place(base + IVec3::Y * y, BlockId::WOOD, None);
+                }
+            }
+            &Feature::Boulder { center, radius } => {
+                for offset in sphere_offsets(radius) {
+                    place(center + offset, BlockId::STONE, None);
+                }
+            }
+            &Feature::OreVein {
+                start,
+                ore,
+                length,
+                seed,
+            } => {
+                let mut pos = start;
+                for step in 0..length {
+                    place(pos, ore, Some(BlockId::STONE));
+                    let axis = hash(seed ^ hash(step)) % 6;
+                    let direction = match axis {
+                        0 => IVec3::NEG_X,
+                        1 => IVec3::X,
+                        2 => IVec3::NEG_Y,
+                        3 => IVec3::Y,
+                        4 => IVec3::NEG_Z,
+                        _ => IVec3::Z,
+                    };
+                    // Keep the walk within reach of the start.
+                    let next = pos + direction;
+                    if (next - start).abs().max_element() <= MAX_FEATURE_REACH / 2 {
+                        pos = next;
+                    }
+                }
+            }
+            Feature::Structure { anchor, schematic } => {
+                for (offset, block) in schematic.blocks() {
+                    place(*anchor + offset, block, None);
+                }
+            }
+        }
+    }
+}
+
+fn sphere_offsets(radius: f32) -> impl Iterator<Item = IVec3> {
+    let r = radius.ceil() as i32;
+    (-r..=r)
+        .flat_map(move |x| (-r..=r).flat_map(move |y| (-r..=r).map(move |z| IVec3::new(x, y, z))))
+        .filter(move |offset| offset.as_vec3().length_squared() <= radius * radius)
+}
+
+/// Uniform in [0, 1) for the `i`th draw of a cell.
+fn random(cell: IVec3, seed: u32, i: u32) -> f32 {
+    (hash3(cell, seed ^ hash(i)) >> 8) as f32 * (1.0 / 16777216.0)
+}
+
+/// Number of features for an expected `count`, the fraction decided by `roll`.
+fn feature_count(count: f32, roll: f32) -> u32 {
+    count.floor() as u32 + u32::from(roll < count.fract())
+}
+
+/// Trees, boulders and structures of a chunk column, standing on the surface `ground`
+/// gives the height of, `None` where the column is under water.
+pub fn surface_features(
+    column: IVec2,
+    terrain: &TerrainParams,
+    structures: &[StructureSpawn],
+    ground: &impl Fn(IVec2) -> Option<f32>,
+) -> Vec<Feature> {
+    let n = CHUNK_SIZE as i32;
+    let cell = column.extend(0);
+    let origin = column * n;
+    let biome = biome_at((origin + n / 2).as_vec2(), terrain);
+    let decoration = biome.decoration();
+
+    let mut draw = 0;
+    let mut next = || {
+        draw += 1;
+        random(cell, terrain.seed.wrapping_add(401), draw)
+    };
+
+    let mut features = Vec::new();
+    for kind in 0..2 {
+        let expected = if kind == 0 {
+            decoration.trees
+        } else {
+            decoration.boulders
+        };
+        for _ in 0..feature_count(expected, next()) {
+            let xz = origin + IVec2::new((next() * n as f32) as i32, (next() * n as f32) as i32);
+            let size = next();
+            let Some(ground) = ground(xz) else {
+                continue;
+            };
+            let ground = IVec3::new(xz.x, ground.floor() as i32, xz.y);
+            features.push(if kind == 0 {
+                Feature::Tree {
+                    base: ground,
+                    height: 4 + (size * 3.0) as i32,
+                }
+            } else {
+                Feature::Boulder {
+                    center: ground,
+                    radius: 1.0 + size * 1.5,
+                }
+            });
+        }
+    }
+
+    // Drawn apart from the trees and boulders, which adding structures leaves in place.
+    for (i, spawn) in structures.iter().enumerate() {
+        if !spawn.biomes.is_empty() && !spawn.biomes.contains(&biome) {
+            continue;
+        }
+        let mut draw = 0;
+        let mut next = || {
+            draw += 1;
+            random(cell, terrain.seed.wrapping_add(601 + i as u32), draw)
+        };
+        for _ in 0..feature_count(spawn.per_chunk, next()) {
+            let xz = origin + IVec2::new((next() * n as f32) as i32, (next() * n as f32) as i32);
+            let Some(ground) = ground(xz) else {
+                continue;
+            };
+            features.push(Feature::Structure {
+                anchor: IVec3::new(xz.x, ground.floor() as i32 + 1, xz.y),
+                schematic: spawn.schematic.clone(),
+            });
+        }
+    }
+    features
+}
+
+/// Ore veins of a cell, only replacing stone.
+pub fn ore_features(cell: IVec3, seed: u32) -> Vec<Feature> {
+    let n = CHUNK_SIZE as i32;
+    (0..ORE_VEINS_PER_CELL)
+        .map(|vein| {
+            let draw = |i: u32| random(cell, seed.wrapping_add(503), vein * 8 + i);
+            let start = cell * n
+                + IVec3::new(
+                    (draw(0) * n as f32) as i32,
+                    (draw(1) * n as f32) as i32,
+                    (draw(2) * n as f32) as i32,
+                );
+            Feature::OreVein {
+                start,
+                ore: if draw(3) < 0.7 {
+                    BlockId::COAL_ORE
+                } else {
+                    BlockId::IRON_ORE
+                },
+                length: 6 + (draw(4) * 8.0) as u32,
+                seed: hash3(cell, seed.wrapping_add(vein)),
+            }
+        })
+        .collect()
+}
+
+/// Calls `place(voxel, block, replace)` for every feature block inside `chunk`,
+/// `voxel` indexing the chunk's voxels.
+pub fn decorate_chunk(
+    chunk: IVec3,
+    terrain: &TerrainParams,
+    structures: &[StructureSpawn],
+    ground: &impl Fn(IVec2) -> Option<f32>,
+    mut place: impl FnMut(usize, BlockId, Option<BlockId>),
+) {
+    let n = CHUNK_SIZE as i32;
+    let min = chunk * n;
+    let mut clipped = |world: IVec3, block: BlockId, replace: Option<BlockId>| {
+        let local = world - min;
+        if local.cmpge(IVec3::ZERO).all() && local.cmplt(IVec3::splat(n)).all() {
+            place(
+                (local.z * n * n + local.y * n + local.x) as usize,
+                block,
+                replace,
+            );
+        }
+    };
+
+    for x in -1..=1 {
+        for z in -1..=1 {
+            for feature in
+                surface_features(chunk.xz() + IVec2::new(x, z), terrain, structures, ground)
+            {
+                feature.place(&mut clipped);
+            }
+            for y in -1..=1 {
+                for feature in ore_features(chunk + IVec3::new(x, y, z), terrain.seed) {
+                    feature.place(&mut clipped);
+                }
+            }
+        }
+    }
+}
+
+/// Decorates voxels generated on the CPU.
+pub fn decorate_voxels(
+    voxels: &mut ChunkVoxels,
+    chunk: IVec3,
+    terrain: &TerrainParams,
+    structures: &[StructureSpawn],
+    threshold: f32,
+    water: &WaterData,
+) {
+    let ground = |xz: IVec2| water.ground(xz, terrain_height(xz.as_vec2(), terrain));
+    decorate_chunk(
+        chunk,
+        terrain,
+        structures,
+        &ground,
+        |voxel, block, replace| {
+            if replace.is_some_and(|replace| voxels.blocks[voxel] != replace) {
+                return;
+            }
+            voxels.blocks[voxel] = block;
+            voxels.density[voxel] = voxels.density[voxel].max(threshold + FEATURE_DENSITY);
+        },
+    );
+}
+
+/// Writes decorating a chunk generated on the GPU into `slot`.
+pub fn decoration_writes(
+    chunk: IVec3,
+    slot: u32,
+    terrain: &TerrainParams,
+    structures: &[StructureSpawn],
+    threshold: f32,
+    ground: &impl Fn(IVec2) -> Option<f32>,
+) -> Vec<VoxelWrite> {
+    let mut writes: Vec<VoxelWrite> = Vec::new();
+    let mut written = HashMap::new();
+    decorate_chunk(
+        chunk,
+        terrain,
+        structures,
+        ground,
+        |voxel, block, replace| {
+            let write = VoxelWrite {
+                slot,
+                voxel: voxel as u32,
+                block: block.0,
+                replace: replace.map_or(u32::MAX, |replace| replace.0),
+                density: threshold + FEATURE_DENSITY,
+            };
+            // The GPU applies the writes all at once, so each voxel gets one: a later write
+            // takes over when it would overwrite the earlier one applied in order.
+            match written.get(&voxel) {
+                None => {
+                    written.insert(voxel, writes.len());
+                    writes.push(write);
+                }
+                Some(&i) if replace.is_none() => writes[i] = write,
+                Some(&i) if writes[i].block == write.replace => {
+                    writes[i].block = write.block;
+                }
+                Some(_) => {}
+            }
+        },
+    );
+    writes
+}
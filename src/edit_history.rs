@@ -0,0 +1,326 @@
+//! Undo and redo of voxel edits. Every change made through [`VoxelWorld::set_voxel`]
+//! is recorded as the voxel's previous and new value, grouped per chunk into steps:
+//! one step per frame, or per stroke for tools that hold one open.
+
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+
+use crate::{
+    block::BlockId,
+    chunks_partition::CHUNK_SIZE,
+    terrain_generator::{ActiveTerrainGenerator, TerrainParams},
+    voxel_world::{VoxelChange, VoxelWorld, flush_voxel_edits, voxel_chunk, voxel_index},
+};
+
+/// One voxel of a [`ChunkDiff`].
+#[derive(Clone, Copy, Debug)]
+struct DiffEntry {
+    index: u16,
+    before_block: BlockId,
+    before_density: f32,
+    after_block: BlockId,
+    after_density: f32,
+}
+
+/// Voxels of one chunk a step changed, sorted by index.
+#[derive(Clone, Debug)]
+struct ChunkDiff {
+    chunk: IVec3,
+    entries: Vec<DiffEntry>,
+}
+
+/// Changes undone and redone together.
+#[derive(Clone, Debug, Default)]
+struct EditStep {
+    chunks: Vec<ChunkDiff>,
+}
+
+impl EditStep {
+    fn bytes(&self) -> usize {
+        self.chunks
+            .iter()
+            .map(|diff| {
+                std::mem::size_of::<ChunkDiff>()
+                    + diff.entries.len() * std::mem::size_of::<DiffEntry>()
+            })
+            .sum()
+    }
+
+    /// Writes the values from before the step, or after it when `redo`.
+    fn apply(&self, voxel_world: &mut VoxelWorld, redo: bool) {
+        let n = CHUNK_SIZE as i32;
+        for diff in &self.chunks {
+            for entry in &diff.entries {
+                let i = entry.index as i32;
+                let local = IVec3::new(i % n, (i / n) % n, i / (n * n));
+                let (block, density) = if redo {
+                    (entry.after_block, entry.after_density)
+                } else {
+                    (entry.before_block, entry.before_density)
+                };
+                // Chunks unloaded since have been regenerated without the edit.
+                voxel_world.restore_voxel(diff.chunk * n + local, block, density);
+            }
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct VoxelEditHistory {
+    undo: VecDeque<EditStep>,
+    redo: Vec<EditStep>,
+    /// Changes of the step being recorded, by chunk and voxel index.
+    open: HashMap<IVec3, HashMap<u16, DiffEntry>>,
+    /// Set by tools every frame a stroke goes on, keeps the open step open.
+    stroke_held: bool,
+    /// Size of the undo and redo steps the oldest steps are dropped beyond.
+    pub max_bytes: usize,
+    bytes: usize,
+}
+
+impl Default for VoxelEditHistory {
+    fn default() -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            open: HashMap::new(),
+            stroke_held: false,
+            max_bytes: 64 << 20,
+            bytes: 0,
+        }
+    }
+}
+
+impl VoxelEditHistory {
+    /// Keeps recording into the current step through the next frame, so a brush
+    /// stroke is undone as a whole.
+    pub fn hold_stroke(&mut self) {
+        self.stroke_held = true;
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self {
+            max_bytes: self.max_bytes,
+            ..default()
+        };
+    }
+
+    fn record(&mut self, changes: Vec<VoxelChange>) {
+        for change in changes {
+            let (chunk, local) = voxel_chunk(change.voxel);
+            let entry = DiffEntry {
+                index: voxel_index(local) as u16,
+                before_block: change.before.0,
+                before_density: change.before.1,
+                after_block: change.after.0,
+                after_density: change.after.1,
+            };
+            // A voxel edited again in the same step keeps its first previous value.
+            self.open
+                .entry(chunk)
+                .or_default()
+                .entry(entry.index)
+                .and_modify(|open| {
+                    open.after_block = entry.after_block;
+                    open.after_density = entry.after_density;
+                })
+                .or_insert(entry);
+        }
+    }
+
+    /// Records a frame's changes, closing the step unless a stroke held it open.
+    fn end_frame(&mut self, changes: Vec<VoxelChange>) {
+        self.record(changes);
+        if !std::mem::take(&mut self.stroke_held) {
+            self.close_step();
+        }
+    }
+
+    fn close_step(&mut self) {
+        if self.open.is_empty() {
+            return;
+        }
+
+        let step = EditStep {
+            chunks: self
+                .open
+                .drain()
+                .map(|(chunk, entries)| {
+                    let mut entries: Vec<DiffEntry> = entries.into_values().collect();
+                    entries.sort_unstable_by_key(|entry| entry.index);
+                    ChunkDiff { chunk, entries }
+                })
+                .collect(),
+        };
+        self.bytes -= self.redo.drain(..).map(|step| step.bytes()).sum::<usize>();
+        self.push_undo(step);
+    }
+
+    fn push_undo(&mut self, step: EditStep) {
+        self.bytes += step.bytes();
+        self.undo.push_back(step);
+        while self.bytes > self.max_bytes
+            && let Some(oldest) = self.undo.pop_front()
+        {
+            self.bytes -= oldest.bytes();
+        }
+    }
+
+    /// Reverts the last step, `false` when there is none.
+    pub fn undo(&mut self, voxel_world: &mut VoxelWorld) -> bool {
+        self.record(voxel_world.take_changes());
+        self.close_step();
+        let Some(step) = self.undo.pop_back() else {
+            return false;
+        };
+        step.apply(voxel_world, false);
+        self.redo.push(step);
+        true
+    }
+
+    /// Applies the last undone step again, `false` when there is none.
+    pub fn redo(&mut self, voxel_world: &mut VoxelWorld) -> bool {
+        let Some(step) = self.redo.pop() else {
+            return false;
+        };
+        step.apply(voxel_world, true);
+        // Moved back without clearing the other redo steps.
+        self.bytes -= step.bytes();
+        self.push_undo(step);
+        true
+    }
+}
+
+pub struct EditHistoryPlugin;
+
+impl Plugin for EditHistoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VoxelEditHistory>()
+            .add_systems(
+                Update,
+                (
+                    forget_history.run_if(
+                        resource_changed::<ActiveTerrainGenerator>
+                            .or(resource_changed::<TerrainParams>),
+                    ),
+                    undo_redo_edits,
+                )
+                    .chain()
+                    .before(flush_voxel_edits),
+            )
+            .add_systems(PostUpdate, record_voxel_edits);
+    }
+}
+
+/// Steps can't be applied to chunks regenerated from another terrain.
+fn forget_history(mut history: ResMut<VoxelEditHistory>) {
+    history.clear();
+}
+
+/// Ctrl+Z undoes, Ctrl+Y or Ctrl+Shift+Z redoes.
+fn undo_redo_edits(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut history: ResMut<VoxelEditHistory>,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    if !keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        return;
+    }
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    if keys.just_pressed(KeyCode::KeyZ) && !shift {
+        history.undo(&mut voxel_world);
+    } else if keys.just_pressed(KeyCode::KeyY) || (keys.just_pressed(KeyCode::KeyZ) && shift) {
+        history.redo(&mut voxel_world);
+    }
+}
+
+/// Records the frame's changes, after every tool had its turn.
+fn record_voxel_edits(mut history: ResMut<VoxelEditHistory>, mut voxel_world: ResMut<VoxelWorld>) {
+    history.end_frame(voxel_world.take_changes());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        chunks_partition::CHUNK_VOXELS_COUNT,
+        terrain_generator::{ChunkMesher, ChunkVoxels},
+    };
+
+    /// World of air with the chunk at the origin loaded.
+    fn world() -> VoxelWorld {
+        let mut world = VoxelWorld::new(ChunkMesher::Blocks, 0.0);
+        let voxels = ChunkVoxels {
+            density: vec![-1.0; CHUNK_VOXELS_COUNT],
+            ..default()
+        };
+        world.insert(IVec3::ZERO, Arc::new(voxels));
+        world
+    }
+
+    /// Sets `voxels` to stone and ends the frame.
+    fn frame(history: &mut VoxelEditHistory, world: &mut VoxelWorld, voxels: &[IVec3]) {
+        for voxel in voxels {
+            world.set_block(*voxel, BlockId::STONE);
+        }
+        history.end_frame(world.take_changes());
+    }
+
+    #[test]
+    fn new_edits_clear_the_redo_steps() {
+        let (mut history, mut world) = (VoxelEditHistory::default(), world());
+        frame(&mut history, &mut world, &[IVec3::ZERO]);
+        assert!(history.undo(&mut world));
+        assert_eq!(world.block_at(IVec3::ZERO), Some(BlockId::AIR));
+
+        frame(&mut history, &mut world, &[IVec3::X]);
+        assert!(!history.redo(&mut world));
+        assert_eq!(world.block_at(IVec3::ZERO), Some(BlockId::AIR));
+
+        // Undo and redo move the same step back and forth.
+        assert!(history.undo(&mut world));
+        assert_eq!(world.block_at(IVec3::X), Some(BlockId::AIR));
+        assert!(history.redo(&mut world));
+        assert_eq!(world.block_at(IVec3::X), Some(BlockId::STONE));
+    }
+
+    #[test]
+    fn held_strokes_undo_as_one_step() {
+        let (mut history, mut world) = (VoxelEditHistory::default(), world());
+        let voxels = [IVec3::ZERO, IVec3::X, IVec3::Y];
+        for voxel in voxels {
+            history.hold_stroke();
+            frame(&mut history, &mut world, &[voxel]);
+        }
+        // Released: the frame closes the stroke's step.
+        frame(&mut history, &mut world, &[IVec3::Z]);
+
+        assert!(history.undo(&mut world));
+        for voxel in voxels.into_iter().chain([IVec3::Z]) {
+            assert_eq!(world.block_at(voxel), Some(BlockId::AIR));
+        }
+        assert!(!history.undo(&mut world));
+    }
+
+    #[test]
+    fn the_oldest_steps_are_dropped_past_max_bytes() {
+        let step = std::mem::size_of::<ChunkDiff>() + std::mem::size_of::<DiffEntry>();
+        let mut history = VoxelEditHistory {
+            max_bytes: 2 * step,
+            ..default()
+        };
+        let mut world = world();
+        for voxel in [IVec3::ZERO, IVec3::X, IVec3::Y] {
+            frame(&mut history, &mut world, &[voxel]);
+        }
+
+        assert!(history.undo(&mut world));
+        assert!(history.undo(&mut world));
+        assert!(!history.undo(&mut world));
+        assert_eq!(world.block_at(IVec3::ZERO), Some(BlockId::STONE));
+        assert_eq!(world.block_at(IVec3::X), Some(BlockId::AIR));
+    }
+}
@@ -0,0 +1,597 @@
+//! Hydraulic and thermal erosion of the height field, a region of chunk columns at a time.
+//!
+//! Regions are eroded on the GPU by the `erosion_*` entry points of `voxel_gen.wgsl`
+//! before any of their chunks is generated, then read back so CPU side features stand
+//! on the eroded ground. The CPU reference of those passes is built for the tests.
+//! The erosion fades out towards a region's edge so neighbouring regions meet seamlessly.
+
+use std::{collections::HashMap, sync::Arc};
+
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::ExtractResource, gpu_readback::ReadbackComplete,
+        render_resource::ShaderType,
+    },
+};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    chunk::{ChunkPos, ChunkState},
+    chunk_queue::{ChunkJobs, DroppedJobs},
+    chunks_partition::CHUNK_SIZE,
+    terrain_generator::{TerrainParams, terrain_height},
+};
+
+/// Chunk columns along a region's side.
+pub const REGION_CHUNKS: i32 = 8;
+pub const REGION_COLUMNS: i32 = REGION_CHUNKS * CHUNK_SIZE as i32;
+/// Columns eroded around a region so droplets can flow in from outside.
+pub const REGION_MARGIN: i32 = 16;
+pub const REGION_SIZE: usize = (REGION_COLUMNS + 2 * REGION_MARGIN) as usize;
+/// Regions the GPU keeps eroded heights of.
+pub const REGION_SLOTS: usize = 9;
+/// `[coord.x, coord.y, ticket, _]` bit cast to floats in front of every slot's heights.
+pub const REGION_HEADER: usize = 4;
+pub const REGION_STRIDE: usize = REGION_HEADER + REGION_SIZE * REGION_SIZE;
+
+#[derive(Resource, Clone, Copy, Debug, ExtractResource)]
+pub struct ErosionSettings {
+    pub enabled: bool,
+    /// Droplets simulated per region.
+    pub droplets: u32,
+    /// Steps a droplet lives for.
+    pub lifetime: u32,
+    /// How much a droplet keeps its direction instead of following the slope.
+    pub inertia: f32,
+    /// Sediment carried per unit of speed, water and slope.
+    pub capacity: f32,
+    pub min_capacity: f32,
+    pub deposition: f32,
+    pub erosion: f32,
+    pub evaporation: f32,
+    pub gravity: f32,
+    pub thermal_iterations: u32,
+    /// Height difference between neighbours above which material slides down.
+    pub talus: f32,
+    pub thermal_rate: f32,
+}
+
+impl Default for ErosionSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            droplets: 40_000,
+            lifetime: 48,
+            inertia: 0.05,
+            capacity: 4.0,
+            min_capacity: 0.01,
+            deposition: 0.3,
+            erosion: 0.3,
+            evaporation: 0.02,
+            gravity: 4.0,
+            thermal_iterations: 16,
+            talus: 1.2,
+            thermal_rate: 0.5,
+        }
+    }
+}
+
+/// [`ErosionSettings`] as the `erosion` uniform of `voxel_gen.wgsl`.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, ShaderType, Debug, Default)]
+pub struct ErosionParams {
+    droplets: u32,
+    lifetime: u32,
+    inertia: f32,
+    capacity: f32,
+    min_capacity: f32,
+    deposition: f32,
+    erosion: f32,
+    evaporation: f32,
+    gravity: f32,
+    talus: f32,
+    thermal_rate: f32,
+    _pad: u32,
+}
+
+impl From<&ErosionSettings> for ErosionParams {
+    fn from(settings: &ErosionSettings) -> Self {
+        Self {
+            droplets: settings.droplets,
+            lifetime: settings.lifetime,
+            inertia: settings.inertia,
+            capacity: settings.capacity,
+            min_capacity: settings.min_capacity,
+            deposition: settings.deposition,
+            erosion: settings.erosion,
+            evaporation: settings.evaporation,
+            gravity: settings.gravity,
+            talus: settings.talus,
+            thermal_rate: settings.thermal_rate,
+            _pad: 0,
+        }
+    }
+}
+
+/// Region a world column belongs to.
+pub fn region_of(column: IVec2) -> IVec2 {
+    IVec2::new(
+        column.x.div_euclid(REGION_COLUMNS),
+        column.y.div_euclid(REGION_COLUMNS),
+    )
+}
+
+/// World column of the first height of a region, margin included.
+fn region_origin(region: IVec2) -> IVec2 {
+    region * REGION_COLUMNS - REGION_MARGIN
+}
+
+#[derive(Clone, Debug)]
+enum RegionState {
+    /// Eroded on the GPU this frame, stamped with the frame's [`ChunkJobs::ticket`].
+    Erode(u32),
+    /// Waiting for the readback of the erosion with the given ticket.
+    Eroding(u32),
+    Ready(Arc<Vec<f32>>),
+}
+
+#[derive(Clone, Debug)]
+struct RegionErosion {
+    slot: u32,
+    state: RegionState,
+}
+
+/// Entry of the `region_table` of `voxel_gen.wgsl`.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+pub struct RegionEntry {
+    pub coord: [i32; 2],
+    pub slot: u32,
+    /// 0 empty, 1 erode this frame, 2 eroded.
+    pub state: u32,
+}
+
+/// Regions with a slot of the GPU's region heights buffer.
+#[derive(Resource, Clone, ExtractResource)]
+pub struct ErosionRegions {
+    regions: HashMap<IVec2, RegionErosion>,
+    free: Vec<u32>,
+}
+
+impl Default for ErosionRegions {
+    fn default() -> Self {
+        Self {
+            regions: HashMap::new(),
+            free: (0..REGION_SLOTS as u32).rev().collect(),
+        }
+    }
+}
+
+impl ErosionRegions {
+    /// Eroded surface height of a column, `None` until its region has been read back.
+    pub fn height_at(&self, column: IVec2) -> Option<f32> {
+        let region = region_of(column);
+        let RegionState::Ready(heights) = &self.regions.get(&region)?.state else {
+            return None;
+        };
+        let local = column - region_origin(region);
+        Some(heights[local.y as usize * REGION_SIZE + local.x as usize])
+    }
+
+    /// Whether the columns of `chunk` and its neighbours, where its features stand, are eroded.
+    pub fn is_chunk_ready(&self, chunk: IVec3) -> bool {
+        chunk_regions(chunk).all(|region| {
+            self.regions
+                .get(&region)
+                .is_some_and(|erosion| matches!(erosion.state, RegionState::Ready(_)))
+        })
+    }
+
+    pub fn table(&self) -> Vec<RegionEntry> {
+        let mut table = vec![RegionEntry::default(); REGION_SLOTS];
+        for (region, erosion) in &self.regions {
+            table[erosion.slot as usize] = RegionEntry {
+                coord: region.to_array(),
+                slot: erosion.slot,
+                state: match erosion.state {
+                    RegionState::Erode(_) => 1,
+                    RegionState::Eroding(_) => 0,
+                    RegionState::Ready(_) => 2,
+                },
+            };
+        }
+        table
+    }
+
+    pub fn is_eroding(&self) -> bool {
+        self.regions
+            .values()
+            .any(|erosion| matches!(erosion.state, RegionState::Erode(_)))
+    }
+}
+
+fn chunk_regions(chunk: IVec3) -> impl Iterator<Item = IVec2> {
+    let n = CHUNK_SIZE as i32;
+    let min = region_of((chunk.xz() - 1) * n);
+    let max = region_of((chunk.xz() + 2) * n - 1);
+    (min.x..=max.x).flat_map(move |x| (min.y..=max.y).map(move |y| IVec2::new(x, y)))
+}
+
+/// Gives the regions queued chunks need a slot, one new region is eroded per frame.
+/// Regions no loaded chunk needs anymore free their slot, erosion the GPU dropped is redone.
+pub fn schedule_region_erosion(
+    settings: Res<ErosionSettings>,
+    jobs: Res<ChunkJobs>,
+    dropped: Res<DroppedJobs>,
+    mut regions: ResMut<ErosionRegions>,
+    chunks: Query<(&ChunkPos, &ChunkState)>,
+) {
+    let dropped = dropped.take_erosion();
+    if !settings.enabled {
+        return;
+    }
+
+    for erosion in regions.regions.values_mut() {
+        match erosion.state {
+            RegionState::Erode(ticket) => erosion.state = RegionState::Eroding(ticket),
+            RegionState::Eroding(ticket) if dropped.contains(&ticket) => {
+                erosion.state = RegionState::Erode(jobs.ticket);
+            }
+            _ => {}
+        }
+    }
+
+    let mut needed: Vec<IVec2> = chunks
+        .iter()
+        .filter(|(_, state)| **state != ChunkState::Unloading)
+        .flat_map(|(pos, _)| chunk_regions(**pos))
+        .collect();
+    needed.sort_by_key(|region| (region.x, region.y));
+    needed.dedup();
+
+    let regions = &mut *regions;
+    regions.regions.retain(|region, erosion| {
+        let keep = needed.contains(region);
+        if !keep {
+            regions.free.push(erosion.slot);
+        }
+        keep
+    });
+
+    if regions.is_eroding() {
+        return;
+    }
+
+    let queued = chunks
+        .iter()
+        .filter(|(_, state)| **state == ChunkState::Queued)
+        .flat_map(|(pos, _)| chunk_regions(**pos))
+        .find(|region| !regions.regions.contains_key(region));
+    if let Some(region) = queued
+        && let Some(slot) = regions.free.pop()
+    {
+        regions.regions.insert(
+            region,
+            RegionErosion {
+                slot,
+                state: RegionState::Erode(jobs.ticket),
+            },
+        );
+    }
+}
+
+/// Picks up the heights of regions the GPU finished eroding.
+pub fn read_eroded_regions(
+    event: On<ReadbackComplete>,
+    settings: Res<ErosionSettings>,
+    mut regions: ResMut<ErosionRegions>,
+) {
+    if !settings.enabled {
+        return;
+    }
+
+    let data: Vec<f32> = bytemuck::pod_collect_to_vec(&event.data);
+    for (slot, heights) in data.chunks_exact(REGION_STRIDE).enumerate() {
+        let header: [u32; REGION_HEADER] = std::array::from_fn(|i| heights[i].to_bits());
+        if header[2] == 0 {
+            continue;
+        }
+
+        // Slots keep the heights of their previous region until the new one is eroded.
+        let region = IVec2::new(header[0] as i32, header[1] as i32);
+        let Some(erosion) = regions.regions.get_mut(&region) else {
+            continue;
+        };
+        let finished = match erosion.state {
+            RegionState::Erode(ticket) | RegionState::Eroding(ticket) => ticket == header[2],
+            RegionState::Ready(_) => false,
+        };
+        if erosion.slot == slot as u32 && finished {
+            erosion.state = RegionState::Ready(Arc::new(heights[REGION_HEADER..].to_vec()));
+        }
+    }
+}
+
+/// Surface height features stand on: eroded when erosion is enabled.
+pub fn ground_height(
+    settings: &ErosionSettings,
+    regions: &ErosionRegions,
+    column: IVec2,
+    terrain: &TerrainParams,
+) -> f32 {
+    if settings.enabled
+        && let Some(height) = regions.height_at(column)
+    {
+        return height;
+    }
+    terrain_height(column.as_vec2(), terrain)
+}
+
+/// CPU reference of the GPU erosion passes, operation for operation.
+#[cfg(test)]
+mod reference {
+    use super::*;
+    use crate::noise::hash3;
+
+    /// Columns over which the erosion fades out towards the region's edge.
+    const REGION_BLEND: f32 = 8.0;
+
+    /// Uniform in [0, 1) for the `k`th draw of a droplet.
+    fn droplet_random(droplet: u32, region: IVec2, seed: u32, k: u32) -> f32 {
+        let h = hash3(
+            IVec3::new(droplet as i32, region.x, region.y),
+            seed.wrapping_add(601 + k),
+        );
+        (h >> 8) as f32 * (1.0 / 16777216.0)
+    }
+
+    /// Height and gradient at a position inside a `size` squared grid.
+    fn height_gradient(heights: &[f32], size: usize, pos: Vec2) -> (f32, Vec2) {
+        let cell = pos.floor();
+        let f = pos - cell;
+        let i = cell.y as usize * size + cell.x as usize;
+        let (nw, ne) = (heights[i], heights[i + 1]);
+        let (sw, se) = (heights[i + size], heights[i + size + 1]);
+        let gradient = Vec2::new(
+            (ne - nw) * (1.0 - f.y) + (se - sw) * f.y,
+            (sw - nw) * (1.0 - f.x) + (se - ne) * f.x,
+        );
+        let height = nw * (1.0 - f.x) * (1.0 - f.y)
+            + ne * f.x * (1.0 - f.y)
+            + sw * (1.0 - f.x) * f.y
+            + se * f.x * f.y;
+        (height, gradient)
+    }
+
+    /// Adds `amount` to the four corners of the cell at `pos`, weighted bilinearly.
+    fn deposit(heights: &mut [f32], size: usize, pos: Vec2, amount: f32) {
+        let cell = pos.floor();
+        let f = pos - cell;
+        let i = cell.y as usize * size + cell.x as usize;
+        heights[i] += amount * (1.0 - f.x) * (1.0 - f.y);
+        heights[i + 1] += amount * f.x * (1.0 - f.y);
+        heights[i + size] += amount * (1.0 - f.x) * f.y;
+        heights[i + size + 1] += amount * f.x * f.y;
+    }
+
+    /// Raw heights of a region, margin included, `REGION_SIZE` squared row major.
+    pub fn region_heights(region: IVec2, terrain: &TerrainParams) -> Vec<f32> {
+        let origin = region_origin(region);
+        (0..REGION_SIZE * REGION_SIZE)
+            .map(|i| {
+                let local = IVec2::new((i % REGION_SIZE) as i32, (i / REGION_SIZE) as i32);
+                terrain_height((origin + local).as_vec2(), terrain)
+            })
+            .collect()
+    }
+
+    /// Simulates one droplet after another on a `size` squared grid, the GPU runs them in parallel.
+    pub fn erode_hydraulic(
+        heights: &mut [f32],
+        size: usize,
+        region: IVec2,
+        seed: u32,
+        settings: &ErosionSettings,
+    ) {
+        let max = (size - 2) as f32;
+        for droplet in 0..settings.droplets {
+            let mut pos = Vec2::new(
+                droplet_random(droplet, region, seed, 0),
+                droplet_random(droplet, region, seed, 1),
+            ) * max;
+            let mut dir = Vec2::ZERO;
+            let mut speed = 1.0;
+            let mut water = 1.0;
+            let mut sediment = 0.0;
+
+            for _ in 0..settings.lifetime {
+                let (height, gradient) = height_gradient(heights, size, pos);
+                dir = dir * settings.inertia - gradient * (1.0 - settings.inertia);
+                if dir.length_squared() < 1e-12 {
+                    break;
+                }
+                dir = dir.normalize();
+                let next = pos + dir;
+                if next.cmplt(Vec2::ZERO).any() || next.cmpge(Vec2::splat(max)).any() {
+                    break;
+                }
+
+                let dh = height_gradient(heights, size, next).0 - height;
+                let capacity = (-dh * speed * water * settings.capacity).max(settings.min_capacity);
+                if sediment > capacity || dh > 0.0 {
+                    let amount = if dh > 0.0 {
+                        dh.min(sediment)
+                    } else {
+                        (sediment - capacity) * settings.deposition
+                    };
+                    sediment -= amount;
+                    deposit(heights, size, pos, amount);
+                } else {
+                    let amount = ((capacity - sediment) * settings.erosion).min(-dh);
+                    sediment += amount;
+                    deposit(heights, size, pos, -amount);
+                }
+
+                speed = (speed * speed + dh * settings.gravity).max(0.0).sqrt();
+                water *= 1.0 - settings.evaporation;
+                pos = next;
+            }
+        }
+    }
+
+    /// Moves material from every column of a `size` squared grid to its lower neighbours where
+    /// the slope is steeper than the talus angle. Each column gathers from its neighbours, so the
+    /// result doesn't depend on the order columns are visited in.
+    pub fn erode_thermal(heights: &mut [f32], size: usize, settings: &ErosionSettings) {
+        let size = size as i32;
+        let mut next = heights.to_vec();
+        for _ in 0..settings.thermal_iterations {
+            for y in 0..size {
+                for x in 0..size {
+                    let h = heights[(y * size + x) as usize];
+                    let mut delta = 0.0;
+                    for ny in (y - 1).max(0)..=(y + 1).min(size - 1) {
+                        for nx in (x - 1).max(0)..=(x + 1).min(size - 1) {
+                            let d = h - heights[(ny * size + nx) as usize];
+                            if d > settings.talus {
+                                delta -= (d - settings.talus) * settings.thermal_rate / 8.0;
+                            } else if -d > settings.talus {
+                                delta += (-d - settings.talus) * settings.thermal_rate / 8.0;
+                            }
+                        }
+                    }
+                    next[(y * size + x) as usize] = h + delta;
+                }
+            }
+            heights.copy_from_slice(&next);
+        }
+    }
+
+    /// How much of the erosion a column keeps, fading to nothing at the region's edge.
+    pub fn edge_falloff(local: IVec2) -> f32 {
+        let core = local - REGION_MARGIN;
+        let distance = core.min(REGION_COLUMNS - 1 - core).min_element();
+        (distance as f32 / REGION_BLEND).clamp(0.0, 1.0)
+    }
+
+    /// Blends eroded heights back towards the raw ones at the region's edge.
+    pub fn blend_region_edges(heights: &mut [f32], raw: &[f32]) {
+        for (i, (height, raw)) in heights.iter_mut().zip(raw).enumerate() {
+            let local = IVec2::new((i % REGION_SIZE) as i32, (i / REGION_SIZE) as i32);
+            *height = raw + (*height - raw) * edge_falloff(local);
+        }
+    }
+
+    /// CPU reference of the whole erosion of a region.
+    pub fn erode_region(
+        region: IVec2,
+        terrain: &TerrainParams,
+        settings: &ErosionSettings,
+    ) -> Vec<f32> {
+        let raw = region_heights(region, terrain);
+        let mut heights = raw.clone();
+        erode_hydraulic(&mut heights, REGION_SIZE, region, terrain.seed, settings);
+        erode_thermal(&mut heights, REGION_SIZE, settings);
+        blend_region_edges(&mut heights, &raw);
+        heights
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::reference::*;
+    use super::*;
+
+    const SIZE: usize = 24;
+
+    /// Cone with a cliff across it.
+    fn hill() -> Vec<f32> {
+        (0..SIZE * SIZE)
+            .map(|i| {
+                let p = Vec2::new((i % SIZE) as f32, (i / SIZE) as f32) - SIZE as f32 / 2.0;
+                let cliff = if i % SIZE > SIZE / 2 { 4.0 } else { 0.0 };
+                16.0 - p.length() * 0.8 + cliff
+            })
+            .collect()
+    }
+
+    fn max_slope(heights: &[f32]) -> f32 {
+        let mut max = 0.0f32;
+        for y in 0..SIZE - 1 {
+            for x in 0..SIZE - 1 {
+                let h = heights[y * SIZE + x];
+                for n in [y * SIZE + x + 1, (y + 1) * SIZE + x, (y + 1) * SIZE + x + 1] {
+                    max = max.max((h - heights[n]).abs());
+                }
+            }
+        }
+        max
+    }
+
+    #[test]
+    fn thermal_erosion_conserves_mass() {
+        let mut heights = hill();
+        let before: f32 = heights.iter().sum();
+        erode_thermal(&mut heights, SIZE, &ErosionSettings::default());
+        let after: f32 = heights.iter().sum();
+        assert!((before - after).abs() < 1e-2, "{before} became {after}");
+        assert_ne!(heights, hill());
+    }
+
+    #[test]
+    fn hydraulic_erosion_moves_material_without_creating_any() {
+        let mut heights = hill();
+        let before: f32 = heights.iter().sum();
+        let settings = ErosionSettings {
+            droplets: 500,
+            ..default()
+        };
+        erode_hydraulic(&mut heights, SIZE, IVec2::ZERO, 3, &settings);
+        // Droplets carry the sediment they hold when they leave the grid or stop.
+        let after: f32 = heights.iter().sum();
+        assert!(after <= before + 1e-2, "{before} became {after}");
+        assert_ne!(heights, hill());
+    }
+
+    #[test]
+    fn thermal_erosion_keeps_slopes_under_the_talus() {
+        let settings = ErosionSettings::default();
+        let ramp: Vec<f32> = (0..SIZE * SIZE)
+            .map(|i| ((i % SIZE) + (i / SIZE)) as f32 * settings.talus * 0.45)
+            .collect();
+        let mut heights = ramp.clone();
+        erode_thermal(&mut heights, SIZE, &settings);
+        assert_eq!(heights, ramp);
+    }
+
+    #[test]
+    fn thermal_erosion_flattens_slopes_to_the_talus() {
+        let settings = ErosionSettings {
+            thermal_iterations: 400,
+            ..default()
+        };
+        let mut heights = hill();
+        assert!(max_slope(&heights) > settings.talus * 2.0);
+        erode_thermal(&mut heights, SIZE, &settings);
+        assert!(max_slope(&heights) < settings.talus * 1.05);
+    }
+
+    #[test]
+    fn erosion_is_deterministic() {
+        let terrain = TerrainParams {
+            seed: 5,
+            ..default()
+        };
+        let settings = ErosionSettings {
+            droplets: 2_000,
+            thermal_iterations: 4,
+            ..default()
+        };
+        let region = IVec2::new(1, -2);
+        let heights = erode_region(region, &terrain, &settings);
+        assert_eq!(heights.len(), REGION_SIZE * REGION_SIZE);
+        assert_eq!(heights, erode_region(region, &terrain, &settings));
+        assert_ne!(heights, region_heights(region, &terrain));
+    }
+}
@@ -1,4 +1,16 @@
-use bevy::{input::mouse::MouseMotion, prelude::*};
+use bevy::{
+    input::mouse::{AccumulatedMouseScroll, MouseMotion},
+    prelude::*,
+    window::{CursorGrabMode, CursorOptions},
+};
+
+use crate::{
+    input_map::{Actions, InputAction},
+    player::{MoveMode, PlayerController},
+};
+
+const MIN_SPEED: f32 = 1.0;
+const MAX_SPEED: f32 = 500.0;
 
 #[derive(Component)]
 pub struct FlyCamera {
@@ -10,52 +22,113 @@ pub struct FlyCamera {
 
 pub fn fly_camera(
     time: Res<Time>,
-    keys: Res<ButtonInput<KeyCode>>,
+    actions: Actions,
+    cursors: Query<&CursorOptions>,
     mut mouse_motion: MessageReader<MouseMotion>,
-    mut query: Query<(&mut Transform, &mut FlyCamera)>,
+    mut query: Query<(&mut Transform, &mut FlyCamera, Option<&PlayerController>)>,
 ) {
+    // The mouse only looks around while the cursor is locked.
+    let locked = cursors
+        .iter()
+        .any(|cursor| cursor.grab_mode != CursorGrabMode::None);
     let mut mouse_delta = Vec2::ZERO;
     for ev in mouse_motion.read() {
-        mouse_delta += ev.delta;
+        if locked {
+            mouse_delta += ev.delta;
+        }
     }
+    let stick = Vec2::new(
+        actions.axis(InputAction::LookLeft, InputAction::LookRight),
+        actions.axis(InputAction::LookDown, InputAction::LookUp),
+    ) * actions.map().look_speed
+        * time.delta_secs();
 
-    for (mut transform, mut cam) in &mut query {
-        cam.yaw -= mouse_delta.x * cam.sensitivity;
-        cam.pitch -= mouse_delta.y * cam.sensitivity;
+    for (mut transform, mut cam, player) in &mut query {
+        cam.yaw -= mouse_delta.x * cam.sensitivity + stick.x;
+        cam.pitch -= mouse_delta.y * cam.sensitivity - stick.y;
 
         cam.pitch = cam.pitch.clamp(-1.54, 1.54);
 
         transform.rotation =
             Quat::from_axis_angle(Vec3::Y, cam.yaw) * Quat::from_axis_angle(Vec3::X, cam.pitch);
 
-        let mut dir = Vec3::ZERO;
-        if keys.pressed(KeyCode::KeyW) {
-            dir.z -= 1.0;
-        }
-        if keys.pressed(KeyCode::KeyS) {
-            dir.z += 1.0;
-        }
-        if keys.pressed(KeyCode::KeyA) {
-            dir.x -= 1.0;
-        }
-        if keys.pressed(KeyCode::KeyD) {
-            dir.x += 1.0;
-        }
-        if keys.pressed(KeyCode::Space) {
-            dir.y += 1.0;
-        }
-        if keys.pressed(KeyCode::ControlLeft) {
-            dir.y -= 1.0;
+        // Walking players are moved by `walk_player`.
+        if player.is_some_and(|player| player.mode == MoveMode::Walk) {
+            continue;
         }
 
+        let dir = Vec3::new(
+            actions.axis(InputAction::MoveLeft, InputAction::MoveRight),
+            actions.axis(InputAction::Descend, InputAction::Ascend),
+            actions.axis(InputAction::MoveForward, InputAction::MoveBack),
+        )
+        .clamp_length_max(1.0);
+
         if dir != Vec3::ZERO {
-            let mut speed_mult = 1.0;
-            if keys.pressed(KeyCode::ShiftLeft) {
-                speed_mult = 5.0;
-            }
+            let speed_mult = 1.0 + 4.0 * actions.value(InputAction::Boost);
 
-            let forward = transform.rotation * dir.normalize();
+            let forward = transform.rotation * dir;
             transform.translation += forward * cam.speed * speed_mult * time.delta_secs();
         }
     }
 }
+
+/// The mouse wheel scales the flying speed.
+pub fn scroll_fly_speed(
+    actions: Actions,
+    scroll: Res<AccumulatedMouseScroll>,
+    mut cameras: Query<&mut FlyCamera>,
+) {
+    if scroll.delta.y == 0.0 {
+        return;
+    }
+    let factor = actions.map().wheel_speed_factor.powf(scroll.delta.y);
+    for mut cam in &mut cameras {
+        cam.speed = (cam.speed * factor).clamp(MIN_SPEED, MAX_SPEED);
+    }
+}
+
+/// Locks and hides the cursor, or frees it to reach other windows.
+pub fn toggle_cursor_lock(actions: Actions, mut cursors: Query<&mut CursorOptions>) {
+    if !actions.just_pressed(InputAction::ToggleCursorLock) {
+        return;
+    }
+    for mut cursor in &mut cursors {
+        let lock = cursor.grab_mode == CursorGrabMode::None;
+        cursor.grab_mode = if lock {
+            CursorGrabMode::Locked
+        } else {
+            CursorGrabMode::None
+        };
+        cursor.visible = !lock;
+    }
+}
+
+/// Run condition of the tools mouse clicks drive, which only act on a locked cursor.
+pub fn cursor_locked(cursors: Query<&CursorOptions>) -> bool {
+    cursors
+        .iter()
+        .any(|cursor| cursor.grab_mode != CursorGrabMode::None)
+}
+
+/// Clicking the window while the cursor is free locks it again. The click does nothing
+/// else, so it doesn't break a block or start a stroke where the cursor happened to be.
+pub fn lock_cursor_on_click(
+    mut mouse: ResMut<ButtonInput<MouseButton>>,
+    mut cursors: Query<&mut CursorOptions>,
+) {
+    let mut clicked = false;
+    for button in [MouseButton::Left, MouseButton::Right] {
+        if mouse.just_pressed(button) {
+            mouse.reset(button);
+            clicked = true;
+        }
+    }
+    if !clicked {
+        return;
+    }
+    for mut cursor in &mut cursors {
+        cursor.grab_mode = CursorGrabMode::Locked;
+        cursor.visible = false;
+    }
+}
@@ -0,0 +1,373 @@
+//! Terrain sampled from a heightmap authored in an external tool.
+
+use std::{io, sync::Arc};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, RenderAssetUsages, io::Reader},
+    image::{CompressedImageFormats, ImageSampler, ImageType},
+    prelude::*,
+    render::render_resource::TextureFormat,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::BlockId,
+    chunks_partition::{CHUNK_SIZE, CHUNK_VOXELS_COUNT},
+    terrain_generator::{
+        ActiveTerrainGenerator, ChunkMesher, ChunkVoxels, TerrainGenerator, TerrainParams,
+    },
+};
+
+/// Heights in [0, 1], row major.
+#[derive(Asset, TypePath, Clone, Debug)]
+pub struct HeightMap {
+    pub width: u32,
+    pub height: u32,
+    pub samples: Arc<Vec<f32>>,
+}
+
+/// What is sampled outside of the image.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum HeightMapWrap {
+    #[default]
+    Clamp,
+    Tile,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeightMapSettings {
+    /// Blocks per pixel.
+    pub world_scale: f32,
+    /// Height of a sample of 1.
+    pub vertical_scale: f32,
+    /// Height of a sample of 0.
+    pub offset: f32,
+    pub wrap: HeightMapWrap,
+}
+
+impl Default for HeightMapSettings {
+    fn default() -> Self {
+        Self {
+            world_scale: 1.0,
+            vertical_scale: 64.0,
+            offset: -16.0,
+            wrap: HeightMapWrap::Clamp,
+        }
+    }
+}
+
+impl HeightMap {
+    fn pixel(&self, x: i32, y: i32, wrap: HeightMapWrap) -> f32 {
+        let (w, h) = (self.width as i32, self.height as i32);
+        let (x, y) = match wrap {
+            HeightMapWrap::Clamp => (x.clamp(0, w - 1), y.clamp(0, h - 1)),
+            HeightMapWrap::Tile => (x.rem_euclid(w), y.rem_euclid(h)),
+        };
+        self.samples[(y * w + x) as usize]
+    }
+
+    /// Bilinear sample at a pixel position.
+    pub fn sample(&self, p: Vec2, wrap: HeightMapWrap) -> f32 {
+        let cell = p.floor();
+        let f = p - cell;
+        let (x, y) = (cell.x as i32, cell.y as i32);
+        let top = self.pixel(x, y, wrap).lerp(self.pixel(x + 1, y, wrap), f.x);
+        let bottom = self
+            .pixel(x, y + 1, wrap)
+            .lerp(self.pixel(x + 1, y + 1, wrap), f.x);
+        top.lerp(bottom, f.y)
+    }
+
+    /// Surface height of a world column.
+    pub fn height_at(&self, world_xz: Vec2, settings: &HeightMapSettings) -> f32 {
+        let sample = self.sample(world_xz / settings.world_scale, settings.wrap);
+        settings.offset + sample * settings.vertical_scale
+    }
+
+    fn from_image(image: &Image) -> io::Result<Self> {
+        if image.width() == 0 || image.height() == 0 {
+            return Err(empty_height_map());
+        }
+        let data = image
+            .data
+            .as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "image has no data"))?;
+        let unorm16 = |bytes: &[u8]| u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / 65535.0;
+        let samples = match image.texture_descriptor.format {
+            TextureFormat::R16Uint | TextureFormat::R16Unorm => {
+                data.chunks_exact(2).map(unorm16).collect()
+            }
+            TextureFormat::Rgba16Uint | TextureFormat::Rgba16Unorm => {
+                data.chunks_exact(8).map(unorm16).collect()
+            }
+            TextureFormat::R8Unorm => data.iter().map(|v| *v as f32 / 255.0).collect(),
+            TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb => {
+                data.chunks_exact(4).map(|v| v[0] as f32 / 255.0).collect()
+            }
+            format => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported heightmap format {format:?}"),
+                ));
+            }
+        };
+        Ok(Self {
+            width: image.width(),
+            height: image.height(),
+            samples: Arc::new(samples),
+        })
+    }
+
+    /// Square image of little endian 16 bit samples.
+    fn from_raw(bytes: &[u8]) -> io::Result<Self> {
+        let size = ((bytes.len() / 2) as f64).sqrt() as u32;
+        if size == 0 {
+            return Err(empty_height_map());
+        }
+        if (size * size * 2) as usize != bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "raw heightmaps must be square 16 bit images",
+            ));
+        }
+        Ok(Self {
+            width: size,
+            height: size,
+            samples: Arc::new(
+                bytes
+                    .chunks_exact(2)
+                    .map(|v| u16::from_le_bytes([v[0], v[1]]) as f32 / 65535.0)
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// `sample` reads at least one pixel.
+fn empty_height_map() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "heightmap has no pixels")
+}
+
+/// Loads `.height.png` grayscale images and `.r16` raw heightmaps as [`HeightMap`]s.
+#[derive(Default, TypePath)]
+pub struct HeightMapLoader;
+
+impl AssetLoader for HeightMapLoader {
+    type Asset = HeightMap;
+    type Settings = ();
+    type Error = io::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<HeightMap, io::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+
+        if load_context
+            .path()
+            .extension()
+            .is_some_and(|ext| ext == "r16")
+        {
+            return HeightMap::from_raw(&bytes);
+        }
+
+        let image = Image::from_buffer(
+            &bytes,
+            ImageType::Extension("png"),
+            CompressedImageFormats::NONE,
+            false,
+            ImageSampler::Default,
+            RenderAssetUsages::MAIN_WORLD,
+        )
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        HeightMap::from_image(&image)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["height.png", "r16"]
+    }
+}
+
+/// Generates chunks from a [`HeightMap`] on the CPU, as a height field or as blocks.
+pub struct HeightMapGenerator {
+    pub map: HeightMap,
+    pub settings: HeightMapSettings,
+    pub mesher: ChunkMesher,
+}
+
+impl TerrainGenerator for HeightMapGenerator {
+    fn generate(&self, _terrain: &TerrainParams, chunk: IVec3, voxels: &mut ChunkVoxels) {
+        let n = CHUNK_SIZE as i32;
+        let bottom = (chunk.y * n) as f32;
+        for i in 0..CHUNK_VOXELS_COUNT {
+            let local = IVec3::new(i as i32 % n, (i as i32 / n) % n, i as i32 / (n * n));
+            match self.mesher {
+                // The z=0 slice holds the heights, (x, y) being the column.
+                ChunkMesher::HeightField => {
+                    let world_xz = IVec2::new(chunk.x * n + local.x, chunk.z * n + local.y);
+                    voxels.density[i] =
+                        self.map.height_at(world_xz.as_vec2(), &self.settings) - bottom;
+                    voxels.blocks[i] = BlockId::GRASS;
+                }
+                ChunkMesher::Blocks => {
+                    let world = chunk * n + local;
+                    let depth =
+                        self.map.height_at(world.xz().as_vec2(), &self.settings) - world.y as f32;
+                    voxels.density[i] = depth;
+                    voxels.blocks[i] = if depth < 0.0 {
+                        BlockId::AIR
+                    } else if depth < 1.0 {
+                        BlockId::GRASS
+                    } else if depth < 4.0 {
+                        BlockId::DIRT
+                    } else {
+                        BlockId::STONE
+                    };
+                }
+            }
+        }
+    }
+
+    fn mesher(&self) -> ChunkMesher {
+        self.mesher
+    }
+
+    fn procedural_water(&self) -> bool {
+        false
+    }
+}
+
+/// Heightmap the world is generated from once it has loaded.
+#[derive(Resource, Clone, PartialEq)]
+pub struct HeightMapTerrain {
+    pub map: Handle<HeightMap>,
+    pub settings: HeightMapSettings,
+    pub mesher: ChunkMesher,
+}
+
+pub struct HeightMapPlugin;
+
+impl Plugin for HeightMapPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<HeightMap>()
+            .register_asset_loader(HeightMapLoader)
+            .add_systems(Update, use_height_map_terrain);
+    }
+}
+
+/// Switches to the [`HeightMapTerrain`] when it loads, is changed or its asset is reloaded.
+fn use_height_map_terrain(
+    mut commands: Commands,
+    terrain: Option<Res<HeightMapTerrain>>,
+    maps: Res<Assets<HeightMap>>,
+    mut events: MessageReader<AssetEvent<HeightMap>>,
+    mut pending: Local<bool>,
+) {
+    let Some(terrain) = terrain else {
+        return;
+    };
+
+    let reloaded = events.read().any(|event| match event {
+        AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => {
+            *id == terrain.map.id()
+        }
+        _ => false,
+    });
+    *pending |= reloaded || terrain.is_changed();
+
+    if !*pending {
+        return;
+    }
+
+    let Some(map) = maps.get(&terrain.map) else {
+        return;
+    };
+
+    *pending = false;
+    commands.insert_resource(ActiveTerrainGenerator::new(HeightMapGenerator {
+        map: map.clone(),
+        settings: terrain.settings,
+        mesher: terrain.mesher,
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::{
+        chunk::{ChunkPos, ChunkState},
+        water::{WaterBodies, WaterSettings, apply_water, find_lake, update_water_bodies},
+    };
+
+    #[test]
+    fn raw_heightmaps_need_pixels() {
+        assert!(HeightMap::from_raw(&[]).is_err());
+        assert!(HeightMap::from_raw(&[0, 0, 0]).is_err());
+
+        let map = HeightMap::from_raw(&[0, 0, 0xff, 0xff, 0, 0, 0xff, 0xff]).unwrap();
+        assert_eq!((map.width, map.height), (2, 2));
+        assert_eq!(map.sample(Vec2::new(1.0, 0.0), HeightMapWrap::Clamp), 1.0);
+        assert_eq!(map.sample(Vec2::new(0.5, 7.0), HeightMapWrap::Clamp), 0.5);
+    }
+
+    #[test]
+    fn flat_heightmaps_only_get_the_sea() {
+        // A lake of the procedural terrain, flat ground just below its level on the heightmap.
+        let (terrain, lake) = (0..8)
+            .find_map(|seed| {
+                let terrain = TerrainParams { seed, ..default() };
+                (-8..=8)
+                    .flat_map(|x| (-8..=8).map(move |y| IVec2::new(x, y)))
+                    .find_map(|cell| find_lake(cell, &terrain, Some(0.0)))
+                    .map(|lake| (terrain, lake))
+            })
+            .unwrap();
+        let generator = ActiveTerrainGenerator::new(HeightMapGenerator {
+            map: HeightMap {
+                width: 1,
+                height: 1,
+                samples: Arc::new(vec![0.0]),
+            },
+            settings: HeightMapSettings {
+                offset: lake.level - 4.0,
+                ..default()
+            },
+            mesher: ChunkMesher::Blocks,
+        });
+
+        let n = CHUNK_SIZE as i32;
+        let center = (Vec2::from(lake.center) / CHUNK_SIZE).floor().as_ivec2();
+        let chunk = IVec3::new(
+            center.x,
+            ((lake.level - 2.0) / CHUNK_SIZE).floor() as i32,
+            center.y,
+        );
+        let mut world = World::new();
+        world.insert_resource(WaterSettings {
+            sea_level: Some(0.0),
+            ..default()
+        });
+        world.insert_resource(terrain);
+        world.insert_resource(generator.clone());
+        world.init_resource::<WaterBodies>();
+        world.spawn((ChunkPos(chunk), ChunkState::Ready));
+        world.run_system_once(update_water_bodies).unwrap();
+        let water = world.resource::<WaterBodies>().data.clone();
+
+        let mut voxels = ChunkVoxels::default();
+        generator.generate(&terrain, chunk, &mut voxels);
+        apply_water(&mut voxels, chunk, &water, ChunkMesher::Blocks, 0.0);
+        for i in 0..CHUNK_VOXELS_COUNT {
+            let y = chunk.y * n + (i as i32 / n) % n;
+            if voxels.blocks[i] == BlockId::WATER {
+                assert!(y < 0);
+            }
+        }
+    }
+}
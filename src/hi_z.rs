@@ -0,0 +1,434 @@
+use std::borrow::Cow;
+
+use bevy::{
+    core_pipeline::core_3d::graph::{Core3d, Node3d},
+    prelude::*,
+    render::{
+        Render, RenderApp, RenderStartup, RenderSystems,
+        camera::ExtractedCamera,
+        render_asset::RenderAssets,
+        render_graph::{
+            NodeRunError, RenderGraphContext, RenderGraphExt, RenderLabel, ViewNode, ViewNodeRunner,
+        },
+        render_resource::{
+            UniformBuffer,
+            binding_types::{
+                storage_buffer_read_only, storage_buffer_read_only_sized, storage_buffer_sized,
+                texture_2d, texture_storage_2d, uniform_buffer,
+            },
+            *,
+        },
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        storage::GpuShaderStorageBuffer,
+        view::{ExtractedView, ViewDepthTexture},
+    },
+};
+
+use crate::{
+    chunks_partition::{CHUNK_COUNT, CHUNK_SIZE, VisibleChunks},
+    voxel_compute_grid::{
+        CHUNK_VERTICES_COUNT, CHUNK_WATER_VERTICES_COUNT, ChunkCoord, VoxelComputeGridImage,
+    },
+};
+
+const DOWNSAMPLE_SHADER_ASSET_PATH: &str = "shaders/hi_z_downsample.wgsl";
+const CULL_SHADER_ASSET_PATH: &str = "shaders/hi_z_cull.wgsl";
+
+/// Builds a depth pyramid from every view's depth buffer after the main pass and uses the
+/// previous frame's pyramid to write per-chunk indirect draw args before the chunks are drawn.
+pub struct HiZPlugin;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct HiZCullPass;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct HiZBuildPass;
+
+#[derive(Clone, Copy, ShaderType, Debug, Default)]
+struct HiZCull {
+    clip_from_world: Mat4,
+    pyramid_size: UVec2,
+    mip_count: u32,
+    enabled: u32,
+    chunk_size: f32,
+    vertices_per_chunk: u32,
+    water_vertices_per_chunk: u32,
+}
+
+#[derive(Resource)]
+struct HiZPipelines {
+    downsample_layout: BindGroupLayout,
+    downsample_pipeline: CachedComputePipelineId,
+    cull_layout: BindGroupLayout,
+    cull_pipeline: CachedComputePipelineId,
+}
+
+/// Per-view depth pyramid, the slots the view can see and the chunk draw args culled against both.
+#[derive(Component)]
+pub struct ViewHiZ {
+    depth_size: UVec2,
+    pyramid_size: UVec2,
+    mip_count: u32,
+    pyramid_view: TextureView,
+    mip_views: Vec<TextureView>,
+    /// `clip_from_world` of the frame the pyramid currently holds, `None` until it's built.
+    built_clip_from_world: Option<Mat4>,
+    cull: UniformBuffer<HiZCull>,
+    /// One `u32` per slot, non-zero when the slot is in the view's [`VisibleChunks`].
+    visible: Buffer,
+    pub draw_args: Buffer,
+    /// Draw args of the translucent output, culled like `draw_args`.
+    pub water_draw_args: Buffer,
+}
+
+#[derive(Component)]
+struct ViewHiZBindGroups {
+    downsample: Vec<BindGroup>,
+    cull: BindGroup,
+}
+
+impl Plugin for HiZPlugin {
+    fn build(&self, app: &mut App) {
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .add_systems(RenderStartup, init_hi_z_pipelines)
+            .add_systems(
+                Render,
+                prepare_view_hi_z.in_set(RenderSystems::PrepareResources),
+            )
+            .add_systems(
+                Render,
+                prepare_hi_z_bind_groups.in_set(RenderSystems::PrepareBindGroups),
+            )
+            .add_render_graph_node::<ViewNodeRunner<HiZCullNode>>(Core3d, HiZCullPass)
+            .add_render_graph_node::<ViewNodeRunner<HiZBuildNode>>(Core3d, HiZBuildPass)
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::EndMainPass,
+                    HiZBuildPass,
+                    Node3d::StartMainPassPostProcessing,
+                ),
+            );
+    }
+}
+
+fn init_hi_z_pipelines(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+) {
+    let downsample_layout = render_device.create_bind_group_layout(
+        Some("HiZ Downsample Layout"),
+        &BindGroupLayoutEntries::sequential(
+            ShaderStages::COMPUTE,
+            (
+                texture_2d(TextureSampleType::Float { filterable: false }),
+                texture_storage_2d(TextureFormat::R32Float, StorageTextureAccess::WriteOnly),
+            ),
+        ),
+    );
+
+    let cull_layout = render_device.create_bind_group_layout(
+        Some("HiZ Cull Layout"),
+        &BindGroupLayoutEntries::sequential(
+            ShaderStages::COMPUTE,
+            (
+                uniform_buffer::<HiZCull>(false),
+                storage_buffer_read_only::<ChunkCoord>(false),
+                texture_2d(TextureSampleType::Float { filterable: false }),
+                storage_buffer_sized(false, None),
+                storage_buffer_read_only_sized(false, None),
+                storage_buffer_read_only::<u32>(false),
+                storage_buffer_read_only::<u32>(false),
+                storage_buffer_sized(false, None),
+            ),
+        ),
+    );
+
+    let downsample_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        layout: vec![downsample_layout.clone()],
+        shader: asset_server.load(DOWNSAMPLE_SHADER_ASSET_PATH),
+        entry_point: Some(Cow::from("downsample")),
+        ..default()
+    });
+    let cull_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        layout: vec![cull_layout.clone()],
+        shader: asset_server.load(CULL_SHADER_ASSET_PATH),
+        entry_point: Some(Cow::from("cull_chunks")),
+        ..default()
+    });
+
+    commands.insert_resource(HiZPipelines {
+        downsample_layout,
+        downsample_pipeline,
+        cull_layout,
+        cull_pipeline,
+    });
+}
+
+impl ViewHiZ {
+    fn new(render_device: &RenderDevice, depth_size: UVec2) -> Self {
+        let pyramid_size = (depth_size / 2).max(UVec2::ONE);
+        let mip_count = pyramid_size.max_element().ilog2() + 1;
+
+        let pyramid = render_device.create_texture(&TextureDescriptor {
+            label: Some("hi_z_pyramid"),
+            size: Extent3d {
+                width: pyramid_size.x,
+                height: pyramid_size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: mip_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let mip_views = (0..mip_count)
+            .map(|mip| {
+                pyramid.create_view(&TextureViewDescriptor {
+                    label: Some("hi_z_pyramid_mip"),
+                    base_mip_level: mip,
+                    mip_level_count: Some(1),
+                    ..default()
+                })
+            })
+            .collect();
+
+        let visible = render_device.create_buffer(&BufferDescriptor {
+            label: Some("chunk_view_visibility"),
+            size: (CHUNK_COUNT * std::mem::size_of::<u32>()) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let draw_args = render_device.create_buffer(&BufferDescriptor {
+            label: Some("chunk_draw_args"),
+            size: (CHUNK_COUNT * std::mem::size_of::<DrawIndirectArgs>()) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT,
+            mapped_at_creation: false,
+        });
+
+        let water_draw_args = render_device.create_buffer(&BufferDescriptor {
+            label: Some("chunk_water_draw_args"),
+            size: (CHUNK_COUNT * std::mem::size_of::<DrawIndirectArgs>()) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::INDIRECT,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            depth_size,
+            pyramid_size,
+            mip_count,
+            pyramid_view: pyramid.create_view(&TextureViewDescriptor::default()),
+            mip_views,
+            built_clip_from_world: None,
+            cull: UniformBuffer::default(),
+            visible,
+            draw_args,
+            water_draw_args,
+        }
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn prepare_view_hi_z(
+    mut commands: Commands,
+    render_device: Res<RenderDevice>,
+    queue: Res<RenderQueue>,
+    mut views: Query<(
+        Entity,
+        &ExtractedCamera,
+        &ExtractedView,
+        Option<&VisibleChunks>,
+        Option<&mut ViewHiZ>,
+    )>,
+) {
+    for (entity, camera, view, visible, hi_z) in &mut views {
+        let Some(depth_size) = camera.physical_target_size else {
+            continue;
+        };
+
+        let mut new_hi_z = None;
+        let hi_z = match hi_z {
+            Some(hi_z) if hi_z.depth_size == depth_size => hi_z.into_inner(),
+            _ => new_hi_z.insert(ViewHiZ::new(&render_device, depth_size)),
+        };
+
+        // Cull against what the pyramid holds now, i.e. last frame's depth, then record that
+        // this frame's build pass will overwrite it with the current view.
+        hi_z.cull.set(HiZCull {
+            clip_from_world: hi_z.built_clip_from_world.unwrap_or(Mat4::IDENTITY),
+            pyramid_size: hi_z.pyramid_size,
+            mip_count: hi_z.mip_count,
+            enabled: hi_z.built_clip_from_world.is_some() as u32,
+            chunk_size: CHUNK_SIZE,
+            vertices_per_chunk: CHUNK_VERTICES_COUNT as u32,
+            water_vertices_per_chunk: CHUNK_WATER_VERTICES_COUNT as u32,
+        });
+        hi_z.cull.write_buffer(&render_device, &queue);
+
+        let mut visible_mask = vec![0u32; CHUNK_COUNT];
+        for slot in visible.iter().flat_map(|visible| &visible.slots) {
+            visible_mask[*slot as usize] = 1;
+        }
+        queue.write_buffer(&hi_z.visible, 0, bytemuck::cast_slice(&visible_mask));
+
+        hi_z.built_clip_from_world =
+            Some(view.clip_from_world.unwrap_or_else(|| {
+                view.clip_from_view * view.world_from_view.to_matrix().inverse()
+            }));
+
+        if let Some(new_hi_z) = new_hi_z {
+            commands.entity(entity).insert(new_hi_z);
+        }
+    }
+}
+
+fn prepare_hi_z_bind_groups(
+    mut commands: Commands,
+    pipelines: Res<HiZPipelines>,
+    render_device: Res<RenderDevice>,
+    image: Option<Res<VoxelComputeGridImage>>,
+    buffers: Res<RenderAssets<GpuShaderStorageBuffer>>,
+    views: Query<(Entity, &ViewHiZ, &ViewDepthTexture)>,
+) {
+    let Some(image) = image else {
+        return;
+    };
+
+    let Some(chunks_gpu) = buffers.get(&image.chunks) else {
+        return;
+    };
+
+    let Some(vertex_counts_gpu) = buffers.get(&image.vertex_counts) else {
+        return;
+    };
+
+    let Some(water_vertex_counts_gpu) = buffers.get(&image.water_vertex_counts) else {
+        return;
+    };
+
+    for (entity, hi_z, depth) in &views {
+        let Some(cull_binding) = hi_z.cull.binding() else {
+            continue;
+        };
+
+        let downsample = (0..hi_z.mip_views.len())
+            .map(|mip| {
+                let input = if mip == 0 {
+                    depth.view()
+                } else {
+                    &hi_z.mip_views[mip - 1]
+                };
+
+                render_device.create_bind_group(
+                    Some("hi_z_downsample_bind_group"),
+                    &pipelines.downsample_layout,
+                    &BindGroupEntries::sequential((input, &hi_z.mip_views[mip])),
+                )
+            })
+            .collect();
+
+        let cull = render_device.create_bind_group(
+            Some("hi_z_cull_bind_group"),
+            &pipelines.cull_layout,
+            &BindGroupEntries::sequential((
+                cull_binding,
+                chunks_gpu.buffer.as_entire_buffer_binding(),
+                &hi_z.pyramid_view,
+                hi_z.draw_args.as_entire_buffer_binding(),
+                hi_z.visible.as_entire_buffer_binding(),
+                vertex_counts_gpu.buffer.as_entire_buffer_binding(),
+                water_vertex_counts_gpu.buffer.as_entire_buffer_binding(),
+                hi_z.water_draw_args.as_entire_buffer_binding(),
+            )),
+        );
+
+        commands
+            .entity(entity)
+            .insert(ViewHiZBindGroups { downsample, cull });
+    }
+}
+
+#[derive(Default)]
+struct HiZCullNode;
+
+impl ViewNode for HiZCullNode {
+    type ViewQuery = &'static ViewHiZBindGroups;
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        bind_groups: &'w ViewHiZBindGroups,
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipelines = world.resource::<HiZPipelines>();
+
+        let Some(cull_pipeline) = pipeline_cache.get_compute_pipeline(pipelines.cull_pipeline)
+        else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("hi_z_cull_pass"),
+                    ..default()
+                });
+
+        pass.set_pipeline(cull_pipeline);
+        pass.set_bind_group(0, &bind_groups.cull, &[]);
+        pass.dispatch_workgroups((CHUNK_COUNT as u32).div_ceil(64), 1, 1);
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct HiZBuildNode;
+
+impl ViewNode for HiZBuildNode {
+    type ViewQuery = (&'static ViewHiZ, &'static ViewHiZBindGroups);
+
+    fn run<'w>(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext<'w>,
+        (hi_z, bind_groups): (&'w ViewHiZ, &'w ViewHiZBindGroups),
+        world: &'w World,
+    ) -> Result<(), NodeRunError> {
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let pipelines = world.resource::<HiZPipelines>();
+
+        let Some(downsample_pipeline) =
+            pipeline_cache.get_compute_pipeline(pipelines.downsample_pipeline)
+        else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("hi_z_build_pass"),
+                    ..default()
+                });
+
+        pass.set_pipeline(downsample_pipeline);
+        for (mip, bind_group) in bind_groups.downsample.iter().enumerate() {
+            let mip_size = (hi_z.pyramid_size >> mip as u32).max(UVec2::ONE);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(mip_size.x.div_ceil(8), mip_size.y.div_ceil(8), 1);
+        }
+
+        Ok(())
+    }
+}
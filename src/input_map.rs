@@ -0,0 +1,366 @@
+//! Keyboard, mouse and gamepad bindings of the camera and player actions, loaded from a
+//! RON settings file and applied again every time it is edited.
+
+use std::{collections::HashMap, io};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, io::Reader},
+    ecs::system::SystemParam,
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum InputAction {
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    /// Flies up, jumps when walking.
+    Ascend,
+    /// Flies down, crouches when walking.
+    Descend,
+    /// Flies faster, sprints when walking.
+    Boost,
+    LookUp,
+    LookDown,
+    LookLeft,
+    LookRight,
+    ToggleMoveMode,
+    ToggleCursorLock,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// Analog for the triggers.
+    GamepadButton(GamepadButton),
+    /// One direction of a stick.
+    GamepadAxis {
+        axis: GamepadAxis,
+        positive: bool,
+    },
+}
+
+/// Bindings of every action.
+#[derive(Resource, Clone, PartialEq, Debug)]
+pub struct InputMap {
+    pub bindings: HashMap<InputAction, Vec<Binding>>,
+    /// Radians per second a fully tilted look stick turns the camera.
+    pub look_speed: f32,
+    /// Factor one notch of the mouse wheel multiplies the fly speed by.
+    pub wheel_speed_factor: f32,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        use InputAction::*;
+
+        let key = Binding::Key;
+        let button = Binding::GamepadButton;
+        let stick = |axis, positive| Binding::GamepadAxis { axis, positive };
+        let bindings = HashMap::from([
+            (
+                MoveForward,
+                vec![key(KeyCode::KeyW), stick(GamepadAxis::LeftStickY, true)],
+            ),
+            (
+                MoveBack,
+                vec![key(KeyCode::KeyS), stick(GamepadAxis::LeftStickY, false)],
+            ),
+            (
+                MoveLeft,
+                vec![key(KeyCode::KeyA), stick(GamepadAxis::LeftStickX, false)],
+            ),
+            (
+                MoveRight,
+                vec![key(KeyCode::KeyD), stick(GamepadAxis::LeftStickX, true)],
+            ),
+            (
+                Ascend,
+                vec![key(KeyCode::Space), button(GamepadButton::RightTrigger2)],
+            ),
+            (
+                Descend,
+                vec![key(KeyCode::KeyC), button(GamepadButton::LeftTrigger2)],
+            ),
+            (
+                Boost,
+                vec![key(KeyCode::ShiftLeft), button(GamepadButton::LeftThumb)],
+            ),
+            (LookUp, vec![stick(GamepadAxis::RightStickY, true)]),
+            (LookDown, vec![stick(GamepadAxis::RightStickY, false)]),
+            (LookLeft, vec![stick(GamepadAxis::RightStickX, false)]),
+            (LookRight, vec![stick(GamepadAxis::RightStickX, true)]),
+            (
+                ToggleMoveMode,
+                vec![key(KeyCode::KeyX), button(GamepadButton::North)],
+            ),
+            (
+                ToggleCursorLock,
+                vec![key(KeyCode::Escape), button(GamepadButton::Start)],
+            ),
+        ]);
+
+        Self {
+            bindings,
+            look_speed: 2.5,
+            wheel_speed_factor: 1.2,
+        }
+    }
+}
+
+impl InputMap {
+    pub fn bindings(&self, action: InputAction) -> &[Binding] {
+        self.bindings.get(&action).map_or(&[], Vec::as_slice)
+    }
+}
+
+/// Settings file of the [`InputMap`].
+#[derive(Asset, TypePath, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputSettings {
+    /// Actions bound differently from [`InputMap::default`], unbound when empty.
+    pub bindings: HashMap<InputAction, Vec<Binding>>,
+    pub look_speed: Option<f32>,
+    pub wheel_speed_factor: Option<f32>,
+}
+
+impl InputSettings {
+    pub fn input_map(&self) -> InputMap {
+        let mut map = InputMap::default();
+        map.bindings.extend(self.bindings.clone());
+        map.look_speed = self.look_speed.unwrap_or(map.look_speed);
+        map.wheel_speed_factor = self.wheel_speed_factor.unwrap_or(map.wheel_speed_factor);
+        map
+    }
+}
+
+/// Loads `.input.ron` files as [`InputSettings`].
+#[derive(Default, TypePath)]
+pub struct InputSettingsLoader;
+
+impl AssetLoader for InputSettingsLoader {
+    type Asset = InputSettings;
+    type Settings = ();
+    type Error = io::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<InputSettings, io::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        ron::de::from_bytes(&bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["input.ron"]
+    }
+}
+
+/// Settings the input map is loaded from.
+#[derive(Resource, Clone, Deref)]
+pub struct InputSettingsHandle(pub Handle<InputSettings>);
+
+/// State of the actions through their bindings, the strongest binding winning.
+#[derive(SystemParam)]
+pub struct Actions<'w, 's> {
+    map: Res<'w, InputMap>,
+    keys: Res<'w, ButtonInput<KeyCode>>,
+    mouse: Res<'w, ButtonInput<MouseButton>>,
+    gamepads: Query<'w, 's, &'static Gamepad>,
+}
+
+impl Actions<'_, '_> {
+    /// Whether `key` is part of a Ctrl shortcut such as Ctrl+Z or Ctrl+S, which no
+    /// action answers to.
+    fn in_chord(&self, key: KeyCode) -> bool {
+        let ctrl = [KeyCode::ControlLeft, KeyCode::ControlRight];
+        !ctrl.contains(&key) && self.keys.any_pressed(ctrl)
+    }
+
+    fn binding_value(&self, binding: &Binding) -> f32 {
+        let pressed = |pressed: bool| if pressed { 1.0 } else { 0.0 };
+        match *binding {
+            Binding::Key(key) => pressed(self.keys.pressed(key) && !self.in_chord(key)),
+            Binding::Mouse(button) => pressed(self.mouse.pressed(button)),
+            Binding::GamepadButton(button) => self
+                .gamepads
+                .iter()
+                .filter_map(|gamepad| gamepad.get(button))
+                .fold(0.0, f32::max),
+            Binding::GamepadAxis { axis, positive } => self
+                .gamepads
+                .iter()
+                .filter_map(|gamepad| gamepad.get(axis))
+                .map(|value| if positive { value } else { -value })
+                .fold(0.0, f32::max),
+        }
+    }
+
+    /// How strongly the action is held, from 0 to 1.
+    pub fn value(&self, action: InputAction) -> f32 {
+        self.map
+            .bindings(action)
+            .iter()
+            .map(|binding| self.binding_value(binding))
+            .fold(0.0, f32::max)
+    }
+
+    pub fn pressed(&self, action: InputAction) -> bool {
+        self.value(action) > 0.5
+    }
+
+    /// Whether a button bound to the action went down this frame. Sticks never do.
+    pub fn just_pressed(&self, action: InputAction) -> bool {
+        self.map
+            .bindings(action)
+            .iter()
+            .any(|binding| match *binding {
+                Binding::Key(key) => self.keys.just_pressed(key) && !self.in_chord(key),
+                Binding::Mouse(button) => self.mouse.just_pressed(button),
+                Binding::GamepadButton(button) => self
+                    .gamepads
+                    .iter()
+                    .any(|gamepad| gamepad.just_pressed(button)),
+                Binding::GamepadAxis { .. } => false,
+            })
+    }
+
+    /// `positive` minus `negative`, from -1 to 1.
+    pub fn axis(&self, negative: InputAction, positive: InputAction) -> f32 {
+        self.value(positive) - self.value(negative)
+    }
+
+    pub fn map(&self) -> &InputMap {
+        &self.map
+    }
+}
+
+pub struct InputMapPlugin {
+    pub path: &'static str,
+}
+
+impl Default for InputMapPlugin {
+    fn default() -> Self {
+        Self {
+            path: "default.input.ron",
+        }
+    }
+}
+
+impl Plugin for InputMapPlugin {
+    fn build(&self, app: &mut App) {
+        let path = self.path;
+        app.init_asset::<InputSettings>()
+            .register_asset_loader(InputSettingsLoader)
+            .init_resource::<InputMap>()
+            .add_systems(
+                Startup,
+                move |mut commands: Commands, assets: Res<AssetServer>| {
+                    commands.insert_resource(InputSettingsHandle(assets.load(path)));
+                },
+            )
+            .add_systems(PreUpdate, apply_input_settings);
+    }
+}
+
+fn apply_input_settings(
+    handle: Option<Res<InputSettingsHandle>>,
+    settings: Res<Assets<InputSettings>>,
+    mut events: MessageReader<AssetEvent<InputSettings>>,
+    mut map: ResMut<InputMap>,
+) {
+    let Some(handle) = handle else {
+        return;
+    };
+
+    let reloaded = events.read().any(|event| match event {
+        AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => {
+            *id == handle.id()
+        }
+        _ => false,
+    });
+    if reloaded && let Some(settings) = settings.get(&handle.0) {
+        map.set_if_neq(settings.input_map());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+
+    #[test]
+    fn settings_override_only_the_actions_they_bind() {
+        let settings = InputSettings {
+            bindings: HashMap::from([
+                (InputAction::Ascend, vec![Binding::Key(KeyCode::Enter)]),
+                (InputAction::Boost, Vec::new()),
+            ]),
+            look_speed: Some(1.0),
+            wheel_speed_factor: None,
+        };
+        let map = settings.input_map();
+        let default = InputMap::default();
+        assert_eq!(
+            map.bindings(InputAction::Ascend),
+            [Binding::Key(KeyCode::Enter)]
+        );
+        assert!(map.bindings(InputAction::Boost).is_empty());
+        assert_eq!(
+            map.bindings(InputAction::MoveForward),
+            default.bindings(InputAction::MoveForward)
+        );
+        assert_eq!(map.look_speed, 1.0);
+        assert_eq!(map.wheel_speed_factor, default.wheel_speed_factor);
+
+        // The shipped settings restate the defaults.
+        let shipped: InputSettings =
+            ron::de::from_bytes(include_bytes!("../assets/default.input.ron")).unwrap();
+        assert_eq!(shipped.input_map(), default);
+    }
+
+    /// `(pressed, just_pressed)` of `action` with `keys` just pressed.
+    fn press(map: InputMap, keys: &[KeyCode], action: InputAction) -> (bool, bool) {
+        let mut world = World::new();
+        let mut input = ButtonInput::<KeyCode>::default();
+        for key in keys {
+            input.press(*key);
+        }
+        world.insert_resource(map);
+        world.insert_resource(input);
+        world.init_resource::<ButtonInput<MouseButton>>();
+        world
+            .run_system_once(move |actions: Actions| {
+                (actions.pressed(action), actions.just_pressed(action))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn keys_held_with_ctrl_are_shortcuts_not_actions() {
+        let descend = InputAction::Descend;
+        assert_eq!(
+            press(InputMap::default(), &[KeyCode::KeyC], descend),
+            (true, true)
+        );
+        for ctrl in [KeyCode::ControlLeft, KeyCode::ControlRight] {
+            assert_eq!(
+                press(InputMap::default(), &[ctrl, KeyCode::KeyC], descend),
+                (false, false)
+            );
+        }
+
+        // Ctrl itself can still be bound.
+        let mut map = InputMap::default();
+        map.bindings
+            .insert(descend, vec![Binding::Key(KeyCode::ControlLeft)]);
+        assert_eq!(press(map, &[KeyCode::ControlLeft], descend), (true, true));
+    }
+}
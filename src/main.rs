@@ -1,21 +1,62 @@
 use bevy::dev_tools::fps_overlay::{FpsOverlayConfig, FpsOverlayPlugin, FrameTimeGraphConfig};
+use bevy::input::InputSystems;
 use bevy::prelude::*;
+use bevy::render::render_resource::TextureUsages;
 use bevy::window::{CursorGrabMode, CursorOptions};
 
+mod biome;
+mod block;
+mod block_interaction;
+mod chunk;
+mod chunk_connectivity;
+mod chunk_queue;
 mod chunks_partition;
+mod decoration;
+mod edit_history;
+mod erosion;
 mod fly_camera;
+mod height_map;
+mod hi_z;
+mod input_map;
+mod noise;
+mod player;
+mod schematic;
+mod sculpt;
+mod selection;
+mod terrain_generator;
+mod vox;
 mod voxel_compute_grid;
 mod voxel_material;
 mod voxel_mesh;
 mod voxel_render;
+mod voxel_world;
+mod water;
+mod world_config;
 
 use fly_camera::FlyCamera;
-use fly_camera::fly_camera;
+use fly_camera::{
+    cursor_locked, fly_camera, lock_cursor_on_click, scroll_fly_speed, toggle_cursor_lock,
+};
 use voxel_material::VoxelMaterial;
 use voxel_mesh::make_test_mesh;
 
-use crate::chunks_partition::{VisibleChunks, chunks_partition};
+use crate::block_interaction::BlockInteractionPlugin;
+use crate::chunk::ChunkLifecyclePlugin;
+use crate::chunk_connectivity::ChunkConnectivity;
+use crate::chunks_partition::{ChunkSlots, LoadShape, LoadedChunks, chunks_partition};
+use crate::edit_history::EditHistoryPlugin;
+use crate::height_map::HeightMapPlugin;
+use crate::input_map::InputMapPlugin;
+use crate::player::{PlayerController, PlayerPlugin};
+use crate::schematic::SchematicPlugin;
+use crate::sculpt::{SculptPlugin, sculpting};
+use crate::selection::SelectionPlugin;
+use crate::terrain_generator::{ActiveTerrainGenerator, GpuDensityGenerator};
+use crate::vox::VoxPlugin;
 use crate::voxel_compute_grid::VoxelComputeGridPlugin;
+use crate::voxel_render::VoxelRenderPlugin;
+use crate::voxel_world::VoxelWorldPlugin;
+use crate::world_config::WorldConfigPlugin;
 
 fn grab_cursor(mut q: Query<&mut CursorOptions>) {
     let mut cursor = q.single_mut().unwrap();
@@ -25,13 +66,21 @@ fn grab_cursor(mut q: Query<&mut CursorOptions>) {
 
 fn main() {
     App::new()
-        .add_plugins(DefaultPlugins.set(WindowPlugin {
-            primary_window: Some(Window {
-                present_mode: bevy::window::PresentMode::Immediate,
-                ..Default::default()
-            }),
-            ..Default::default()
-        }))
+        .add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: Some(Window {
+                        present_mode: bevy::window::PresentMode::Immediate,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                // Edits to the world config regenerate the world while a debug build runs.
+                .set(AssetPlugin {
+                    watch_for_changes_override: Some(cfg!(debug_assertions)),
+                    ..Default::default()
+                }),
+        )
         .add_plugins(FpsOverlayPlugin {
             config: FpsOverlayConfig {
                 text_config: TextFont::default(),
@@ -45,11 +94,44 @@ fn main() {
                 },
             },
         })
-        .add_plugins(VoxelComputeGridPlugin)
+        .add_plugins(VoxelComputeGridPlugin {
+            generator: ActiveTerrainGenerator::new(GpuDensityGenerator::default()),
+            ..default()
+        })
+        .add_plugins(HeightMapPlugin)
+        .add_plugins(InputMapPlugin::default())
+        .add_plugins(SchematicPlugin)
+        .add_plugins(VoxPlugin::default())
+        .add_plugins(WorldConfigPlugin::default())
+        .add_plugins(VoxelRenderPlugin)
+        .add_plugins(ChunkLifecyclePlugin)
+        .add_plugins(VoxelWorldPlugin)
+        .add_plugins(BlockInteractionPlugin)
+        .add_plugins(SculptPlugin)
+        .add_plugins(SelectionPlugin)
+        .add_plugins(EditHistoryPlugin)
+        .add_plugins(PlayerPlugin)
         .add_plugins(MaterialPlugin::<VoxelMaterial>::default())
         .add_systems(Startup, setup)
-        .add_systems(Update, fly_camera)
-        .init_resource::<VisibleChunks>()
+        .add_systems(
+            Update,
+            (
+                toggle_cursor_lock,
+                fly_camera,
+                // The wheel sizes the brush while sculpting.
+                scroll_fly_speed.run_if(not(sculpting)),
+            ),
+        )
+        .add_systems(
+            PreUpdate,
+            lock_cursor_on_click
+                .run_if(not(cursor_locked))
+                .after(InputSystems),
+        )
+        .init_resource::<LoadedChunks>()
+        .init_resource::<ChunkSlots>()
+        .init_resource::<LoadShape>()
+        .init_resource::<ChunkConnectivity>()
         .add_systems(Update, chunks_partition)
         .add_systems(Startup, grab_cursor)
         .run();
@@ -70,7 +152,14 @@ fn setup(
     ));
 
     commands.spawn((
-        Camera3d::default(),
+        Camera3d {
+            // The Hi-Z pass reads the depth buffer back as a texture.
+            depth_texture_usages: (TextureUsages::RENDER_ATTACHMENT
+                | TextureUsages::TEXTURE_BINDING)
+                .into(),
+            ..default()
+        },
+        Msaa::Off,
         Transform::from_xyz(0.0, 2.0, 5.0),
         FlyCamera {
             speed: 10.0,
@@ -78,5 +167,6 @@ fn setup(
             pitch: 0.0,
             yaw: 0.0,
         },
+        PlayerController::default(),
     ));
 }
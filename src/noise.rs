@@ -0,0 +1,323 @@
+//! Rust port of `assets/shaders/noise.wgsl`, operation for operation so CPU
+//! generators and gameplay code sample the same terrain the GPU generates.
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum NoiseKind {
+    Value = 0,
+    #[default]
+    Perlin = 1,
+    Simplex = 2,
+}
+
+/// PCG hash.
+pub fn hash(x: u32) -> u32 {
+    let state = x.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+pub fn hash3(p: IVec3, seed: u32) -> u32 {
+    hash(p.x as u32 ^ hash(p.y as u32 ^ hash(p.z as u32 ^ hash(seed))))
+}
+
+/// Uniform in [-1, 1).
+fn hash_to_signed(h: u32) -> f32 {
+    (h >> 8) as f32 * (2.0 / 16777216.0) - 1.0
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn grad(h: u32, x: f32, y: f32, z: f32) -> f32 {
+    let b = h & 15;
+    let u = if b < 8 { x } else { y };
+    let v = if b < 4 {
+        y
+    } else if b == 12 || b == 14 {
+        x
+    } else {
+        z
+    };
+    let result = if b & 2 != 0 { -v } else { v };
+    if b & 1 != 0 { result - u } else { result + u }
+}
+
+fn fade3(f: Vec3) -> Vec3 {
+    Vec3::new(fade(f.x), fade(f.y), fade(f.z))
+}
+
+fn trilerp(c: [f32; 8], u: Vec3) -> f32 {
+    let [c000, c100, c010, c110, c001, c101, c011, c111] = c;
+    let x00 = c000 + (c100 - c000) * u.x;
+    let x10 = c010 + (c110 - c010) * u.x;
+    let x01 = c001 + (c101 - c001) * u.x;
+    let x11 = c011 + (c111 - c011) * u.x;
+    let y0 = x00 + (x10 - x00) * u.y;
+    let y1 = x01 + (x11 - x01) * u.y;
+    y0 + (y1 - y0) * u.z
+}
+
+const CORNERS: [IVec3; 8] = [
+    IVec3::new(0, 0, 0),
+    IVec3::new(1, 0, 0),
+    IVec3::new(0, 1, 0),
+    IVec3::new(1, 1, 0),
+    IVec3::new(0, 0, 1),
+    IVec3::new(1, 0, 1),
+    IVec3::new(0, 1, 1),
+    IVec3::new(1, 1, 1),
+];
+
+pub fn value_noise3(p: Vec3, seed: u32) -> f32 {
+    let cell = p.floor();
+    let i = cell.as_ivec3();
+    let u = fade3(p - cell);
+    trilerp(
+        CORNERS.map(|corner| hash_to_signed(hash3(i.wrapping_add(corner), seed))),
+        u,
+    )
+}
+
+pub fn perlin_noise3(p: Vec3, seed: u32) -> f32 {
+    let cell = p.floor();
+    let i = cell.as_ivec3();
+    let f = p - cell;
+    let u = fade3(f);
+    trilerp(
+        CORNERS.map(|corner| {
+            let d = f - corner.as_vec3();
+            grad(hash3(i.wrapping_add(corner), seed), d.x, d.y, d.z)
+        }),
+        u,
+    )
+}
+
+fn simplex_corner(d: Vec3, h: u32) -> f32 {
+    let t = 0.6 - d.x * d.x - d.y * d.y - d.z * d.z;
+    if t <= 0.0 {
+        return 0.0;
+    }
+    let t2 = t * t;
+    t2 * t2 * grad(h, d.x, d.y, d.z)
+}
+
+pub fn simplex_noise3(p: Vec3, seed: u32) -> f32 {
+    const F3: f32 = 1.0 / 3.0;
+    const G3: f32 = 1.0 / 6.0;
+
+    let s = (p.x + p.y + p.z) * F3;
+    let cell = (p + Vec3::splat(s)).floor();
+    let i = cell.as_ivec3();
+    let t = (cell.x + cell.y + cell.z) * G3;
+    let d0 = p - (cell - Vec3::splat(t));
+
+    let (o1, o2) = if d0.x >= d0.y {
+        if d0.y >= d0.z {
+            (IVec3::X, IVec3::new(1, 1, 0))
+        } else if d0.x >= d0.z {
+            (IVec3::X, IVec3::new(1, 0, 1))
+        } else {
+            (IVec3::Z, IVec3::new(1, 0, 1))
+        }
+    } else if d0.y < d0.z {
+        (IVec3::Z, IVec3::new(0, 1, 1))
+    } else if d0.x < d0.z {
+        (IVec3::Y, IVec3::new(0, 1, 1))
+    } else {
+        (IVec3::Y, IVec3::new(1, 1, 0))
+    };
+
+    let d1 = d0 - o1.as_vec3() + Vec3::splat(G3);
+    let d2 = d0 - o2.as_vec3() + Vec3::splat(2.0 * G3);
+    let d3 = d0 - Vec3::ONE + Vec3::splat(3.0 * G3);
+
+    let n = simplex_corner(d0, hash3(i, seed))
+        + simplex_corner(d1, hash3(i.wrapping_add(o1), seed))
+        + simplex_corner(d2, hash3(i.wrapping_add(o2), seed))
+        + simplex_corner(d3, hash3(i.wrapping_add(IVec3::ONE), seed));
+
+    32.0 * n
+}
+
+pub fn noise3(kind: NoiseKind, p: Vec3, seed: u32) -> f32 {
+    match kind {
+        NoiseKind::Value => value_noise3(p, seed),
+        NoiseKind::Perlin => perlin_noise3(p, seed),
+        NoiseKind::Simplex => simplex_noise3(p, seed),
+    }
+}
+
+/// Fractal Brownian motion, normalised back to roughly [-1, 1].
+pub fn fbm3(kind: NoiseKind, p: Vec3, seed: u32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    let mut q = p;
+    for octave in 0..octaves {
+        sum += noise3(kind, q, seed.wrapping_add(octave)) * amplitude;
+        total += amplitude;
+        amplitude *= gain;
+        q *= lacunarity;
+    }
+    sum / total
+}
+
+pub fn fbm2(kind: NoiseKind, p: Vec2, seed: u32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+    fbm3(kind, p.extend(0.0), seed, octaves, lacunarity, gain)
+}
+
+/// Ridged multifractal in [0, 1], sharp crests where the noise crosses zero.
+pub fn ridged3(
+    kind: NoiseKind,
+    p: Vec3,
+    seed: u32,
+    octaves: u32,
+    lacunarity: f32,
+    gain: f32,
+) -> f32 {
+    let mut sum = 0.0;
+    let mut amplitude = 1.0;
+    let mut total = 0.0;
+    let mut weight = 1.0;
+    let mut q = p;
+    for octave in 0..octaves {
+        let mut ridge = 1.0 - noise3(kind, q, seed.wrapping_add(octave)).abs();
+        ridge = ridge * ridge * weight;
+        weight = (ridge * 2.0).clamp(0.0, 1.0);
+        sum += ridge * amplitude;
+        total += amplitude;
+        amplitude *= gain;
+        q *= lacunarity;
+    }
+    sum / total
+}
+
+pub fn ridged2(
+    kind: NoiseKind,
+    p: Vec2,
+    seed: u32,
+    octaves: u32,
+    lacunarity: f32,
+    gain: f32,
+) -> f32 {
+    ridged3(kind, p.extend(0.0), seed, octaves, lacunarity, gain)
+}
+
+/// Offsets `p` by an fBm vector field, sample any noise at the result.
+pub fn domain_warp3(kind: NoiseKind, p: Vec3, seed: u32, octaves: u32, strength: f32) -> Vec3 {
+    let offset = Vec3::new(
+        fbm3(kind, p, seed, octaves, 2.0, 0.5),
+        fbm3(
+            kind,
+            p + Vec3::new(5.2, 1.3, 7.1),
+            seed.wrapping_add(1013),
+            octaves,
+            2.0,
+            0.5,
+        ),
+        fbm3(
+            kind,
+            p + Vec3::new(1.7, 9.2, 3.4),
+            seed.wrapping_add(2029),
+            octaves,
+            2.0,
+            0.5,
+        ),
+    );
+    p + offset * strength
+}
+
+pub fn domain_warp2(kind: NoiseKind, p: Vec2, seed: u32, octaves: u32, strength: f32) -> Vec2 {
+    domain_warp3(kind, p.extend(0.0), seed, octaves, strength).truncate()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [NoiseKind; 3] = [NoiseKind::Value, NoiseKind::Perlin, NoiseKind::Simplex];
+
+    /// Points spread over several cells, negative coordinates included.
+    fn samples() -> impl Iterator<Item = Vec3> {
+        (0..512).map(|i| {
+            let h = hash(i);
+            Vec3::new(
+                hash_to_signed(h) * 40.0,
+                hash_to_signed(hash(h)) * 40.0,
+                hash_to_signed(hash(h ^ 0x5bd1e995)) * 40.0,
+            )
+        })
+    }
+
+    #[test]
+    fn noise_stays_in_range() {
+        for kind in KINDS {
+            for p in samples() {
+                assert!(
+                    (-1.0..=1.0).contains(&noise3(kind, p, 9)),
+                    "{kind:?} at {p}"
+                );
+                assert!((-1.0..=1.0).contains(&fbm3(kind, p, 9, 5, 2.0, 0.5)));
+                assert!((0.0..=1.0).contains(&ridged3(kind, p, 9, 5, 2.0, 0.5)));
+            }
+        }
+    }
+
+    #[test]
+    fn noise_is_deterministic() {
+        for kind in KINDS {
+            for p in samples() {
+                assert_eq!(noise3(kind, p, 4), noise3(kind, p, 4));
+                assert_eq!(fbm3(kind, p, 4, 4, 2.0, 0.5), fbm3(kind, p, 4, 4, 2.0, 0.5));
+            }
+        }
+        let warped = domain_warp2(NoiseKind::Perlin, Vec2::new(3.5, -7.25), 4, 3, 0.5);
+        assert_eq!(
+            warped,
+            domain_warp2(NoiseKind::Perlin, Vec2::new(3.5, -7.25), 4, 3, 0.5)
+        );
+    }
+
+    #[test]
+    fn seeds_give_different_noise() {
+        for kind in KINDS {
+            let differing = samples()
+                .filter(|p| noise3(kind, *p, 1) != noise3(kind, *p, 2))
+                .count();
+            assert!(differing > 480, "{kind:?}: {differing} of 512 differ");
+        }
+        assert_ne!(hash3(IVec3::new(1, 2, 3), 1), hash3(IVec3::new(1, 2, 3), 2));
+    }
+
+    /// Values worked out by hand from the formulas in `noise.wgsl`, in u32 and f32 arithmetic.
+    #[test]
+    fn matches_the_shader_formulas() {
+        assert_eq!(hash(0), 129708002);
+        assert_eq!(hash(1), 2831084092);
+        assert_eq!(hash(u32::MAX), 3861530882);
+        assert_eq!(hash3(IVec3::new(1, -2, 3), 7), 3763772765);
+
+        // Value noise on a lattice point is the corner's hash.
+        assert_eq!(value_noise3(Vec3::new(3.0, -1.0, 2.0), 5), -0.630_518_8);
+        // Gradients vanish on lattice points.
+        assert_eq!(perlin_noise3(Vec3::new(3.0, -1.0, 2.0), 5), 0.0);
+
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-6;
+        assert!(close(
+            perlin_noise3(Vec3::new(0.5, 0.25, 0.75), 3),
+            -0.046_023_846
+        ));
+        assert!(close(
+            value_noise3(Vec3::new(0.5, 0.25, 0.75), 3),
+            -0.467_718
+        ));
+        assert!(close(
+            perlin_noise3(Vec3::new(-1.25, 2.5, 0.125), 11),
+            0.473_099_44
+        ));
+    }
+}
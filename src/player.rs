@@ -0,0 +1,470 @@
+//! Walking with gravity and collisions against the solid voxels of the [`VoxelWorld`].
+//!
+//! [`InputAction::ToggleMoveMode`] toggles between flying, noclip as the [`FlyCamera`]
+//! always moved, and walking. Walking, [`InputAction::Ascend`] jumps,
+//! [`InputAction::Boost`] sprints and [`InputAction::Descend`] crouches, which keeps the
+//! player from walking off edges. One block ledges are stepped onto.
+//!
+//! [`step_player`] only depends on its inputs and the solid voxels, so the same inputs
+//! always end in the same position.
+
+use bevy::prelude::*;
+
+use crate::{
+    fly_camera::FlyCamera,
+    input_map::{Actions, InputAction},
+    voxel_world::VoxelWorld,
+};
+
+/// Half the width of the player's box.
+const HALF_WIDTH: f32 = 0.3;
+const HEIGHT: f32 = 1.8;
+const CROUCH_HEIGHT: f32 = 1.5;
+/// Eyes below the top of the box.
+const EYE_OFFSET: f32 = 0.2;
+const GRAVITY: f32 = 28.0;
+const TERMINAL_SPEED: f32 = 60.0;
+/// Clears a bit more than one block.
+const JUMP_SPEED: f32 = 8.9;
+const WALK_SPEED: f32 = 4.3;
+const SPRINT_SPEED: f32 = 6.5;
+const CROUCH_SPEED: f32 = 1.5;
+/// Highest ledge walked onto without jumping.
+const STEP_HEIGHT: f32 = 1.0;
+/// Deepest drop a crouching player walks off.
+const EDGE_DROP: f32 = 0.6;
+/// Gap kept between the box and voxel faces it touches, against rounding.
+const SKIN: f32 = 1e-3;
+/// Longest frame simulated in one step.
+const MAX_STEP: f32 = 0.1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum MoveMode {
+    #[default]
+    Fly,
+    Walk,
+}
+
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct PlayerController {
+    pub mode: MoveMode,
+    pub velocity: Vec3,
+    pub on_ground: bool,
+    pub crouching: bool,
+}
+
+impl PlayerController {
+    pub fn height(&self) -> f32 {
+        if self.crouching {
+            CROUCH_HEIGHT
+        } else {
+            HEIGHT
+        }
+    }
+
+    /// Eyes above the feet.
+    pub fn eye_height(&self) -> f32 {
+        self.height() - EYE_OFFSET
+    }
+}
+
+/// Movement asked for in one step.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PlayerInput {
+    /// Horizontal direction to walk in, shorter than 1 to walk slower.
+    pub wish: Vec2,
+    pub jump: bool,
+    pub sprint: bool,
+    pub crouch: bool,
+}
+
+/// Box of a player standing on `feet`.
+fn player_box(feet: Vec3, height: f32) -> (Vec3, Vec3) {
+    (
+        feet - Vec3::new(HALF_WIDTH, 0.0, HALF_WIDTH),
+        feet + Vec3::new(HALF_WIDTH, height, HALF_WIDTH),
+    )
+}
+
+/// How far the box `min..max` can move by `distance` along `axis` before touching a
+/// solid voxel.
+pub fn sweep_axis(
+    solid: &impl Fn(IVec3) -> bool,
+    min: Vec3,
+    max: Vec3,
+    axis: usize,
+    distance: f32,
+) -> f32 {
+    if distance == 0.0 {
+        return 0.0;
+    }
+    // Voxels the box covers across the axis.
+    let lo = (min + SKIN).floor().as_ivec3();
+    let hi = (max - SKIN).floor().as_ivec3();
+    let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+    let layer_solid = |layer: i32| {
+        (lo[a]..=hi[a]).any(|i| {
+            (lo[b]..=hi[b]).any(|j| {
+                let mut voxel = IVec3::ZERO;
+                voxel[axis] = layer;
+                voxel[a] = i;
+                voxel[b] = j;
+                solid(voxel)
+            })
+        })
+    };
+
+    if distance > 0.0 {
+        let first = (max[axis] - SKIN).floor() as i32 + 1;
+        let last = (max[axis] + distance).ceil() as i32 - 1;
+        for layer in first..=last {
+            if layer_solid(layer) {
+                return (layer as f32 - SKIN - max[axis]).clamp(0.0, distance);
+            }
+        }
+    } else {
+        let first = (min[axis] + SKIN).floor() as i32 - 1;
+        let last = (min[axis] + distance).floor() as i32;
+        for layer in (last..=first).rev() {
+            if layer_solid(layer) {
+                return ((layer + 1) as f32 + SKIN - min[axis]).clamp(distance, 0.0);
+            }
+        }
+    }
+    distance
+}
+
+/// Moves a box of `height` standing on `feet` by `motion`, vertically first, sliding
+/// along what it hits. Returns the new feet and the axes it was stopped on.
+pub fn move_box(
+    solid: &impl Fn(IVec3) -> bool,
+    mut feet: Vec3,
+    height: f32,
+    motion: Vec3,
+) -> (Vec3, BVec3) {
+    let mut blocked = BVec3::FALSE;
+    for axis in [1, 0, 2] {
+        let (min, max) = player_box(feet, height);
+        let moved = sweep_axis(solid, min, max, axis, motion[axis]);
+        feet[axis] += moved;
+        blocked.set(axis, moved != motion[axis]);
+    }
+    (feet, blocked)
+}
+
+/// Whether ground is at most `depth` below the box standing on `feet`.
+fn ground_below(solid: &impl Fn(IVec3) -> bool, feet: Vec3, height: f32, depth: f32) -> bool {
+    let (min, max) = player_box(feet, height);
+    sweep_axis(solid, min, max, 1, -depth) > -depth
+}
+
+/// Advances a walking player by `dt` seconds and returns its new feet.
+pub fn step_player(
+    solid: &impl Fn(IVec3) -> bool,
+    feet: Vec3,
+    player: &mut PlayerController,
+    input: &PlayerInput,
+    dt: f32,
+) -> Vec3 {
+    let dt = dt.min(MAX_STEP);
+
+    // Standing up needs room above the crouched box.
+    if input.crouch {
+        player.crouching = true;
+    } else if player.crouching {
+        let (min, max) = player_box(feet, CROUCH_HEIGHT);
+        let rise = HEIGHT - CROUCH_HEIGHT;
+        if sweep_axis(solid, min, max, 1, rise) == rise {
+            player.crouching = false;
+        }
+    }
+    let height = player.height();
+
+    let speed = if player.crouching {
+        CROUCH_SPEED
+    } else if input.sprint {
+        SPRINT_SPEED
+    } else {
+        WALK_SPEED
+    };
+    let wish = input.wish.clamp_length_max(1.0) * speed;
+    player.velocity.x = wish.x;
+    player.velocity.z = wish.y;
+    if player.on_ground && input.jump {
+        player.velocity.y = JUMP_SPEED;
+    }
+    player.velocity.y = (player.velocity.y - GRAVITY * dt).max(-TERMINAL_SPEED);
+
+    let motion = player.velocity * dt;
+    let was_on_ground = player.on_ground;
+    let (mut moved, blocked) = move_box(solid, feet, height, Vec3::new(0.0, motion.y, 0.0));
+    if blocked.y {
+        player.velocity.y = 0.0;
+    }
+    player.on_ground = blocked.y && motion.y < 0.0;
+
+    // Horizontal axes one at a time, so crouching slides along edges instead of stopping.
+    let on_ground = player.on_ground || was_on_ground;
+    for axis in [0, 2] {
+        let mut step = Vec3::ZERO;
+        step[axis] = motion[axis];
+        if step == Vec3::ZERO {
+            continue;
+        }
+        let (mut next, blocked) = move_box(solid, moved, height, step);
+
+        // Try the move again from a step higher, then settle back down.
+        if blocked.test(axis) && on_ground {
+            let (raised, _) = move_box(solid, moved, height, Vec3::Y * STEP_HEIGHT);
+            let (stepped, _) = move_box(solid, raised, height, step);
+            let (landed, _) = move_box(solid, stepped, height, Vec3::NEG_Y * STEP_HEIGHT);
+            if (landed[axis] - moved[axis]).abs() > (next[axis] - moved[axis]).abs() {
+                next = landed;
+            }
+        }
+
+        if player.crouching && player.on_ground && !ground_below(solid, next, height, EDGE_DROP) {
+            continue;
+        }
+        moved = next;
+    }
+    moved
+}
+
+pub struct PlayerPlugin;
+
+impl Plugin for PlayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (toggle_move_mode, walk_player).chain());
+    }
+}
+
+fn toggle_move_mode(actions: Actions, mut players: Query<&mut PlayerController>) {
+    if !actions.just_pressed(InputAction::ToggleMoveMode) {
+        return;
+    }
+    for mut player in &mut players {
+        *player = PlayerController {
+            mode: match player.mode {
+                MoveMode::Fly => MoveMode::Walk,
+                MoveMode::Walk => MoveMode::Fly,
+            },
+            ..default()
+        };
+    }
+}
+
+/// Moves walking players, whose camera sits at their eyes. Chunks that aren't loaded
+/// are solid, so nobody falls through the world while it generates.
+fn walk_player(
+    time: Res<Time>,
+    actions: Actions,
+    voxel_world: Res<VoxelWorld>,
+    mut players: Query<(&mut Transform, &FlyCamera, &mut PlayerController)>,
+) {
+    let solid = |voxel: IVec3| voxel_world.is_solid(voxel).unwrap_or(true);
+    for (mut transform, camera, mut player) in &mut players {
+        if player.mode != MoveMode::Walk {
+            continue;
+        }
+
+        let local = Vec2::new(
+            actions.axis(InputAction::MoveLeft, InputAction::MoveRight),
+            actions.axis(InputAction::MoveForward, InputAction::MoveBack),
+        )
+        .clamp_length_max(1.0);
+        // Walking follows the yaw only, looking down doesn't slow it.
+        let wish = Vec2::from_angle(-camera.yaw).rotate(local);
+        let input = PlayerInput {
+            wish,
+            jump: actions.pressed(InputAction::Ascend),
+            sprint: actions.pressed(InputAction::Boost),
+            crouch: actions.pressed(InputAction::Descend),
+        };
+
+        let feet = transform.translation - Vec3::Y * player.eye_height();
+        let feet = step_player(&solid, feet, &mut player, &input, time.delta_secs());
+        transform.translation = feet + Vec3::Y * player.eye_height();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 1.0 / 60.0;
+
+    fn walking() -> PlayerController {
+        PlayerController {
+            mode: MoveMode::Walk,
+            ..default()
+        }
+    }
+
+    fn walk(x: f32) -> PlayerInput {
+        PlayerInput {
+            wish: Vec2::new(x, 0.0),
+            ..default()
+        }
+    }
+
+    fn run(
+        solid: &impl Fn(IVec3) -> bool,
+        mut feet: Vec3,
+        player: &mut PlayerController,
+        input: &PlayerInput,
+        seconds: f32,
+    ) -> Vec3 {
+        for _ in 0..(seconds / DT).round() as u32 {
+            feet = step_player(solid, feet, player, input, DT);
+        }
+        feet
+    }
+
+    fn floor(voxel: IVec3) -> bool {
+        voxel.y < 0
+    }
+
+    #[test]
+    fn falls_and_lands_on_the_floor() {
+        let mut player = walking();
+        let feet = run(
+            &floor,
+            Vec3::new(0.5, 5.0, 0.5),
+            &mut player,
+            &default(),
+            2.0,
+        );
+        assert!(feet.y.abs() < 0.01, "{feet}");
+        assert!(player.on_ground);
+        assert_eq!(player.velocity.y, 0.0);
+    }
+
+    #[test]
+    fn slides_along_walls() {
+        let solid = |voxel: IVec3| floor(voxel) || voxel.x >= 2;
+        let mut player = walking();
+        let input = PlayerInput {
+            wish: Vec2::ONE.normalize(),
+            ..default()
+        };
+        let feet = run(&solid, Vec3::new(1.0, 0.0, 0.5), &mut player, &input, 1.0);
+        assert!(
+            feet.x <= 2.0 - HALF_WIDTH && feet.x > 2.0 - HALF_WIDTH - 0.01,
+            "{feet}"
+        );
+        assert!(feet.z > 0.5 + 2.0, "{feet}");
+        assert!(feet.y.abs() < 0.01, "{feet}");
+    }
+
+    #[test]
+    fn ceilings_stop_jumps() {
+        let solid = |voxel: IVec3| floor(voxel) || voxel.y >= 3;
+        let mut player = walking();
+        let mut feet = run(
+            &solid,
+            Vec3::new(0.5, 0.0, 0.5),
+            &mut player,
+            &default(),
+            0.1,
+        );
+        let jump = PlayerInput {
+            jump: true,
+            ..default()
+        };
+        let mut highest: f32 = 0.0;
+        for _ in 0..60 {
+            feet = step_player(&solid, feet, &mut player, &jump, DT);
+            highest = highest.max(feet.y);
+        }
+        // Without the ceiling the jump would lift the feet about 1.4.
+        assert!(
+            highest <= 3.0 - HEIGHT && highest > 3.0 - HEIGHT - 0.01,
+            "{highest}"
+        );
+    }
+
+    #[test]
+    fn steps_up_one_block_ledges() {
+        let solid = |voxel: IVec3| floor(voxel) || (voxel.x >= 2 && voxel.y < 1);
+        let mut player = walking();
+        let feet = run(
+            &solid,
+            Vec3::new(0.5, 0.0, 0.5),
+            &mut player,
+            &walk(1.0),
+            1.5,
+        );
+        assert!(feet.x > 2.5, "{feet}");
+        assert!((feet.y - 1.0).abs() < 0.01, "{feet}");
+    }
+
+    #[test]
+    fn does_not_step_up_two_block_ledges() {
+        let solid = |voxel: IVec3| floor(voxel) || (voxel.x >= 2 && voxel.y < 2);
+        let mut player = walking();
+        let feet = run(
+            &solid,
+            Vec3::new(0.5, 0.0, 0.5),
+            &mut player,
+            &walk(1.0),
+            1.5,
+        );
+        assert!(feet.x <= 2.0 - HALF_WIDTH, "{feet}");
+        assert!(feet.y.abs() < 0.01, "{feet}");
+    }
+
+    #[test]
+    fn crouching_stops_at_edges() {
+        let solid = |voxel: IVec3| voxel.y < 0 && voxel.x < 2;
+        let crouch = PlayerInput {
+            crouch: true,
+            ..walk(1.0)
+        };
+
+        let mut player = walking();
+        let feet = run(&solid, Vec3::new(0.5, 0.0, 0.5), &mut player, &crouch, 3.0);
+        assert!(feet.x < 2.0 + HALF_WIDTH, "{feet}");
+        assert!(feet.y.abs() < 0.01, "{feet}");
+        assert!(player.on_ground);
+
+        // Standing up, the same walk goes over the edge.
+        let mut player = walking();
+        let feet = run(
+            &solid,
+            Vec3::new(0.5, 0.0, 0.5),
+            &mut player,
+            &walk(1.0),
+            3.0,
+        );
+        assert!(feet.y < -EDGE_DROP, "{feet}");
+    }
+
+    #[test]
+    fn long_frames_are_clamped() {
+        let start = Vec3::new(0.5, 5.0, 0.5);
+        let (mut long, mut clamped) = (walking(), walking());
+        let long_feet = step_player(&floor, start, &mut long, &walk(1.0), 1.0);
+        let clamped_feet = step_player(&floor, start, &mut clamped, &walk(1.0), MAX_STEP);
+        assert_eq!(long_feet, clamped_feet);
+        assert_eq!(long.velocity, clamped.velocity);
+    }
+
+    #[test]
+    fn steps_are_deterministic() {
+        let solid = |voxel: IVec3| floor(voxel) || (voxel.x >= 3 && voxel.y < 1) || voxel.z >= 4;
+        let input = PlayerInput {
+            wish: Vec2::new(0.8, 0.6),
+            jump: true,
+            sprint: true,
+            ..default()
+        };
+        let (mut a, mut b) = (walking(), walking());
+        let start = Vec3::new(0.5, 2.0, 0.5);
+        let feet_a = run(&solid, start, &mut a, &input, 2.0);
+        let feet_b = run(&solid, start, &mut b, &input, 2.0);
+        assert_eq!(feet_a, feet_b);
+        assert_eq!(a.velocity, b.velocity);
+        assert_eq!(a.on_ground, b.on_ground);
+    }
+}
@@ -0,0 +1,379 @@
+//! Voxel structures saved to `.schematic` files, pasted into the world or placed by the
+//! decoration stage of world generation.
+//!
+//! A file holds, little endian:
+//! - the magic `VXSC` and a `u16` format version,
+//! - the size and the origin, three `i32` each,
+//! - a `u16` palette length, at most `u16::MAX`, and per entry a `u32` block and an
+//!   `i16` density relative to the threshold, in 1/16ths and within 64 of it,
+//! - runs covering the voxels in chunk order, a `u16` length and a `u16` palette index
+//!   each.
+//!
+//! In the selection tool `Ctrl+S` saves the selection with its first corner as origin to
+//! [`SELECTION_SCHEMATIC`], `Ctrl+O` loads that file into the clipboard.
+
+use std::{collections::HashMap, io, sync::Arc};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, io::Reader},
+    prelude::*,
+};
+
+use crate::{
+    biome::Biome,
+    block::BlockId,
+    sculpt::sculpting,
+    selection::{Clipboard, Selection, VoxelRegion, selecting},
+    voxel_world::VoxelWorld,
+};
+
+const MAGIC: &[u8; 4] = b"VXSC";
+const VERSION: u16 = 1;
+/// Steps a density is stored in per unit.
+const DENSITY_STEPS: f32 = 16.0;
+/// Densities are clamped this far from the threshold, keeping the palette small.
+const MAX_DENSITY: f32 = 64.0;
+/// Largest schematic loaded, guarding against corrupt sizes.
+const MAX_VOXELS: i64 = 1 << 24;
+
+/// Where `Ctrl+S` saves the selection, relative to the assets folder.
+pub const SELECTION_SCHEMATIC: &str = "schematics/selection.schematic";
+
+#[derive(Asset, TypePath, Clone, Debug, PartialEq)]
+pub struct Schematic {
+    /// Voxel put on the anchor a schematic is placed at, from the region's lowest corner.
+    pub origin: IVec3,
+    /// Voxels, their densities relative to the threshold.
+    pub region: VoxelRegion,
+}
+
+impl Schematic {
+    /// Copies `min..=max` out of the world, anchored on `anchor`.
+    pub fn from_world(voxel_world: &VoxelWorld, min: IVec3, max: IVec3, anchor: IVec3) -> Self {
+        let mut region = VoxelRegion::copy(voxel_world, min, max);
+        for density in &mut region.density {
+            *density -= voxel_world.threshold();
+        }
+        Self {
+            origin: anchor - min,
+            region,
+        }
+    }
+
+    /// The voxels with densities for a world solid at and above `threshold`.
+    pub fn region(&self, threshold: f32) -> VoxelRegion {
+        let mut region = self.region.clone();
+        for density in &mut region.density {
+            *density += threshold;
+        }
+        region
+    }
+
+    /// Offsets from the origin and blocks of the voxels that aren't air.
+    pub fn blocks(&self) -> impl Iterator<Item = (IVec3, BlockId)> + '_ {
+        let size = self.region.size;
+        self.region
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| **block != BlockId::AIR)
+            .map(move |(i, block)| {
+                let i = i as i32;
+                let pos = IVec3::new(i % size.x, (i / size.x) % size.y, i / (size.x * size.y));
+                (pos - self.origin, *block)
+            })
+    }
+
+    /// Farthest a voxel is from the origin along any axis.
+    pub fn reach(&self) -> i32 {
+        self.origin
+            .max(self.region.size - 1 - self.origin)
+            .max_element()
+    }
+
+    /// Fails when the voxels need more palette entries than a `u16` counts.
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut palette: Vec<(BlockId, i16)> = Vec::new();
+        let mut palette_index = HashMap::new();
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        for (block, density) in self.region.blocks.iter().zip(&self.region.density) {
+            let entry = (*block, quantize(*density));
+            let index = match palette_index.get(&entry) {
+                Some(index) => *index,
+                None => {
+                    let index = u16::try_from(palette.len())
+                        .ok()
+                        .filter(|index| *index < u16::MAX)
+                        .ok_or_else(|| {
+                            invalid(format!("more than {} block and density pairs", u16::MAX))
+                        })?;
+                    palette.push(entry);
+                    palette_index.insert(entry, index);
+                    index
+                }
+            };
+            match runs.last_mut() {
+                Some((length, last)) if *last == index && *length < u16::MAX => *length += 1,
+                _ => runs.push((1, index)),
+            }
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        for value in self
+            .region
+            .size
+            .to_array()
+            .into_iter()
+            .chain(self.origin.to_array())
+        {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(palette.len() as u16).to_le_bytes());
+        for (block, density) in palette {
+            bytes.extend_from_slice(&block.0.to_le_bytes());
+            bytes.extend_from_slice(&density.to_le_bytes());
+        }
+        for (length, index) in runs {
+            bytes.extend_from_slice(&length.to_le_bytes());
+            bytes.extend_from_slice(&index.to_le_bytes());
+        }
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = ByteReader(bytes);
+        if reader.take(4)? != MAGIC {
+            return Err(invalid("not a schematic"));
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported schematic version {version}")));
+        }
+        let size = IVec3::new(reader.i32()?, reader.i32()?, reader.i32()?);
+        let origin = IVec3::new(reader.i32()?, reader.i32()?, reader.i32()?);
+        if size.cmple(IVec3::ZERO).any() || size.as_i64vec3().element_product() > MAX_VOXELS {
+            return Err(invalid(format!("invalid schematic size {size}")));
+        }
+
+        let palette = (0..reader.u16()?)
+            .map(|_| Ok((BlockId(reader.u32()?), reader.i16()? as f32 / DENSITY_STEPS)))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let mut region = VoxelRegion::new(size, 0.0);
+        let mut voxel = 0;
+        while voxel < region.blocks.len() {
+            let length = reader.u16()? as usize;
+            let (block, density) = *palette
+                .get(reader.u16()? as usize)
+                .ok_or_else(|| invalid("palette index out of range"))?;
+            let end = voxel + length;
+            if end > region.blocks.len() {
+                return Err(invalid("voxel runs overflow the schematic"));
+            }
+            region.blocks[voxel..end].fill(block);
+            region.density[voxel..end].fill(density);
+            voxel = end;
+        }
+        Ok(Self { origin, region })
+    }
+}
+
+fn quantize(density: f32) -> i16 {
+    (density.clamp(-MAX_DENSITY, MAX_DENSITY) * DENSITY_STEPS).round() as i16
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+struct ByteReader<'a>(&'a [u8]);
+
+impl ByteReader<'_> {
+    fn take(&mut self, count: usize) -> io::Result<&[u8]> {
+        if self.0.len() < count {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(i16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+}
+
+/// Loads `.schematic` files as [`Schematic`]s.
+#[derive(Default, TypePath)]
+pub struct SchematicLoader;
+
+impl AssetLoader for SchematicLoader {
+    type Asset = Schematic;
+    type Settings = ();
+    type Error = io::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Schematic, io::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        Schematic::decode(&bytes)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["schematic"]
+    }
+}
+
+/// Structure the decoration stage places on the surface.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StructureSpawn {
+    pub schematic: Arc<Schematic>,
+    /// Expected number per chunk column.
+    pub per_chunk: f32,
+    /// Biomes it is placed in, any when empty.
+    pub biomes: Vec<Biome>,
+}
+
+/// Structures of the world config, once their schematics are loaded.
+#[derive(Resource, Clone, Default, PartialEq)]
+pub struct DecorationStructures(pub Arc<Vec<StructureSpawn>>);
+
+/// Schematic being loaded into the clipboard.
+#[derive(Resource, Default)]
+pub struct PendingClipboard(pub Option<Handle<Schematic>>);
+
+pub struct SchematicPlugin;
+
+impl Plugin for SchematicPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_asset::<Schematic>()
+            .register_asset_loader(SchematicLoader)
+            .init_resource::<DecorationStructures>()
+            .init_resource::<PendingClipboard>()
+            .add_systems(
+                Update,
+                (
+                    save_load_selection.run_if(selecting.and(not(sculpting))),
+                    fill_clipboard,
+                )
+                    .chain(),
+            );
+    }
+}
+
+/// `Ctrl+S` saves the selection, `Ctrl+O` loads the saved selection into the clipboard.
+fn save_load_selection(
+    keys: Res<ButtonInput<KeyCode>>,
+    assets: Res<AssetServer>,
+    selection: Res<Selection>,
+    voxel_world: Res<VoxelWorld>,
+    mut pending: ResMut<PendingClipboard>,
+) {
+    if !keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::KeyS)
+        && let (Some((min, max)), Some(anchor)) = (selection.bounds(), selection.first)
+    {
+        let schematic = Schematic::from_world(&voxel_world, min, max, anchor);
+        let path = std::path::Path::new("assets").join(SELECTION_SCHEMATIC);
+        let saved = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, schematic.encode()?));
+        match saved {
+            Ok(()) => info!("saved selection to {}", path.display()),
+            Err(err) => error!("failed to save {}: {err}", path.display()),
+        }
+    }
+    if keys.just_pressed(KeyCode::KeyO) {
+        pending.0 = Some(assets.load(SELECTION_SCHEMATIC));
+    }
+}
+
+fn fill_clipboard(
+    schematics: Res<Assets<Schematic>>,
+    voxel_world: Res<VoxelWorld>,
+    mut pending: ResMut<PendingClipboard>,
+    mut clipboard: ResMut<Clipboard>,
+) {
+    let Some(schematic) = pending.0.as_ref().and_then(|handle| schematics.get(handle)) else {
+        return;
+    };
+    clipboard.0 = Some(schematic.region(voxel_world.threshold()));
+    pending.0 = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(size: IVec3, voxel: impl Fn(usize) -> (BlockId, f32)) -> Schematic {
+        let mut region = VoxelRegion::new(size, -1.0);
+        for i in 0..region.blocks.len() {
+            (region.blocks[i], region.density[i]) = voxel(i);
+        }
+        Schematic {
+            origin: IVec3::new(1, 0, 2),
+            region,
+        }
+    }
+
+    #[test]
+    fn encoding_round_trips() {
+        let schematic = filled(IVec3::new(5, 3, 4), |i| match i % 7 {
+            0..=3 => (BlockId::AIR, -1.0),
+            4 => (BlockId::STONE, 2.5),
+            5 => (BlockId::WOOD, 0.0625),
+            _ => (BlockId::LEAVES, -MAX_DENSITY),
+        });
+        let decoded = Schematic::decode(&schematic.encode().unwrap()).unwrap();
+        assert_eq!(decoded, schematic);
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        let schematic = filled(IVec3::new(300, 300, 1), |_| (BlockId::STONE, 1.0));
+        let decoded = Schematic::decode(&schematic.encode().unwrap()).unwrap();
+        assert_eq!(decoded, schematic);
+    }
+
+    #[test]
+    fn oversized_palettes_are_refused() {
+        // Every voxel a different block.
+        let schematic = filled(IVec3::new(256, 257, 1), |i| (BlockId(i as u32), 0.0));
+        assert!(schematic.encode().is_err());
+
+        let fits = filled(IVec3::new(255, 257, 1), |i| (BlockId(i as u32), 0.0));
+        let decoded = Schematic::decode(&fits.encode().unwrap()).unwrap();
+        assert_eq!(decoded, fits);
+    }
+
+    #[test]
+    fn corrupt_files_are_rejected() {
+        let bytes = filled(IVec3::new(2, 2, 2), |_| (BlockId::STONE, 1.0))
+            .encode()
+            .unwrap();
+        assert!(Schematic::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(Schematic::decode(b"VXSD").is_err());
+    }
+}
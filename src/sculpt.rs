@@ -0,0 +1,332 @@
+//! Brushes editing the densities of many voxels at once, for level editing.
+//!
+//! `B` toggles sculpting, `Tab` cycles the tools and holding the left mouse button
+//! applies the brush where the view ray hits. The mouse wheel changes the radius,
+//! with `Alt` held the strength. Edits go through [`VoxelWorld::set_voxel`], so every
+//! chunk a stroke touches is uploaded and remeshed once per frame, and a stroke is
+//! undone as a single step.
+
+use bevy::{input::mouse::AccumulatedMouseScroll, prelude::*};
+
+use crate::{
+    block::BlockId,
+    block_interaction::SelectedBlock,
+    edit_history::VoxelEditHistory,
+    fly_camera::{FlyCamera, cursor_locked},
+    voxel_world::{VoxelWorld, flush_voxel_edits},
+};
+
+/// How far away the brush reaches, in blocks.
+const SCULPT_REACH: f32 = 64.0;
+const MIN_RADIUS: f32 = 1.0;
+const MAX_RADIUS: f32 = 16.0;
+const MIN_STRENGTH: f32 = 0.25;
+const MAX_STRENGTH: f32 = 32.0;
+/// Density of the voxels a box fill makes solid, on top of the threshold.
+const FILL_DENSITY: f32 = 1.0;
+/// Density per block of distance to the plane [`BrushTool::Flatten`] levels to.
+const FLATTEN_SLOPE: f32 = 1.0;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum BrushTool {
+    /// Raises the density in a sphere, filling with the selected block.
+    #[default]
+    Add,
+    /// Lowers the density in a sphere.
+    Subtract,
+    /// Fills a cube with the selected block.
+    BoxFill,
+    /// Blends densities towards their neighbours'.
+    Smooth,
+    /// Levels the surface to the plane of the face the ray hit.
+    Flatten,
+    /// Replaces the block of solid voxels with the selected block.
+    Paint,
+}
+
+impl BrushTool {
+    pub const ALL: [BrushTool; 6] = [
+        BrushTool::Add,
+        BrushTool::Subtract,
+        BrushTool::BoxFill,
+        BrushTool::Smooth,
+        BrushTool::Flatten,
+        BrushTool::Paint,
+    ];
+
+    pub fn next(self) -> Self {
+        Self::ALL[(self as usize + 1) % Self::ALL.len()]
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct SculptBrush {
+    /// Whether the mouse sculpts instead of breaking and placing blocks.
+    pub active: bool,
+    pub tool: BrushTool,
+    /// Radius of the sphere, half the side of the box, in blocks.
+    pub radius: f32,
+    /// Density added or removed per second at the brush's centre, blend rate per
+    /// second of smoothing and flattening.
+    pub strength: f32,
+}
+
+impl Default for SculptBrush {
+    fn default() -> Self {
+        Self {
+            active: false,
+            tool: BrushTool::Add,
+            radius: 3.0,
+            strength: 4.0,
+        }
+    }
+}
+
+/// Run condition of the tools the mouse drives outside of sculpting.
+pub fn sculpting(brush: Option<Res<SculptBrush>>) -> bool {
+    brush.is_some_and(|brush| brush.active)
+}
+
+/// Applies `dt` seconds of the brush centred on `center`. `normal` is the normal of
+/// the face the view ray hit, the plane [`BrushTool::Flatten`] levels to.
+pub fn apply_brush(
+    voxel_world: &mut VoxelWorld,
+    brush: &SculptBrush,
+    material: BlockId,
+    center: Vec3,
+    normal: Vec3,
+    dt: f32,
+) {
+    let threshold = voxel_world.threshold();
+    let radius = brush.radius;
+    let amount = brush.strength * dt;
+    let normal = normal.try_normalize().unwrap_or(Vec3::Y);
+
+    // Smoothing blends with the densities from before this call, margin included, so
+    // voxels smoothed earlier in the loop don't feed into their neighbours.
+    let min = (center - radius).floor().as_ivec3();
+    let max = (center + radius).ceil().as_ivec3();
+    let size = max - min + 3;
+    let before: Vec<Option<f32>> = if brush.tool == BrushTool::Smooth {
+        (0..size.x * size.y * size.z)
+            .map(|i| {
+                let offset = IVec3::new(i % size.x, (i / size.x) % size.y, i / (size.x * size.y));
+                voxel_world.density_at(min - 1 + offset)
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let density_before = |voxel: IVec3| {
+        let offset = voxel - min + 1;
+        before[(offset.z * size.x * size.y + offset.y * size.x + offset.x) as usize]
+    };
+
+    for z in min.z..=max.z {
+        for y in min.y..=max.y {
+            for x in min.x..=max.x {
+                let voxel = IVec3::new(x, y, z);
+                let (Some(density), Some(block)) =
+                    (voxel_world.density_at(voxel), voxel_world.block_at(voxel))
+                else {
+                    continue;
+                };
+
+                let p = voxel.as_vec3() + 0.5;
+                let falloff = 1.0 - p.distance(center) / radius;
+                if brush.tool != BrushTool::BoxFill && falloff <= 0.0 {
+                    continue;
+                }
+                let blend = (amount * falloff).min(1.0);
+
+                let new_density = match brush.tool {
+                    BrushTool::Add => density + amount * falloff,
+                    BrushTool::Subtract => density - amount * falloff,
+                    BrushTool::BoxFill => {
+                        if (p - center).abs().max_element() > radius {
+                            continue;
+                        }
+                        density.max(threshold + FILL_DENSITY)
+                    }
+                    BrushTool::Smooth => {
+                        let neighbours: Vec<f32> = [
+                            IVec3::X,
+                            IVec3::NEG_X,
+                            IVec3::Y,
+                            IVec3::NEG_Y,
+                            IVec3::Z,
+                            IVec3::NEG_Z,
+                        ]
+                        .into_iter()
+                        .filter_map(|offset| density_before(voxel + offset))
+                        .collect();
+                        let average = (density + neighbours.iter().sum::<f32>())
+                            / (neighbours.len() + 1) as f32;
+                        density + (average - density) * blend
+                    }
+                    BrushTool::Flatten => {
+                        let target = threshold + (center - p).dot(normal) * FLATTEN_SLOPE;
+                        density + (target - density) * blend
+                    }
+                    BrushTool::Paint => density,
+                };
+
+                let solid = new_density >= threshold;
+                let new_block = if brush.tool == BrushTool::Paint {
+                    if block.is_solid() { material } else { block }
+                } else if solid == (density >= threshold) {
+                    block
+                } else if solid {
+                    material
+                } else {
+                    BlockId::AIR
+                };
+
+                if new_block != block || new_density != density {
+                    voxel_world.set_voxel(voxel, new_block, new_density);
+                }
+            }
+        }
+    }
+}
+
+pub struct SculptPlugin;
+
+impl Plugin for SculptPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SculptBrush>().add_systems(
+            Update,
+            (pick_brush, sculpt.run_if(cursor_locked))
+                .chain()
+                .before(flush_voxel_edits),
+        );
+    }
+}
+
+fn pick_brush(
+    keys: Res<ButtonInput<KeyCode>>,
+    scroll: Res<AccumulatedMouseScroll>,
+    mut brush: ResMut<SculptBrush>,
+) {
+    if keys.just_pressed(KeyCode::KeyB) {
+        brush.active = !brush.active;
+    }
+    if !brush.active {
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::Tab) {
+        brush.tool = brush.tool.next();
+    }
+    if scroll.delta.y != 0.0 {
+        if keys.pressed(KeyCode::AltLeft) {
+            brush.strength =
+                (brush.strength * 1.25f32.powf(scroll.delta.y)).clamp(MIN_STRENGTH, MAX_STRENGTH);
+        } else {
+            brush.radius = (brush.radius + scroll.delta.y).clamp(MIN_RADIUS, MAX_RADIUS);
+        }
+    }
+}
+
+/// Outlines the brush where the view ray hits and applies it while the left mouse
+/// button is held.
+#[allow(clippy::too_many_arguments)]
+fn sculpt(
+    time: Res<Time>,
+    mouse: Res<ButtonInput<MouseButton>>,
+    brush: Res<SculptBrush>,
+    selected: Res<SelectedBlock>,
+    cameras: Query<&GlobalTransform, With<FlyCamera>>,
+    mut voxel_world: ResMut<VoxelWorld>,
+    mut history: ResMut<VoxelEditHistory>,
+    mut gizmos: Gizmos,
+) {
+    if !brush.active {
+        return;
+    }
+    // The whole stroke is undone at once.
+    if mouse.pressed(MouseButton::Left) {
+        history.hold_stroke();
+    }
+
+    for transform in &cameras {
+        let (origin, dir) = (transform.translation(), *transform.forward());
+        let Some(hit) = voxel_world.raycast(origin, dir, SCULPT_REACH) else {
+            continue;
+        };
+        let center = origin + dir * hit.distance;
+
+        let color = Color::srgb(1.0, 0.8, 0.2);
+        if brush.tool == BrushTool::BoxFill {
+            gizmos.cuboid(
+                Transform::from_translation(center).with_scale(Vec3::splat(brush.radius * 2.0)),
+                color,
+            );
+        } else {
+            gizmos.sphere(Isometry3d::from_translation(center), brush.radius, color);
+        }
+
+        if mouse.pressed(MouseButton::Left) {
+            apply_brush(
+                &mut voxel_world,
+                &brush,
+                **selected,
+                center,
+                hit.normal.as_vec3(),
+                time.delta_secs(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        chunks_partition::CHUNK_VOXELS_COUNT,
+        terrain_generator::{ChunkMesher, ChunkVoxels},
+    };
+
+    /// World of air with the chunk at the origin loaded.
+    fn world() -> VoxelWorld {
+        let mut world = VoxelWorld::new(ChunkMesher::Blocks, 0.0);
+        let voxels = ChunkVoxels {
+            density: vec![-1.0; CHUNK_VOXELS_COUNT],
+            ..default()
+        };
+        world.insert(IVec3::ZERO, Arc::new(voxels));
+        world
+    }
+
+    #[test]
+    fn add_raises_the_density_inside_the_radius_only() {
+        let mut world = world();
+        let brush = SculptBrush {
+            radius: 3.0,
+            strength: 4.0,
+            ..default()
+        };
+        let center = Vec3::splat(8.5);
+        apply_brush(&mut world, &brush, BlockId::STONE, center, Vec3::Y, 0.5);
+
+        for z in 0..16 {
+            for y in 0..16 {
+                for x in 0..16 {
+                    let voxel = IVec3::new(x, y, z);
+                    let density = world.density_at(voxel).unwrap();
+                    if (voxel.as_vec3() + 0.5).distance(center) < brush.radius {
+                        assert!(density > -1.0);
+                    } else {
+                        assert_eq!(density, -1.0);
+                        assert_eq!(world.block_at(voxel), Some(BlockId::AIR));
+                    }
+                }
+            }
+        }
+        // The centre gets the full two units and turns solid.
+        assert_eq!(world.density_at(IVec3::splat(8)), Some(1.0));
+        assert_eq!(world.block_at(IVec3::splat(8)), Some(BlockId::STONE));
+    }
+}
@@ -0,0 +1,469 @@
+//! WorldEdit style editing of a box of voxels.
+//!
+//! `G` toggles the selection tool. Left click marks the first corner on the targeted
+//! voxel, right click the second. On the selection, `F` fills it with the selected
+//! block, `R` replaces the targeted block with it, `H` turns it into a hollow box and
+//! `L` builds its four walls. `Ctrl+C` copies it to the clipboard and `Ctrl+V` pastes
+//! the clipboard against the targeted face, `.` rotates the clipboard a quarter turn,
+//! `M` mirrors it along the axis the camera faces. `T` stacks a copy of the selection
+//! next to it in the direction the camera faces and moves the selection onto it.
+//!
+//! Every operation goes through [`VoxelWorld::edit_region`], one chunk at a time, and
+//! is a single undo step.
+
+use bevy::prelude::*;
+
+use crate::{
+    block::BlockId,
+    block_interaction::SelectedBlock,
+    fly_camera::{FlyCamera, cursor_locked},
+    sculpt::sculpting,
+    voxel_world::{VoxelWorld, flush_voxel_edits},
+};
+
+/// How far away corners can be marked, in blocks.
+pub const SELECT_REACH: f32 = 64.0;
+
+/// Two corners marked in world voxel coordinates, both inside the box.
+#[derive(Resource, Clone, Copy, Debug, Default)]
+pub struct Selection {
+    pub active: bool,
+    pub first: Option<IVec3>,
+    pub second: Option<IVec3>,
+}
+
+impl Selection {
+    /// Lowest and highest voxel of the box, once both corners are marked.
+    pub fn bounds(&self) -> Option<(IVec3, IVec3)> {
+        let (first, second) = (self.first?, self.second?);
+        Some((first.min(second), first.max(second)))
+    }
+}
+
+/// Run condition of the tools the mouse drives outside of selecting.
+pub fn selecting(selection: Option<Res<Selection>>) -> bool {
+    selection.is_some_and(|selection| selection.active)
+}
+
+/// Box of voxels copied out of the world, indexed like the chunks' voxels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VoxelRegion {
+    pub size: IVec3,
+    pub blocks: Vec<BlockId>,
+    pub density: Vec<f32>,
+}
+
+impl VoxelRegion {
+    /// Region of air of the given size.
+    pub fn new(size: IVec3, air_density: f32) -> Self {
+        let count = (size.x * size.y * size.z).max(0) as usize;
+        Self {
+            size,
+            blocks: vec![BlockId::AIR; count],
+            density: vec![air_density; count],
+        }
+    }
+
+    pub fn index(&self, pos: IVec3) -> usize {
+        (pos.z * self.size.x * self.size.y + pos.y * self.size.x + pos.x) as usize
+    }
+
+    /// Copies `min..=max` out of the world. Voxels of chunks that aren't loaded are air.
+    pub fn copy(voxel_world: &VoxelWorld, min: IVec3, max: IVec3) -> Self {
+        let air = voxel_world.block_density(BlockId::AIR);
+        let mut region = Self::new(max - min + 1, air);
+        voxel_world.visit_region(min, max, |voxel, block, density| {
+            let i = region.index(voxel - min);
+            region.blocks[i] = block;
+            region.density[i] = density;
+        });
+        region
+    }
+
+    /// Writes the region with its lowest corner on `origin`. Air voxels are skipped
+    /// when `skip_air`, so pasting keeps what surrounds a structure.
+    pub fn paste(&self, voxel_world: &mut VoxelWorld, origin: IVec3, skip_air: bool) -> usize {
+        voxel_world.edit_region(origin, origin + self.size - 1, |voxel, _, _| {
+            let i = self.index(voxel - origin);
+            if skip_air && self.blocks[i] == BlockId::AIR {
+                return None;
+            }
+            Some((self.blocks[i], self.density[i]))
+        })
+    }
+
+    /// The region turned `quarter_turns` times a quarter clockwise around the Y axis,
+    /// seen from above.
+    pub fn rotated_y(&self, quarter_turns: i32) -> Self {
+        let mut region = self.clone();
+        for _ in 0..quarter_turns.rem_euclid(4) {
+            let size = IVec3::new(region.size.z, region.size.y, region.size.x);
+            let mut rotated = region.clone();
+            rotated.size = size;
+            for z in 0..region.size.z {
+                for y in 0..region.size.y {
+                    for x in 0..region.size.x {
+                        let from = region.index(IVec3::new(x, y, z));
+                        let to = rotated.index(IVec3::new(region.size.z - 1 - z, y, x));
+                        rotated.blocks[to] = region.blocks[from];
+                        rotated.density[to] = region.density[from];
+                    }
+                }
+            }
+            region = rotated;
+        }
+        region
+    }
+
+    /// The region flipped along `axis`, 0 for X, 1 for Y and 2 for Z.
+    pub fn mirrored(&self, axis: usize) -> Self {
+        let mut mirrored = self.clone();
+        for z in 0..self.size.z {
+            for y in 0..self.size.y {
+                for x in 0..self.size.x {
+                    let pos = IVec3::new(x, y, z);
+                    let mut flipped = pos;
+                    flipped[axis] = self.size[axis] - 1 - pos[axis];
+                    let (from, to) = (self.index(pos), self.index(flipped));
+                    mirrored.blocks[to] = self.blocks[from];
+                    mirrored.density[to] = self.density[from];
+                }
+            }
+        }
+        mirrored
+    }
+}
+
+/// Sets every voxel of `min..=max` to `block`.
+pub fn fill(voxel_world: &mut VoxelWorld, min: IVec3, max: IVec3, block: BlockId) -> usize {
+    let density = voxel_world.block_density(block);
+    voxel_world.edit_region(min, max, |_, _, _| Some((block, density)))
+}
+
+/// Turns the voxels of `from` in `min..=max` into `to`. Densities are kept when both
+/// blocks are equally solid, so replacing doesn't flatten sculpted surfaces.
+pub fn replace(
+    voxel_world: &mut VoxelWorld,
+    min: IVec3,
+    max: IVec3,
+    from: BlockId,
+    to: BlockId,
+) -> usize {
+    let density = voxel_world.block_density(to);
+    let keep_density = from.is_solid() == to.is_solid();
+    voxel_world.edit_region(min, max, |_, block, old_density| {
+        (block == from).then_some((to, if keep_density { old_density } else { density }))
+    })
+}
+
+/// Fills the one block thick shell of `min..=max` with `block` and clears the inside.
+pub fn hollow(voxel_world: &mut VoxelWorld, min: IVec3, max: IVec3, block: BlockId) -> usize {
+    let density = voxel_world.block_density(block);
+    let air = voxel_world.block_density(BlockId::AIR);
+    voxel_world.edit_region(min, max, |voxel, _, _| {
+        let shell = voxel.cmpeq(min).any() || voxel.cmpeq(max).any();
+        Some(if shell {
+            (block, density)
+        } else {
+            (BlockId::AIR, air)
+        })
+    })
+}
+
+/// Fills the four vertical sides of `min..=max` with `block`, leaving the rest alone.
+pub fn walls(voxel_world: &mut VoxelWorld, min: IVec3, max: IVec3, block: BlockId) -> usize {
+    let density = voxel_world.block_density(block);
+    voxel_world.edit_region(min, max, |voxel, _, _| {
+        let side = voxel.x == min.x || voxel.x == max.x || voxel.z == min.z || voxel.z == max.z;
+        side.then_some((block, density))
+    })
+}
+
+/// Repeats `min..=max` `count` times next to itself along `dir`. Returns the bounds of
+/// the last copy.
+pub fn stack(
+    voxel_world: &mut VoxelWorld,
+    min: IVec3,
+    max: IVec3,
+    dir: IVec3,
+    count: i32,
+) -> (IVec3, IVec3) {
+    let region = VoxelRegion::copy(voxel_world, min, max);
+    let offset = dir * region.size;
+    for i in 1..=count {
+        region.paste(voxel_world, min + offset * i, false);
+    }
+    (min + offset * count, max + offset * count)
+}
+
+/// Axis `dir` is closest to, 0 for X, 1 for Y and 2 for Z.
+fn dominant_axis(dir: Vec3) -> usize {
+    let abs = dir.abs();
+    if abs.x >= abs.y && abs.x >= abs.z {
+        0
+    } else if abs.y >= abs.z {
+        1
+    } else {
+        2
+    }
+}
+
+/// Axis aligned unit vector closest to `dir`.
+fn facing(dir: Vec3) -> IVec3 {
+    let axis = dominant_axis(dir);
+    let mut facing = IVec3::ZERO;
+    facing[axis] = if dir[axis] < 0.0 { -1 } else { 1 };
+    facing
+}
+
+/// Region `Ctrl+C` copied.
+#[derive(Resource, Default)]
+pub struct Clipboard(pub Option<VoxelRegion>);
+
+pub struct SelectionPlugin;
+
+impl Plugin for SelectionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Selection>()
+            .init_resource::<Clipboard>()
+            .add_systems(
+                Update,
+                (
+                    toggle_selection,
+                    (
+                        mark_corners.run_if(cursor_locked),
+                        edit_selection,
+                        draw_selection,
+                    )
+                        .run_if(selecting.and(not(sculpting))),
+                )
+                    .chain()
+                    .before(flush_voxel_edits),
+            );
+    }
+}
+
+fn toggle_selection(keys: Res<ButtonInput<KeyCode>>, mut selection: ResMut<Selection>) {
+    if keys.just_pressed(KeyCode::KeyG) {
+        selection.active = !selection.active;
+    }
+}
+
+fn mark_corners(
+    mouse: Res<ButtonInput<MouseButton>>,
+    cameras: Query<&GlobalTransform, With<FlyCamera>>,
+    voxel_world: Res<VoxelWorld>,
+    mut selection: ResMut<Selection>,
+) {
+    let first = mouse.just_pressed(MouseButton::Left);
+    let second = mouse.just_pressed(MouseButton::Right);
+    if !first && !second {
+        return;
+    }
+
+    for transform in &cameras {
+        let Some(hit) =
+            voxel_world.raycast(transform.translation(), *transform.forward(), SELECT_REACH)
+        else {
+            continue;
+        };
+        if first {
+            selection.first = Some(hit.voxel);
+        } else {
+            selection.second = Some(hit.voxel);
+        }
+    }
+}
+
+fn edit_selection(
+    keys: Res<ButtonInput<KeyCode>>,
+    selected: Res<SelectedBlock>,
+    cameras: Query<&GlobalTransform, With<FlyCamera>>,
+    mut voxel_world: ResMut<VoxelWorld>,
+    mut selection: ResMut<Selection>,
+    mut clipboard: ResMut<Clipboard>,
+) {
+    let Ok(transform) = cameras.single() else {
+        return;
+    };
+    let (origin, dir) = (transform.translation(), *transform.forward());
+    let ctrl = keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]);
+
+    if keys.just_pressed(KeyCode::Period)
+        && let Some(region) = &mut clipboard.0
+    {
+        *region = region.rotated_y(1);
+    }
+    if keys.just_pressed(KeyCode::KeyM)
+        && let Some(region) = &mut clipboard.0
+    {
+        *region = region.mirrored(dominant_axis(dir));
+    }
+    if ctrl
+        && keys.just_pressed(KeyCode::KeyV)
+        && let Some(region) = &clipboard.0
+        && let Some(hit) = voxel_world.raycast(origin, dir, SELECT_REACH)
+    {
+        region.paste(&mut voxel_world, hit.voxel + hit.normal, true);
+    }
+
+    let Some((min, max)) = selection.bounds() else {
+        return;
+    };
+    if ctrl {
+        if keys.just_pressed(KeyCode::KeyC) {
+            clipboard.0 = Some(VoxelRegion::copy(&voxel_world, min, max));
+        }
+        return;
+    }
+
+    if keys.just_pressed(KeyCode::KeyF) {
+        fill(&mut voxel_world, min, max, **selected);
+    } else if keys.just_pressed(KeyCode::KeyR) {
+        if let Some(hit) = voxel_world.raycast(origin, dir, SELECT_REACH) {
+            replace(&mut voxel_world, min, max, hit.block, **selected);
+        }
+    } else if keys.just_pressed(KeyCode::KeyH) {
+        hollow(&mut voxel_world, min, max, **selected);
+    } else if keys.just_pressed(KeyCode::KeyL) {
+        walls(&mut voxel_world, min, max, **selected);
+    } else if keys.just_pressed(KeyCode::KeyT) {
+        let (min, max) = stack(&mut voxel_world, min, max, facing(dir), 1);
+        selection.first = Some(min);
+        selection.second = Some(max);
+    }
+}
+
+fn draw_selection(selection: Res<Selection>, mut gizmos: Gizmos) {
+    for (corner, color) in [
+        (selection.first, Color::srgb(0.2, 0.6, 1.0)),
+        (selection.second, Color::srgb(1.0, 0.4, 0.2)),
+    ] {
+        if let Some(corner) = corner {
+            gizmos.cuboid(
+                Transform::from_translation(corner.as_vec3() + 0.5).with_scale(Vec3::splat(1.02)),
+                color,
+            );
+        }
+    }
+
+    if let Some((min, max)) = selection.bounds() {
+        let size = (max - min + 1).as_vec3();
+        gizmos.cuboid(
+            Transform::from_translation(min.as_vec3() + size / 2.0).with_scale(size + 0.04),
+            Color::WHITE,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        chunks_partition::CHUNK_VOXELS_COUNT,
+        terrain_generator::{ChunkMesher, ChunkVoxels},
+    };
+
+    /// World of air with the chunks around the origin loaded.
+    fn world() -> VoxelWorld {
+        let mut world = VoxelWorld::new(ChunkMesher::Blocks, 0.0);
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let voxels = ChunkVoxels {
+                        density: vec![-1.0; CHUNK_VOXELS_COUNT],
+                        ..default()
+                    };
+                    world.insert(IVec3::new(x, y, z), Arc::new(voxels));
+                }
+            }
+        }
+        world
+    }
+
+    /// Voxels of `min..=max` holding `block`.
+    fn count(world: &VoxelWorld, min: IVec3, max: IVec3, block: BlockId) -> usize {
+        let mut count = 0;
+        world.visit_region(min, max, |_, found, _| count += (found == block) as usize);
+        count
+    }
+
+    /// Region with a different block and density in every voxel.
+    fn numbered(size: IVec3) -> VoxelRegion {
+        let mut region = VoxelRegion::new(size, -1.0);
+        for i in 0..region.blocks.len() {
+            region.blocks[i] = BlockId(i as u32);
+            region.density[i] = i as f32;
+        }
+        region
+    }
+
+    #[test]
+    fn four_quarter_turns_are_the_identity() {
+        let region = numbered(IVec3::new(3, 2, 4));
+        let turned = region.rotated_y(1);
+        assert_eq!(turned.size, IVec3::new(4, 2, 3));
+        assert_ne!(turned, region);
+        // (x, y, z) lands on (size.z - 1 - z, y, x).
+        let from = region.index(IVec3::new(2, 1, 0));
+        assert_eq!(
+            turned.blocks[turned.index(IVec3::new(3, 1, 2))],
+            region.blocks[from]
+        );
+
+        assert_eq!(region.rotated_y(4), region);
+        assert_eq!(turned.rotated_y(3), region);
+        assert_eq!(region.rotated_y(-1), region.rotated_y(3));
+    }
+
+    #[test]
+    fn mirroring_twice_is_the_identity() {
+        let region = numbered(IVec3::new(3, 2, 4));
+        for axis in 0..3 {
+            let mirrored = region.mirrored(axis);
+            assert_ne!(mirrored, region);
+            assert_eq!(mirrored.mirrored(axis), region);
+        }
+    }
+
+    #[test]
+    fn hollow_keeps_only_the_shell() {
+        let mut world = world();
+        let (min, max) = (IVec3::new(-2, -1, -2), IVec3::new(1, 1, 1));
+        fill(&mut world, min, max, BlockId::DIRT);
+        assert_eq!(hollow(&mut world, min, max, BlockId::STONE), 4 * 3 * 4);
+        // 48 voxels, the inside is 2x1x2.
+        assert_eq!(count(&world, min, max, BlockId::STONE), 44);
+        assert_eq!(count(&world, min, max, BlockId::AIR), 4);
+    }
+
+    #[test]
+    fn walls_leave_the_inside_alone() {
+        let mut world = world();
+        let (min, max) = (IVec3::new(-2, -1, -2), IVec3::new(1, 1, 1));
+        fill(&mut world, min, max, BlockId::DIRT);
+        assert_eq!(walls(&mut world, min, max, BlockId::STONE), 12 * 3);
+        assert_eq!(count(&world, min, max, BlockId::STONE), 36);
+        assert_eq!(count(&world, min, max, BlockId::DIRT), 12);
+    }
+
+    #[test]
+    fn stack_repeats_the_selection() {
+        let mut world = world();
+        world.set_block(IVec3::ZERO, BlockId::STONE);
+        world.set_block(IVec3::X, BlockId::DIRT);
+        let last = stack(&mut world, IVec3::ZERO, IVec3::X, IVec3::X, 2);
+        assert_eq!(last, (IVec3::new(4, 0, 0), IVec3::new(5, 0, 0)));
+        for x in 0..6 {
+            let block = if x % 2 == 0 {
+                BlockId::STONE
+            } else {
+                BlockId::DIRT
+            };
+            assert_eq!(world.block_at(IVec3::new(x, 0, 0)), Some(block));
+        }
+        assert_eq!(world.block_at(IVec3::new(6, 0, 0)), Some(BlockId::AIR));
+    }
+}
@@ -0,0 +1,449 @@
+use std::sync::Arc;
+
+use bevy::{
+    prelude::*,
+    render::{extract_resource::ExtractResource, render_resource::ShaderType},
+    tasks::{AsyncComputeTaskPool, Task, futures::check_ready},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    biome::{BiomeTable, GpuBiomeParams, blended_height, surface_biome_at},
+    block::BlockId,
+    chunk::{ChunkPos, ChunkSlot, ChunkState},
+    chunk_queue::{ChunkJob, ChunkJobs},
+    chunks_partition::{CHUNK_SIZE, CHUNK_VOXELS_COUNT},
+    decoration::decorate_voxels,
+    noise::{NoiseKind, domain_warp2, fbm3, ridged3},
+    schematic::StructureSpawn,
+    voxel_world::VoxelWorld,
+    water::{WaterData, apply_water},
+};
+
+/// Fills the voxels of a chunk, either on the GPU through an entry point of
+/// `voxel_gen.wgsl` or on the CPU on the [`AsyncComputeTaskPool`].
+///
+/// Voxels are laid out `z * N * N + y * N + x` with `N = CHUNK_SIZE`.
+pub trait TerrainGenerator: Send + Sync + 'static {
+    /// Compute entry point writing `voxel_buffer` and `blocks` for every job,
+    /// `None` for CPU generators.
+    fn shader_entry_point(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Generates a chunk on the CPU, only called when there is no shader entry point.
+    fn generate(&self, _terrain: &TerrainParams, _chunk: IVec3, _voxels: &mut ChunkVoxels) {}
+
+    /// How the voxels this generator writes are meshed.
+    fn mesher(&self) -> ChunkMesher {
+        ChunkMesher::HeightField
+    }
+
+    /// Density at and above which a voxel is solid, for [`ChunkMesher::Blocks`].
+    fn density_threshold(&self) -> f32 {
+        0.0
+    }
+
+    /// Whether the seeded features of `decoration.rs` are placed on top, they stand
+    /// on [`terrain_height`] and need [`ChunkMesher::Blocks`].
+    fn decorated(&self) -> bool {
+        false
+    }
+
+    /// Whether lakes and rivers are traced, they follow [`terrain_height`] so
+    /// generators with another surface only get the sea.
+    fn procedural_water(&self) -> bool {
+        true
+    }
+}
+
+/// Mesher entry point of `voxel_gen.wgsl`, `Globals::mesher` on the GPU.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ChunkMesher {
+    /// The z=0 slice holds one surface height per column, relative to the chunk's bottom.
+    HeightField = 0,
+    /// Every voxel holds a density, solid voxels are meshed as cubes.
+    Blocks = 1,
+}
+
+/// Densities, or heights for [`ChunkMesher::HeightField`], and block types of one chunk.
+#[derive(Clone)]
+pub struct ChunkVoxels {
+    pub density: Vec<f32>,
+    pub blocks: Vec<BlockId>,
+}
+
+impl Default for ChunkVoxels {
+    fn default() -> Self {
+        Self {
+            density: vec![0.0; CHUNK_VOXELS_COUNT],
+            blocks: vec![BlockId::AIR; CHUNK_VOXELS_COUNT],
+        }
+    }
+}
+
+/// Octaves of the noises the terrain is built from.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NoiseOctaves {
+    /// Temperature and humidity.
+    pub climate: u32,
+    /// Domain warp of the column heights.
+    pub warp: u32,
+    /// 3D shape of the density generators.
+    pub density: u32,
+    pub caves: u32,
+}
+
+impl Default for NoiseOctaves {
+    fn default() -> Self {
+        Self {
+            climate: 3,
+            warp: 2,
+            density: 4,
+            caves: 3,
+        }
+    }
+}
+
+/// Seed, octaves and biomes the world is generated from, `Globals::seed` and the
+/// `terrain` uniform of `biome.wgsl` on the GPU.
+#[derive(Resource, Clone, Copy, PartialEq, Debug, Default, ExtractResource)]
+pub struct TerrainParams {
+    pub seed: u32,
+    pub octaves: NoiseOctaves,
+    pub biomes: BiomeTable,
+}
+
+/// `TerrainParams` of `biome.wgsl`, the seed lives in `Globals`.
+#[derive(Clone, Copy, ShaderType, Debug)]
+pub struct TerrainUniform {
+    biomes: [GpuBiomeParams; 5],
+    climate_octaves: u32,
+    warp_octaves: u32,
+    density_octaves: u32,
+    cave_octaves: u32,
+}
+
+impl From<&TerrainParams> for TerrainUniform {
+    fn from(terrain: &TerrainParams) -> Self {
+        Self {
+            biomes: terrain.biomes.0.each_ref().map(GpuBiomeParams::from),
+            climate_octaves: terrain.octaves.climate,
+            warp_octaves: terrain.octaves.warp,
+            density_octaves: terrain.octaves.density,
+            cave_octaves: terrain.octaves.caves,
+        }
+    }
+}
+
+/// Generator new chunks are generated with.
+#[derive(Resource, Clone, Deref, ExtractResource)]
+pub struct ActiveTerrainGenerator(pub Arc<dyn TerrainGenerator>);
+
+impl ActiveTerrainGenerator {
+    pub fn new(generator: impl TerrainGenerator) -> Self {
+        Self(Arc::new(generator))
+    }
+}
+
+/// `generate_height_map` of `voxel_gen.wgsl`.
+pub struct GpuHeightMapGenerator;
+
+impl TerrainGenerator for GpuHeightMapGenerator {
+    fn shader_entry_point(&self) -> Option<&'static str> {
+        Some("generate_height_map")
+    }
+}
+
+/// CPU port of [`GpuHeightMapGenerator`].
+pub struct CpuHeightMapGenerator;
+
+impl TerrainGenerator for CpuHeightMapGenerator {
+    fn generate(&self, terrain: &TerrainParams, chunk: IVec3, voxels: &mut ChunkVoxels) {
+        let n = CHUNK_SIZE as i32;
+        let columns: Vec<(f32, BlockId)> = (0..n * n)
+            .map(|i| {
+                let world_xz = IVec2::new(chunk.x * n + i % n, chunk.z * n + i / n).as_vec2();
+                let top = surface_biome_at(world_xz, terrain).surface().top;
+                (
+                    terrain_height(world_xz, terrain) - (chunk.y * n) as f32,
+                    top,
+                )
+            })
+            .collect();
+        for i in 0..CHUNK_VOXELS_COUNT {
+            let (height, top) = columns[i % (n * n) as usize];
+            voxels.density[i] = height;
+            voxels.blocks[i] = top;
+        }
+    }
+}
+
+const TERRAIN_FREQUENCY: f32 = 1.0 / 128.0;
+
+/// Surface height of a world column, the biome heights sampled at a warped position.
+pub fn terrain_height(world_xz: Vec2, terrain: &TerrainParams) -> f32 {
+    let warped = domain_warp2(
+        NoiseKind::Perlin,
+        world_xz * TERRAIN_FREQUENCY,
+        terrain.seed,
+        terrain.octaves.warp,
+        0.5,
+    ) / TERRAIN_FREQUENCY;
+    blended_height(world_xz, warped, terrain)
+}
+
+/// `generate_density` of `voxel_gen.wgsl`: 3D noise biased by [`terrain_height`],
+/// giving overhangs, arches and caves.
+#[derive(Default)]
+pub struct GpuDensityGenerator {
+    pub threshold: f32,
+}
+
+impl TerrainGenerator for GpuDensityGenerator {
+    fn shader_entry_point(&self) -> Option<&'static str> {
+        Some("generate_density")
+    }
+
+    fn mesher(&self) -> ChunkMesher {
+        ChunkMesher::Blocks
+    }
+
+    fn density_threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    fn decorated(&self) -> bool {
+        true
+    }
+}
+
+/// CPU port of [`GpuDensityGenerator`].
+#[derive(Default)]
+pub struct CpuDensityGenerator {
+    pub threshold: f32,
+}
+
+impl TerrainGenerator for CpuDensityGenerator {
+    fn generate(&self, terrain: &TerrainParams, chunk: IVec3, voxels: &mut ChunkVoxels) {
+        let n = CHUNK_SIZE as i32;
+        for i in 0..CHUNK_VOXELS_COUNT {
+            let local = IVec3::new(i as i32 % n, (i as i32 / n) % n, i as i32 / (n * n));
+            let world = (chunk * n + local).as_vec3();
+            let density = terrain_density(world, terrain);
+            voxels.density[i] = density;
+            voxels.blocks[i] = if density >= self.threshold {
+                terrain_block(world, terrain)
+            } else {
+                BlockId::AIR
+            };
+        }
+    }
+
+    fn mesher(&self) -> ChunkMesher {
+        ChunkMesher::Blocks
+    }
+
+    fn density_threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    fn decorated(&self) -> bool {
+        true
+    }
+}
+
+const DENSITY_FREQUENCY: f32 = 1.0 / 32.0;
+/// Density per block of distance to the height-field surface.
+const DENSITY_HEIGHT_BIAS: f32 = 1.0 / 12.0;
+const CAVE_FREQUENCY: f32 = 1.0 / 48.0;
+/// Ridges of the cave noise above this become tunnels.
+const CAVE_RIDGE: f32 = 0.85;
+const CAVE_STRENGTH: f32 = 8.0;
+
+/// Density of [`GpuDensityGenerator`] at a world position, positive is solid.
+pub fn terrain_density(world: Vec3, terrain: &TerrainParams) -> f32 {
+    let surface = terrain_height(world.xz(), terrain);
+    let bias = (surface - world.y) * DENSITY_HEIGHT_BIAS;
+    let shape = fbm3(
+        NoiseKind::Perlin,
+        world * DENSITY_FREQUENCY,
+        terrain.seed.wrapping_add(31),
+        terrain.octaves.density,
+        2.0,
+        0.5,
+    );
+    let caves = ridged3(
+        NoiseKind::Perlin,
+        world * CAVE_FREQUENCY,
+        terrain.seed.wrapping_add(67),
+        terrain.octaves.caves,
+        2.0,
+        0.5,
+    );
+    shape + bias - (caves - CAVE_RIDGE).max(0.0) * CAVE_STRENGTH
+}
+
+/// Block of a solid voxel, by depth below the surface of its column's biome.
+pub fn terrain_block(world: Vec3, terrain: &TerrainParams) -> BlockId {
+    let depth = terrain_height(world.xz(), terrain) - world.y;
+    surface_biome_at(world.xz(), terrain).surface_block(depth)
+}
+
+/// Voxels a CPU generator is producing for a chunk.
+#[derive(Component)]
+pub struct ChunkGenTask(Task<ChunkVoxels>);
+
+/// CPU generated voxels to copy into their slot of the voxel buffer this frame.
+#[derive(Resource, Default, Clone, ExtractResource)]
+pub struct ChunkVoxelUploads {
+    pub chunks: Vec<(u32, Arc<ChunkVoxels>)>,
+}
+
+pub fn spawn_chunk_gen_task(
+    commands: &mut Commands,
+    generator: &ActiveTerrainGenerator,
+    terrain: TerrainParams,
+    structures: Arc<Vec<StructureSpawn>>,
+    water: Arc<WaterData>,
+    entity: Entity,
+    chunk: IVec3,
+) {
+    let generator = generator.0.clone();
+    let task = AsyncComputeTaskPool::get().spawn(async move {
+        let mut voxels = ChunkVoxels::default();
+        let threshold = generator.density_threshold();
+        generator.generate(&terrain, chunk, &mut voxels);
+        apply_water(&mut voxels, chunk, &water, generator.mesher(), threshold);
+        if generator.decorated() {
+            decorate_voxels(&mut voxels, chunk, &terrain, &structures, threshold, &water);
+        }
+        voxels
+    });
+    commands.entity(entity).insert(ChunkGenTask(task));
+}
+
+/// Uploads the voxels of finished CPU tasks and queues their chunks for meshing.
+pub fn poll_chunk_gen_tasks(
+    mut commands: Commands,
+    mut tasks: Query<(
+        Entity,
+        &ChunkPos,
+        &ChunkSlot,
+        &mut ChunkState,
+        &mut ChunkGenTask,
+    )>,
+    mut uploads: ResMut<ChunkVoxelUploads>,
+    mut jobs: ResMut<ChunkJobs>,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    uploads.chunks.clear();
+
+    for (entity, pos, slot, mut state, mut task) in &mut tasks {
+        let Some(voxels) = check_ready(&mut task.0) else {
+            continue;
+        };
+
+        commands
+            .entity(entity)
+            .remove::<ChunkGenTask>()
+            .insert(ChunkJob(jobs.ticket));
+        let voxels = Arc::new(voxels);
+        voxel_world.insert(**pos, voxels.clone());
+        uploads.chunks.push((**slot, voxels));
+        jobs.mesh.push((**pos, **slot));
+        *state = ChunkState::Meshing;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const N: i32 = CHUNK_SIZE as i32;
+
+    fn generate(
+        generator: &dyn TerrainGenerator,
+        terrain: &TerrainParams,
+        chunk: IVec3,
+    ) -> ChunkVoxels {
+        let mut voxels = ChunkVoxels::default();
+        generator.generate(terrain, chunk, &mut voxels);
+        voxels
+    }
+
+    fn terrain(seed: u32) -> TerrainParams {
+        TerrainParams { seed, ..default() }
+    }
+
+    /// Chunk holding the surface of the column at the chunk origin.
+    fn surface_chunk(terrain: &TerrainParams) -> IVec3 {
+        let surface = terrain_height(Vec2::ZERO, terrain);
+        IVec3::new(0, (surface / CHUNK_SIZE).floor() as i32, 0)
+    }
+
+    #[test]
+    fn generators_are_deterministic_for_a_seed() {
+        let terrain = terrain(42);
+        let chunk = surface_chunk(&terrain);
+        let generators: [&dyn TerrainGenerator; 2] =
+            [&CpuHeightMapGenerator, &CpuDensityGenerator::default()];
+        for generator in generators {
+            let a = generate(generator, &terrain, chunk);
+            let b = generate(generator, &terrain, chunk);
+            assert_eq!(a.density, b.density);
+            assert_eq!(a.blocks, b.blocks);
+        }
+    }
+
+    #[test]
+    fn height_map_is_solid_below_and_air_above_the_surface() {
+        let terrain = terrain(7);
+        let chunk = surface_chunk(&terrain);
+
+        let below = generate(&CpuHeightMapGenerator, &terrain, chunk - IVec3::Y * 8);
+        let above = generate(&CpuHeightMapGenerator, &terrain, chunk + IVec3::Y * 8);
+        for i in 0..(N * N) as usize {
+            // Heights are relative to the chunk's bottom, solid where `y + 0.5 < height`.
+            assert!(below.density[i] >= CHUNK_SIZE);
+            assert!(above.density[i] <= 0.0);
+        }
+
+        let voxels = generate(&CpuHeightMapGenerator, &terrain, chunk);
+        for i in 0..(N * N) as usize {
+            let world_xz = IVec2::new(i as i32 % N, i as i32 / N).as_vec2();
+            let height = terrain_height(world_xz, &terrain) - (chunk.y * N) as f32;
+            assert_eq!(voxels.density[i], height);
+        }
+    }
+
+    #[test]
+    fn density_is_solid_deep_below_and_air_high_above_the_surface() {
+        let terrain = terrain(7);
+        let generator = CpuDensityGenerator::default();
+        let surface_chunk = surface_chunk(&terrain);
+
+        let (mut solid, mut air) = (0, 0);
+        for chunk_y in -4..=4 {
+            let chunk = surface_chunk + IVec3::Y * chunk_y;
+            let voxels = generate(&generator, &terrain, chunk);
+            for i in 0..CHUNK_VOXELS_COUNT {
+                let local = IVec3::new(i as i32 % N, (i as i32 / N) % N, i as i32 / (N * N));
+                let world = (chunk * N + local).as_vec3();
+                let depth = terrain_height(world.xz(), &terrain) - world.y;
+                // Noise and caves shift the density by less than the height bias over these.
+                if depth > 40.0 {
+                    assert!(voxels.density[i] >= generator.threshold);
+                    assert_ne!(voxels.blocks[i], BlockId::AIR);
+                    solid += 1;
+                } else if depth < -24.0 {
+                    assert!(voxels.density[i] < generator.threshold);
+                    assert_eq!(voxels.blocks[i], BlockId::AIR);
+                    air += 1;
+                }
+            }
+        }
+        assert!(solid > 0 && air > 0);
+    }
+}
@@ -0,0 +1,429 @@
+//! MagicaVoxel `.vox` models loaded as [`Schematic`]s, so they paste into the world and
+//! decorate it like saved selections, or stand alone as a [`VoxelVolume`].
+//!
+//! Palette colours become blocks through a [`VoxBlockMapping`], the one of the
+//! [`VoxPlugin`] unless the model's `.meta` file sets another. In the selection tool
+//! `Ctrl+I` loads [`IMPORT_VOX`] into the clipboard and `Ctrl+Shift+I` spawns it as a
+//! volume in front of the camera.
+
+use std::{collections::HashMap, io};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, io::Reader},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block::BlockId,
+    fly_camera::FlyCamera,
+    schematic::{PendingClipboard, Schematic},
+    sculpt::sculpting,
+    selection::{VoxelRegion, selecting},
+    voxel_mesh::region_mesh,
+};
+
+/// Model the import keys load, relative to the assets folder.
+pub const IMPORT_VOX: &str = "models/import.vox";
+
+/// Blocks picked by colour for palette entries without an explicit mapping.
+const MAPPED_BLOCKS: [BlockId; 10] = [
+    BlockId::STONE,
+    BlockId::DIRT,
+    BlockId::GRASS,
+    BlockId::SAND,
+    BlockId::SNOW,
+    BlockId::GRAVEL,
+    BlockId::WOOD,
+    BlockId::LEAVES,
+    BlockId::COAL_ORE,
+    BlockId::IRON_ORE,
+];
+
+/// RGBA of the palette indices of files without an `RGBA` chunk, MagicaVoxel's default:
+/// a 6×6×6 colour cube without black, then ramps of red, green, blue and grey.
+const DEFAULT_PALETTE: [[u8; 4]; 256] = {
+    const CUBE: [u8; 6] = [0xff, 0xcc, 0x99, 0x66, 0x33, 0x00];
+    const RAMP: [u8; 10] = [0xee, 0xdd, 0xbb, 0xaa, 0x88, 0x77, 0x55, 0x44, 0x22, 0x11];
+    let mut palette = [[0; 4]; 256];
+    let mut i = 0;
+    while i < 215 {
+        palette[1 + i] = [CUBE[i / 36], CUBE[i / 6 % 6], CUBE[i % 6], 0xff];
+        i += 1;
+    }
+    let mut i = 0;
+    while i < RAMP.len() {
+        let v = RAMP[i];
+        palette[216 + i] = [v, 0, 0, 0xff];
+        palette[226 + i] = [0, v, 0, 0xff];
+        palette[236 + i] = [0, 0, v, 0xff];
+        palette[246 + i] = [v, v, v, 0xff];
+        i += 1;
+    }
+    palette
+};
+
+/// Largest model MagicaVoxel makes along any axis.
+const MAX_MODEL_SIZE: u32 = 256;
+/// Densities of imported voxels relative to the threshold, as placed blocks have.
+const SOLID_DENSITY: f32 = 1.0;
+
+/// One model of a `.vox` file, Z up as MagicaVoxel stores it.
+#[derive(Clone, Debug, Default)]
+pub struct VoxModel {
+    pub size: UVec3,
+    /// Position and palette index of every filled voxel.
+    pub voxels: Vec<([u8; 3], u8)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct VoxFile {
+    pub models: Vec<VoxModel>,
+    /// RGBA of palette indices 1 to 255 at `index - 1`, `None` when the file has no
+    /// `RGBA` chunk and uses MagicaVoxel's default palette.
+    pub palette: Option<Vec<[u8; 4]>>,
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> io::Result<u32> {
+    bytes
+        .get(at..at + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+impl VoxFile {
+    /// Reads the models and the palette of a file, skipping the scene graph and
+    /// material chunks.
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.get(..4) != Some(b"VOX ".as_slice()) {
+            return Err(invalid("not a MagicaVoxel file"));
+        }
+
+        let mut file = VoxFile {
+            models: Vec::new(),
+            palette: None,
+        };
+        // Chunks follow each other flat after MAIN's header, whose children they are.
+        let mut at = 8 + 12;
+        if bytes.get(8..12) != Some(b"MAIN".as_slice()) {
+            return Err(invalid("missing MAIN chunk"));
+        }
+        while at < bytes.len() {
+            let id = bytes.get(at..at + 4).ok_or(io::ErrorKind::UnexpectedEof)?;
+            let content = read_u32(bytes, at + 4)? as usize;
+            let children = read_u32(bytes, at + 8)? as usize;
+            let body = bytes
+                .get(at + 12..at + 12 + content)
+                .ok_or(io::ErrorKind::UnexpectedEof)?;
+
+            match id {
+                b"SIZE" => file.models.push(VoxModel {
+                    size: UVec3::new(read_u32(body, 0)?, read_u32(body, 4)?, read_u32(body, 8)?),
+                    voxels: Vec::new(),
+                }),
+                b"XYZI" => {
+                    let model = file
+                        .models
+                        .last_mut()
+                        .ok_or_else(|| invalid("XYZI chunk before SIZE"))?;
+                    let count = read_u32(body, 0)? as usize;
+                    let data = body
+                        .get(4..4 + count * 4)
+                        .ok_or(io::ErrorKind::UnexpectedEof)?;
+                    model.voxels = data
+                        .chunks_exact(4)
+                        .map(|v| ([v[0], v[1], v[2]], v[3]))
+                        .collect();
+                }
+                b"RGBA" => {
+                    file.palette = Some(
+                        body.chunks_exact(4)
+                            .take(255)
+                            .map(|c| [c[0], c[1], c[2], c[3]])
+                            .collect(),
+                    );
+                }
+                _ => {}
+            }
+            at += 12 + content + children;
+        }
+
+        if file.models.is_empty() {
+            return Err(invalid("no models"));
+        }
+        Ok(file)
+    }
+}
+
+/// How palette indices of `.vox` models turn into blocks.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VoxBlockMapping {
+    /// Blocks of palette indices, 1 to 255.
+    pub indices: HashMap<u8, BlockId>,
+    /// Block of the other indices, the block of the closest colour when `None`.
+    pub fallback: Option<BlockId>,
+}
+
+impl VoxBlockMapping {
+    /// `rgba` is the colour of the index.
+    pub fn block(&self, index: u8, [r, g, b, _]: [u8; 4]) -> BlockId {
+        if let Some(block) = self.indices.get(&index) {
+            return *block;
+        }
+        if let Some(block) = self.fallback {
+            return block;
+        }
+        let color = Vec3::new(r as f32, g as f32, b as f32) / 255.0;
+        MAPPED_BLOCKS
+            .into_iter()
+            .min_by(|a, b| {
+                a.color()
+                    .distance_squared(color)
+                    .total_cmp(&b.color().distance_squared(color))
+            })
+            .unwrap()
+    }
+}
+
+/// Schematic of one model of a file, Y up, anchored on the centre of its bottom.
+pub fn vox_schematic(
+    file: &VoxFile,
+    model: usize,
+    mapping: &VoxBlockMapping,
+) -> io::Result<Schematic> {
+    let model = file
+        .models
+        .get(model)
+        .ok_or_else(|| invalid(format!("no model {model}")))?;
+    if model.size.max_element() > MAX_MODEL_SIZE {
+        return Err(invalid(format!("model size {} out of range", model.size)));
+    }
+    // MagicaVoxel is right handed with Z up.
+    let size = IVec3::new(
+        model.size.x as i32,
+        model.size.z as i32,
+        model.size.y as i32,
+    );
+    let mut region = VoxelRegion::new(size, -SOLID_DENSITY);
+
+    let palette: Vec<BlockId> = (0..=255u8)
+        .map(|index| {
+            let rgba = file
+                .palette
+                .as_ref()
+                .and_then(|palette| palette.get((index as usize).wrapping_sub(1)).copied())
+                .unwrap_or(DEFAULT_PALETTE[index as usize]);
+            mapping.block(index, rgba)
+        })
+        .collect();
+    for ([x, y, z], index) in &model.voxels {
+        let pos = IVec3::new(*x as i32, *z as i32, size.z - 1 - *y as i32);
+        if pos.cmpge(IVec3::ZERO).all() && pos.cmplt(size).all() {
+            let i = region.index(pos);
+            region.blocks[i] = palette[*index as usize];
+            region.density[i] = SOLID_DENSITY;
+        }
+    }
+
+    Ok(Schematic {
+        origin: IVec3::new(size.x / 2, 0, size.z / 2),
+        region,
+    })
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct VoxLoaderSettings {
+    /// Model of the file to load, files can hold several.
+    pub model: usize,
+    /// Replaces the mapping of the [`VoxPlugin`].
+    pub mapping: Option<VoxBlockMapping>,
+}
+
+/// Loads `.vox` files as [`Schematic`]s.
+#[derive(TypePath)]
+pub struct VoxLoader {
+    mapping: VoxBlockMapping,
+}
+
+impl AssetLoader for VoxLoader {
+    type Asset = Schematic;
+    type Settings = VoxLoaderSettings;
+    type Error = io::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        settings: &VoxLoaderSettings,
+        _load_context: &mut LoadContext<'_>,
+    ) -> Result<Schematic, io::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let mapping = settings.mapping.as_ref().unwrap_or(&self.mapping);
+        vox_schematic(&VoxFile::parse(&bytes)?, settings.model, mapping)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["vox"]
+    }
+}
+
+/// Voxel model drawn on its own, outside of the chunk grid.
+#[derive(Component, Clone)]
+pub struct VoxelVolume(pub Handle<Schematic>);
+
+#[derive(Default)]
+pub struct VoxPlugin {
+    pub mapping: VoxBlockMapping,
+}
+
+impl Plugin for VoxPlugin {
+    fn build(&self, app: &mut App) {
+        app.register_asset_loader(VoxLoader {
+            mapping: self.mapping.clone(),
+        })
+        .add_systems(
+            Update,
+            (
+                import_vox.run_if(selecting.and(not(sculpting))),
+                mesh_voxel_volumes,
+            )
+                .chain(),
+        );
+    }
+}
+
+/// `Ctrl+I` loads [`IMPORT_VOX`] into the clipboard, `Ctrl+Shift+I` spawns it.
+fn import_vox(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    assets: Res<AssetServer>,
+    cameras: Query<&GlobalTransform, With<FlyCamera>>,
+    mut pending: ResMut<PendingClipboard>,
+) {
+    if !keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight])
+        || !keys.just_pressed(KeyCode::KeyI)
+    {
+        return;
+    }
+
+    if !keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        pending.0 = Some(assets.load(IMPORT_VOX));
+        return;
+    }
+    for transform in &cameras {
+        let position = transform.translation() + *transform.forward() * 8.0;
+        commands.spawn((
+            VoxelVolume(assets.load(IMPORT_VOX)),
+            Transform::from_translation(position.floor()),
+        ));
+    }
+}
+
+/// Meshes volumes once their model is loaded, and again when it is edited.
+fn mesh_voxel_volumes(
+    mut commands: Commands,
+    schematics: Res<Assets<Schematic>>,
+    mut events: MessageReader<AssetEvent<Schematic>>,
+    volumes: Query<(Entity, &VoxelVolume, Has<Mesh3d>)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut material: Local<Option<Handle<StandardMaterial>>>,
+) {
+    let modified: Vec<AssetId<Schematic>> = events
+        .read()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    for (entity, volume, meshed) in &volumes {
+        if meshed && !modified.contains(&volume.0.id()) {
+            continue;
+        }
+        let Some(schematic) = schematics.get(&volume.0) else {
+            continue;
+        };
+        // Shading is baked into the vertex colours, the scene has no lights.
+        let material = material
+            .get_or_insert_with(|| {
+                materials.add(StandardMaterial {
+                    unlit: true,
+                    ..default()
+                })
+            })
+            .clone();
+        commands.entity(entity).insert((
+            Mesh3d(meshes.add(region_mesh(&schematic.region, schematic.origin))),
+            MeshMaterial3d(material),
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    /// File of a 2×1×1 model, palette index 1 at x = 0 and 255 at x = 1.
+    fn file(rgba: Option<&[[u8; 4]]>) -> Vec<u8> {
+        let size: Vec<u8> = [2u32, 1, 1].iter().flat_map(|v| v.to_le_bytes()).collect();
+        let mut children = chunk(b"SIZE", &size);
+        children.extend(chunk(b"XYZI", &[2, 0, 0, 0, 0, 0, 0, 1, 1, 0, 0, 255]));
+        if let Some(rgba) = rgba {
+            children.extend(chunk(b"RGBA", rgba.as_flattened()));
+        }
+
+        let mut bytes = b"VOX ".to_vec();
+        bytes.extend_from_slice(&150u32.to_le_bytes());
+        bytes.extend_from_slice(b"MAIN");
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&(children.len() as u32).to_le_bytes());
+        bytes.extend(children);
+        bytes
+    }
+
+    fn blocks(bytes: &[u8]) -> Vec<BlockId> {
+        let file = VoxFile::parse(bytes).unwrap();
+        let schematic = vox_schematic(&file, 0, &VoxBlockMapping::default()).unwrap();
+        schematic.region.blocks
+    }
+
+    #[test]
+    fn default_palette_matches_magicavoxel() {
+        assert_eq!(DEFAULT_PALETTE[0], [0, 0, 0, 0]);
+        assert_eq!(DEFAULT_PALETTE[1], [0xff, 0xff, 0xff, 0xff]);
+        assert_eq!(DEFAULT_PALETTE[2], [0xff, 0xff, 0xcc, 0xff]);
+        assert_eq!(DEFAULT_PALETTE[7], [0xff, 0xcc, 0xff, 0xff]);
+        assert_eq!(DEFAULT_PALETTE[215], [0x00, 0x00, 0x33, 0xff]);
+        assert_eq!(DEFAULT_PALETTE[216], [0xee, 0x00, 0x00, 0xff]);
+        assert_eq!(DEFAULT_PALETTE[226], [0x00, 0xee, 0x00, 0xff]);
+        assert_eq!(DEFAULT_PALETTE[236], [0x00, 0x00, 0xee, 0xff]);
+        assert_eq!(DEFAULT_PALETTE[255], [0x11, 0x11, 0x11, 0xff]);
+    }
+
+    #[test]
+    fn files_without_a_palette_use_the_default_colours() {
+        assert_eq!(blocks(&file(None)), [BlockId::SNOW, BlockId::COAL_ORE]);
+    }
+
+    #[test]
+    fn files_with_a_palette_use_its_colours() {
+        let mut rgba = [[0, 0, 0, 0xff]; 256];
+        rgba[0] = [0x4c, 0x99, 0x33, 0xff];
+        rgba[254] = [0xdb, 0xcc, 0x8c, 0xff];
+        assert_eq!(blocks(&file(Some(&rgba))), [BlockId::GRASS, BlockId::SAND]);
+    }
+}
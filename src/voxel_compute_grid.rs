@@ -4,12 +4,16 @@ use bevy::{
     render::{
         Render, RenderApp, RenderStartup, RenderSystems,
         extract_resource::{ExtractResource, ExtractResourcePlugin},
+        gpu_readback::Readback,
         graph::CameraDriverLabel,
         render_asset::RenderAssets,
         render_graph::{Node, NodeRunError, RenderGraph, RenderLabel},
         render_resource::{
             UniformBuffer,
-            binding_types::{storage_buffer, storage_buffer_read_only, uniform_buffer},
+            binding_types::{
+                storage_buffer, storage_buffer_read_only, storage_buffer_read_only_sized,
+                storage_buffer_sized, uniform_buffer,
+            },
             *,
         },
         renderer::{RenderContext, RenderDevice, RenderQueue},
@@ -18,15 +22,55 @@ use bevy::{
 };
 use bytemuck::{Pod, Zeroable};
 use std::borrow::Cow;
-
-use crate::chunks_partition::{CHUNK_COUNT, CHUNK_SIZE, CHUNK_VOXELS_COUNT, VisibleChunks};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::block::BlockId;
+use crate::chunk::mark_meshed_chunks_ready;
+use crate::chunk_connectivity::{ChunkSolidMask, read_solid_masks};
+use crate::chunk_queue::{ChunkJobs, DroppedJobs, MAX_VOXEL_WRITES};
+use crate::chunks_partition::{CHUNK_COUNT, CHUNK_SIZE, CHUNK_VOXELS_COUNT, LoadedChunks};
+use crate::decoration::VoxelWrite;
+use crate::erosion::{
+    ErosionParams, ErosionRegions, ErosionSettings, REGION_SIZE, REGION_SLOTS, REGION_STRIDE,
+    RegionEntry, read_eroded_regions,
+};
+use crate::noise::hash3;
+use crate::terrain_generator::{
+    ActiveTerrainGenerator, ChunkMesher, ChunkVoxelUploads, GpuHeightMapGenerator, TerrainParams,
+    TerrainUniform,
+};
+use crate::water::{WATER_BODIES_SIZE, WaterBodies, WaterData, WaterSettings};
 
 const SHADER_ASSET_PATH: &str = "shaders/voxel_gen.wgsl";
+/// Import modules of the generator and chunk shaders.
+const SHADER_MODULE_PATHS: [&str; 3] = [
+    "shaders/noise.wgsl",
+    "shaders/block.wgsl",
+    "shaders/biome.wgsl",
+];
+
+/// Vertex budget of a slot. The height-field mesher always writes `(N - 1)^2 * 6`,
+/// the block mesher drops faces past it and reports the count it needed in the solid mask.
+pub const CHUNK_VERTICES_COUNT: usize = 6144;
+/// Translucent vertex budget of a slot.
+pub const CHUNK_WATER_VERTICES_COUNT: usize = 3072;
+
+/// Entries of the coordinate to slot table the block mesher finds neighbours with.
+const CHUNK_LOOKUP_SIZE: usize = (2 * CHUNK_COUNT).next_power_of_two();
 
 #[repr(C)]
 #[derive(Clone, Copy, ShaderType, Pod, Zeroable, Debug)]
 pub struct Globals {
     chunk_size: u32,
+    seed: u32,
+    vertices_per_chunk: u32,
+    mesher: u32,
+    density_threshold: f32,
+    erosion: u32,
+    water_vertices_per_chunk: u32,
+    /// [`ChunkJobs::ticket`] of the jobs dispatched this frame.
+    job_ticket: u32,
 }
 
 #[repr(C)]
@@ -39,10 +83,23 @@ pub struct ChunkCoord {
 #[repr(C)]
 #[derive(Clone, Copy, Pod, Zeroable, ShaderType, Debug)]
 pub struct Vertex {
+    /// World position, `w` is the face's `BlockId`.
     coord: [f32; 4],
 }
 
-pub struct VoxelComputeGridPlugin;
+pub struct VoxelComputeGridPlugin {
+    pub generator: ActiveTerrainGenerator,
+    pub seed: u32,
+}
+
+impl Default for VoxelComputeGridPlugin {
+    fn default() -> Self {
+        Self {
+            generator: ActiveTerrainGenerator::new(GpuHeightMapGenerator),
+            seed: 0,
+        }
+    }
+}
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
 struct VoxelComputeNode;
@@ -56,66 +113,218 @@ impl Node for VoxelComputeNodeImpl {
         render_context: &mut RenderContext,
         world: &World,
     ) -> Result<(), NodeRunError> {
-        let pipeline_cache = world.resource::<PipelineCache>();
-        let pipeline = world.resource::<VoxelComputeGridPipeline>();
-
-        let Some(height_map_pipeline) =
-            pipeline_cache.get_compute_pipeline(pipeline.height_map_pipeline)
-        else {
-            return Ok(());
-        };
-
-        let Some(vert_pipeline) = pipeline_cache.get_compute_pipeline(pipeline.vert_pipeline)
-        else {
+        let Some(jobs) = world.get_resource::<ChunkJobs>() else {
             return Ok(());
         };
 
+        // Work the pipelines aren't ready for is handed back to the main world.
+        let dropped = world.resource::<DroppedJobs>();
+        let eroding = world.resource::<ErosionRegions>().is_eroding();
         let Some(bind_group) = world.get_resource::<VoxelComputeGridBindGroup>() else {
+            if eroding {
+                dropped.push_erosion(jobs.ticket);
+            }
+            if !jobs.is_empty() {
+                dropped.push_chunks(jobs.ticket);
+            }
             return Ok(());
         };
 
-        let mut pass =
-            render_context
-                .command_encoder()
-                .begin_compute_pass(&ComputePassDescriptor {
-                    label: Some("voxel_compute_pass"),
-                    ..default()
-                });
+        if eroding && dispatch_erosion(render_context, world, bind_group).is_none() {
+            dropped.push_erosion(jobs.ticket);
+        }
 
-        pass.set_pipeline(height_map_pipeline);
+        if !jobs.is_empty()
+            && dispatch_chunk_jobs(render_context, world, bind_group, jobs).is_none()
+        {
+            dropped.push_chunks(jobs.ticket);
+        }
+
+        Ok(())
+    }
+}
+
+/// Erodes the regions marked for erosion this frame, `None` when a pipeline isn't compiled yet.
+fn dispatch_erosion(
+    render_context: &mut RenderContext,
+    world: &World,
+    bind_group: &VoxelComputeGridBindGroup,
+) -> Option<()> {
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let pipeline = world.resource::<VoxelComputeGridPipeline>();
+
+    let erosion_pipelines = pipeline
+        .erosion_pipelines
+        .map(|id| pipeline_cache.get_compute_pipeline(id));
+    let [
+        Some(fill),
+        Some(hydraulic),
+        Some(thermal),
+        Some(thermal_copy),
+        Some(finish),
+    ] = erosion_pipelines
+    else {
+        return None;
+    };
+
+    let settings = world.resource::<ErosionSettings>();
+    let mut pass = render_context
+        .command_encoder()
+        .begin_compute_pass(&ComputePassDescriptor {
+            label: Some("erosion_pass"),
+            ..default()
+        });
+    pass.set_bind_group(0, &bind_group.0, &[]);
+
+    let grid_wg = (REGION_SIZE as u32).div_ceil(8);
+    let slots = REGION_SLOTS as u32;
+    pass.set_pipeline(fill);
+    pass.dispatch_workgroups(grid_wg, grid_wg, slots);
+    pass.set_pipeline(hydraulic);
+    pass.dispatch_workgroups(settings.droplets.div_ceil(64), 1, slots);
+    for _ in 0..settings.thermal_iterations {
+        pass.set_pipeline(thermal);
+        pass.dispatch_workgroups(grid_wg, grid_wg, slots);
+        pass.set_pipeline(thermal_copy);
+        pass.dispatch_workgroups(grid_wg, grid_wg, slots);
+    }
+    pass.set_pipeline(finish);
+    pass.dispatch_workgroups(grid_wg, grid_wg, slots);
+
+    Some(())
+}
+
+/// Generates, decorates and meshes this frame's jobs, `None` when a pipeline isn't compiled yet.
+fn dispatch_chunk_jobs(
+    render_context: &mut RenderContext,
+    world: &World,
+    bind_group: &VoxelComputeGridBindGroup,
+    jobs: &ChunkJobs,
+) -> Option<()> {
+    let pipeline_cache = world.resource::<PipelineCache>();
+    let pipeline = world.resource::<VoxelComputeGridPipeline>();
+    let vert_pipeline = pipeline_cache.get_compute_pipeline(pipeline.vert_pipeline)?;
+    let block_pipeline = pipeline_cache.get_compute_pipeline(pipeline.block_pipeline)?;
+    let apply_writes_pipeline =
+        pipeline_cache.get_compute_pipeline(pipeline.apply_writes_pipeline)?;
+    let apply_water_pipeline =
+        pipeline_cache.get_compute_pipeline(pipeline.apply_water_pipeline)?;
+    let reset_counts_pipeline =
+        pipeline_cache.get_compute_pipeline(pipeline.reset_counts_pipeline)?;
+    let solid_mask_pipeline = pipeline_cache.get_compute_pipeline(pipeline.solid_mask_pipeline)?;
+
+    // The jobs buffer holds the generate jobs first, then the mesh-only ones.
+    let generate_count = jobs.generate.len() as u32;
+    let job_count = generate_count + jobs.mesh.len() as u32;
+
+    let generator = world.resource::<ActiveTerrainGenerator>();
+    let generator_pipeline = match generator.shader_entry_point() {
+        Some(entry_point) => {
+            let id = pipeline.generator_pipelines.get(entry_point)?;
+            Some(pipeline_cache.get_compute_pipeline(*id)?)
+        }
+        None => None,
+    };
+
+    let mut pass = render_context
+        .command_encoder()
+        .begin_compute_pass(&ComputePassDescriptor {
+            label: Some("voxel_compute_pass"),
+            ..default()
+        });
+
+    if let Some(generator_pipeline) = generator_pipeline
+        && generate_count > 0
+    {
+        pass.set_pipeline(generator_pipeline);
         pass.set_bind_group(0, &bind_group.0, &[]);
 
         let h_wg = 4;
         let h_wg_per_axis = CHUNK_SIZE as u32 / h_wg;
-        pass.dispatch_workgroups(
-            h_wg_per_axis,
-            h_wg_per_axis,
-            CHUNK_COUNT as u32 * h_wg_per_axis,
-        );
+        pass.dispatch_workgroups(h_wg_per_axis, h_wg_per_axis, generate_count * h_wg_per_axis);
 
-        pass.set_pipeline(vert_pipeline);
+        pass.set_pipeline(apply_water_pipeline);
+        pass.dispatch_workgroups(h_wg_per_axis, h_wg_per_axis, generate_count * h_wg_per_axis);
+    }
+
+    if !jobs.writes.is_empty() {
+        pass.set_pipeline(apply_writes_pipeline);
         pass.set_bind_group(0, &bind_group.0, &[]);
+        pass.dispatch_workgroups(jobs.writes.len().div_ceil(64) as u32, 1, 1);
+    }
 
-        let v_wg_x = 8;
-        let v_wg_y = 8;
+    pass.set_pipeline(reset_counts_pipeline);
+    pass.set_bind_group(0, &bind_group.0, &[]);
+    pass.dispatch_workgroups(job_count.div_ceil(64), 1, 1);
+
+    match generator.mesher() {
+        ChunkMesher::HeightField => {
+            pass.set_pipeline(vert_pipeline);
+            pass.set_bind_group(0, &bind_group.0, &[]);
+
+            let v_wg_x = 8;
+            let v_wg_y = 8;
+
+            let dispatch_x = (CHUNK_SIZE as u32 - 1).div_ceil(v_wg_x);
+            let dispatch_y = (CHUNK_SIZE as u32 - 1).div_ceil(v_wg_y);
+            let dispatch_z = job_count;
+            pass.dispatch_workgroups(dispatch_x, dispatch_y, dispatch_z);
+        }
+        ChunkMesher::Blocks => {
+            pass.set_pipeline(block_pipeline);
+            pass.set_bind_group(0, &bind_group.0, &[]);
+
+            let b_wg_per_axis = CHUNK_SIZE as u32 / 4;
+            pass.dispatch_workgroups(b_wg_per_axis, b_wg_per_axis, job_count * b_wg_per_axis);
+        }
+    }
 
-        let dispatch_x = (CHUNK_SIZE as u32 - 1 + v_wg_x - 1) / v_wg_x;
-        let dispatch_y = (CHUNK_SIZE as u32 - 1 + v_wg_y - 1) / v_wg_y;
-        let dispatch_z = CHUNK_COUNT as u32;
-        pass.dispatch_workgroups(dispatch_x, dispatch_y, dispatch_z);
+    pass.set_pipeline(solid_mask_pipeline);
+    pass.set_bind_group(0, &bind_group.0, &[]);
+    pass.dispatch_workgroups(1, 1, job_count);
 
-        Ok(())
-    }
+    Some(())
 }
 
 impl Plugin for VoxelComputeGridPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, setup_voxel_compute_grid)
-            .add_plugins(ExtractResourcePlugin::<VisibleChunks>::default())
+        let dropped_jobs = DroppedJobs::default();
+        app.insert_resource(self.generator.clone())
+            .insert_resource(dropped_jobs.clone())
+            .insert_resource(TerrainParams {
+                seed: self.seed,
+                ..default()
+            })
+            .add_systems(Startup, setup_voxel_compute_grid)
+            .init_resource::<ErosionSettings>()
+            .init_resource::<ErosionRegions>()
+            .init_resource::<WaterSettings>()
+            .init_resource::<WaterBodies>()
+            .add_systems(
+                Update,
+                (
+                    sync_generator_globals.run_if(resource_changed::<ActiveTerrainGenerator>),
+                    sync_erosion_globals.run_if(resource_changed::<ErosionSettings>),
+                    sync_terrain_globals.run_if(resource_changed::<TerrainParams>),
+                ),
+            )
+            .add_plugins(ExtractResourcePlugin::<LoadedChunks>::default())
+            .add_plugins(ExtractResourcePlugin::<ChunkJobs>::default())
+            .add_plugins(ExtractResourcePlugin::<ChunkVoxelUploads>::default())
+            .add_plugins(ExtractResourcePlugin::<ActiveTerrainGenerator>::default())
+            .add_plugins(ExtractResourcePlugin::<TerrainParams>::default())
+            .add_plugins(ExtractResourcePlugin::<ErosionSettings>::default())
+            .add_plugins(ExtractResourcePlugin::<ErosionRegions>::default())
+            .add_plugins(ExtractResourcePlugin::<WaterBodies>::default())
             .add_plugins(ExtractResourcePlugin::<VoxelComputeGridImage>::default());
         let render_app = app.sub_app_mut(RenderApp);
         render_app
+            .insert_resource(dropped_jobs)
             .add_systems(RenderStartup, init_voxel_compute_grid_pipeline)
+            .add_systems(
+                Render,
+                queue_generator_pipeline.in_set(RenderSystems::PrepareAssets),
+            )
             .add_systems(Render, prepare_voxel_buffers.in_set(RenderSystems::Prepare))
             .add_systems(
                 Render,
@@ -132,8 +341,20 @@ impl Plugin for VoxelComputeGridPlugin {
 pub struct VoxelComputeGridImage {
     pub globals: Globals,
     pub chunks: Handle<ShaderStorageBuffer>,
+    pub jobs: Handle<ShaderStorageBuffer>,
     pub voxel_buffer: Handle<ShaderStorageBuffer>,
     pub vertecies_output: Handle<ShaderStorageBuffer>,
+    pub solid_masks: Handle<ShaderStorageBuffer>,
+    pub vertex_counts: Handle<ShaderStorageBuffer>,
+    pub chunk_lookup: Handle<ShaderStorageBuffer>,
+    pub blocks: Handle<ShaderStorageBuffer>,
+    pub voxel_writes: Handle<ShaderStorageBuffer>,
+    pub region_table: Handle<ShaderStorageBuffer>,
+    pub region_heights: Handle<ShaderStorageBuffer>,
+    pub region_scratch: Handle<ShaderStorageBuffer>,
+    pub water_bodies: Handle<ShaderStorageBuffer>,
+    pub water_vertices: Handle<ShaderStorageBuffer>,
+    pub water_vertex_counts: Handle<ShaderStorageBuffer>,
 }
 
 #[derive(Resource)]
@@ -143,42 +364,190 @@ struct VoxelComputeGridBindGroup(BindGroup);
 pub struct VoxelComputeGridPipeline {
     bind_group_layout: BindGroupLayout,
     vert_pipeline: CachedComputePipelineId,
-    height_map_pipeline: CachedComputePipelineId,
+    block_pipeline: CachedComputePipelineId,
+    reset_counts_pipeline: CachedComputePipelineId,
+    apply_writes_pipeline: CachedComputePipelineId,
+    apply_water_pipeline: CachedComputePipelineId,
+    shader: Handle<Shader>,
+    /// Kept alive so the `voxel::*` modules stay importable.
+    _shader_modules: Vec<Handle<Shader>>,
+    /// Pipelines of the generator entry points, queued the first time a generator uses them.
+    generator_pipelines: HashMap<&'static str, CachedComputePipelineId>,
+    solid_mask_pipeline: CachedComputePipelineId,
+    /// Fill, hydraulic, thermal, thermal copy and finish passes of the region erosion.
+    erosion_pipelines: [CachedComputePipelineId; 5],
 }
 
 fn setup_voxel_compute_grid(
     mut commands: Commands,
     mut buffers: ResMut<Assets<ShaderStorageBuffer>>,
+    terrain: Res<TerrainParams>,
+    generator: Res<ActiveTerrainGenerator>,
+    erosion: Res<ErosionSettings>,
 ) {
     let chunk_count = CHUNK_COUNT * std::mem::size_of::<ChunkCoord>();
     let mut chunks_ssb = ShaderStorageBuffer::with_size(chunk_count, RenderAssetUsages::all());
     chunks_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_DST;
     let chunks = buffers.add(chunks_ssb);
 
+    let mut jobs_ssb = ShaderStorageBuffer::with_size(chunk_count, RenderAssetUsages::all());
+    jobs_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_DST;
+    let jobs = buffers.add(jobs_ssb);
+
     let total_voxels = CHUNK_COUNT * CHUNK_VOXELS_COUNT;
     let buffer_size_bytes = total_voxels * std::mem::size_of::<f32>();
     let mut voxels_ssb =
         ShaderStorageBuffer::with_size(buffer_size_bytes, RenderAssetUsages::all());
-    voxels_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_DST;
+    // Read back into the `VoxelWorld` once a chunk is meshed.
+    voxels_ssb.buffer_description.usage =
+        BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC;
     let voxels = buffers.add(voxels_ssb);
 
-    let vertecies_count = CHUNK_COUNT * CHUNK_VOXELS_COUNT * std::mem::size_of::<Vertex>();
+    let blocks_size = total_voxels * std::mem::size_of::<BlockId>();
+    let mut blocks_ssb = ShaderStorageBuffer::with_size(blocks_size, RenderAssetUsages::all());
+    blocks_ssb.buffer_description.usage =
+        BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC;
+    let blocks = buffers.add(blocks_ssb);
+
+    let mut voxel_writes_ssb = ShaderStorageBuffer::with_size(
+        MAX_VOXEL_WRITES * std::mem::size_of::<VoxelWrite>(),
+        RenderAssetUsages::all(),
+    );
+    voxel_writes_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_DST;
+    let voxel_writes = buffers.add(voxel_writes_ssb);
+
+    let vertecies_count = CHUNK_COUNT * CHUNK_VERTICES_COUNT * std::mem::size_of::<Vertex>();
     let mut vertecies_output_ssb =
         ShaderStorageBuffer::with_size(vertecies_count, RenderAssetUsages::all());
     vertecies_output_ssb.buffer_description.usage =
         BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::VERTEX;
     let vertecies_output = buffers.add(vertecies_output_ssb);
 
+    let solid_masks_size = CHUNK_COUNT * std::mem::size_of::<ChunkSolidMask>();
+    let mut solid_masks_ssb =
+        ShaderStorageBuffer::with_size(solid_masks_size, RenderAssetUsages::all());
+    solid_masks_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_SRC;
+    let solid_masks = buffers.add(solid_masks_ssb);
+
+    let mut vertex_counts_ssb = ShaderStorageBuffer::with_size(
+        CHUNK_COUNT * std::mem::size_of::<u32>(),
+        RenderAssetUsages::all(),
+    );
+    vertex_counts_ssb.buffer_description.usage = BufferUsages::STORAGE;
+    let vertex_counts = buffers.add(vertex_counts_ssb);
+
+    let mut chunk_lookup_ssb = ShaderStorageBuffer::with_size(
+        CHUNK_LOOKUP_SIZE * std::mem::size_of::<ChunkCoord>(),
+        RenderAssetUsages::all(),
+    );
+    chunk_lookup_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_DST;
+    let chunk_lookup = buffers.add(chunk_lookup_ssb);
+
+    let mut region_table_ssb = ShaderStorageBuffer::with_size(
+        REGION_SLOTS * std::mem::size_of::<RegionEntry>(),
+        RenderAssetUsages::all(),
+    );
+    region_table_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_DST;
+    let region_table = buffers.add(region_table_ssb);
+
+    let mut region_heights_ssb = ShaderStorageBuffer::with_size(
+        REGION_SLOTS * REGION_STRIDE * std::mem::size_of::<f32>(),
+        RenderAssetUsages::all(),
+    );
+    region_heights_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_SRC;
+    let region_heights = buffers.add(region_heights_ssb);
+
+    let mut region_scratch_ssb = ShaderStorageBuffer::with_size(
+        REGION_SLOTS * REGION_SIZE * REGION_SIZE * std::mem::size_of::<f32>(),
+        RenderAssetUsages::all(),
+    );
+    region_scratch_ssb.buffer_description.usage = BufferUsages::STORAGE;
+    let region_scratch = buffers.add(region_scratch_ssb);
+
+    let mut water_bodies_ssb =
+        ShaderStorageBuffer::with_size(WATER_BODIES_SIZE, RenderAssetUsages::all());
+    water_bodies_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::COPY_DST;
+    let water_bodies = buffers.add(water_bodies_ssb);
+
+    let water_vertices_size =
+        CHUNK_COUNT * CHUNK_WATER_VERTICES_COUNT * std::mem::size_of::<Vertex>();
+    let mut water_vertices_ssb =
+        ShaderStorageBuffer::with_size(water_vertices_size, RenderAssetUsages::all());
+    water_vertices_ssb.buffer_description.usage = BufferUsages::STORAGE | BufferUsages::VERTEX;
+    let water_vertices = buffers.add(water_vertices_ssb);
+
+    let mut water_vertex_counts_ssb = ShaderStorageBuffer::with_size(
+        CHUNK_COUNT * std::mem::size_of::<u32>(),
+        RenderAssetUsages::all(),
+    );
+    water_vertex_counts_ssb.buffer_description.usage = BufferUsages::STORAGE;
+    let water_vertex_counts = buffers.add(water_vertex_counts_ssb);
+
+    commands
+        .spawn(Readback::buffer(region_heights.clone()))
+        .observe(read_eroded_regions);
+
+    commands
+        .spawn(Readback::buffer(solid_masks.clone()))
+        .observe(read_solid_masks)
+        .observe(mark_meshed_chunks_ready);
+
     commands.insert_resource(VoxelComputeGridImage {
         globals: Globals {
             chunk_size: CHUNK_SIZE as u32,
+            seed: terrain.seed,
+            vertices_per_chunk: CHUNK_VERTICES_COUNT as u32,
+            mesher: generator.mesher() as u32,
+            density_threshold: generator.density_threshold(),
+            erosion: erosion.enabled as u32,
+            water_vertices_per_chunk: CHUNK_WATER_VERTICES_COUNT as u32,
+            job_ticket: 0,
         },
         chunks,
+        jobs,
         voxel_buffer: voxels,
         vertecies_output,
+        solid_masks,
+        vertex_counts,
+        chunk_lookup,
+        blocks,
+        voxel_writes,
+        region_table,
+        region_heights,
+        region_scratch,
+        water_bodies,
+        water_vertices,
+        water_vertex_counts,
     });
 }
 
+/// Keeps the mesher and threshold in [`Globals`] in sync when the generator is swapped.
+fn sync_generator_globals(
+    generator: Res<ActiveTerrainGenerator>,
+    image: Option<ResMut<VoxelComputeGridImage>>,
+) {
+    if let Some(mut image) = image {
+        image.globals.mesher = generator.mesher() as u32;
+        image.globals.density_threshold = generator.density_threshold();
+    }
+}
+
+fn sync_erosion_globals(
+    erosion: Res<ErosionSettings>,
+    image: Option<ResMut<VoxelComputeGridImage>>,
+) {
+    if let Some(mut image) = image {
+        image.globals.erosion = erosion.enabled as u32;
+    }
+}
+
+fn sync_terrain_globals(terrain: Res<TerrainParams>, image: Option<ResMut<VoxelComputeGridImage>>) {
+    if let Some(mut image) = image {
+        image.globals.seed = terrain.seed;
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn prepare_bind_group(
     mut commands: Commands,
     pipeline: Res<VoxelComputeGridPipeline>,
@@ -186,8 +555,11 @@ fn prepare_bind_group(
     render_device: Res<RenderDevice>,
     buffers: Res<RenderAssets<GpuShaderStorageBuffer>>,
     queue: Res<RenderQueue>,
+    erosion: Res<ErosionSettings>,
+    terrain: Res<TerrainParams>,
+    jobs: Res<ChunkJobs>,
 ) {
-    let Some(chunks_gpu) = buffers.get(&image.chunks) else {
+    let Some(jobs_gpu) = buffers.get(&image.jobs) else {
         return;
     };
 
@@ -199,17 +571,83 @@ fn prepare_bind_group(
         return;
     };
 
-    let mut s = UniformBuffer::from(image.globals);
+    let Some(solid_masks_gpu) = buffers.get(&image.solid_masks) else {
+        return;
+    };
+
+    let Some(vertex_counts_gpu) = buffers.get(&image.vertex_counts) else {
+        return;
+    };
+
+    let Some(chunk_lookup_gpu) = buffers.get(&image.chunk_lookup) else {
+        return;
+    };
+
+    let Some(blocks_gpu) = buffers.get(&image.blocks) else {
+        return;
+    };
+
+    let Some(voxel_writes_gpu) = buffers.get(&image.voxel_writes) else {
+        return;
+    };
+
+    let Some(region_table_gpu) = buffers.get(&image.region_table) else {
+        return;
+    };
+
+    let Some(region_heights_gpu) = buffers.get(&image.region_heights) else {
+        return;
+    };
+
+    let Some(region_scratch_gpu) = buffers.get(&image.region_scratch) else {
+        return;
+    };
+
+    let Some(water_bodies_gpu) = buffers.get(&image.water_bodies) else {
+        return;
+    };
+
+    let Some(water_vertices_gpu) = buffers.get(&image.water_vertices) else {
+        return;
+    };
+
+    let Some(water_vertex_counts_gpu) = buffers.get(&image.water_vertex_counts) else {
+        return;
+    };
+
+    let mut s = UniformBuffer::from(Globals {
+        job_ticket: jobs.ticket,
+        ..image.globals
+    });
     s.write_buffer(&render_device, &queue);
 
+    let mut erosion = UniformBuffer::from(ErosionParams::from(&*erosion));
+    erosion.write_buffer(&render_device, &queue);
+
+    let mut terrain = UniformBuffer::from(TerrainUniform::from(&*terrain));
+    terrain.write_buffer(&render_device, &queue);
+
     let bind_group = render_device.create_bind_group(
         Some("voxel_compute_bind_group"),
         &pipeline.bind_group_layout,
         &BindGroupEntries::sequential((
             &s,
-            chunks_gpu.buffer.as_entire_buffer_binding(),
+            jobs_gpu.buffer.as_entire_buffer_binding(),
             voxels_gpu.buffer.as_entire_buffer_binding(),
             vertecies_gpu.buffer.as_entire_buffer_binding(),
+            solid_masks_gpu.buffer.as_entire_buffer_binding(),
+            vertex_counts_gpu.buffer.as_entire_buffer_binding(),
+            chunk_lookup_gpu.buffer.as_entire_buffer_binding(),
+            blocks_gpu.buffer.as_entire_buffer_binding(),
+            voxel_writes_gpu.buffer.as_entire_buffer_binding(),
+            region_table_gpu.buffer.as_entire_buffer_binding(),
+            region_heights_gpu.buffer.as_entire_buffer_binding(),
+            region_scratch_gpu.buffer.as_entire_buffer_binding(),
+            &erosion,
+            water_bodies_gpu.buffer.as_entire_buffer_binding(),
+            water_vertices_gpu.buffer.as_entire_buffer_binding(),
+            water_vertex_counts_gpu.buffer.as_entire_buffer_binding(),
+            &terrain,
         )),
     );
 
@@ -231,48 +669,245 @@ fn init_voxel_compute_grid_pipeline(
                 storage_buffer_read_only::<ChunkCoord>(false),
                 storage_buffer::<f32>(false),
                 storage_buffer::<Vertex>(false),
+                storage_buffer_sized(false, None),
+                storage_buffer::<u32>(false),
+                storage_buffer_read_only::<ChunkCoord>(false),
+                storage_buffer::<u32>(false),
+                storage_buffer_read_only_sized(false, None),
+                storage_buffer_read_only_sized(false, None),
+                storage_buffer::<f32>(false),
+                storage_buffer::<f32>(false),
+                uniform_buffer::<ErosionParams>(false),
+                storage_buffer_read_only_sized(false, None),
+                storage_buffer::<Vertex>(false),
+                storage_buffer::<u32>(false),
+                uniform_buffer::<TerrainUniform>(false),
             ),
         ),
     );
 
     let shader: Handle<Shader> = asset_server.load(SHADER_ASSET_PATH);
+    let shader_modules: Vec<Handle<Shader>> = SHADER_MODULE_PATHS
+        .iter()
+        .map(|path| asset_server.load(*path))
+        .collect();
     let vert_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
         layout: vec![bind_group_layout.clone()],
         shader: shader.clone(),
         entry_point: Some(Cow::from("generate_vertecies")),
         ..default()
     });
-    let heeight_map_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+    let block_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
         layout: vec![bind_group_layout.clone()],
-        shader,
-        entry_point: Some(Cow::from("generate_height_map")),
+        shader: shader.clone(),
+        entry_point: Some(Cow::from("generate_block_vertices")),
+        ..default()
+    });
+    let apply_writes_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        layout: vec![bind_group_layout.clone()],
+        shader: shader.clone(),
+        entry_point: Some(Cow::from("apply_voxel_writes")),
         ..default()
     });
+    let apply_water_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        layout: vec![bind_group_layout.clone()],
+        shader: shader.clone(),
+        entry_point: Some(Cow::from("apply_water")),
+        ..default()
+    });
+    let reset_counts_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        layout: vec![bind_group_layout.clone()],
+        shader: shader.clone(),
+        entry_point: Some(Cow::from("reset_vertex_counts")),
+        ..default()
+    });
+    let solid_mask_pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        layout: vec![bind_group_layout.clone()],
+        shader: shader.clone(),
+        entry_point: Some(Cow::from("generate_solid_mask")),
+        ..default()
+    });
+    let erosion_pipelines = [
+        "erosion_fill",
+        "erosion_hydraulic",
+        "erosion_thermal",
+        "erosion_thermal_copy",
+        "erosion_finish",
+    ]
+    .map(|entry_point| {
+        pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+            layout: vec![bind_group_layout.clone()],
+            shader: shader.clone(),
+            entry_point: Some(Cow::from(entry_point)),
+            ..default()
+        })
+    });
 
     commands.insert_resource(VoxelComputeGridPipeline {
         bind_group_layout,
         vert_pipeline,
-        height_map_pipeline: heeight_map_pipeline,
+        block_pipeline,
+        reset_counts_pipeline,
+        apply_writes_pipeline,
+        apply_water_pipeline,
+        solid_mask_pipeline,
+        shader,
+        _shader_modules: shader_modules,
+        generator_pipelines: HashMap::new(),
+        erosion_pipelines,
+    });
+}
+
+fn queue_generator_pipeline(
+    generator: Res<ActiveTerrainGenerator>,
+    mut pipeline: ResMut<VoxelComputeGridPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+) {
+    let Some(entry_point) = generator.shader_entry_point() else {
+        return;
+    };
+
+    if pipeline.generator_pipelines.contains_key(entry_point) {
+        return;
+    }
+
+    let id = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        layout: vec![pipeline.bind_group_layout.clone()],
+        shader: pipeline.shader.clone(),
+        entry_point: Some(Cow::from(entry_point)),
+        ..default()
     });
+    pipeline.generator_pipelines.insert(entry_point, id);
 }
 
+#[allow(clippy::too_many_arguments)]
 fn prepare_voxel_buffers(
-    visible: Res<VisibleChunks>,
+    loaded: Res<LoadedChunks>,
+    jobs: Res<ChunkJobs>,
+    regions: Res<ErosionRegions>,
+    water: Res<WaterBodies>,
+    mut uploaded_water: Local<Option<Arc<WaterData>>>,
+    uploads: Res<ChunkVoxelUploads>,
     image: Res<VoxelComputeGridImage>,
-    mut gpu_buffers: ResMut<RenderAssets<GpuShaderStorageBuffer>>,
+    gpu_buffers: Res<RenderAssets<GpuShaderStorageBuffer>>,
     render_queue: Res<RenderQueue>,
 ) {
-    let Some(chunks_gpu) = gpu_buffers.get_mut(&image.chunks) else {
+    let Some(chunks_gpu) = gpu_buffers.get(&image.chunks) else {
+        return;
+    };
+
+    let Some(jobs_gpu) = gpu_buffers.get(&image.jobs) else {
+        return;
+    };
+
+    render_queue.write_buffer(
+        &chunks_gpu.buffer,
+        0,
+        bytemuck::cast_slice(&chunk_coords(&loaded.chunks)),
+    );
+
+    if let Some(water_bodies_gpu) = gpu_buffers.get(&image.water_bodies)
+        && !uploaded_water
+            .as_ref()
+            .is_some_and(|uploaded| Arc::ptr_eq(uploaded, &water.data))
+    {
+        render_queue.write_buffer(&water_bodies_gpu.buffer, 0, &water.data.gpu_bytes());
+        *uploaded_water = Some(water.data.clone());
+    }
+
+    if let Some(region_table_gpu) = gpu_buffers.get(&image.region_table) {
+        render_queue.write_buffer(
+            &region_table_gpu.buffer,
+            0,
+            bytemuck::cast_slice(&regions.table()),
+        );
+    }
+
+    if !jobs.is_empty() {
+        let mut job_coords = chunk_coords(&jobs.generate);
+        job_coords.extend(chunk_coords(&jobs.mesh));
+        render_queue.write_buffer(&jobs_gpu.buffer, 0, bytemuck::cast_slice(&job_coords));
+
+        if let Some(writes_gpu) = gpu_buffers.get(&image.voxel_writes)
+            && !jobs.writes.is_empty()
+        {
+            // Pad to whole workgroups, `apply_voxel_writes` skips writes without a slot.
+            let mut writes = jobs.writes.clone();
+            let skip = VoxelWrite {
+                slot: u32::MAX,
+                ..writes[0]
+            };
+            writes.resize(
+                writes.len().next_multiple_of(64).min(MAX_VOXEL_WRITES),
+                skip,
+            );
+            render_queue.write_buffer(&writes_gpu.buffer, 0, bytemuck::cast_slice(&writes));
+        }
+
+        if let Some(lookup_gpu) = gpu_buffers.get(&image.chunk_lookup) {
+            let with_voxels = loaded
+                .chunks
+                .iter()
+                .chain(&jobs.generate)
+                .chain(&jobs.mesh)
+                .filter(|(_, slot)| *slot != u32::MAX);
+            render_queue.write_buffer(
+                &lookup_gpu.buffer,
+                0,
+                bytemuck::cast_slice(&chunk_lookup(with_voxels)),
+            );
+        }
+    }
+
+    let Some(voxels_gpu) = gpu_buffers.get(&image.voxel_buffer) else {
         return;
     };
 
-    let mut chunk_data = Vec::with_capacity(visible.chunks.len());
-    for c in &visible.chunks {
-        chunk_data.push(ChunkCoord {
+    let Some(blocks_gpu) = gpu_buffers.get(&image.blocks) else {
+        return;
+    };
+
+    for (slot, voxels) in &uploads.chunks {
+        let offset = *slot as usize * CHUNK_VOXELS_COUNT * std::mem::size_of::<f32>();
+        render_queue.write_buffer(
+            &voxels_gpu.buffer,
+            offset as u64,
+            bytemuck::cast_slice(&voxels.density),
+        );
+        render_queue.write_buffer(
+            &blocks_gpu.buffer,
+            offset as u64,
+            bytemuck::cast_slice(&voxels.blocks),
+        );
+    }
+}
+
+fn chunk_coords(chunks: &[(IVec3, u32)]) -> Vec<ChunkCoord> {
+    chunks
+        .iter()
+        .map(|c| ChunkCoord {
             coord: c.0.to_array(),
             global_index: c.1,
-        });
-    }
+        })
+        .collect()
+}
 
-    render_queue.write_buffer(&chunks_gpu.buffer, 0, bytemuck::cast_slice(&chunk_data));
+/// Open addressing table from coordinate to slot, probed by `lookup_slot` in `voxel_gen.wgsl`.
+fn chunk_lookup<'a>(chunks: impl Iterator<Item = &'a (IVec3, u32)>) -> Vec<ChunkCoord> {
+    let empty = ChunkCoord {
+        coord: [0; 3],
+        global_index: u32::MAX,
+    };
+    let mut table = vec![empty; CHUNK_LOOKUP_SIZE];
+    for &(coord, slot) in chunks {
+        let mut index = hash3(coord, 0) as usize & (CHUNK_LOOKUP_SIZE - 1);
+        while table[index].global_index != u32::MAX && table[index].coord != coord.to_array() {
+            index = (index + 1) & (CHUNK_LOOKUP_SIZE - 1);
+        }
+        table[index] = ChunkCoord {
+            coord: coord.to_array(),
+            global_index: slot,
+        };
+    }
+    table
 }
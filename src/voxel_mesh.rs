@@ -1,8 +1,10 @@
 use bevy::asset::RenderAssetUsages;
-use bevy::math::Vec3A;
+use bevy::math::{IVec3, Vec3};
 use bevy::mesh::{Indices, Mesh, VertexAttributeValues};
 use bevy::render::render_resource::PrimitiveTopology;
 
+use crate::selection::VoxelRegion;
+
 const CUBE_VERTS: [[f32; 3]; 24] = [
     // Front (+Z)
     [-0.5, -0.5, 0.5], // 0
@@ -66,3 +68,66 @@ pub fn make_test_mesh() -> Mesh {
 
     mesh
 }
+
+/// Neighbour each face of [`CUBE_VERTS`] looks at, in order.
+const FACE_NORMALS: [IVec3; 6] = [
+    IVec3::Z,
+    IVec3::NEG_Z,
+    IVec3::NEG_X,
+    IVec3::X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+];
+
+/// Light of each face baked into the vertex colours of [`region_mesh`], which is drawn
+/// unlit.
+const FACE_SHADE: [f32; 6] = [0.8, 0.7, 0.75, 0.85, 1.0, 0.55];
+
+/// Cubes of the solid voxels of a region with the faces between two of them culled,
+/// coloured by block and centred on `origin`.
+pub fn region_mesh(region: &VoxelRegion, origin: IVec3) -> Mesh {
+    let solid = |pos: IVec3| {
+        pos.cmpge(IVec3::ZERO).all()
+            && pos.cmplt(region.size).all()
+            && region.blocks[region.index(pos)].is_solid()
+    };
+
+    let mut positions = Vec::new();
+    let mut normals = Vec::new();
+    let mut colors = Vec::new();
+    let mut indices = Vec::new();
+    for z in 0..region.size.z {
+        for y in 0..region.size.y {
+            for x in 0..region.size.x {
+                let pos = IVec3::new(x, y, z);
+                if !solid(pos) {
+                    continue;
+                }
+                let color = region.blocks[region.index(pos)].color();
+                let center = (pos - origin).as_vec3() + 0.5;
+                for (face, normal) in FACE_NORMALS.into_iter().enumerate() {
+                    if solid(pos + normal) {
+                        continue;
+                    }
+                    let first = positions.len() as u32;
+                    for vert in &CUBE_VERTS[face * 4..face * 4 + 4] {
+                        positions.push((Vec3::from_array(*vert) + center).to_array());
+                        normals.push(normal.as_vec3().to_array());
+                        colors.push((color * FACE_SHADE[face]).extend(1.0).to_array());
+                    }
+                    indices.extend(CUBE_INDICES[..6].iter().map(|i| first + i));
+                }
+            }
+        }
+    }
+
+    let mut mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::RENDER_WORLD,
+    );
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh.insert_indices(Indices::U32(indices));
+    mesh
+}
@@ -1,39 +1,78 @@
-use std::{borrow::Cow, sync::Arc};
+use std::borrow::Cow;
 
 use bevy::{
-    asset::RenderAssetUsages,
+    core_pipeline::core_3d::{
+        CORE_3D_DEPTH_FORMAT,
+        graph::{Core3d, Node3d},
+    },
     mesh::VertexBufferLayout,
     prelude::*,
     render::{
         Render, RenderApp, RenderStartup, RenderSystems,
-        extract_resource::{ExtractResource, ExtractResourcePlugin},
-        graph::CameraDriverLabel,
+        extract_component::ExtractComponentPlugin,
         render_asset::RenderAssets,
-        render_graph::{Node, NodeRunError, RenderGraph, RenderGraphContext, RenderLabel},
-        render_resource::{
-            UniformBuffer,
-            binding_types::{storage_buffer, storage_buffer_read_only, uniform_buffer},
-            *,
+        render_graph::{
+            NodeRunError, RenderGraphContext, RenderGraphExt, RenderLabel, ViewNode, ViewNodeRunner,
+        },
+        render_resource::{binding_types::uniform_buffer, *},
+        renderer::{RenderContext, RenderDevice},
+        storage::GpuShaderStorageBuffer,
+        view::{
+            ExtractedView, ViewDepthTexture, ViewTarget, ViewUniform, ViewUniformOffset,
+            ViewUniforms,
         },
-        renderer::{RenderContext, RenderDevice, RenderQueue},
-        storage::{GpuShaderStorageBuffer, ShaderStorageBuffer},
-        view::ViewTarget,
     },
 };
 
 use crate::{
-    chunks_partition::{CHUNK_COUNT, CHUNK_VOXELS_COUNT},
+    chunks_partition::{CHUNK_COUNT, CHUNK_SIZE, LoadedChunks, VisibleChunks},
+    hi_z::{HiZCullPass, HiZPlugin, ViewHiZ},
     voxel_compute_grid::{Vertex, VoxelComputeGridImage},
 };
 
-const SHADER_ASSET_PATH: &str = "shaders/voxel.wgsl";
+const SHADER_ASSET_PATH: &str = "shaders/voxel_chunks.wgsl";
+
+pub struct VoxelRenderPlugin;
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+struct VoxelDrawPass;
 
 #[derive(Resource)]
 pub struct VoxelRenderPipeline {
     pub pipeline_id: CachedRenderPipelineId,
+    /// Blended over the opaque chunks without writing depth.
+    pub water_pipeline_id: CachedRenderPipelineId,
     pub layout: BindGroupLayout,
 }
 
+#[derive(Component)]
+struct VoxelViewBindGroup(BindGroup);
+
+impl Plugin for VoxelRenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(HiZPlugin)
+            .add_plugins(ExtractComponentPlugin::<VisibleChunks>::default());
+
+        let render_app = app.sub_app_mut(RenderApp);
+        render_app
+            .add_systems(RenderStartup, init_voxel_render_pipeline)
+            .add_systems(
+                Render,
+                prepare_voxel_view_bind_groups.in_set(RenderSystems::PrepareBindGroups),
+            )
+            .add_render_graph_node::<ViewNodeRunner<VoxelDrawNode>>(Core3d, VoxelDrawPass)
+            .add_render_graph_edges(
+                Core3d,
+                (
+                    Node3d::MainOpaquePass,
+                    HiZCullPass,
+                    VoxelDrawPass,
+                    Node3d::MainTransmissivePass,
+                ),
+            );
+    }
+}
+
 fn init_voxel_render_pipeline(
     mut commands: Commands,
     pipeline_cache: Res<PipelineCache>,
@@ -42,6 +81,14 @@ fn init_voxel_render_pipeline(
 ) {
     let shader = asset_server.load(SHADER_ASSET_PATH);
 
+    let layout = render_device.create_bind_group_layout(
+        Some("voxel_view_layout"),
+        &BindGroupLayoutEntries::single(
+            ShaderStages::VERTEX_FRAGMENT,
+            uniform_buffer::<ViewUniform>(true),
+        ),
+    );
+
     let vertex_layout = VertexBufferLayout {
         array_stride: std::mem::size_of::<Vertex>() as u64,
         step_mode: VertexStepMode::Vertex,
@@ -52,9 +99,9 @@ fn init_voxel_render_pipeline(
         }],
     };
 
-    let pipeline_id = pipeline_cache.queue_render_pipeline(RenderPipelineDescriptor {
+    let descriptor = RenderPipelineDescriptor {
         label: Some("voxel_render_pipeline".into()),
-        layout: vec![], // no bind groups yet
+        layout: vec![layout.clone()],
         vertex: VertexState {
             shader: shader.clone(),
             entry_point: Some(Cow::from("vs_main")),
@@ -75,26 +122,90 @@ fn init_voxel_render_pipeline(
             topology: PrimitiveTopology::TriangleList,
             ..default()
         },
-        depth_stencil: None,
+        depth_stencil: Some(DepthStencilState {
+            format: CORE_3D_DEPTH_FORMAT,
+            depth_write_enabled: true,
+            // Reverse-Z
+            depth_compare: CompareFunction::GreaterEqual,
+            stencil: StencilState::default(),
+            bias: DepthBiasState::default(),
+        }),
         multisample: MultisampleState::default(),
         push_constant_ranges: vec![],
         ..Default::default()
-    });
+    };
+
+    let mut water_descriptor = descriptor.clone();
+    water_descriptor.label = Some("voxel_water_pipeline".into());
+    if let Some(fragment) = &mut water_descriptor.fragment {
+        fragment.entry_point = Some(Cow::from("fs_water"));
+        fragment.targets[0].as_mut().unwrap().blend = Some(BlendState::ALPHA_BLENDING);
+    }
+    if let Some(depth_stencil) = &mut water_descriptor.depth_stencil {
+        depth_stencil.depth_write_enabled = false;
+    }
+
+    let pipeline_id = pipeline_cache.queue_render_pipeline(descriptor);
+    let water_pipeline_id = pipeline_cache.queue_render_pipeline(water_descriptor);
 
     commands.insert_resource(VoxelRenderPipeline {
         pipeline_id,
-        layout: render_device.create_bind_group_layout(None, &[]),
+        water_pipeline_id,
+        layout,
     });
 }
 
+fn prepare_voxel_view_bind_groups(
+    mut commands: Commands,
+    pipeline: Res<VoxelRenderPipeline>,
+    render_device: Res<RenderDevice>,
+    view_uniforms: Res<ViewUniforms>,
+    views: Query<Entity, With<ViewTarget>>,
+) {
+    let Some(view_binding) = view_uniforms.uniforms.binding() else {
+        return;
+    };
+
+    for entity in &views {
+        let bind_group = render_device.create_bind_group(
+            Some("voxel_view_bind_group"),
+            &pipeline.layout,
+            &BindGroupEntries::single(view_binding.clone()),
+        );
+        commands
+            .entity(entity)
+            .insert(VoxelViewBindGroup(bind_group));
+    }
+}
+
+#[derive(Default)]
 struct VoxelDrawNode;
 
-impl Node for VoxelDrawNode {
-    fn run(
+impl ViewNode for VoxelDrawNode {
+    type ViewQuery = (
+        &'static ViewTarget,
+        &'static ViewDepthTexture,
+        &'static ViewUniformOffset,
+        &'static VoxelViewBindGroup,
+        &'static ViewHiZ,
+        &'static ExtractedView,
+        Option<&'static VisibleChunks>,
+    );
+
+    fn run<'w>(
         &self,
         _graph: &mut RenderGraphContext,
-        render_context: &mut RenderContext,
-        world: &World,
+        render_context: &mut RenderContext<'w>,
+        (view_target, depth, view_uniform_offset, view_bind_group, hi_z, view, visible): (
+            &'w ViewTarget,
+            &'w ViewDepthTexture,
+            &'w ViewUniformOffset,
+            &'w VoxelViewBindGroup,
+            &'w ViewHiZ,
+            &'w ExtractedView,
+            Option<&'w VisibleChunks>,
+        ),
+        world: &'w World,
     ) -> Result<(), NodeRunError> {
         let pipeline_cache = world.resource::<PipelineCache>();
         let pipeline = world.resource::<VoxelRenderPipeline>();
@@ -105,38 +216,54 @@ impl Node for VoxelDrawNode {
             return Ok(());
         };
 
+        let Some(gpu_water_buffer) = buffers.get(&image.water_vertices) else {
+            return Ok(());
+        };
+
         let Some(render_pipeline) = pipeline_cache.get_render_pipeline(pipeline.pipeline_id) else {
             return Ok(());
         };
 
-        for entity in world.iter_resources() {
-            if let Some(view_target) = Arc::new(entity)::<ViewTarget>() {}
-        }
+        let Some(water_pipeline) = pipeline_cache.get_render_pipeline(pipeline.water_pipeline_id)
+        else {
+            return Ok(());
+        };
 
-        for view_target in world.query::<&ViewTarget>().iter(world) {
-            let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
-                label: Some("voxel_draw_pass"),
-                color_attachments: &[Some(RenderPassColorAttachment {
-                    view: view_target.main_texture_view(),
-                    resolve_target: None,
-                    ops: Operations {
-                        load: LoadOp::Load,
-                        store: StoreOp::Store,
-                    },
-                    depth_slice: None,
-                })],
-                depth_stencil_attachment: None,
-                ..Default::default()
-            });
-
-            pass.set_render_pipeline(render_pipeline);
-            pass.set_vertex_buffer(0, gpu_vertex_buffer.buffer.slice(..));
-
-            // IMPORTANT: vertex count must match what compute wrote
-            let vertex_count = (CHUNK_COUNT * CHUNK_VOXELS_COUNT * 3) as u32;
-            pass.draw(0..vertex_count, 0..1);
+        let mut pass = render_context.begin_tracked_render_pass(RenderPassDescriptor {
+            label: Some("voxel_draw_pass"),
+            color_attachments: &[Some(view_target.get_color_attachment())],
+            depth_stencil_attachment: Some(depth.get_attachment(StoreOp::Store)),
+            ..Default::default()
+        });
+
+        pass.set_render_pipeline(render_pipeline);
+        pass.set_bind_group(0, &view_bind_group.0, &[view_uniform_offset.offset]);
+        pass.set_vertex_buffer(0, gpu_vertex_buffer.buffer.slice(..));
 
+        // One draw per chunk slot; the Hi-Z cull pass zeroes the instance count of hidden chunks.
+        pass.multi_draw_indirect(&hi_z.draw_args, 0, CHUNK_COUNT as u32);
+
+        // Water after every opaque chunk, blended back to front one chunk at a time.
+        pass.set_render_pipeline(water_pipeline);
+        pass.set_vertex_buffer(0, gpu_water_buffer.buffer.slice(..));
+        let Some(visible) = visible else {
             return Ok(());
+        };
+        let loaded = world.resource::<LoadedChunks>();
+        let eye = view.world_from_view.translation();
+        let mut slots: Vec<(f32, u32)> = visible
+            .slots
+            .iter()
+            .filter_map(|slot| {
+                let (coord, loaded_slot) = *loaded.chunks.get(*slot as usize)?;
+                let center = (coord.as_vec3() + 0.5) * CHUNK_SIZE;
+                (loaded_slot == *slot).then(|| (center.distance_squared(eye), *slot))
+            })
+            .collect();
+        slots.sort_by(|a, b| b.0.total_cmp(&a.0));
+        for (_, slot) in slots {
+            let offset = slot as u64 * std::mem::size_of::<DrawIndirectArgs>() as u64;
+            pass.draw_indirect(&hi_z.water_draw_args, offset);
         }
 
         Ok(())
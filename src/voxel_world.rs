@@ -0,0 +1,628 @@
+//! CPU copy of the loaded chunks' voxels for gameplay queries. CPU generated chunks are
+//! stored as soon as their task finishes, GPU generated ones are read back from their
+//! slot once they are meshed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use bevy::{
+    prelude::*,
+    render::gpu_readback::{Readback, ReadbackComplete},
+};
+
+use crate::{
+    block::BlockId,
+    chunk::{ChunkPos, ChunkSlot, ChunkState, ChunkUnloaded},
+    chunk_queue::RemeshChunk,
+    chunks_partition::{CHUNK_SIZE, CHUNK_VOXELS_COUNT, ChunkSlots},
+    terrain_generator::{
+        ActiveTerrainGenerator, ChunkMesher, ChunkVoxelUploads, ChunkVoxels, poll_chunk_gen_tasks,
+    },
+    voxel_compute_grid::VoxelComputeGridImage,
+};
+
+/// Voxel a ray hit.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RaycastHit {
+    pub voxel: IVec3,
+    /// Normal of the face the ray entered through, zero when it started inside the voxel.
+    pub normal: IVec3,
+    pub distance: f32,
+    pub block: BlockId,
+}
+
+/// Block and density of a voxel before and after an edit.
+#[derive(Clone, Copy, Debug)]
+pub struct VoxelChange {
+    pub voxel: IVec3,
+    pub before: (BlockId, f32),
+    pub after: (BlockId, f32),
+}
+
+/// Density and blocks of a GPU chunk, each half filled in as its readback completes.
+type PendingReadback = (Option<Vec<f32>>, Option<Vec<BlockId>>);
+
+/// Voxels of the loaded chunks, by chunk coordinate.
+#[derive(Resource)]
+pub struct VoxelWorld {
+    chunks: HashMap<IVec3, Arc<ChunkVoxels>>,
+    mesher: ChunkMesher,
+    threshold: f32,
+    /// Halves of GPU chunks being read back, by chunk entity.
+    pending: HashMap<Entity, PendingReadback>,
+    /// Chunks edited since the last flush, uploaded and remeshed.
+    edited: HashSet<IVec3>,
+    /// Neighbours of edits on a chunk border, only remeshed.
+    remesh: HashSet<IVec3>,
+    changes: Vec<VoxelChange>,
+}
+
+impl Default for VoxelWorld {
+    fn default() -> Self {
+        Self::new(ChunkMesher::Blocks, 0.0)
+    }
+}
+
+/// Distance of an edited voxel's density from the threshold.
+const EDIT_DENSITY: f32 = 1.0;
+
+/// Index of a voxel in its chunk's [`ChunkVoxels`].
+pub fn voxel_index(local: IVec3) -> usize {
+    let n = CHUNK_SIZE as i32;
+    (local.z * n * n + local.y * n + local.x) as usize
+}
+
+/// Chunk of a world voxel and the voxel's position inside it.
+pub fn voxel_chunk(voxel: IVec3) -> (IVec3, IVec3) {
+    let n = CHUNK_SIZE as i32;
+    (
+        voxel.div_euclid(IVec3::splat(n)),
+        voxel.rem_euclid(IVec3::splat(n)),
+    )
+}
+
+impl VoxelWorld {
+    /// Empty world whose chunks are meshed with `mesher`, solid at and above `threshold`.
+    pub fn new(mesher: ChunkMesher, threshold: f32) -> Self {
+        Self {
+            chunks: HashMap::new(),
+            mesher,
+            threshold,
+            pending: HashMap::new(),
+            edited: HashSet::new(),
+            remesh: HashSet::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, chunk: IVec3, voxels: Arc<ChunkVoxels>) {
+        self.chunks.insert(chunk, voxels);
+    }
+
+    pub fn remove(&mut self, chunk: IVec3) {
+        self.chunks.remove(&chunk);
+    }
+
+    /// Density at and above which a voxel is solid.
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    /// Density of a voxel of a [`ChunkMesher::Blocks`] world, `None` when its chunk
+    /// isn't loaded.
+    pub fn density_at(&self, voxel: IVec3) -> Option<f32> {
+        if self.mesher != ChunkMesher::Blocks {
+            return None;
+        }
+        let (chunk, local) = voxel_chunk(voxel);
+        Some(self.chunks.get(&chunk)?.density[voxel_index(local)])
+    }
+
+    /// Whether a voxel is solid, `None` when its chunk isn't loaded.
+    pub fn is_solid(&self, voxel: IVec3) -> Option<bool> {
+        let (chunk, local) = voxel_chunk(voxel);
+        let voxels = self.chunks.get(&chunk)?;
+        Some(match self.mesher {
+            // The z=0 slice holds one height per (x, z) column, relative to the chunk's bottom.
+            ChunkMesher::HeightField => {
+                local.y as f32 + 0.5 < voxels.density[voxel_index(IVec3::new(local.x, local.z, 0))]
+            }
+            ChunkMesher::Blocks => voxels.density[voxel_index(local)] >= self.threshold,
+        })
+    }
+
+    /// Block of a voxel, `None` when its chunk isn't loaded.
+    pub fn block_at(&self, voxel: IVec3) -> Option<BlockId> {
+        let (chunk, local) = voxel_chunk(voxel);
+        let voxels = self.chunks.get(&chunk)?;
+        Some(match self.mesher {
+            // Height fields only store the top block of a column and the water surface.
+            ChunkMesher::HeightField => {
+                let column = IVec3::new(local.x, local.z, 0);
+                let y = local.y as f32 + 0.5;
+                if y < voxels.density[voxel_index(column)] {
+                    voxels.blocks[voxel_index(column)]
+                } else if y < voxels.density[voxel_index(column + IVec3::Z)] {
+                    BlockId::WATER
+                } else {
+                    BlockId::AIR
+                }
+            }
+            ChunkMesher::Blocks => voxels.blocks[voxel_index(local)],
+        })
+    }
+
+    /// Sets a voxel of a loaded chunk to `block`, solid or not as the block is. Only
+    /// [`ChunkMesher::Blocks`] worlds can be edited. The chunk is uploaded and remeshed,
+    /// with its neighbours when the voxel is on its border, at the next flush.
+    pub fn set_block(&mut self, voxel: IVec3, block: BlockId) -> bool {
+        self.set_voxel(voxel, block, self.block_density(block))
+    }
+
+    /// Sets the block and density of a voxel of a loaded chunk, see [`Self::set_block`].
+    /// The change is recorded for the [`VoxelEditHistory`](crate::edit_history::VoxelEditHistory).
+    pub fn set_voxel(&mut self, voxel: IVec3, block: BlockId, density: f32) -> bool {
+        let Some(before) = self.write_voxel(voxel, block, density) else {
+            return false;
+        };
+        self.changes.push(VoxelChange {
+            voxel,
+            before,
+            after: (block, density),
+        });
+        true
+    }
+
+    /// Density [`Self::set_block`] gives a voxel of `block`.
+    pub fn block_density(&self, block: BlockId) -> f32 {
+        if block.is_solid() {
+            self.threshold + EDIT_DENSITY
+        } else {
+            self.threshold - EDIT_DENSITY
+        }
+    }
+
+    /// Loaded chunks overlapping `min..=max`, with the lowest and highest local voxel
+    /// of the overlap.
+    fn region_chunks(&self, min: IVec3, max: IVec3) -> Vec<(IVec3, IVec3, IVec3)> {
+        let n = CHUNK_SIZE as i32;
+        let (min_chunk, _) = voxel_chunk(min);
+        let (max_chunk, _) = voxel_chunk(max);
+        let mut chunks = Vec::new();
+        for cz in min_chunk.z..=max_chunk.z {
+            for cy in min_chunk.y..=max_chunk.y {
+                for cx in min_chunk.x..=max_chunk.x {
+                    let chunk = IVec3::new(cx, cy, cz);
+                    if self.chunks.contains_key(&chunk) {
+                        let lo = (min - chunk * n).max(IVec3::ZERO);
+                        let hi = (max - chunk * n).min(IVec3::splat(n - 1));
+                        chunks.push((chunk, lo, hi));
+                    }
+                }
+            }
+        }
+        chunks
+    }
+
+    /// Calls `visit` with the block and density of every voxel of the loaded chunks in
+    /// `min..=max` of a [`ChunkMesher::Blocks`] world, one chunk at a time.
+    pub fn visit_region(&self, min: IVec3, max: IVec3, mut visit: impl FnMut(IVec3, BlockId, f32)) {
+        if self.mesher != ChunkMesher::Blocks {
+            return;
+        }
+        let n = CHUNK_SIZE as i32;
+        for (chunk, lo, hi) in self.region_chunks(min, max) {
+            let voxels = &self.chunks[&chunk];
+            for z in lo.z..=hi.z {
+                for y in lo.y..=hi.y {
+                    for x in lo.x..=hi.x {
+                        let local = IVec3::new(x, y, z);
+                        let i = voxel_index(local);
+                        visit(chunk * n + local, voxels.blocks[i], voxels.density[i]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Calls `edit` with the block and density of every voxel of the loaded chunks in
+    /// `min..=max` and writes what it returns, one chunk at a time. Meant for large
+    /// regions, where [`Self::set_voxel`] would look the chunk up for every voxel.
+    /// Changes are recorded the same way. Returns the number of voxels changed.
+    pub fn edit_region(
+        &mut self,
+        min: IVec3,
+        max: IVec3,
+        mut edit: impl FnMut(IVec3, BlockId, f32) -> Option<(BlockId, f32)>,
+    ) -> usize {
+        if self.mesher != ChunkMesher::Blocks {
+            return 0;
+        }
+        let n = CHUNK_SIZE as i32;
+        let mut changed = 0;
+
+        for (chunk, lo, hi) in self.region_chunks(min, max) {
+            let voxels = self.chunks.get_mut(&chunk).unwrap();
+            let mut chunk_changed = false;
+            for z in lo.z..=hi.z {
+                for y in lo.y..=hi.y {
+                    for x in lo.x..=hi.x {
+                        let local = IVec3::new(x, y, z);
+                        let i = voxel_index(local);
+                        let before = (voxels.blocks[i], voxels.density[i]);
+                        let voxel = chunk * n + local;
+                        let Some(after) = edit(voxel, before.0, before.1) else {
+                            continue;
+                        };
+                        if after == before {
+                            continue;
+                        }
+                        let voxels = Arc::make_mut(voxels);
+                        voxels.blocks[i] = after.0;
+                        voxels.density[i] = after.1;
+                        self.changes.push(VoxelChange {
+                            voxel,
+                            before,
+                            after,
+                        });
+                        chunk_changed = true;
+                        changed += 1;
+                    }
+                }
+            }
+
+            if !chunk_changed {
+                continue;
+            }
+            self.edited.insert(chunk);
+            for axis in 0..3 {
+                let mut offset = IVec3::ZERO;
+                if lo[axis] == 0 {
+                    offset[axis] = -1;
+                    self.remesh.insert(chunk + offset);
+                }
+                if hi[axis] == n - 1 {
+                    offset[axis] = 1;
+                    self.remesh.insert(chunk + offset);
+                }
+            }
+        }
+        changed
+    }
+
+    /// Sets a voxel like [`Self::set_voxel`] without recording the change, for undoing.
+    pub fn restore_voxel(&mut self, voxel: IVec3, block: BlockId, density: f32) -> bool {
+        self.write_voxel(voxel, block, density).is_some()
+    }
+
+    /// Changes made through [`Self::set_voxel`] since the last call, oldest first.
+    pub fn take_changes(&mut self) -> Vec<VoxelChange> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Writes a voxel and marks its chunk for the flush, returning what it held.
+    fn write_voxel(
+        &mut self,
+        voxel: IVec3,
+        block: BlockId,
+        density: f32,
+    ) -> Option<(BlockId, f32)> {
+        if self.mesher != ChunkMesher::Blocks {
+            return None;
+        }
+        let (chunk, local) = voxel_chunk(voxel);
+        let voxels = Arc::make_mut(self.chunks.get_mut(&chunk)?);
+        let i = voxel_index(local);
+        let before = (voxels.blocks[i], voxels.density[i]);
+        voxels.blocks[i] = block;
+        voxels.density[i] = density;
+
+        self.edited.insert(chunk);
+        let n = CHUNK_SIZE as i32;
+        for axis in 0..3 {
+            let mut offset = IVec3::ZERO;
+            if local[axis] == 0 {
+                offset[axis] = -1;
+            } else if local[axis] == n - 1 {
+                offset[axis] = 1;
+            } else {
+                continue;
+            }
+            self.remesh.insert(chunk + offset);
+        }
+        Some(before)
+    }
+
+    /// First solid voxel along a ray, walking the grid with Amanatides-Woo DDA.
+    /// `None` when nothing is hit within `max_dist` or the ray reaches a chunk that
+    /// isn't loaded first.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<RaycastHit> {
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
+            return None;
+        }
+
+        let mut voxel = origin.floor().as_ivec3();
+        let step = IVec3::new(
+            if dir.x < 0.0 { -1 } else { 1 },
+            if dir.y < 0.0 { -1 } else { 1 },
+            if dir.z < 0.0 { -1 } else { 1 },
+        );
+        // Distance along the ray between two boundaries on each axis, and to the next one.
+        let t_delta = dir.recip().abs();
+        let boundary = voxel.as_vec3() + step.max(IVec3::ZERO).as_vec3();
+        let mut t_max = Vec3::select(
+            dir.cmpeq(Vec3::ZERO),
+            Vec3::INFINITY,
+            (boundary - origin) / dir,
+        );
+        let mut normal = IVec3::ZERO;
+        let mut distance = 0.0;
+
+        loop {
+            if self.is_solid(voxel)? {
+                return Some(RaycastHit {
+                    voxel,
+                    normal,
+                    distance,
+                    block: self.block_at(voxel)?,
+                });
+            }
+
+            let axis = if t_max.x < t_max.y && t_max.x < t_max.z {
+                0
+            } else if t_max.y < t_max.z {
+                1
+            } else {
+                2
+            };
+            distance = t_max[axis];
+            if distance > max_dist {
+                return None;
+            }
+            voxel[axis] += step[axis];
+            t_max[axis] += t_delta[axis];
+            normal = IVec3::ZERO;
+            normal[axis] = -step[axis];
+        }
+    }
+}
+
+/// Chunk whose voxels the GPU generated, read back once it is [`ChunkState::Ready`].
+#[derive(Component)]
+pub struct ReadBackVoxels;
+
+/// Half of a chunk's voxels being read back from its slot.
+#[derive(Component, Clone, Copy)]
+struct ChunkVoxelReadback {
+    chunk: Entity,
+    pos: IVec3,
+    slot: u32,
+    blocks: bool,
+}
+
+pub struct VoxelWorldPlugin;
+
+impl Plugin for VoxelWorldPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<VoxelWorld>().add_systems(
+            Update,
+            (
+                sync_voxel_world_generator
+                    .run_if(resource_changed::<ActiveTerrainGenerator>)
+                    .before(poll_chunk_gen_tasks),
+                read_back_generated_chunks,
+                forget_unloaded_chunks,
+                flush_voxel_edits.after(poll_chunk_gen_tasks),
+            ),
+        );
+    }
+}
+
+/// Stored voxels are meshed the old generator's way, the chunks regenerate anyway.
+fn sync_voxel_world_generator(
+    generator: Res<ActiveTerrainGenerator>,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    *voxel_world = VoxelWorld::new(generator.mesher(), generator.density_threshold());
+}
+
+fn read_back_generated_chunks(
+    mut commands: Commands,
+    image: Option<Res<VoxelComputeGridImage>>,
+    chunks: Query<(Entity, &ChunkPos, &ChunkSlot, &ChunkState), With<ReadBackVoxels>>,
+) {
+    let Some(image) = image else {
+        return;
+    };
+
+    for (entity, pos, slot, state) in &chunks {
+        if *state != ChunkState::Ready {
+            continue;
+        }
+
+        commands.entity(entity).remove::<ReadBackVoxels>();
+        for (buffer, size, blocks) in [
+            (&image.voxel_buffer, std::mem::size_of::<f32>(), false),
+            (&image.blocks, std::mem::size_of::<BlockId>(), true),
+        ] {
+            let bytes = (CHUNK_VOXELS_COUNT * size) as u64;
+            commands
+                .spawn((
+                    Readback::buffer_range(buffer.clone(), **slot as u64 * bytes, bytes),
+                    ChunkVoxelReadback {
+                        chunk: entity,
+                        pos: **pos,
+                        slot: **slot,
+                        blocks,
+                    },
+                ))
+                .observe(read_chunk_voxels);
+        }
+    }
+}
+
+fn read_chunk_voxels(
+    event: On<ReadbackComplete>,
+    mut commands: Commands,
+    readbacks: Query<&ChunkVoxelReadback>,
+    chunks: Query<(&ChunkPos, &ChunkSlot, &ChunkState)>,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    let Ok(readback) = readbacks.get(event.entity) else {
+        return;
+    };
+    // Readbacks repeat every frame until despawned.
+    commands.entity(event.entity).despawn();
+
+    // The slot may have moved on to another chunk or been regenerated since.
+    let current = chunks.get(readback.chunk).is_ok_and(|(pos, slot, state)| {
+        **pos == readback.pos && **slot == readback.slot && *state == ChunkState::Ready
+    });
+    if !current {
+        voxel_world.pending.remove(&readback.chunk);
+        return;
+    }
+
+    let pending = voxel_world.pending.entry(readback.chunk).or_default();
+    if readback.blocks {
+        pending.1 = Some(bytemuck::pod_collect_to_vec(&event.data));
+    } else {
+        pending.0 = Some(bytemuck::pod_collect_to_vec(&event.data));
+    }
+    if pending.0.is_some()
+        && pending.1.is_some()
+        && let Some((Some(density), Some(blocks))) = voxel_world.pending.remove(&readback.chunk)
+    {
+        voxel_world.insert(readback.pos, Arc::new(ChunkVoxels { density, blocks }));
+    }
+}
+
+fn forget_unloaded_chunks(
+    mut unloaded: MessageReader<ChunkUnloaded>,
+    mut voxel_world: ResMut<VoxelWorld>,
+) {
+    for event in unloaded.read() {
+        voxel_world.remove(event.pos);
+        voxel_world.pending.remove(&event.entity);
+    }
+}
+
+/// Uploads the chunks edited this frame and remeshes them once, whatever the number
+/// of edits. Runs after `poll_chunk_gen_tasks`, which starts the frame's uploads.
+pub fn flush_voxel_edits(
+    mut commands: Commands,
+    slots: Res<ChunkSlots>,
+    mut voxel_world: ResMut<VoxelWorld>,
+    mut uploads: ResMut<ChunkVoxelUploads>,
+    states: Query<&ChunkState>,
+) {
+    let voxel_world = &mut *voxel_world;
+    for chunk in voxel_world.edited.drain() {
+        let (Some(slot), Some(entity), Some(voxels)) = (
+            slots.slot_of(chunk),
+            slots.entity_of(chunk),
+            voxel_world.chunks.get(&chunk),
+        ) else {
+            continue;
+        };
+        // Chunks being regenerated would get the stale voxels the edit was made on.
+        if !matches!(
+            states.get(entity),
+            Ok(ChunkState::Meshing | ChunkState::Ready)
+        ) {
+            continue;
+        }
+        uploads.chunks.push((slot, voxels.clone()));
+        commands.entity(entity).insert(RemeshChunk);
+    }
+    for chunk in voxel_world.remesh.drain() {
+        if let Some(entity) = slots.entity_of(chunk) {
+            commands.entity(entity).insert(RemeshChunk);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// World of air with the chunks `chunks` loaded and `solid` set to stone.
+    fn world(chunks: &[IVec3], solid: &[IVec3]) -> VoxelWorld {
+        let mut world = VoxelWorld::new(ChunkMesher::Blocks, 0.0);
+        for chunk in chunks {
+            let mut voxels = ChunkVoxels {
+                density: vec![-1.0; CHUNK_VOXELS_COUNT],
+                ..default()
+            };
+            for voxel in solid {
+                let (voxel_chunk, local) = voxel_chunk(*voxel);
+                if voxel_chunk == *chunk {
+                    voxels.density[voxel_index(local)] = 1.0;
+                    voxels.blocks[voxel_index(local)] = BlockId::STONE;
+                }
+            }
+            world.insert(*chunk, Arc::new(voxels));
+        }
+        world
+    }
+
+    fn around_origin() -> Vec<IVec3> {
+        (-1..=1)
+            .flat_map(|x| (-1..=1).flat_map(move |y| (-1..=1).map(move |z| IVec3::new(x, y, z))))
+            .collect()
+    }
+
+    #[test]
+    fn raycast_hits_the_face_it_enters() {
+        let world = world(&around_origin(), &[IVec3::new(5, 0, 0)]);
+        let hit = world.raycast(Vec3::splat(0.5), Vec3::X, 10.0).unwrap();
+        assert_eq!(hit.voxel, IVec3::new(5, 0, 0));
+        assert_eq!(hit.normal, IVec3::NEG_X);
+        assert_eq!(hit.distance, 4.5);
+        assert_eq!(hit.block, BlockId::STONE);
+
+        let hit = world
+            .raycast(Vec3::new(5.5, 0.5, -3.5), Vec3::Z, 10.0)
+            .unwrap();
+        assert_eq!(hit.voxel, IVec3::new(5, 0, 0));
+        assert_eq!(hit.normal, IVec3::NEG_Z);
+        assert_eq!(hit.distance, 3.5);
+    }
+
+    #[test]
+    fn raycast_walks_diagonals() {
+        let world = world(&around_origin(), &[IVec3::new(3, -3, 0)]);
+        let hit = world
+            .raycast(Vec3::new(0.5, 0.5, 0.5), Vec3::new(1.0, -1.0, 0.0), 10.0)
+            .unwrap();
+        assert_eq!(hit.voxel, IVec3::new(3, -3, 0));
+        assert!([IVec3::NEG_X, IVec3::Y].contains(&hit.normal));
+        assert!((hit.distance - 2.5 * std::f32::consts::SQRT_2).abs() < 1e-4);
+    }
+
+    #[test]
+    fn raycast_from_inside_a_solid_voxel_hits_it() {
+        let world = world(&around_origin(), &[IVec3::ZERO]);
+        let hit = world.raycast(Vec3::splat(0.25), Vec3::Y, 10.0).unwrap();
+        assert_eq!(hit.voxel, IVec3::ZERO);
+        assert_eq!(hit.normal, IVec3::ZERO);
+        assert_eq!(hit.distance, 0.0);
+    }
+
+    #[test]
+    fn raycast_stops_at_max_dist() {
+        let world = world(&around_origin(), &[IVec3::new(5, 0, 0)]);
+        assert!(world.raycast(Vec3::splat(0.5), Vec3::X, 4.0).is_none());
+        assert!(world.raycast(Vec3::splat(0.5), Vec3::X, 4.5).is_some());
+    }
+
+    #[test]
+    fn raycast_stops_at_unloaded_chunks() {
+        // Chunk (1, 0, 0) is missing between the ray's start and the solid voxel.
+        let world = world(&[IVec3::ZERO, IVec3::new(2, 0, 0)], &[IVec3::new(40, 0, 0)]);
+        assert!(world.raycast(Vec3::splat(0.5), Vec3::X, 100.0).is_none());
+    }
+}
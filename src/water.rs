@@ -0,0 +1,723 @@
+//! Sea, lakes and rivers, filled in after a chunk is generated.
+//!
+//! Lakes fill basins of the terrain and rivers follow splines traced downhill from
+//! high ground until they reach the sea. Both are traced on the CPU from the world
+//! seed for every cell around the loaded chunks and evaluated per column by
+//! `apply_water` in `voxel_gen.wgsl`, which [`apply_water`] mirrors.
+
+use std::{collections::HashMap, sync::Arc};
+
+use bevy::{prelude::*, render::extract_resource::ExtractResource};
+use bytemuck::{Pod, Zeroable};
+
+use crate::{
+    block::BlockId,
+    chunk::{ChunkPos, ChunkState},
+    chunks_partition::{CHUNK_SIZE, CHUNK_VOXELS_COUNT},
+    noise::{hash, hash3},
+    terrain_generator::{
+        ActiveTerrainGenerator, ChunkMesher, ChunkVoxels, TerrainParams, terrain_height,
+    },
+};
+
+const LAKE_CELL: i32 = 256;
+const LAKE_CHANCE: f32 = 0.6;
+const LAKE_SEARCH_STEP: f32 = 8.0;
+const LAKE_SEARCH_STEPS: u32 = 16;
+const LAKE_RADIUS: f32 = 24.0;
+/// Lakes shallower than this between their bottom and their lowest rim are dropped.
+const MIN_LAKE_DEPTH: f32 = 2.0;
+/// How far up from its bottom to its rim a lake is filled.
+const LAKE_FILL: f32 = 0.7;
+/// Columns a lake may lie away from its cell.
+const LAKE_REACH: f32 = LAKE_SEARCH_STEP * LAKE_SEARCH_STEPS as f32 + LAKE_RADIUS;
+
+const RIVER_CELL: i32 = 384;
+const RIVER_CHANCE: f32 = 0.5;
+/// Height above the sea a river's source has to be at.
+const MIN_SOURCE_HEIGHT: f32 = 16.0;
+const RIVER_STEP: f32 = 24.0;
+const RIVER_MAX_STEPS: usize = 24;
+/// Segments each span of the spline is split into.
+const RIVER_SUBDIVISIONS: usize = 4;
+/// Water surface of a river below the terrain along its path.
+const RIVER_BANK: f32 = 1.0;
+const RIVER_DEPTH: f32 = 3.0;
+/// Rise of the carved valley per column away from the river.
+const RIVER_BANK_SLOPE: f32 = 1.5;
+/// Columns beside a river its valley is carved over.
+const RIVER_CARVE_REACH: f32 = 64.0;
+const RIVER_REACH: f32 = RIVER_STEP * RIVER_MAX_STEPS as f32 + RIVER_CARVE_REACH;
+
+pub const MAX_LAKES: usize = 64;
+pub const MAX_RIVERS: usize = 64;
+pub const MAX_RIVER_SEGMENTS: usize = MAX_RIVERS * RIVER_MAX_STEPS * RIVER_SUBDIVISIONS;
+
+/// Density voxels above a river's carved bed lose per block, like the terrain's height bias.
+const CARVE_STRENGTH: f32 = 1.0 / 4.0;
+/// Level of columns without water.
+const NO_WATER: f32 = -1e30;
+const NO_CARVE: f32 = 1e30;
+
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct WaterSettings {
+    /// Air below this is filled with water, `None` for a world without a sea.
+    pub sea_level: Option<f32>,
+    pub lakes: bool,
+    pub rivers: bool,
+}
+
+impl Default for WaterSettings {
+    fn default() -> Self {
+        Self {
+            sea_level: Some(0.0),
+            lakes: true,
+            rivers: true,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+pub struct Lake {
+    pub center: [f32; 2],
+    pub radius: f32,
+    pub level: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+pub struct River {
+    /// Bounds of the columns the river carves.
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+    pub first_segment: u32,
+    pub segment_count: u32,
+    pub width: f32,
+    pub depth: f32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+pub struct RiverSegment {
+    pub start: [f32; 2],
+    pub end: [f32; 2],
+    /// Water surface at either end.
+    pub start_level: f32,
+    pub end_level: f32,
+    _pad: [f32; 2],
+}
+
+/// Header of the `water_bodies` buffer of `voxel_gen.wgsl`.
+#[repr(C)]
+#[derive(Clone, Copy, Pod, Zeroable, Debug, Default)]
+struct WaterHeader {
+    sea_level: f32,
+    has_sea: u32,
+    lake_count: u32,
+    river_count: u32,
+}
+
+/// Water surface and carved river bed of a column.
+#[derive(Clone, Copy, Debug)]
+pub struct ColumnWater {
+    /// Terrain above this is carved away.
+    pub carve: f32,
+    /// Air below this is water.
+    pub level: f32,
+}
+
+/// Water bodies around the loaded chunks, as uploaded to the GPU.
+#[derive(Clone, Debug, Default)]
+pub struct WaterData {
+    sea_level: Option<f32>,
+    lakes: Vec<Lake>,
+    rivers: Vec<River>,
+    segments: Vec<RiverSegment>,
+    /// Lakes and rivers whose bounds overlap each chunk column.
+    bins: HashMap<IVec2, WaterBin>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct WaterBin {
+    lakes: Vec<u32>,
+    rivers: Vec<u32>,
+}
+
+/// Chunk columns overlapping the bounds `min..=max`.
+fn chunk_columns(min: Vec2, max: Vec2) -> impl Iterator<Item = IVec2> {
+    cells(IRect::from_corners(
+        (min / CHUNK_SIZE).floor().as_ivec2(),
+        (max / CHUNK_SIZE).floor().as_ivec2(),
+    ))
+}
+
+impl WaterData {
+    pub fn new(
+        sea_level: Option<f32>,
+        lakes: Vec<Lake>,
+        rivers: Vec<River>,
+        segments: Vec<RiverSegment>,
+    ) -> Self {
+        let mut bins = HashMap::<IVec2, WaterBin>::new();
+        for (i, lake) in lakes.iter().enumerate() {
+            let center = Vec2::from(lake.center);
+            for column in chunk_columns(center - lake.radius, center + lake.radius) {
+                bins.entry(column).or_default().lakes.push(i as u32);
+            }
+        }
+        for (i, river) in rivers.iter().enumerate() {
+            for column in chunk_columns(Vec2::from(river.min), Vec2::from(river.max)) {
+                bins.entry(column).or_default().rivers.push(i as u32);
+            }
+        }
+
+        Self {
+            sea_level,
+            lakes,
+            rivers,
+            segments,
+            bins,
+        }
+    }
+
+    pub fn column(&self, xz: Vec2) -> ColumnWater {
+        let mut water = ColumnWater {
+            carve: NO_CARVE,
+            level: self.sea_level.unwrap_or(NO_WATER),
+        };
+
+        let Some(bin) = self.bins.get(&(xz / CHUNK_SIZE).floor().as_ivec2()) else {
+            return water;
+        };
+
+        for lake in bin.lakes.iter().map(|i| &self.lakes[*i as usize]) {
+            if xz.distance(Vec2::from(lake.center)) < lake.radius {
+                water.level = water.level.max(lake.level);
+            }
+        }
+
+        for river in bin.rivers.iter().map(|i| &self.rivers[*i as usize]) {
+            if xz.cmplt(Vec2::from(river.min)).any() || xz.cmpgt(Vec2::from(river.max)).any() {
+                continue;
+            }
+
+            let segments =
+                &self.segments[river.first_segment as usize..][..river.segment_count as usize];
+            let (distance, level) = segments
+                .iter()
+                .map(|segment| segment_distance(segment, xz))
+                .fold((f32::INFINITY, 0.0), |best, next| {
+                    if next.0 < best.0 { next } else { best }
+                });
+
+            water.carve = water.carve.min(river_carve(distance, level, river));
+            if distance < river.width {
+                water.level = water.level.max(level);
+            }
+        }
+        water
+    }
+
+    /// Height features stand on at a column whose terrain is at `height`,
+    /// `None` under water.
+    pub fn ground(&self, xz: IVec2, height: f32) -> Option<f32> {
+        let water = self.column(xz.as_vec2());
+        let ground = height.min(water.carve);
+        (ground >= water.level).then_some(ground)
+    }
+
+    /// Contents of the `water_bodies` buffer.
+    pub fn gpu_bytes(&self) -> Vec<u8> {
+        let header = WaterHeader {
+            sea_level: self.sea_level.unwrap_or(0.0),
+            has_sea: self.sea_level.is_some() as u32,
+            lake_count: self.lakes.len() as u32,
+            river_count: self.rivers.len() as u32,
+        };
+        let mut lakes = self.lakes.clone();
+        lakes.resize(MAX_LAKES, Lake::default());
+        let mut rivers = self.rivers.clone();
+        rivers.resize(MAX_RIVERS, River::default());
+        let mut segments = self.segments.clone();
+        segments.resize(MAX_RIVER_SEGMENTS, RiverSegment::default());
+
+        let mut bytes = bytemuck::bytes_of(&header).to_vec();
+        bytes.extend_from_slice(bytemuck::cast_slice(&lakes));
+        bytes.extend_from_slice(bytemuck::cast_slice(&rivers));
+        bytes.extend_from_slice(bytemuck::cast_slice(&segments));
+        bytes
+    }
+}
+
+/// Size of the `water_bodies` buffer.
+pub const WATER_BODIES_SIZE: usize = std::mem::size_of::<WaterHeader>()
+    + MAX_LAKES * std::mem::size_of::<Lake>()
+    + MAX_RIVERS * std::mem::size_of::<River>()
+    + MAX_RIVER_SEGMENTS * std::mem::size_of::<RiverSegment>();
+
+/// Distance to a segment and the water surface at the nearest point.
+fn segment_distance(segment: &RiverSegment, xz: Vec2) -> (f32, f32) {
+    let start = Vec2::from(segment.start);
+    let along = Vec2::from(segment.end) - start;
+    let t = ((xz - start).dot(along) / along.length_squared().max(1e-6)).clamp(0.0, 1.0);
+    (
+        xz.distance(start + along * t),
+        segment.start_level.lerp(segment.end_level, t),
+    )
+}
+
+/// Height terrain is carved down to at `distance` from a river.
+fn river_carve(distance: f32, level: f32, river: &River) -> f32 {
+    if distance < river.width {
+        let t = distance / river.width;
+        level - river.depth * (1.0 - t * t)
+    } else {
+        level + (distance - river.width) * RIVER_BANK_SLOPE
+    }
+}
+
+/// Carves rivers into and fills water over freshly generated voxels.
+pub fn apply_water(
+    voxels: &mut ChunkVoxels,
+    chunk: IVec3,
+    water: &WaterData,
+    mesher: ChunkMesher,
+    threshold: f32,
+) {
+    let n = CHUNK_SIZE as i32;
+    let bottom = (chunk.y * n) as f32;
+    for i in 0..CHUNK_VOXELS_COUNT {
+        let local = IVec3::new(i as i32 % n, (i as i32 / n) % n, i as i32 / (n * n));
+        match mesher {
+            // The z=0 slice holds the heights, the z=1 slice the water surface.
+            ChunkMesher::HeightField => {
+                if local.z > 1 {
+                    continue;
+                }
+                let column = water
+                    .column(IVec2::new(chunk.x * n + local.x, chunk.z * n + local.y).as_vec2());
+                voxels.density[i] = if local.z == 0 {
+                    voxels.density[i].min(column.carve - bottom)
+                } else {
+                    column.level - bottom
+                };
+            }
+            ChunkMesher::Blocks => {
+                let world = chunk * n + local;
+                let column = water.column(world.xz().as_vec2());
+                let y = world.y as f32;
+                if y > column.carve {
+                    voxels.density[i] =
+                        voxels.density[i].min(threshold + (column.carve - y) * CARVE_STRENGTH);
+                }
+                if voxels.density[i] < threshold {
+                    voxels.blocks[i] = if y < column.level {
+                        BlockId::WATER
+                    } else {
+                        BlockId::AIR
+                    };
+                }
+            }
+        }
+    }
+}
+
+/// Uniform in [0, 1) for the `i`th draw of a cell.
+fn random(cell: IVec2, seed: u32, i: u32) -> f32 {
+    (hash3(cell.extend(0), seed ^ hash(i)) >> 8) as f32 * (1.0 / 16777216.0)
+}
+
+/// Lake of a cell: the basin found walking downhill from a random point, filled
+/// part of the way up to its lowest rim.
+pub fn find_lake(cell: IVec2, terrain: &TerrainParams, sea_level: Option<f32>) -> Option<Lake> {
+    let lake_seed = terrain.seed.wrapping_add(701);
+    if random(cell, lake_seed, 0) >= LAKE_CHANCE {
+        return None;
+    }
+
+    let height = |p: Vec2| terrain_height(p, terrain);
+    let mut bottom = (cell.as_vec2()
+        + Vec2::new(random(cell, lake_seed, 1), random(cell, lake_seed, 2)))
+        * LAKE_CELL as f32;
+    let mut bottom_height = height(bottom);
+    for _ in 0..LAKE_SEARCH_STEPS {
+        let lowest = [Vec2::X, Vec2::NEG_X, Vec2::Y, Vec2::NEG_Y]
+            .map(|dir| bottom + dir * LAKE_SEARCH_STEP)
+            .into_iter()
+            .map(|p| (p, height(p)))
+            .fold(
+                (bottom, bottom_height),
+                |a, b| if b.1 < a.1 { b } else { a },
+            );
+        if lowest.0 == bottom {
+            break;
+        }
+        (bottom, bottom_height) = lowest;
+    }
+
+    if sea_level.is_some_and(|sea| bottom_height < sea + 1.0) {
+        return None;
+    }
+
+    let rim = (0..16)
+        .map(|i| {
+            let angle = i as f32 * std::f32::consts::TAU / 16.0;
+            height(bottom + Vec2::from_angle(angle) * LAKE_RADIUS)
+        })
+        .fold(f32::INFINITY, f32::min);
+    if rim - bottom_height < MIN_LAKE_DEPTH {
+        return None;
+    }
+
+    Some(Lake {
+        center: bottom.to_array(),
+        radius: LAKE_RADIUS,
+        level: bottom_height + (rim - bottom_height) * LAKE_FILL,
+    })
+}
+
+/// Control points of a river of a cell, `(position, water surface)`, walked downhill
+/// from a random source on high ground until it reaches the sea.
+pub fn trace_river(
+    cell: IVec2,
+    terrain: &TerrainParams,
+    sea_level: Option<f32>,
+) -> Vec<(Vec2, f32)> {
+    let river_seed = terrain.seed.wrapping_add(809);
+    if random(cell, river_seed, 0) >= RIVER_CHANCE {
+        return Vec::new();
+    }
+
+    let sea = sea_level.unwrap_or(0.0);
+    let height = |p: Vec2| terrain_height(p, terrain);
+    let mut pos = (cell.as_vec2()
+        + Vec2::new(random(cell, river_seed, 1), random(cell, river_seed, 2)))
+        * RIVER_CELL as f32;
+    let mut level = height(pos) - RIVER_BANK;
+    if level < sea + MIN_SOURCE_HEIGHT {
+        return Vec::new();
+    }
+
+    let mut points = vec![(pos, level)];
+    for step in 0..RIVER_MAX_STEPS as u32 {
+        let dx = height(pos + Vec2::X * RIVER_STEP) - height(pos - Vec2::X * RIVER_STEP);
+        let dz = height(pos + Vec2::Y * RIVER_STEP) - height(pos - Vec2::Y * RIVER_STEP);
+        let downhill = -Vec2::new(dx, dz);
+        if downhill.length_squared() < 1e-6 {
+            break;
+        }
+
+        // Meander a little around the steepest descent.
+        let bend = (random(cell, river_seed, 3 + step) - 0.5) * 0.6;
+        pos += Vec2::from_angle(bend).rotate(downhill.normalize()) * RIVER_STEP;
+        level = level.min(height(pos) - RIVER_BANK);
+        points.push((pos, level));
+        if level < sea {
+            break;
+        }
+    }
+
+    if points.len() < 3 {
+        return Vec::new();
+    }
+    points
+}
+
+/// Catmull-Rom spline through the control points, as segments.
+fn river_segments(points: &[(Vec2, f32)]) -> Vec<RiverSegment> {
+    let point = |i: isize| points[i.clamp(0, points.len() as isize - 1) as usize];
+    let mut samples = Vec::new();
+    for span in 0..points.len() as isize - 1 {
+        let [p0, p1, p2, p3] = [span - 1, span, span + 1, span + 2].map(point);
+        for step in 0..RIVER_SUBDIVISIONS {
+            let t = step as f32 / RIVER_SUBDIVISIONS as f32;
+            let (t2, t3) = (t * t, t * t * t);
+            let pos = 0.5
+                * (2.0 * p1.0
+                    + (p2.0 - p0.0) * t
+                    + (2.0 * p0.0 - 5.0 * p1.0 + 4.0 * p2.0 - p3.0) * t2
+                    + (3.0 * p1.0 - p0.0 - 3.0 * p2.0 + p3.0) * t3);
+            samples.push((pos, p1.1.lerp(p2.1, t)));
+        }
+    }
+    samples.push(*points.last().unwrap());
+
+    samples
+        .windows(2)
+        .map(|pair| RiverSegment {
+            start: pair[0].0.to_array(),
+            end: pair[1].0.to_array(),
+            start_level: pair[0].1,
+            end_level: pair[1].1,
+            _pad: [0.0; 2],
+        })
+        .collect()
+}
+
+/// Water bodies traced for the cells around the loaded chunks.
+#[derive(Resource, Clone, Default, ExtractResource)]
+pub struct WaterBodies {
+    pub data: Arc<WaterData>,
+    lakes: HashMap<IVec2, Option<Lake>>,
+    rivers: HashMap<IVec2, Arc<Vec<(Vec2, f32)>>>,
+    /// Cell ranges `data` was built for.
+    bounds: Option<(IRect, IRect)>,
+}
+
+fn cell_range(min: Vec2, max: Vec2, reach: f32, cell: i32) -> IRect {
+    IRect::from_corners(
+        ((min - reach) / cell as f32).floor().as_ivec2(),
+        ((max + reach) / cell as f32).floor().as_ivec2(),
+    )
+}
+
+fn cells(rect: IRect) -> impl Iterator<Item = IVec2> {
+    (rect.min.x..=rect.max.x)
+        .flat_map(move |x| (rect.min.y..=rect.max.y).map(move |y| IVec2::new(x, y)))
+}
+
+/// Traces the water bodies that can reach any loaded chunk. Bodies are only added
+/// once the loaded area comes within their reach, so chunks generated earlier never
+/// miss one.
+pub fn update_water_bodies(
+    settings: Res<WaterSettings>,
+    terrain: Res<TerrainParams>,
+    generator: Res<ActiveTerrainGenerator>,
+    mut bodies: ResMut<WaterBodies>,
+    chunks: Query<(&ChunkPos, &ChunkState)>,
+) {
+    if settings.is_changed() || terrain.is_changed() || generator.is_changed() {
+        *bodies = WaterBodies::default();
+    }
+
+    let n = CHUNK_SIZE;
+    let Some((min, max)) = chunks
+        .iter()
+        .filter(|(_, state)| **state != ChunkState::Unloading)
+        .map(|(pos, _)| pos.xz().as_vec2() * n)
+        .fold(None, |bounds: Option<(Vec2, Vec2)>, corner| {
+            Some(bounds.map_or((corner, corner + n), |(min, max)| {
+                (min.min(corner), max.max(corner + n))
+            }))
+        })
+    else {
+        return;
+    };
+
+    let lake_cells = cell_range(min, max, LAKE_REACH, LAKE_CELL);
+    let river_cells = cell_range(min, max, RIVER_REACH, RIVER_CELL);
+    if bodies.bounds == Some((lake_cells, river_cells)) {
+        return;
+    }
+
+    let bodies = &mut *bodies;
+    bodies.bounds = Some((lake_cells, river_cells));
+    // Bodies nearest to the loaded area win when there are more than the GPU buffers hold.
+    let distance_to_loaded = |lo: Vec2, hi: Vec2| (lo - max).max(min - hi).max(Vec2::ZERO).length();
+
+    let mut lakes = Vec::new();
+    if settings.lakes && generator.procedural_water() {
+        bodies.lakes.retain(|cell, _| lake_cells.contains(*cell));
+        for cell in cells(lake_cells) {
+            let lake = *bodies
+                .lakes
+                .entry(cell)
+                .or_insert_with(|| find_lake(cell, &terrain, settings.sea_level));
+            lakes.extend(lake);
+        }
+        lakes.sort_by(|a: &Lake, b: &Lake| {
+            let distance = |lake: &Lake| {
+                let center = Vec2::from(lake.center);
+                distance_to_loaded(center - lake.radius, center + lake.radius)
+            };
+            distance(a).total_cmp(&distance(b))
+        });
+        lakes.truncate(MAX_LAKES);
+    }
+
+    let mut rivers = Vec::new();
+    let mut segments = Vec::new();
+    if settings.rivers && generator.procedural_water() {
+        bodies.rivers.retain(|cell, _| river_cells.contains(*cell));
+        let mut traced = Vec::new();
+        for cell in cells(river_cells) {
+            let points = bodies
+                .rivers
+                .entry(cell)
+                .or_insert_with(|| Arc::new(trace_river(cell, &terrain, settings.sea_level)));
+            if points.is_empty() {
+                continue;
+            }
+
+            let river_segments = river_segments(points);
+            let (min, max) = river_segments.iter().fold(
+                (Vec2::INFINITY, Vec2::NEG_INFINITY),
+                |(min, max), segment| {
+                    let (start, end) = (Vec2::from(segment.start), Vec2::from(segment.end));
+                    (min.min(start).min(end), max.max(start).max(end))
+                },
+            );
+            let river = River {
+                min: (min - RIVER_CARVE_REACH).to_array(),
+                max: (max + RIVER_CARVE_REACH).to_array(),
+                first_segment: 0,
+                segment_count: river_segments.len() as u32,
+                width: 4.0 + random(cell, terrain.seed, 97) * 3.0,
+                depth: RIVER_DEPTH,
+            };
+            traced.push((distance_to_loaded(min, max), river, river_segments));
+        }
+
+        traced.sort_by(|a, b| a.0.total_cmp(&b.0));
+        for (_, mut river, river_segments) in traced.into_iter().take(MAX_RIVERS) {
+            river.first_segment = segments.len() as u32;
+            rivers.push(river);
+            segments.extend(river_segments);
+        }
+    }
+
+    bodies.data = Arc::new(WaterData::new(settings.sea_level, lakes, rivers, segments));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(start: Vec2, end: Vec2, level: f32) -> RiverSegment {
+        RiverSegment {
+            start: start.to_array(),
+            end: end.to_array(),
+            start_level: level,
+            end_level: level,
+            _pad: [0.0; 2],
+        }
+    }
+
+    /// Sea at 0, a lake at (100, 100) and a straight river along z = 0 from x = 300 to 400.
+    fn water() -> WaterData {
+        let lake = Lake {
+            center: [100.0, 100.0],
+            radius: LAKE_RADIUS,
+            level: 10.0,
+        };
+        let river = River {
+            min: [300.0 - RIVER_CARVE_REACH, -RIVER_CARVE_REACH],
+            max: [400.0 + RIVER_CARVE_REACH, RIVER_CARVE_REACH],
+            first_segment: 0,
+            segment_count: 1,
+            width: 4.0,
+            depth: RIVER_DEPTH,
+        };
+        let segments = vec![segment(Vec2::new(300.0, 0.0), Vec2::new(400.0, 0.0), 5.0)];
+        WaterData::new(Some(0.0), vec![lake], vec![river], segments)
+    }
+
+    #[test]
+    fn river_segments_follow_the_control_points() {
+        let points = [
+            (Vec2::new(0.0, 0.0), 30.0),
+            (Vec2::new(24.0, 5.0), 25.0),
+            (Vec2::new(40.0, 24.0), 20.0),
+            (Vec2::new(45.0, 48.0), 10.0),
+        ];
+        let segments = river_segments(&points);
+
+        assert_eq!(segments.len(), (points.len() - 1) * RIVER_SUBDIVISIONS);
+        assert_eq!(Vec2::from(segments[0].start), points[0].0);
+        assert_eq!(Vec2::from(segments.last().unwrap().end), points[3].0);
+        for pair in segments.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert_eq!(pair[0].end_level, pair[1].start_level);
+            assert!(pair[1].end_level <= pair[0].start_level);
+        }
+        // The spline passes through every control point.
+        for (i, point) in points.iter().enumerate().skip(1) {
+            let segment = &segments[i * RIVER_SUBDIVISIONS - 1];
+            assert_eq!(Vec2::from(segment.end), point.0);
+            assert_eq!(segment.end_level, point.1);
+        }
+    }
+
+    #[test]
+    fn lakes_are_deterministic_and_fill_their_basin() {
+        // Lakes are rare, search a few worlds for some.
+        let mut found = 0;
+        for seed in 0..8 {
+            let terrain = TerrainParams { seed, ..default() };
+            for cell in cells(IRect::new(-8, -8, 8, 8)) {
+                let lake = find_lake(cell, &terrain, None);
+                let again = find_lake(cell, &terrain, None);
+                assert_eq!(lake.is_some(), again.is_some());
+                let (Some(lake), Some(again)) = (lake, again) else {
+                    continue;
+                };
+                assert_eq!(lake.center, again.center);
+                assert_eq!(lake.level, again.level);
+
+                found += 1;
+                let center = Vec2::from(lake.center);
+                assert_eq!(lake.radius, LAKE_RADIUS);
+                assert!(lake.level > terrain_height(center, &terrain));
+                // Never reaches over the lowest point of the rim.
+                let rim = (0..16)
+                    .map(|i| {
+                        let angle = i as f32 * std::f32::consts::TAU / 16.0;
+                        terrain_height(center + Vec2::from_angle(angle) * LAKE_RADIUS, &terrain)
+                    })
+                    .fold(f32::INFINITY, f32::min);
+                assert!(lake.level < rim);
+            }
+        }
+        assert!(found > 0);
+    }
+
+    #[test]
+    fn lakes_stay_out_of_the_sea() {
+        let terrain = TerrainParams::default();
+        for cell in cells(IRect::new(-8, -8, 8, 8)) {
+            assert!(find_lake(cell, &terrain, Some(1e6)).is_none());
+        }
+    }
+
+    #[test]
+    fn columns_take_the_highest_water_and_carve_rivers() {
+        let water = water();
+
+        // Inside the lake, also in chunk columns other than the centre's.
+        assert_eq!(water.column(Vec2::new(100.0, 100.0)).level, 10.0);
+        assert_eq!(water.column(Vec2::new(120.0, 100.0)).level, 10.0);
+        assert_eq!(water.column(Vec2::new(100.0, 77.0)).level, 10.0);
+        // Just outside it only the sea is left.
+        let sea = water.column(Vec2::new(125.0, 100.0));
+        assert_eq!(sea.level, 0.0);
+        assert_eq!(sea.carve, NO_CARVE);
+
+        // On the river the bed is carved under its surface, beside it the banks slope up.
+        let river = water.column(Vec2::new(350.0, 0.0));
+        assert_eq!(river.level, 5.0);
+        assert_eq!(river.carve, 5.0 - RIVER_DEPTH);
+        let bank = water.column(Vec2::new(350.0, 10.0));
+        assert_eq!(bank.level, 0.0);
+        assert_eq!(bank.carve, 5.0 + 6.0 * RIVER_BANK_SLOPE);
+
+        let far = water.column(Vec2::new(-1000.0, 1000.0));
+        assert_eq!(far.level, 0.0);
+        assert_eq!(far.carve, NO_CARVE);
+    }
+
+    #[test]
+    fn ground_is_none_under_water() {
+        let water = water();
+
+        assert_eq!(water.ground(IVec2::new(100, 100), 8.0), None);
+        assert_eq!(water.ground(IVec2::new(100, 100), 12.0), Some(12.0));
+        assert_eq!(water.ground(IVec2::new(200, 200), -1.0), None);
+        assert_eq!(water.ground(IVec2::new(200, 200), 3.0), Some(3.0));
+        assert_eq!(water.ground(IVec2::new(350, 0), 20.0), None);
+        assert_eq!(
+            water.ground(IVec2::new(350, 10), 20.0),
+            Some(5.0 + 6.0 * RIVER_BANK_SLOPE)
+        );
+    }
+}
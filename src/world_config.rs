@@ -0,0 +1,420 @@
+//! World settings authored in a RON file, applied when it loads and every time it is
+//! edited while the game runs.
+
+use std::{collections::HashMap, io, path::Path, sync::Arc};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, io::Reader},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    biome::{Biome, BiomeParams, BiomeTable},
+    chunk_queue::ChunkBudget,
+    chunks_partition::{CHUNK_COUNT, CHUNK_EXTENT_XZ, CHUNK_EXTENT_Y, LoadShape},
+    decoration::MAX_STRUCTURE_REACH,
+    height_map::{HeightMapSettings, HeightMapTerrain},
+    schematic::{DecorationStructures, Schematic, StructureSpawn},
+    terrain_generator::{
+        ActiveTerrainGenerator, ChunkMesher, CpuDensityGenerator, CpuHeightMapGenerator,
+        GpuDensityGenerator, GpuHeightMapGenerator, NoiseOctaves, TerrainParams,
+    },
+    water::WaterSettings,
+};
+
+/// Procedural generator of `terrain_generator.rs` the world is built with.
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum GeneratorKind {
+    GpuHeightMap,
+    CpuHeightMap,
+    GpuDensity { threshold: f32 },
+    CpuDensity { threshold: f32 },
+}
+
+impl GeneratorKind {
+    pub fn generator(self) -> ActiveTerrainGenerator {
+        match self {
+            GeneratorKind::GpuHeightMap => ActiveTerrainGenerator::new(GpuHeightMapGenerator),
+            GeneratorKind::CpuHeightMap => ActiveTerrainGenerator::new(CpuHeightMapGenerator),
+            GeneratorKind::GpuDensity { threshold } => {
+                ActiveTerrainGenerator::new(GpuDensityGenerator { threshold })
+            }
+            GeneratorKind::CpuDensity { threshold } => {
+                ActiveTerrainGenerator::new(CpuDensityGenerator { threshold })
+            }
+        }
+    }
+}
+
+/// Heightmap image the world is generated from instead of `generator`.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct HeightMapConfig {
+    /// Path of the `.height.png` or `.r16` file, relative to the assets folder.
+    pub path: String,
+    #[serde(default)]
+    pub settings: HeightMapSettings,
+    pub mesher: ChunkMesher,
+}
+
+/// Shape of the region loaded around the cameras, see [`LoadShape`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum ViewShape {
+    /// `view_distance` chunks along the horizontal axes, [`CHUNK_EXTENT_Y`] up and down.
+    Box,
+    /// `view_distance` chunks around horizontally, [`CHUNK_EXTENT_Y`] up and down.
+    #[default]
+    Cylinder,
+    /// `view_distance` chunks around.
+    Sphere,
+}
+
+/// Schematic the decoration stage scatters over the surface, its origin on the first
+/// voxel above the ground.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct StructureConfig {
+    /// Path of the `.schematic` file, relative to the assets folder.
+    pub path: String,
+    /// Expected number per chunk column.
+    pub per_chunk: f32,
+    /// Biomes it is placed in, any when empty.
+    #[serde(default)]
+    pub biomes: Vec<Biome>,
+}
+
+#[derive(Asset, TypePath, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WorldConfig {
+    pub seed: u32,
+    pub generator: GeneratorKind,
+    /// Replaces `generator` once the image has loaded.
+    pub height_map: Option<HeightMapConfig>,
+    pub octaves: NoiseOctaves,
+    /// `None` for a world without a sea.
+    pub sea_level: Option<f32>,
+    /// Biomes that differ from [`BiomeTable::default`].
+    pub biomes: HashMap<Biome, BiomeParams>,
+    /// Radius of the loaded region around the cameras, in chunks, at most
+    /// [`CHUNK_EXTENT_XZ`] since the GPU buffers have no slots for more.
+    pub view_distance: i32,
+    pub view_shape: ViewShape,
+    /// Chunks generated and meshed per frame.
+    pub chunk_budget: ChunkBudget,
+    pub structures: Vec<StructureConfig>,
+}
+
+impl Default for WorldConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            generator: GeneratorKind::GpuDensity { threshold: 0.0 },
+            height_map: None,
+            octaves: NoiseOctaves::default(),
+            sea_level: Some(0.0),
+            biomes: HashMap::new(),
+            view_distance: CHUNK_EXTENT_XZ,
+            view_shape: ViewShape::Cylinder,
+            chunk_budget: ChunkBudget::default(),
+            structures: Vec::new(),
+        }
+    }
+}
+
+impl WorldConfig {
+    pub fn terrain(&self) -> TerrainParams {
+        let mut biomes = BiomeTable::default();
+        for (biome, params) in &self.biomes {
+            *biomes.get_mut(*biome) = *params;
+        }
+        TerrainParams {
+            seed: self.seed,
+            octaves: self.octaves,
+            biomes,
+        }
+    }
+
+    pub fn load_shape(&self) -> LoadShape {
+        let radius = self.view_distance;
+        match self.view_shape {
+            ViewShape::Box => LoadShape::Box {
+                extent_xz: radius,
+                extent_y: CHUNK_EXTENT_Y,
+            },
+            ViewShape::Cylinder => LoadShape::Cylinder {
+                radius,
+                extent_y: CHUNK_EXTENT_Y,
+            },
+            ViewShape::Sphere => LoadShape::Sphere { radius },
+        }
+    }
+
+    /// Clamps the settings the world can't be built with, warning about each.
+    fn validate(&mut self, path: &Path) {
+        let view_distance = self.view_distance.clamp(1, CHUNK_EXTENT_XZ);
+        if view_distance != self.view_distance {
+            warn!(
+                "{}: view_distance {} is outside 1..={CHUNK_EXTENT_XZ}, using {view_distance}",
+                path.display(),
+                self.view_distance
+            );
+            self.view_distance = view_distance;
+        }
+
+        let shape = self.load_shape();
+        if !shape.fits_budget() {
+            warn!(
+                "{}: {:?} needs {} chunk slots, only {CHUNK_COUNT} exist, using a cylinder",
+                path.display(),
+                shape,
+                shape.chunk_count()
+            );
+            self.view_shape = ViewShape::Cylinder;
+        }
+    }
+}
+
+/// Loads `.world.ron` files as [`WorldConfig`]s.
+#[derive(Default, TypePath)]
+pub struct WorldConfigLoader;
+
+impl AssetLoader for WorldConfigLoader {
+    type Asset = WorldConfig;
+    type Settings = ();
+    type Error = io::Error;
+
+    async fn load(
+        &self,
+        reader: &mut dyn Reader,
+        _settings: &(),
+        load_context: &mut LoadContext<'_>,
+    ) -> Result<WorldConfig, io::Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).await?;
+        let mut config: WorldConfig = ron::de::from_bytes(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        config.validate(load_context.path());
+        Ok(config)
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["world.ron"]
+    }
+}
+
+/// Config the world is generated from.
+#[derive(Resource, Clone, Deref)]
+pub struct WorldConfigHandle(pub Handle<WorldConfig>);
+
+/// Schematics of the config's structures.
+#[derive(Resource, Default)]
+struct StructureHandles(Vec<(Handle<Schematic>, StructureConfig)>);
+
+pub struct WorldConfigPlugin {
+    pub path: &'static str,
+}
+
+impl Default for WorldConfigPlugin {
+    fn default() -> Self {
+        Self {
+            path: "default.world.ron",
+        }
+    }
+}
+
+impl Plugin for WorldConfigPlugin {
+    fn build(&self, app: &mut App) {
+        let path = self.path;
+        app.init_asset::<WorldConfig>()
+            .register_asset_loader(WorldConfigLoader)
+            .init_resource::<StructureHandles>()
+            .add_systems(
+                Startup,
+                move |mut commands: Commands, assets: Res<AssetServer>| {
+                    commands.insert_resource(WorldConfigHandle(assets.load(path)));
+                },
+            )
+            .add_systems(
+                Update,
+                (apply_world_config, collect_decoration_structures).chain(),
+            );
+    }
+}
+
+/// Copies the config into the resources the world is generated from when it loads or
+/// is edited. Only what changed is touched, so `regenerate_on_generator_change` only
+/// regenerates the chunks when the terrain actually differs.
+#[allow(clippy::too_many_arguments)]
+fn apply_world_config(
+    mut commands: Commands,
+    assets: Res<AssetServer>,
+    handle: Option<Res<WorldConfigHandle>>,
+    configs: Res<Assets<WorldConfig>>,
+    mut events: MessageReader<AssetEvent<WorldConfig>>,
+    mut terrain: ResMut<TerrainParams>,
+    mut water: ResMut<WaterSettings>,
+    mut shape: ResMut<LoadShape>,
+    mut budget: ResMut<ChunkBudget>,
+    mut structures: ResMut<StructureHandles>,
+    height_map_terrain: Option<Res<HeightMapTerrain>>,
+    mut generator: Local<Option<GeneratorKind>>,
+) {
+    let Some(handle) = handle else {
+        return;
+    };
+
+    let reloaded = events.read().any(|event| match event {
+        AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => {
+            *id == handle.id()
+        }
+        _ => false,
+    });
+    if !reloaded {
+        return;
+    }
+
+    let Some(config) = configs.get(&handle.0) else {
+        return;
+    };
+
+    terrain.set_if_neq(config.terrain());
+    water.set_if_neq(WaterSettings {
+        sea_level: config.sea_level,
+        ..*water
+    });
+    shape.set_if_neq(config.load_shape());
+    budget.set_if_neq(config.chunk_budget);
+    match &config.height_map {
+        Some(height_map) => {
+            // `use_height_map_terrain` switches generators once the image has loaded.
+            *generator = None;
+            let terrain = HeightMapTerrain {
+                map: assets.load(&height_map.path),
+                settings: height_map.settings,
+                mesher: height_map.mesher,
+            };
+            if height_map_terrain.as_deref() != Some(&terrain) {
+                commands.insert_resource(terrain);
+            }
+        }
+        None => {
+            if height_map_terrain.is_some() {
+                commands.remove_resource::<HeightMapTerrain>();
+            }
+            if *generator != Some(config.generator) {
+                *generator = Some(config.generator);
+                commands.insert_resource(config.generator.generator());
+            }
+        }
+    }
+    if structures
+        .0
+        .iter()
+        .map(|(_, structure)| structure)
+        .ne(&config.structures)
+    {
+        structures.0 = config
+            .structures
+            .iter()
+            .map(|structure| (assets.load(&structure.path), structure.clone()))
+            .collect();
+    }
+}
+
+/// Hands the structures to the decoration stage once none of their schematics is still
+/// loading, and again whenever one of them is edited.
+fn collect_decoration_structures(
+    assets: Res<AssetServer>,
+    schematics: Res<Assets<Schematic>>,
+    mut events: MessageReader<AssetEvent<Schematic>>,
+    handles: Res<StructureHandles>,
+    mut structures: ResMut<DecorationStructures>,
+    mut dirty: Local<bool>,
+) {
+    let reloaded = events.read().any(|event| match event {
+        AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => {
+            handles.0.iter().any(|(handle, _)| handle.id() == *id)
+        }
+        _ => false,
+    });
+    *dirty |= reloaded || handles.is_changed();
+    if !*dirty
+        || handles
+            .0
+            .iter()
+            .any(|(handle, _)| assets.load_state(handle).is_loading())
+    {
+        return;
+    }
+    *dirty = false;
+
+    // Failed schematics were already reported by the asset server.
+    let spawns = handles
+        .0
+        .iter()
+        .filter_map(|(handle, config)| {
+            let schematic = schematics.get(handle)?;
+            if schematic.reach() > MAX_STRUCTURE_REACH {
+                warn!(
+                    "{} reaches {} blocks from its origin, more than {MAX_STRUCTURE_REACH}",
+                    config.path,
+                    schematic.reach()
+                );
+                return None;
+            }
+            Some(StructureSpawn {
+                schematic: Arc::new(schematic.clone()),
+                per_chunk: config.per_chunk,
+                biomes: config.biomes.clone(),
+            })
+        })
+        .collect();
+    structures.set_if_neq(DecorationStructures(Arc::new(spawns)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_default_world_loads() {
+        let mut config: WorldConfig =
+            ron::de::from_bytes(include_bytes!("../assets/default.world.ron")).unwrap();
+        let parsed = config.clone();
+        config.validate(Path::new("default.world.ron"));
+        assert_eq!(config, parsed);
+        assert_eq!(config.terrain(), WorldConfig::default().terrain());
+        assert_eq!(config.load_shape(), LoadShape::default());
+    }
+
+    #[test]
+    fn validate_clamps_the_view_distance() {
+        for (view_distance, clamped) in [(0, 1), (3, 3), (100, CHUNK_EXTENT_XZ)] {
+            let mut config = WorldConfig {
+                view_distance,
+                ..default()
+            };
+            config.validate(Path::new("test.world.ron"));
+            assert_eq!(config.view_distance, clamped);
+        }
+    }
+
+    #[test]
+    fn validate_falls_back_to_a_cylinder_past_the_budget() {
+        let mut config = WorldConfig {
+            view_shape: ViewShape::Box,
+            ..default()
+        };
+        config.validate(Path::new("test.world.ron"));
+        assert_eq!(config.view_shape, ViewShape::Cylinder);
+
+        // A box of a shorter view distance fits.
+        let mut config = WorldConfig {
+            view_shape: ViewShape::Box,
+            view_distance: 4,
+            ..default()
+        };
+        config.validate(Path::new("test.world.ron"));
+        assert_eq!(config.view_shape, ViewShape::Box);
+        assert!(config.load_shape().fits_budget());
+    }
+}